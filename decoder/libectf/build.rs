@@ -0,0 +1,39 @@
+//! This build script generates the mask table used by `MASKS` in `masks.rs`. The table can be tuned
+//! with the `ECTF_MASKS` environment variable, a comma separated list of mask widths like
+//! `ECTF_MASKS=0,3,6,9`. More mask widths means encoded packets are larger and subscriptions are
+//! smaller, and less mask widths means vice versa.
+
+use std::{env, fs, path::PathBuf};
+
+const DEFAULT_MASKS: &str = "0,3,6,9,12,15,18,21,24,27,30,33,36,39,42,45,48,51,54,57,60";
+
+/// Size of each subscription key, `KEY_SIZE_BYTES` in `key.rs`.
+const KEY_SIZE: u128 = 16;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=ECTF_MASKS");
+
+    let masks = env::var("ECTF_MASKS").unwrap_or(DEFAULT_MASKS.to_string());
+    let masks: Vec<u8> = masks.split(',')
+        .map(|m| m.trim().parse().unwrap_or_else(|_| panic!("ECTF_MASKS: invalid mask width {:?}", m)))
+        .collect();
+
+    // The smallest mask has to cover single timestamps, each mask has to be wider than the last,
+    // and none of them can cover the whole 64 bit timestamp range.
+    assert_eq!(masks.first(), Some(&0), "ECTF_MASKS must start at 0");
+    assert!(masks.windows(2).all(|w| w[0] < w[1]), "ECTF_MASKS must be strictly increasing");
+    assert!(masks.iter().all(|&m| m < 64), "ECTF_MASKS must all be under 64");
+
+    // `MAX_BITRANGES` is computed as a usize on the target, and subscriptions that big have to be
+    // addressable, so make sure it and the keys for it fit.
+    let pointer_width: u32 = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap().parse().unwrap();
+    let max_size = (1u128 << pointer_width) - 1;
+    let shifts = masks.windows(2).map(|w| (w[1] - w[0]) as u32).chain([64 - *masks.last().unwrap() as u32]);
+    assert!(shifts.clone().all(|s| s < pointer_width), "ECTF_MASKS has a gap of {} bits or more between widths, or its widest mask is too narrow", pointer_width);
+    let max_bitranges = (1u128 << (64 - *masks.last().unwrap() as u32))
+        + masks.windows(2).map(|w| 2 * ((1u128 << (w[1] - w[0])) - 1)).sum::<u128>();
+    assert!(max_bitranges * KEY_SIZE <= max_size, "ECTF_MASKS allows subscriptions with {} keys, which is too many for a {} bit target", max_bitranges, pointer_width);
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("masks.rs"), format!("{:?}", masks)).unwrap();
+}
@@ -1,27 +1,126 @@
-use core::fmt::Debug;
+use core::{fmt::{Debug, Display}, mem::{align_of, offset_of, size_of}};
 
-use rkyv::{Archive, Deserialize, Serialize};
-use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs1v15::SigningKey, signature::SignerMut};
+use rkyv::{ser::{Positional, Writer, WriterExt}, Archive, Deserialize, Serialize};
+use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs1v15::{Signature, SigningKey, VerifyingKey}, signature::{self, Signer, Verifier}};
 
 use alloc::boxed::Box;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 
-use crate::{key::Key, masks::MASKS};
+#[cfg(feature = "aead")]
+use crate::key::{NONCE_SIZE, TAG_SIZE};
+use crate::{key::{ArchivedKey, Cipher, Key, KEY_SIZE_BYTES}, masks::{block_span, MASKS}};
 
-/// Size of each frame in bytes.
+/// Size of each frame in bytes. This is the only place the frame size is defined, the encoder and
+/// decoder both use it from here. Frames are encrypted with AES, so it has to be a multiple of 16.
 pub const FRAME_SIZE: usize = 64;
 
+const _: () = assert!(FRAME_SIZE.is_multiple_of(16), "FRAME_SIZE must be a whole number of AES blocks");
+const _: () = assert!(FRAME_SIZE <= u8::MAX as usize, "payload lengths are sent as a u8");
+
+/// Payload length of a frame that's all payload.
+pub const FULL_FRAME_LENGTH: u8 = FRAME_SIZE as u8;
+
 /// The number of encrypted frames in an encoded frame packet.
 pub const NUM_ENCRYPTED_KEYS: usize = MASKS.len();
 
+/// Size in bits of the RSA key frames are signed with. This is fixed at compile time so the
+/// decoder knows how big a frame packet is. Enable the `rsa-2048` feature for 2048-bit keys.
+#[cfg(not(feature = "rsa-2048"))]
+pub const RSA_KEY_BITS: usize = 1024;
+#[cfg(feature = "rsa-2048")]
+pub const RSA_KEY_BITS: usize = 2048;
+
+/// Smallest RSA key we will sign frames with. Secrets with a smaller key are refused, even if a
+/// build was configured for one.
+pub const MIN_RSA_KEY_BITS: usize = 1024;
+
+const _: () = assert!(RSA_KEY_BITS >= MIN_RSA_KEY_BITS, "RSA_KEY_BITS is below MIN_RSA_KEY_BITS");
+
+/// Size of a frame's signature in bytes.
+pub const SIGNATURE_SIZE: usize = RSA_KEY_BITS / 8;
+
+/// Size of the data that is authenticated along with each frame: the timestamp, channel, and
+/// payload length.
+pub const ASSOCIATED_DATA_SIZE: usize = 8 + 4 + 1;
+
+/// Size of the message that is signed for each frame: the associated data and frame contents.
+pub const SIGNED_MESSAGE_SIZE: usize = ASSOCIATED_DATA_SIZE + FRAME_SIZE;
+
+/// Version of the encoded frame packet format. Bump it whenever the packet changes, so decoders
+/// reject packets they would otherwise misread. Packets with sealed frames (the `aead` feature)
+/// are version 6. Versions 1 and 2 derived keys from the secrets without domain separation (see
+/// [`crate::key::derive_secret`]), and versions 3 and 4 had no payload length.
+#[cfg(not(feature = "aead"))]
+pub const FRAME_FORMAT_VERSION: u8 = 5;
+#[cfg(feature = "aead")]
+pub const FRAME_FORMAT_VERSION: u8 = 6;
+
+/// Nonce frames are sealed with. Every frame key belongs to a single timestamp and channel, so
+/// the nonce doesn't need to change to be unique under a key.
+#[cfg(feature = "aead")]
+pub const FRAME_NONCE: [u8; NONCE_SIZE] = [0; NONCE_SIZE];
+
+/// Size of the prefix in front of every encoded frame packet: the format version, three zero
+/// bytes, and the length of the rest of the packet as a u32. The padding keeps the archived packet
+/// after it aligned.
+pub const FRAME_PREFIX_SIZE: usize = 8;
+
+/// Hash of everything an encoder and decoder have to agree on to read each other's packets: the
+/// frame size, the mask widths, the key size, and the signature size. A decoder reports it in
+/// VERSION, so a host built with a different configuration can refuse to talk to it. Use
+/// [`compat_hash`] for this build's.
+pub fn compat_hash_for(frame_size: usize, masks: &[u8], key_size: usize, signature_size: usize) -> u32 {
+    let mut hasher = Sha256::new();
+    hasher.update((frame_size as u32).to_le_bytes());
+    hasher.update((masks.len() as u32).to_le_bytes());
+    hasher.update(masks);
+    hasher.update((key_size as u32).to_le_bytes());
+    hasher.update((signature_size as u32).to_le_bytes());
+    u32::from_le_bytes(hasher.finalize()[..4].try_into().unwrap())
+}
+
+/// [`compat_hash_for`] this build's [`FRAME_SIZE`], [`MASKS`], key size, and [`SIGNATURE_SIZE`].
+pub fn compat_hash() -> u32 {
+    compat_hash_for(FRAME_SIZE, MASKS, KEY_SIZE_BYTES, SIGNATURE_SIZE)
+}
+
+/// The prefix for an encoded frame packet with `length` bytes after the prefix.
+pub fn frame_prefix(length: u32) -> [u8; FRAME_PREFIX_SIZE] {
+    let mut prefix = [0u8; FRAME_PREFIX_SIZE];
+    prefix[0] = FRAME_FORMAT_VERSION;
+    prefix[4..].copy_from_slice(&length.to_le_bytes());
+    prefix
+}
+
+/// Whether frames on `channel` are signed. With the `aead` feature only channel 0 frames are,
+/// since every decoder has the channel 0 keys and could forge a sealed one. Frames on other
+/// channels are sealed with AES-GCM under their frame key, and the tag takes the start of the
+/// signature with the rest left zero.
+pub fn is_signed(channel: u32) -> bool {
+    channel == 0 || !cfg!(feature = "aead")
+}
+
+/// The `(format_version, length)` from the prefix of an encoded frame packet, or `None` if there
+/// aren't enough bytes for one.
+pub fn parse_frame_prefix(bytes: &[u8]) -> Option<(u8, u32)> {
+    let prefix = bytes.get(..FRAME_PREFIX_SIZE)?;
+    Some((prefix[0], u32::from_le_bytes(prefix[4..].try_into().unwrap())))
+}
+
 #[derive(Archive, Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct Frame(pub [u8; FRAME_SIZE]);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Frame(#[cfg_attr(feature = "serde", serde(with = "crate::serde_hex"))] pub [u8; FRAME_SIZE]);
 
 #[derive(Debug, Archive, Serialize, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EncodedFramePacketHeader {
     pub timestamp: u64,
     pub channel: u32,
-    pub signature: [u8; 128],
+    /// How many bytes at the start of the frame are payload, see [`Frame::from_payload`]. The
+    /// rest is padding the decoder doesn't output.
+    pub length: u8,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_hex"))]
+    pub signature: [u8; SIGNATURE_SIZE],
     pub frame: Frame,
 }
 
@@ -32,46 +131,313 @@ pub struct EncodedFramePacket {
     pub keys: [Key; NUM_ENCRYPTED_KEYS],
 }
 
-impl Frame {
-    pub fn encode(&self, timestamp: u64, channel: u32, secrets: &[u8]) -> EncodedFramePacket {
-        let mut signing_key = SigningKey::<Sha256>::from_pkcs1_der(secrets).unwrap();
-        let signature: Box<[u8]> = signing_key.sign(&self.0).try_into().unwrap();
+const _: () = assert!(FRAME_PREFIX_SIZE.is_multiple_of(align_of::<ArchivedEncodedFramePacket>()));
 
-        let frame_key = Key::for_frame(timestamp, channel, secrets);
-        let mut encrypted_frame = self.clone();
-        frame_key.cipher().encrypt_frame(&mut encrypted_frame);
+/// Size of an encoded frame packet as it's sent to the decoder, [`frame_prefix`] included. Every
+/// packet is this size, and it changes with [`NUM_ENCRYPTED_KEYS`] and [`SIGNATURE_SIZE`], so
+/// tools built with a different configuration than the decoder disagree on it.
+pub const ENCODED_FRAME_PACKET_SIZE: usize = FRAME_PREFIX_SIZE + size_of::<ArchivedEncodedFramePacket>();
+
+/// Reasons bytes can fail to parse as an encoded frame packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// There aren't enough bytes for the [`frame_prefix`].
+    MissingPrefix,
+    /// The prefix is for a format version other than [`FRAME_FORMAT_VERSION`].
+    UnsupportedVersion(u8),
+    /// The packet, or the length in its prefix, isn't the size every encoded frame packet is.
+    WrongSize { expected: usize, actual: usize },
+    /// The packet isn't aligned for an archived packet, so it can't be read in place.
+    Misaligned,
+}
 
-        let mut data: [Key; NUM_ENCRYPTED_KEYS] = core::array::from_fn(|_| frame_key.clone());
+/// Checks the prefix and size of an encoded frame packet, returning the packet after the prefix.
+fn check_frame_packet(bytes: &[u8]) -> Result<&[u8], ParseError> {
+    let (version, length) = parse_frame_prefix(bytes).ok_or(ParseError::MissingPrefix)?;
+    if version != FRAME_FORMAT_VERSION {
+        return Err(ParseError::UnsupportedVersion(version));
+    }
+
+    let expected = size_of::<ArchivedEncodedFramePacket>();
+    let body = &bytes[FRAME_PREFIX_SIZE..];
+    for actual in [length as usize, body.len()] {
+        if actual != expected {
+            return Err(ParseError::WrongSize { expected, actual });
+        }
+    }
+    Ok(body)
+}
+
+impl EncodedFramePacket {
+    /// Parses a packet written by [`Frame::encode_into`], prefix included. The bytes don't have to
+    /// be aligned, since each field is copied out of them.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let body = check_frame_packet(bytes)?;
+        let field = |offset: usize, len: usize| &body[offset..offset + len];
+
+        let header = offset_of!(ArchivedEncodedFramePacket, header);
+        let keys = offset_of!(ArchivedEncodedFramePacket, keys);
+        Ok(EncodedFramePacket {
+            header: EncodedFramePacketHeader {
+                timestamp: u64::from_le_bytes(field(header + offset_of!(ArchivedEncodedFramePacketHeader, timestamp), 8).try_into().unwrap()),
+                channel: u32::from_le_bytes(field(header + offset_of!(ArchivedEncodedFramePacketHeader, channel), 4).try_into().unwrap()),
+                length: field(header + offset_of!(ArchivedEncodedFramePacketHeader, length), 1)[0],
+                signature: field(header + offset_of!(ArchivedEncodedFramePacketHeader, signature), SIGNATURE_SIZE).try_into().unwrap(),
+                frame: Frame(field(header + offset_of!(ArchivedEncodedFramePacketHeader, frame), FRAME_SIZE).try_into().unwrap()),
+            },
+            keys: core::array::from_fn(|i| Key(field(keys + i * size_of::<ArchivedKey>(), size_of::<ArchivedKey>()).try_into().unwrap())),
+        })
+    }
+}
+
+impl ArchivedEncodedFramePacket {
+    /// Reads a packet written by [`Frame::encode_into`] in place, prefix included. Unlike
+    /// `rkyv::access_unchecked` this checks the packet's size and alignment first, so any bytes
+    /// are safe to pass in.
+    pub fn from_bytes(bytes: &[u8]) -> Result<&Self, ParseError> {
+        let body = check_frame_packet(bytes)?;
+        if !body.as_ptr().cast::<Self>().is_aligned() {
+            return Err(ParseError::Misaligned);
+        }
 
-        // Loop through every possible mask and encrypt the frame with the key for the bitrange
-        // that contains this frame.
-        for (mask_idx, mask) in MASKS.iter().enumerate() {
-            let key = Key::for_bitrange(timestamp & !((1 << *mask as u64) - 1), mask_idx as u8, channel, secrets);
-            key.cipher().encrypt(&mut data[mask_idx].0);
+        // Safety: the body is exactly the size of a packet and aligned for one. The packet is only
+        // integers and byte arrays, with no relative pointers, so any bytes are a valid packet.
+        Ok(unsafe { &*body.as_ptr().cast::<Self>() })
+    }
+}
+
+/// Reasons an encoded frame packet can fail to decode.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The frame's signature couldn't be parsed.
+    InvalidSignature(signature::Error),
+    /// The frame's signature, or its tag if it was sealed, doesn't match the frame.
+    BadSignature,
+    /// The payload length is longer than a frame.
+    BadLength(u8),
+}
+
+impl ArchivedEncodedFramePacket {
+    /// Decrypts the frame key for mask `mask_idx` with `subscription_key`, the decrypted
+    /// subscription key for the frame's bitrange of that mask, and then the frame with it. The
+    /// frame is checked against its signature, or its tag if it was sealed (see [`is_signed`]),
+    /// before it's returned. Only the first [`ArchivedEncodedFramePacketHeader::length`] bytes of
+    /// it are payload, which [`Frame::payload`] gives.
+    pub fn decode(&self, subscription_key: &Key, mask_idx: u8, verifying_key: &VerifyingKey<Sha256>) -> Result<Frame, DecodeError> {
+        self.decode_with_cipher(&mut subscription_key.cipher(), mask_idx, verifying_key)
+    }
+
+    /// [`ArchivedEncodedFramePacket::decode`] with a [`Cipher`] for the subscription key, so a
+    /// decoder can keep one around for the frames in the same bitrange instead of running the AES
+    /// key schedule for it on every frame.
+    pub fn decode_with_cipher(&self, subscription_cipher: &mut Cipher, mask_idx: u8, verifying_key: &VerifyingKey<Sha256>) -> Result<Frame, DecodeError> {
+        let mut frame_key = self.keys[mask_idx as usize].0;
+        subscription_cipher.decrypt(&mut frame_key);
+
+        let mut f = self.header.frame.0;
+        let (timestamp, channel, length) = (self.header.timestamp(), self.header.channel(), self.header.length);
+        if length as usize > FRAME_SIZE {
+            return Err(DecodeError::BadLength(length));
+        }
+        if !is_signed(channel) {
+            #[cfg(feature = "aead")]
+            Key(frame_key).cipher().open_frame(&mut f, timestamp, channel, length, self.header.tag())
+                .map_err(|_| DecodeError::BadSignature)?;
+            return Ok(Frame(f));
         }
+        Key(frame_key).cipher().decrypt(&mut f);
+
+        // Verify that the signature matches the decrypted frame and the header it was sent with
+        let signature = Signature::try_from(self.header.signature.as_slice()).map_err(DecodeError::InvalidSignature)?;
+        let frame = Frame(f);
+        verifying_key.verify(&frame.signed_message(timestamp, channel, length), &signature).map_err(|_| DecodeError::BadSignature)?;
+        Ok(frame)
+    }
+}
+
+impl ArchivedEncodedFramePacketHeader {
+    /// When the frame was encoded.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp.to_native()
+    }
+
+    /// The channel the frame was sent on.
+    pub fn channel(&self) -> u32 {
+        self.channel.to_native()
+    }
+
+    /// How many bytes of the frame are payload.
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    /// The AES-GCM tag of a sealed frame. See [`is_signed`].
+    #[cfg(feature = "aead")]
+    pub fn tag(&self) -> &[u8; TAG_SIZE] {
+        self.signature[..TAG_SIZE].try_into().unwrap()
+    }
+}
+
+impl Frame {
+    /// A frame holding `payload`, zero padded if it's short and truncated if it's long. Encode it
+    /// with the length of the payload, `payload.len().min(FRAME_SIZE)`, so the decoder only
+    /// outputs the payload.
+    pub fn from_payload(payload: &[u8]) -> Frame {
+        let mut frame = [0u8; FRAME_SIZE];
+        let len = payload.len().min(FRAME_SIZE);
+        frame[..len].copy_from_slice(&payload[..len]);
+        Frame(frame)
+    }
+
+    /// The first `len` bytes of the frame, which are the payload of a frame sent with that length.
+    /// Panics if `len` is longer than a frame.
+    pub fn payload(&self, len: usize) -> &[u8] {
+        &self.0[..len]
+    }
+
+    /// The header fields that are authenticated along with a frame, so that it can't be replayed
+    /// under a different header or have its payload cut short.
+    pub fn associated_data(timestamp: u64, channel: u32, length: u8) -> [u8; ASSOCIATED_DATA_SIZE] {
+        let mut data = [0u8; ASSOCIATED_DATA_SIZE];
+        data[..8].copy_from_slice(&timestamp.to_le_bytes());
+        data[8..12].copy_from_slice(&channel.to_le_bytes());
+        data[12] = length;
+        data
+    }
+
+    /// The message that is signed for this frame: its [`Frame::associated_data`] followed by the
+    /// frame.
+    pub fn signed_message(&self, timestamp: u64, channel: u32, length: u8) -> [u8; SIGNED_MESSAGE_SIZE] {
+        let mut message = [0u8; SIGNED_MESSAGE_SIZE];
+        message[..ASSOCIATED_DATA_SIZE].copy_from_slice(&Self::associated_data(timestamp, channel, length));
+        message[ASSOCIATED_DATA_SIZE..].copy_from_slice(&self.0);
+        message
+    }
+
+    /// Encode a frame whose first `length` bytes are payload. Pass [`FULL_FRAME_LENGTH`] for a
+    /// full frame.
+    pub fn encode(&self, timestamp: u64, channel: u32, length: u8, secrets: &[u8]) -> EncodedFramePacket {
+        let signing_key = SigningKey::<Sha256>::from_pkcs1_der(secrets).unwrap();
+        self.encode_with_key(timestamp, channel, length, secrets, &signing_key)
+    }
+
+    /// Same as [`Frame::encode`], but with the signing key from `secrets` already parsed, so that
+    /// encoding many frames doesn't parse it again for each one.
+    pub fn encode_with_key(&self, timestamp: u64, channel: u32, length: u8, secrets: &[u8], signing_key: &SigningKey<Sha256>) -> EncodedFramePacket {
+        let (signature, frame_key, encrypted_frame) = self.sign_and_encrypt(timestamp, channel, length, secrets, signing_key);
 
         EncodedFramePacket {
             header: EncodedFramePacketHeader {
                 channel,
                 timestamp,
-                signature: signature.to_vec().try_into().unwrap(),
+                length,
+                signature,
                 frame: encrypted_frame
             },
-            keys: data,
+            keys: core::array::from_fn(|mask_idx| encrypted_frame_key(&frame_key, timestamp, channel, mask_idx, secrets)),
+        }
+    }
+
+    /// Writes the packet that is sent to the decoder: the [`frame_prefix`] followed by the same
+    /// bytes as serializing [`Frame::encode_with_key`] with rkyv. The packet is written one field
+    /// at a time, so it's never built in memory, and keys are encrypted as they're written.
+    pub fn encode_into<W: Writer<E> + ?Sized, E>(&self, timestamp: u64, channel: u32, length: u8, secrets: &[u8], signing_key: &SigningKey<Sha256>, writer: &mut W) -> Result<(), E> {
+        let (signature, frame_key, encrypted_frame) = self.sign_and_encrypt(timestamp, channel, length, secrets, signing_key);
+
+        // The prefix is a multiple of the packet's alignment, so the packet is aligned after it
+        writer.align_for::<ArchivedEncodedFramePacket>()?;
+        writer.write(&frame_prefix(size_of::<ArchivedEncodedFramePacket>() as u32))?;
+
+        // The archived packet has no relative pointers, so it's just its fields at their offsets
+        let start = writer.pos();
+        let mut write_at = |offset: usize, bytes: &[u8]| {
+            writer.pad(start + offset - writer.pos())?;
+            writer.write(bytes)
+        };
+
+        let header = offset_of!(ArchivedEncodedFramePacket, header);
+        write_at(header + offset_of!(ArchivedEncodedFramePacketHeader, timestamp), &timestamp.to_le_bytes())?;
+        write_at(header + offset_of!(ArchivedEncodedFramePacketHeader, channel), &channel.to_le_bytes())?;
+        write_at(header + offset_of!(ArchivedEncodedFramePacketHeader, length), &[length])?;
+        write_at(header + offset_of!(ArchivedEncodedFramePacketHeader, signature), &signature)?;
+        write_at(header + offset_of!(ArchivedEncodedFramePacketHeader, frame), &encrypted_frame.0)?;
+
+        let keys = offset_of!(ArchivedEncodedFramePacket, keys);
+        for mask_idx in 0..NUM_ENCRYPTED_KEYS {
+            let key = encrypted_frame_key(&frame_key, timestamp, channel, mask_idx, secrets);
+            write_at(keys + mask_idx * size_of::<ArchivedKey>(), &key.0)?;
+        }
+
+        // Trailing padding
+        write_at(size_of::<ArchivedEncodedFramePacket>(), &[])
+    }
+
+    /// Signs the frame and encrypts it with its frame key, or seals it if it isn't signed (see
+    /// [`is_signed`]). Returns the signature, frame key, and encrypted frame.
+    fn sign_and_encrypt(&self, timestamp: u64, channel: u32, length: u8, secrets: &[u8], signing_key: &SigningKey<Sha256>) -> ([u8; SIGNATURE_SIZE], Key, Frame) {
+        let frame_key = Key::for_frame(timestamp, channel, secrets);
+        let mut encrypted_frame = self.clone();
+        let mut signature = [0u8; SIGNATURE_SIZE];
+
+        if is_signed(channel) {
+            let s: Box<[u8]> = signing_key.sign(&self.signed_message(timestamp, channel, length)).into();
+            signature.copy_from_slice(&s);
+            frame_key.cipher().encrypt_frame(&mut encrypted_frame);
+        } else {
+            #[cfg(feature = "aead")]
+            signature[..TAG_SIZE].copy_from_slice(&frame_key.cipher().seal_frame(&mut encrypted_frame, timestamp, channel, length));
+        }
+
+        (signature, frame_key, encrypted_frame)
+    }
+}
+
+/// Encrypts the frame key with the key for the bitrange of mask `mask_idx` that contains this
+/// frame. A packet has one of these for every possible mask.
+fn encrypted_frame_key(frame_key: &Key, timestamp: u64, channel: u32, mask_idx: usize, secrets: &[u8]) -> Key {
+    let key = Key::for_bitrange(timestamp & !block_span(MASKS[mask_idx]), mask_idx as u8, channel, secrets);
+
+    let mut encrypted_key = frame_key.0;
+    key.cipher().encrypt(&mut encrypted_key);
+    Key(encrypted_key)
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::MissingPrefix => write!(f, "frame packet is too short for its prefix"),
+            ParseError::UnsupportedVersion(v) => write!(f, "unsupported frame format version {}", v),
+            ParseError::WrongSize { expected, actual } => write!(f, "frame packet is {} bytes, expected {}", actual, expected),
+            ParseError::Misaligned => write!(f, "frame packet isn't aligned"),
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecodeError::InvalidSignature(e) => write!(f, "frame signature is invalid: {:?}", e),
+            DecodeError::BadSignature => write!(f, "frame doesn't match its signature"),
+            DecodeError::BadLength(len) => write!(f, "frame payload length {} is longer than a frame", len),
         }
     }
 }
 
 impl Debug for Frame {
+    /// Only shows a hash of the frame, so decrypted frames don't end up in logs. Enable the
+    /// `debug-plaintext` feature to print frames that are valid UTF-8 as strings.
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        match str::from_utf8(&self.0) {
-            Ok(s) => {
-                write!(f, "Frame(b\"{}\")", s)
-            },
-            Err(_) => {
-                write!(f, "Frame(ENCRYPTED)")
-            },
+        #[cfg(feature = "debug-plaintext")]
+        if let Ok(s) = core::str::from_utf8(&self.0) {
+            return write!(f, "Frame(b\"{}\")", s);
+        }
+
+        let hash = Sha256::digest(self.0);
+        write!(f, "Frame({} bytes, sha256 ", self.0.len())?;
+        for c in &hash[..4] {
+            write!(f, "{:02x}", c)?;
         }
+        write!(f, ")")
     }
 }
 
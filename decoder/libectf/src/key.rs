@@ -1,21 +1,40 @@
 use core::fmt::Debug;
 
 use aes::Aes128;
+#[cfg(feature = "aead")]
+use aes_gcm::{aead::AeadInPlace, Aes128Gcm};
 use cipher::{generic_array::GenericArray, BlockDecryptMut, BlockEncryptMut, KeyInit, KeySizeUser};
 use hmac::{Hmac, Mac};
 use rkyv::{Archive, Deserialize, Serialize};
 use sha2::Sha256;
 
+#[cfg(feature = "aead")]
+use crate::frame::FRAME_NONCE;
 use crate::frame::{Frame, FRAME_SIZE};
 
 pub const KEY_SIZE_BYTES: usize = 16;
 
+/// Size of an AES-GCM nonce.
+#[cfg(feature = "aead")]
+pub const NONCE_SIZE: usize = 12;
+
+/// Size of an AES-GCM authentication tag.
+#[cfg(feature = "aead")]
+pub const TAG_SIZE: usize = 16;
+
 /// 96-bit key that is extended with zeros to form an AES128 key
 #[derive(Archive, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[rkyv(derive(Debug))]
-pub struct Key(pub [u8; KEY_SIZE_BYTES]);
+pub struct Key(#[cfg_attr(feature = "serde", serde(with = "crate::serde_hex"))] pub [u8; KEY_SIZE_BYTES]);
 
 /// Used to encrypt and decrypt data. Generated from a [`Key`].
+///
+/// This is AES in ECB mode with no padding, so only whole 16 byte blocks can be encrypted. Everything
+/// we encrypt is a fixed size array that's a whole number of blocks: frames are [`FRAME_SIZE`]
+/// bytes, checked where it's defined, and keys are [`KEY_SIZE_BYTES`]. [`Cipher::encrypt`] and
+/// [`Cipher::decrypt`] check the size of the array at compile time, so a new format can't leave
+/// a partial block in plaintext.
 pub struct Cipher(Aes128);
 
 impl ArchivedKey {
@@ -47,47 +66,91 @@ impl Key {
 
     /// Generate a device key using the device id and the global secrets.
     pub fn for_device(device_id: u32, secrets: &[u8]) -> Key {
-        let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(secrets).unwrap();
-        hasher.update(&device_id.to_le_bytes());
-        let hash: [u8; 32] = hasher.finalize().into_bytes().into();
-        Key(hash[..KEY_SIZE_BYTES].try_into().unwrap())
+        derive_key(DEVICE_LABEL, secrets, &[&device_id.to_le_bytes()])
     }
 
     /// Generate a subscripton key for a bitrange.
     pub fn for_bitrange(start_timestamp: u64, mask_idx: u8, channel: u32, secrets: &[u8]) -> Key {
-        let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(secrets).unwrap();
-        hasher.update(&start_timestamp.to_le_bytes());
-        hasher.update(&mask_idx.to_le_bytes());
-        hasher.update(&channel.to_le_bytes());
-        let hash: [u8; 32] = hasher.finalize().into_bytes().into();
-        Key(hash[..KEY_SIZE_BYTES].try_into().unwrap())
+        derive_key(BITRANGE_LABEL, secrets, &[&start_timestamp.to_le_bytes(), &mask_idx.to_le_bytes(), &channel.to_le_bytes()])
     }
 
-    /// Generate a subscripton key for a bitrange.
+    /// Generate a frame key for a timestamp and channel.
     pub fn for_frame(timestamp: u64, channel: u32, secrets: &[u8]) -> Key {
-        let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(secrets).unwrap();
-        hasher.update(&timestamp.to_le_bytes());
-        hasher.update(&channel.to_le_bytes());
-        let hash: [u8; 32] = hasher.finalize().into_bytes().into();
-        Key(hash[..KEY_SIZE_BYTES].try_into().unwrap())
+        derive_key(FRAME_LABEL, secrets, &[&timestamp.to_le_bytes(), &channel.to_le_bytes()])
+    }
+}
+
+/// Domain separation labels for the secrets each kind of key is derived from.
+pub const DEVICE_LABEL: &[u8] = b"device";
+pub const BITRANGE_LABEL: &[u8] = b"bitrange";
+pub const FRAME_LABEL: &[u8] = b"frame";
+
+/// Derive the secret for one kind of key from the global secrets: HMAC-SHA256 keyed with the
+/// secrets over a fixed prefix and `label`. The global secrets are the RSA signing key, so this
+/// keeps the raw key bytes out of every other derivation, and keeps a device key from ever being
+/// the same as a bitrange or frame key.
+pub fn derive_secret(secrets: &[u8], label: &[u8]) -> [u8; 32] {
+    let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(secrets).unwrap();
+    hasher.update(b"ectf25 kdf ");
+    hasher.update(label);
+    hasher.finalize().into_bytes().into()
+}
+
+/// Derive a key from the secret for `label` and the fields that pick out the key.
+fn derive_key(label: &[u8], secrets: &[u8], fields: &[&[u8]]) -> Key {
+    let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(&derive_secret(secrets, label)).unwrap();
+    for field in fields {
+        hasher.update(field);
     }
+    let hash: [u8; 32] = hasher.finalize().into_bytes().into();
+    Key(hash[..KEY_SIZE_BYTES].try_into().unwrap())
 }
 
 impl Cipher {
-    /// Encrypt an array with AES.
+    /// Encrypt an array with AES. The array has to be a whole number of AES blocks, which is
+    /// checked at compile time.
+    ///
+    /// ```compile_fail,E0080
+    /// libectf::key::Key([0; 16]).cipher().encrypt(&mut [0u8; 20]);
+    /// ```
     pub fn encrypt<const N: usize>(&mut self, data: &mut [u8; N]) {
+        const { assert!(N.is_multiple_of(16), "AES can only encrypt whole 16 byte blocks") };
         for chunk in data.chunks_exact_mut(16) {
             self.0.encrypt_block_mut(chunk.into());
         }
     }
 
-    /// Decrypt an array with AES.
+    /// Decrypt an array with AES. Like [`Cipher::encrypt`], the array has to be a whole number of
+    /// AES blocks.
+    ///
+    /// ```compile_fail,E0080
+    /// libectf::key::Key([0; 16]).cipher().decrypt(&mut [0u8; 20]);
+    /// ```
     pub fn decrypt<const N: usize>(&mut self, data: &mut [u8; N]) {
+        const { assert!(N.is_multiple_of(16), "AES can only decrypt whole 16 byte blocks") };
         for chunk in data.chunks_exact_mut(16) {
             self.0.decrypt_block_mut(chunk.into());
         }
     }
 
+    /// Encrypt data in place with AES-GCM, returning the tag that authenticates it and
+    /// `associated_data`. A nonce must never be used twice with the same key.
+    #[cfg(feature = "aead")]
+    pub fn seal(&mut self, nonce: &[u8; NONCE_SIZE], associated_data: &[u8], data: &mut [u8]) -> [u8; TAG_SIZE] {
+        Aes128Gcm::from(self.0.clone())
+            .encrypt_in_place_detached(nonce.into(), associated_data, data)
+            .unwrap()
+            .into()
+    }
+
+    /// Decrypt data sealed with [`Cipher::seal`]. The tag is checked before anything is
+    /// decrypted, so if the data, tag, or `associated_data` were changed this fails and leaves
+    /// `data` as it was.
+    #[cfg(feature = "aead")]
+    pub fn open(&mut self, nonce: &[u8; NONCE_SIZE], associated_data: &[u8], data: &mut [u8], tag: &[u8; TAG_SIZE]) -> Result<(), aes_gcm::Error> {
+        Aes128Gcm::from(self.0.clone()).decrypt_in_place_detached(nonce.into(), associated_data, data, tag.into())
+    }
+
     /// Encrypt a single frame with AES. Not to be confused with frame encoding.
     pub fn encrypt_frame(&mut self, frame: &mut Frame) {
         self.encrypt(&mut frame.0);
@@ -97,6 +160,21 @@ impl Cipher {
     pub fn decode_frame(&mut self, frame: &mut [u8; FRAME_SIZE]) {
         self.decrypt(frame);
     }
+
+    /// Seal a single frame with AES-GCM, authenticating the timestamp, channel, and payload length
+    /// it's sent with.
+    /// Returns the tag.
+    #[cfg(feature = "aead")]
+    pub fn seal_frame(&mut self, frame: &mut Frame, timestamp: u64, channel: u32, length: u8) -> [u8; TAG_SIZE] {
+        self.seal(&FRAME_NONCE, &Frame::associated_data(timestamp, channel, length), &mut frame.0)
+    }
+
+    /// Open a frame sealed with [`Cipher::seal_frame`]. Fails without decrypting anything if the
+    /// frame, tag, timestamp, channel, or length don't match what was sealed.
+    #[cfg(feature = "aead")]
+    pub fn open_frame(&mut self, frame: &mut [u8; FRAME_SIZE], timestamp: u64, channel: u32, length: u8, tag: &[u8; TAG_SIZE]) -> Result<(), aes_gcm::Error> {
+        self.open(&FRAME_NONCE, &Frame::associated_data(timestamp, channel, length), frame, tag)
+    }
 }
 
 impl Debug for Key {
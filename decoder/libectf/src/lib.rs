@@ -1,5 +1,3 @@
-#![feature(inherent_str_constructors)]
-
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 #[cfg(any(test, feature = "std"))] extern crate std;
@@ -10,23 +8,829 @@ pub mod masks;
 pub mod key;
 pub mod frame;
 pub mod subscription;
+pub mod mac;
+pub mod rekey;
+pub mod secrets;
+#[cfg(feature = "serde")]
+mod serde_hex;
+
+// Archived integers are little-endian on every host (rkyv's `little_endian` feature), which is the
+// byte order they're hashed and signed in as well. Building tooling on a big-endian host can't
+// change what goes over the wire or into a MAC.
+const _: fn(rkyv::Archived<u64>) -> rkyv::rend::u64_le = |x| x;
+const _: fn(rkyv::Archived<u32>) -> rkyv::rend::u32_le = |x| x;
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use rand::{rngs::OsRng, Rng};
+    use rkyv::util::AlignedVec;
+    use rsa::{pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey}, pkcs1v15::{Signature, SigningKey}, sha2::Sha256, signature::{Keypair, SignerMut, Verifier}, RsaPrivateKey};
 
-    use crate::frame::Frame;
+    use crate::{frame::{compat_hash, compat_hash_for, frame_prefix, is_signed, parse_frame_prefix, ArchivedEncodedFramePacket, ArchivedEncodedFramePacketHeader, DecodeError, EncodedFramePacket, EncodedFramePacketHeader, Frame, ParseError, ENCODED_FRAME_PACKET_SIZE, FRAME_FORMAT_VERSION, FRAME_PREFIX_SIZE, FRAME_SIZE, FULL_FRAME_LENGTH, RSA_KEY_BITS, SIGNATURE_SIZE}, key::{derive_secret, ArchivedKey, Key, BITRANGE_LABEL, DEVICE_LABEL, FRAME_LABEL, KEY_SIZE_BYTES}, mac::{ct_eq, SubscriptionMac}, rekey::{unwrap_device_key, wrap_device_key, REKEY_SIZE}, masks::{block_span, characterize_range, characterize_range_with, MASKS}, secrets::{crc32, parse_secrets, Secrets, SecretsError, SECRETS_MAGIC, SECRETS_VERSION}, subscription::{ArchivedEncodedSubscriptionKey, ArchivedSubscriptionDataHeader, ChannelInfo, SubscriptionData, SubscriptionDataHeader, SubscriptionError, MAX_SUBSCRIPTION_KEYS}};
+
+    /// Generate a throwaway secrets file (a PKCS#1 DER RSA key) for tests.
+    fn test_secrets() -> Vec<u8> {
+        let private_key = RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS).unwrap();
+        private_key.to_pkcs1_der().unwrap().as_bytes().to_vec()
+    }
 
     #[test]
     fn test_encode() {
-        let secrets = fs::read("../../global.secrets").unwrap();
+        let secrets = test_secrets();
 
         let test_frame = Frame(*b"abcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcd");
 
-        let encoded_frame = test_frame.encode(12, 1, &secrets);
+        let encoded_frame = test_frame.encode(12, 1, FULL_FRAME_LENGTH, &secrets);
+
+        assert_eq!(encoded_frame.header.timestamp, 12);
+        assert_eq!(encoded_frame.header.channel, 1);
+        assert!(encoded_frame.header.frame != test_frame);
+    }
+
+    #[test]
+    fn test_encode_matches_reference() {
+        let secrets = test_secrets();
+        let frame = Frame(*b"abcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcd");
+
+        // Sealed frames are checked in test_sealed_frames
+        for (timestamp, channel) in [(0, 0), (12, 1), (u64::MAX, u32::MAX), (0x1234_5678_9abc_def0, 7)].into_iter().filter(|&(_, channel)| is_signed(channel)) {
+            // Encode the frame one step at a time, the way the encoder is documented to
+            let mut signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+            let signature: Box<[u8]> = signing_key.sign(&frame.signed_message(timestamp, channel, FULL_FRAME_LENGTH)).into();
+
+            let frame_key = Key::for_frame(timestamp, channel, &secrets);
+            let mut encrypted_frame = frame.clone();
+            frame_key.cipher().encrypt_frame(&mut encrypted_frame);
+
+            let keys = MASKS.iter().enumerate().map(|(mask_idx, mask)| {
+                let mut k = frame_key.clone();
+                Key::for_bitrange(timestamp & !((1 << *mask as u64) - 1), mask_idx as u8, channel, &secrets).cipher().encrypt(&mut k.0);
+                k
+            }).collect::<Vec<_>>();
+
+            let expected = EncodedFramePacket {
+                header: EncodedFramePacketHeader { timestamp, channel, length: FULL_FRAME_LENGTH, signature: signature.to_vec().try_into().unwrap(), frame: encrypted_frame },
+                keys: keys.try_into().unwrap(),
+            };
+
+            assert_eq!(
+                rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode(timestamp, channel, FULL_FRAME_LENGTH, &secrets)).unwrap().as_slice(),
+                rkyv::to_bytes::<rkyv::rancor::Error>(&expected).unwrap().as_slice(),
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "aead"))]
+    fn test_signature_binds_header() {
+        let secrets = test_secrets();
+        let verifying_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap().verifying_key();
+
+        let frame = Frame([7; FRAME_SIZE]);
+        let encoded_frame = frame.encode(1000, 1, FULL_FRAME_LENGTH, &secrets);
+        let signature = Signature::try_from(encoded_frame.header.signature.as_slice()).unwrap();
+
+        assert!(verifying_key.verify(&frame.signed_message(1000, 1, FULL_FRAME_LENGTH), &signature).is_ok());
+
+        // Tampering with the channel or timestamp in the header invalidates the signature
+        assert!(verifying_key.verify(&frame.signed_message(1000, 2, FULL_FRAME_LENGTH), &signature).is_err());
+        assert!(verifying_key.verify(&frame.signed_message(1001, 1, FULL_FRAME_LENGTH), &signature).is_err());
+    }
+
+    #[test]
+    fn test_max_subscription_keys() {
+        // Ranges that just miss block boundaries at every level need the most keys
+        let worst = characterize_range(1, u64::MAX - 1).len();
+        assert!(worst <= MAX_SUBSCRIPTION_KEYS);
+
+        for _ in 0..1000 {
+            let (a, b) = (rand::random::<u64>(), rand::random::<u64>());
+            assert!(characterize_range(a.min(b), a.max(b)).len() <= MAX_SUBSCRIPTION_KEYS);
+        }
+    }
+
+    /// Assert that bitranges cover exactly `[a, b]`, in order and without overlapping.
+    fn assert_tiles(masks: &[u8], a: u64, b: u64, bitranges: &[(u64, u8)]) {
+        let mut next = Some(a);
+        for &(start, mask_idx) in bitranges {
+            let span = block_span(masks[mask_idx as usize]);
+            assert_eq!(Some(start), next, "bitranges for [{}, {}] aren't contiguous", a, b);
+            assert_eq!(start & span, 0, "bitrange {} isn't aligned to its mask", start);
+            assert!(start | span <= b, "bitrange {} goes past {}", start, b);
+            next = (start | span).checked_add(1);
+        }
+        assert_eq!(next, b.checked_add(1), "bitranges for [{}, {}] stop early", a, b);
+    }
+
+    #[test]
+    fn test_characterize_range_tiles() {
+        for _ in 0..1000 {
+            let (a, b) = (rand::random::<u64>(), rand::random::<u64>());
+            let (a, b) = (a.min(b), a.max(b));
+            assert_tiles(MASKS, a, b, &characterize_range(a, b));
+        }
+
+        // Ranges touching either end of the timestamps, where the last block ends by overflowing
+        for x in [0, 1, 2, 1 << 20, rand::random::<u64>(), u64::MAX - 1, u64::MAX] {
+            assert_tiles(MASKS, 0, x, &characterize_range(0, x));
+            assert_tiles(MASKS, x, u64::MAX, &characterize_range(x, u64::MAX));
+            assert_tiles(MASKS, x, x, &characterize_range(x, x));
+        }
+
+        // The whole range is covered by blocks of the widest mask
+        let all = characterize_range(0, u64::MAX);
+        assert_eq!(all.len(), 1 << (64 - MASKS[MASKS.len() - 1]));
+        assert!(all.iter().all(|&(_, mask_idx)| mask_idx as usize == MASKS.len() - 1));
+    }
+
+    #[test]
+    fn test_key_for_frame_cached() {
+        for i in 0..200 {
+            let (a, b) = (rand::random::<u64>(), rand::random::<u64>());
+            let (a, b) = if i % 2 == 0 { (a.min(b), a.max(b)) } else { (a, a.saturating_add(b % 100_000)) };
+
+            let data = SubscriptionData::generate(b"test secrets", a, b, 1, None).unwrap();
+            let header_bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap();
+            let header = unsafe { rkyv::access_unchecked::<ArchivedSubscriptionDataHeader>(&header_bytes) };
+            let keys: Vec<ArchivedEncodedSubscriptionKey> = data.keys.iter().map(|k| ArchivedEncodedSubscriptionKey { key: ArchivedKey(k.key.0) }).collect();
+            let bitranges = header.bitranges();
+
+            let mut timestamps = vec![a, b, a.wrapping_sub(1), b.wrapping_add(1)];
+            timestamps.extend((0..50).map(|_| a + rand::random::<u64>() % (b - a).saturating_add(1)));
+
+            for timestamp in timestamps {
+                for channel in [1, 2] {
+                    let frame_bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&EncodedFramePacketHeader { timestamp, channel, length: FULL_FRAME_LENGTH, signature: [0; SIGNATURE_SIZE], frame: Frame([0; FRAME_SIZE]) }).unwrap();
+                    let frame = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacketHeader>(&frame_bytes) };
+
+                    let scanned = header.key_for_frame(frame, &keys).map(|(k, mask_idx)| (k.key.0, mask_idx));
+                    let cached = header.key_for_frame_cached(frame, &keys, &bitranges).map(|(k, mask_idx)| (k.key.0, mask_idx));
+                    assert_eq!(cached, scanned, "keys differ for [{}, {}] at {}", a, b, timestamp);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "ct")]
+    fn test_key_for_frame_ct() {
+        for i in 0..200 {
+            let (a, b) = (rand::random::<u64>(), rand::random::<u64>());
+            let (a, b) = if i % 2 == 0 { (a.min(b), a.max(b)) } else { (a, a.saturating_add(b % 100_000)) };
+
+            let data = SubscriptionData::generate(b"test secrets", a, b, 1, None).unwrap();
+            let header_bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap();
+            let header = unsafe { rkyv::access_unchecked::<ArchivedSubscriptionDataHeader>(&header_bytes) };
+            let keys: Vec<ArchivedEncodedSubscriptionKey> = data.keys.iter().map(|k| ArchivedEncodedSubscriptionKey { key: ArchivedKey(k.key.0) }).collect();
+            let bitranges = header.bitranges();
+
+            let mut timestamps = vec![a, b, a.wrapping_sub(1), b.wrapping_add(1)];
+            timestamps.extend((0..50).map(|_| a + rand::random::<u64>() % (b - a).saturating_add(1)));
+
+            // Both find the same key at the same index, or neither finds one
+            for timestamp in timestamps {
+                for channel in [1, 2] {
+                    let frame_bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&EncodedFramePacketHeader { timestamp, channel, length: FULL_FRAME_LENGTH, signature: [0; SIGNATURE_SIZE], frame: Frame([0; FRAME_SIZE]) }).unwrap();
+                    let frame = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacketHeader>(&frame_bytes) };
+
+                    let fast = header.bitrange_for_frame(frame, &bitranges);
+                    assert_eq!(header.bitrange_for_frame_ct(frame, &bitranges), fast, "bitranges differ for [{}, {}] at {}", a, b, timestamp);
+
+                    let key_index = |(k, mask_idx): (&ArchivedEncodedSubscriptionKey, u8)| (keys.iter().position(|other| core::ptr::eq(other, k)).unwrap(), mask_idx);
+                    assert_eq!(header.key_for_frame_ct(frame, &keys).map(key_index), fast);
+                    assert_eq!(header.key_for_frame(frame, &keys).map(key_index), fast);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_timestamp_subscription() {
+        // The range includes both ends, so start == end is one timestamp with a single mask 0 key
+        let data = SubscriptionData::generate(b"test secrets", 1000, 1000, 1, None).unwrap();
+        assert_eq!(data.header.bitranges(), [(1000, 0)]);
+        assert_eq!(data.keys.len(), 1);
+
+        let header_bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap();
+        let header = unsafe { rkyv::access_unchecked::<ArchivedSubscriptionDataHeader>(&header_bytes) };
+        let keys: Vec<ArchivedEncodedSubscriptionKey> = data.keys.iter().map(|k| ArchivedEncodedSubscriptionKey { key: ArchivedKey(k.key.0) }).collect();
+        for (timestamp, decodes) in [(999, false), (1000, true), (1001, false)] {
+            let frame_bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&EncodedFramePacketHeader { timestamp, channel: 1, length: FULL_FRAME_LENGTH, signature: [0; SIGNATURE_SIZE], frame: Frame([0; FRAME_SIZE]) }).unwrap();
+            let frame = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacketHeader>(&frame_bytes) };
+            assert_eq!(header.key_for_frame(frame, &keys).is_some(), decodes, "timestamp {}", timestamp);
+        }
+
+        // An inverted range isn't a subscription at all
+        assert_eq!(SubscriptionData::generate(b"test secrets", 1001, 1000, 1, None).err(), Some(SubscriptionError::InvertedRange { start: 1001, end: 1000 }));
+    }
+
+    #[test]
+    fn test_channel_0_subscription() {
+        let secrets = b"test secrets";
+
+        // Channel 0 keys are built into decoders, not generated as subscriptions for them
+        assert_eq!(SubscriptionData::generate(secrets, 0, 10, 0, None).err(), Some(SubscriptionError::Channel0));
+        assert_eq!(SubscriptionData::generate(secrets, 0, 10, 0, Some(7)).err(), Some(SubscriptionError::Channel0));
+
+        let data = SubscriptionData::generate_channel_0(secrets);
+        assert_eq!((data.header.channel, data.header.start_timestamp, data.header.end_timestamp), (0, 0, u64::MAX));
+        assert_eq!(data.header.mac_hash, [0; 32]);
+        for (k, (start_timestamp, mask_idx)) in data.keys.iter().zip(data.header.bitranges()) {
+            assert_eq!(k.key.0, Key::for_bitrange(start_timestamp, mask_idx, 0, secrets).0);
+        }
+    }
+
+    #[test]
+    fn test_channel_0_window() {
+        let secrets = b"test secrets";
+
+        assert_eq!(SubscriptionData::generate_channel_0_window(secrets, 10, 5, Some(7)).err(), Some(SubscriptionError::InvertedRange { start: 10, end: 5 }));
+
+        // A window is sent like a subscription, so it's encrypted and MAC'd for the device
+        let data = SubscriptionData::generate_channel_0_window(secrets, 1000, 5000, Some(7)).unwrap();
+        assert_eq!((data.header.channel, data.header.start_timestamp, data.header.end_timestamp), (0, 1000, 5000));
+
+        let device_key = Key::for_device(7, secrets);
+        let mut hasher = SubscriptionMac::new(&device_key, 1000, 5000, 0);
+        let mut cipher = device_key.cipher();
+        for (k, (start_timestamp, mask_idx)) in data.keys.iter().zip(data.header.bitranges()) {
+            let mut key = k.key.0;
+            cipher.decrypt(&mut key);
+            assert_eq!(key, Key::for_bitrange(start_timestamp, mask_idx, 0, secrets).0);
+            hasher.update_key(&key);
+        }
+        assert_eq!(hasher.finalize(), data.header.mac_hash);
+    }
+
+    #[test]
+    fn test_channel_info_order() {
+        let info = |channel, start, end| ChannelInfo { channel, start, end };
+        let mut infos = vec![info(5, 10, 20), info(2, 300, 400), info(5, 0, 50), info(2, 300, 400), info(5, 0, 30)];
+
+        // Sorted by channel, then start, then end, so duplicates end up next to each other
+        infos.sort();
+        assert_eq!(infos, [info(2, 300, 400), info(2, 300, 400), info(5, 0, 30), info(5, 0, 50), info(5, 10, 20)]);
+        infos.dedup();
+        assert_eq!(infos, [info(2, 300, 400), info(5, 0, 30), info(5, 0, 50), info(5, 10, 20)]);
+    }
+
+    #[test]
+    fn test_characterize_range_custom_masks() {
+        for masks in [MASKS, &[0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 44, 48, 52, 56, 60], &[0, 1, 2, 5, 9, 14, 20, 26, 32, 38, 44, 50, 56, 62, 63]] {
+            for _ in 0..1000 {
+                let (a, b) = (rand::random::<u64>(), rand::random::<u64>());
+                let (a, b) = (a.min(b), a.max(b));
+                assert_tiles(masks, a, b, &characterize_range_with(masks, a, b));
+
+                // Short ranges end between block boundaries at every level
+                let b = a.saturating_add(b % 100_000);
+                assert_tiles(masks, a, b, &characterize_range_with(masks, a, b));
+            }
+        }
+    }
+
+    #[test]
+    fn test_characterize_range_widest_masks() {
+        assert_eq!(block_span(0), 0);
+        assert_eq!(block_span(63), u64::MAX >> 1);
+        assert_eq!(block_span(64), u64::MAX);
+
+        // A 63 bit mask covers everything in two blocks, and a 64 bit one in a single block
+        let masks: &[u8] = &[0, 9, 18, 27, 36, 45, 54, 63];
+        assert_eq!(characterize_range_with(masks, 0, u64::MAX), [(0, 7), (1 << 63, 7)]);
+        assert_eq!(characterize_range_with(masks, 1 << 63, u64::MAX), [(1 << 63, 7)]);
+        assert_tiles(masks, 1, u64::MAX, &characterize_range_with(masks, 1, u64::MAX));
+
+        let masks: &[u8] = &[0, 8, 16, 24, 32, 40, 48, 56, 64];
+        assert_eq!(characterize_range_with(masks, 0, u64::MAX), [(0, 8)]);
+        assert_tiles(masks, 1, u64::MAX - 1, &characterize_range_with(masks, 1, u64::MAX - 1));
+    }
+
+    #[test]
+    fn test_subscription_mac_detects_bit_flips() {
+        let secrets = b"test secrets";
+        let device_key = Key::for_device(7, secrets);
+        let data = SubscriptionData::generate(secrets, 1000, 5000, 3, Some(7)).unwrap();
+        assert!(data.would_authenticate(&device_key));
+        assert!(!data.would_authenticate(&Key::for_device(8, secrets)));
+
+        for bit in 0..64 {
+            let mut flipped = SubscriptionData::generate(secrets, 1000, 5000, 3, Some(7)).unwrap();
+            flipped.header.start_timestamp ^= 1 << bit;
+            assert!(!flipped.would_authenticate(&device_key));
+
+            let mut flipped = SubscriptionData::generate(secrets, 1000, 5000, 3, Some(7)).unwrap();
+            flipped.header.end_timestamp ^= 1 << bit;
+            assert!(!flipped.would_authenticate(&device_key));
+        }
+        for bit in 0..32 {
+            let mut flipped = SubscriptionData::generate(secrets, 1000, 5000, 3, Some(7)).unwrap();
+            flipped.header.channel ^= 1 << bit;
+            assert!(!flipped.would_authenticate(&device_key));
+        }
+        for i in 0..data.keys.len() {
+            for bit in 0..128 {
+                let mut flipped = SubscriptionData::generate(secrets, 1000, 5000, 3, Some(7)).unwrap();
+                flipped.keys[i].key.0[bit / 8] ^= 1 << (bit % 8);
+                assert!(!flipped.would_authenticate(&device_key));
+            }
+        }
+        for bit in 0..256 {
+            let mut flipped = SubscriptionData::generate(secrets, 1000, 5000, 3, Some(7)).unwrap();
+            flipped.header.mac_hash[bit / 8] ^= 1 << (bit % 8);
+            assert!(!flipped.would_authenticate(&device_key));
+        }
+    }
+
+    #[test]
+    fn test_would_authenticate_leaves_keys_encrypted() {
+        let secrets = b"test secrets";
+        let device_key = Key::for_device(7, secrets);
+        for (data, expected) in [
+            (SubscriptionData::generate(secrets, 1000, 5000, 3, Some(7)).unwrap(), true),
+            (SubscriptionData::generate(secrets, 1000, 5000, 3, Some(8)).unwrap(), false),
+        ] {
+            let before = rkyv::to_bytes::<rkyv::rancor::Error>(&data).unwrap();
+            assert_eq!(data.would_authenticate(&device_key), expected);
+            assert_eq!(rkyv::to_bytes::<rkyv::rancor::Error>(&data).unwrap().as_slice(), before.as_slice());
+
+            // Same verdict as decrypting the keys in place like the decoder
+            let mut keys: Vec<_> = data.keys.iter().map(|k| k.key.0).collect();
+            let mut mac = SubscriptionMac::new(&device_key, data.header.start_timestamp, data.header.end_timestamp, data.header.channel);
+            for key in &mut keys {
+                device_key.cipher().decrypt(key);
+                mac.update_key(key);
+            }
+            assert_eq!(mac.verify(&data.header.mac_hash), expected);
+        }
+    }
+
+    #[test]
+    fn test_subscription_mac_byte_order() {
+        // Every field is hashed little-endian, so the MAC is the same on any host. This value is
+        // HMAC-SHA256 over the little-endian fields computed outside of this crate.
+        const PINNED: [u8; 32] = [
+            0xeb, 0xe1, 0x90, 0xee, 0x80, 0xe6, 0xde, 0x96, 0x35, 0x0e, 0x0c, 0xbc, 0xbc, 0xf2, 0xe1, 0x0f,
+            0x2e, 0xcb, 0xf8, 0x35, 0x7c, 0xdc, 0x0d, 0x1a, 0x1b, 0x32, 0xe8, 0x07, 0xd7, 0x4b, 0xd7, 0xc7,
+        ];
+        let device_key = Key([0x11; KEY_SIZE_BYTES]);
+        let header = SubscriptionDataHeader { start_timestamp: 0x0102030405060708, end_timestamp: 0x1112131415161718, channel: 0x21222324, mac_hash: [0; 32] };
+
+        let mut mac = SubscriptionMac::new(&device_key, header.start_timestamp, header.end_timestamp, header.channel);
+        mac.update_key(&[0x42; KEY_SIZE_BYTES]);
+        assert_eq!(mac.finalize(), PINNED);
+
+        // The archived header is little-endian too, and its MAC matches
+        let header_bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&header).unwrap();
+        assert_eq!(header_bytes[..20], [8, 7, 6, 5, 4, 3, 2, 1, 0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11, 0x24, 0x23, 0x22, 0x21]);
+        let archived = unsafe { rkyv::access_unchecked::<ArchivedSubscriptionDataHeader>(&header_bytes) };
+        let mut mac = archived.mac(&device_key);
+        mac.update_key(&[0x42; KEY_SIZE_BYTES]);
+        assert_eq!(mac.finalize(), PINNED);
+    }
+
+    #[test]
+    fn test_key_derivation_labels() {
+        let secrets = test_secrets();
+        let labels = [DEVICE_LABEL, BITRANGE_LABEL, FRAME_LABEL];
+        let derived = labels.map(|label| derive_secret(&secrets, label));
+        for (i, a) in derived.iter().enumerate() {
+            assert!(derived[i + 1..].iter().all(|b| a != b), "{:?} isn't separated", core::str::from_utf8(labels[i]));
+        }
+
+        // Changing one label changes only the secret derived with it
+        for i in 0..labels.len() {
+            let mut label = labels[i].to_vec();
+            label[0] ^= 1;
+            for (j, l) in labels.iter().enumerate() {
+                let label = if i == j { &label[..] } else { l };
+                assert_eq!(derive_secret(&secrets, label) == derived[j], i != j);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cipher_covers_every_block() {
+        let mut frame = [0u8; FRAME_SIZE];
+        let mut key = [0u8; KEY_SIZE_BYTES];
+        let mut cipher = Key([1; KEY_SIZE_BYTES]).cipher();
+        cipher.encrypt(&mut frame);
+        cipher.encrypt(&mut key);
+
+        // No block, including the last, is left in plaintext
+        assert!(frame.chunks(16).chain(key.chunks(16)).all(|block| block != [0; 16]));
+
+        cipher.decrypt(&mut frame);
+        cipher.decrypt(&mut key);
+        assert_eq!(frame, [0; FRAME_SIZE]);
+        assert_eq!(key, [0; KEY_SIZE_BYTES]);
+    }
+
+    #[test]
+    fn test_ct_eq() {
+        let a = [0x5au8; 32];
+        assert!(ct_eq(&a, &a));
+
+        for i in 0..a.len() {
+            let mut b = a;
+            b[i] ^= 0x01;
+            assert!(!ct_eq(&a, &b));
+        }
+    }
+
+    #[test]
+    fn test_secrets_channels() {
+        let key = test_secrets();
+        let secrets = Secrets { channels: Some(vec![1, 3]), masks: Some(MASKS.to_vec()), key: key.clone() };
+
+        let parsed = parse_secrets(&secrets.to_bytes()).unwrap();
+        assert_eq!(parsed, secrets);
+        assert!(parsed.has_channel(0) && parsed.has_channel(1) && parsed.has_channel(3));
+        assert!(!parsed.has_channel(2));
+
+        // Legacy secrets are only the key and allow any channel
+        let legacy = parse_secrets(&key).unwrap();
+        assert_eq!(legacy, Secrets { channels: None, masks: None, key });
+        assert!(legacy.has_channel(2));
+    }
+
+    #[test]
+    fn test_secrets_framing() {
+        let bytes = Secrets { channels: Some(vec![1, 3]), masks: None, key: test_secrets() }.to_bytes();
+
+        assert_eq!(parse_secrets(&bytes[..bytes.len() - 1]), Err(SecretsError::Truncated));
+        assert_eq!(parse_secrets(&bytes[..8]), Err(SecretsError::Truncated));
+        assert_eq!(parse_secrets(b"garbage"), Err(SecretsError::UnknownFormat));
+        // The unframed version 1 format, a version byte and then the payload, isn't accepted anymore
+        let unframed = [&[1], &bytes[13..]].concat();
+        assert_eq!(parse_secrets(&unframed), Err(SecretsError::UnknownFormat));
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(parse_secrets(&corrupted), Err(SecretsError::CrcMismatch { .. })));
+
+        let bad_key = Secrets { channels: Some(vec![1, 3]), masks: None, key: b"not a key".to_vec() }.to_bytes();
+        assert_eq!(parse_secrets(&bad_key), Err(SecretsError::InvalidKey));
+        assert_eq!(parse_secrets(&[0x30, 0x03, 0x02, 0x01, 0x00]), Err(SecretsError::InvalidKey));
+
+        let mut future = bytes.clone();
+        future[4] = SECRETS_VERSION + 1;
+        assert_eq!(parse_secrets(&future), Err(SecretsError::UnsupportedVersion(SECRETS_VERSION + 1)));
+    }
+
+    #[test]
+    fn test_secrets_masks() {
+        let key = test_secrets();
+
+        // Secrets that don't record masks are written with this build's
+        let parsed = parse_secrets(&Secrets { channels: Some(vec![1]), masks: None, key: key.clone() }.to_bytes()).unwrap();
+        assert_eq!(parsed.masks.as_deref(), Some(MASKS));
+
+        // Secrets for other masks are refused, since nothing made with them would match
+        let other: Vec<u8> = MASKS[..MASKS.len() - 1].to_vec();
+        let bytes = Secrets { channels: Some(vec![1]), masks: Some(other.clone()), key: key.clone() }.to_bytes();
+        assert_eq!(parse_secrets(&bytes), Err(SecretsError::WrongMasks(other)));
+
+        // Version 2 secrets, the channels and then the key, are still read and take this build's
+        let mut payload = [1u32.to_le_bytes(), 1u32.to_le_bytes()].concat();
+        payload.extend_from_slice(&key);
+        let mut v2 = SECRETS_MAGIC.to_vec();
+        v2.push(2);
+        v2.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        v2.extend_from_slice(&crc32(&payload).to_le_bytes());
+        v2.extend_from_slice(&payload);
+        assert_eq!(parse_secrets(&v2), Ok(Secrets { channels: Some(vec![1]), masks: None, key }));
+    }
+
+    #[test]
+    #[cfg(not(feature = "debug-plaintext"))]
+    fn test_frame_debug_redacted() {
+        let frame = Frame(*b"attack at dawn, bring the secret password hunter2 and some tea!!");
+        let formatted = format!("{:?}", frame);
+
+        assert!(!formatted.contains("attack"));
+        assert!(!formatted.contains("hunter2"));
+        assert!(formatted.starts_with(&format!("Frame({} bytes, sha256 ", FRAME_SIZE)));
+    }
+
+    #[test]
+    fn test_encode_into_matches_rkyv() {
+        let secrets = test_secrets();
+        let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+        let frame = Frame(core::array::from_fn(|i| i as u8));
+
+        for (timestamp, channel) in [(0, 0), (12345, 3), (u64::MAX, u32::MAX)] {
+            let expected = rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode_with_key(timestamp, channel, FULL_FRAME_LENGTH, &secrets, &signing_key)).unwrap();
+
+            let mut streamed = Vec::new();
+            frame.encode_into::<_, rkyv::rancor::Error>(timestamp, channel, FULL_FRAME_LENGTH, &secrets, &signing_key, &mut streamed).unwrap();
+
+            assert_eq!(streamed[..FRAME_PREFIX_SIZE], frame_prefix(expected.len() as u32));
+            assert_eq!(parse_frame_prefix(&streamed), Some((FRAME_FORMAT_VERSION, expected.len() as u32)));
+            assert_eq!(&streamed[FRAME_PREFIX_SIZE..], expected.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_decode_frame_packet() {
+        let secrets = test_secrets();
+        let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+        let verifying_key = signing_key.verifying_key();
+        let frame = Frame(core::array::from_fn(|i| i as u8));
+
+        for (timestamp, channel) in [(0, 0), (12345, 3)] {
+            let mut bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode_with_key(timestamp, channel, FULL_FRAME_LENGTH, &secrets, &signing_key)).unwrap();
+
+            // Any mask's subscription key decodes the frame
+            for (mask_idx, mask) in MASKS.iter().enumerate() {
+                let subscription_key = Key::for_bitrange(timestamp & !((1 << *mask as u64) - 1), mask_idx as u8, channel, &secrets);
+                let archived = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacket>(&bytes) };
+                assert_eq!(archived.decode(&subscription_key, mask_idx as u8, &verifying_key).unwrap(), frame);
+            }
+
+            // The key for another bitrange doesn't
+            let wrong_key = Key::for_bitrange(timestamp + (1 << MASKS[0]), 0, channel, &secrets);
+            let archived = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacket>(&bytes) };
+            assert!(matches!(archived.decode(&wrong_key, 0, &verifying_key), Err(DecodeError::BadSignature)));
+
+            // Neither does a frame that was changed in transit
+            let subscription_key = Key::for_bitrange(timestamp & !((1 << MASKS[0] as u64) - 1), 0, channel, &secrets);
+            let frame_offset = core::mem::offset_of!(ArchivedEncodedFramePacket, header) + core::mem::offset_of!(ArchivedEncodedFramePacketHeader, frame);
+            bytes[frame_offset] ^= 1;
+            let archived = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacket>(&bytes) };
+            assert!(matches!(archived.decode(&subscription_key, 0, &verifying_key), Err(DecodeError::BadSignature)));
+        }
+    }
+
+    #[test]
+    fn test_decode_with_reused_cipher() {
+        let secrets = test_secrets();
+        let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+        let verifying_key = signing_key.verifying_key();
+
+        // One cipher for the widest bitrange's key decodes every frame in it, the same as a new one
+        let mask_idx = MASKS.len() - 1;
+        let subscription_key = Key::for_bitrange(0, mask_idx as u8, 5, &secrets);
+        let mut cipher = subscription_key.cipher();
+        for timestamp in [0, 1, 999, 1 << 40] {
+            let frame = Frame(core::array::from_fn(|i| (i as u64 ^ timestamp) as u8));
+            let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode_with_key(timestamp, 5, FULL_FRAME_LENGTH, &secrets, &signing_key)).unwrap();
+            let archived = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacket>(&bytes) };
+            assert_eq!(archived.decode_with_cipher(&mut cipher, mask_idx as u8, &verifying_key).unwrap(), frame);
+            assert_eq!(archived.decode(&subscription_key, mask_idx as u8, &verifying_key).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn test_frame_payload_round_trip() {
+        let secrets = test_secrets();
+        let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+        let verifying_key = signing_key.verifying_key();
+        let subscription_key = Key::for_bitrange(1000 & !((1 << MASKS[0] as u64) - 1), 0, 1, &secrets);
+
+        for len in [1, 63, 64] {
+            let payload: Vec<u8> = (1..=len).collect();
+            let frame = Frame::from_payload(&payload);
+            assert_eq!(frame.payload(len as usize), payload);
+            assert!(frame.0[len as usize..].iter().all(|&b| b == 0));
+
+            let mut bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode_with_key(1000, 1, len, &secrets, &signing_key)).unwrap();
+            let archived = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacket>(&bytes) };
+            assert_eq!(archived.header.length(), len as usize);
+            assert_eq!(archived.decode(&subscription_key, 0, &verifying_key).unwrap().payload(archived.header.length()), payload);
+
+            // The length is authenticated, so it can't be changed to cut the payload short
+            let length_offset = core::mem::offset_of!(ArchivedEncodedFramePacket, header) + core::mem::offset_of!(ArchivedEncodedFramePacketHeader, length);
+            bytes[length_offset] -= 1;
+            let archived = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacket>(&bytes) };
+            assert!(matches!(archived.decode(&subscription_key, 0, &verifying_key), Err(DecodeError::BadSignature)));
+        }
+
+        // Long payloads are truncated to a frame, and lengths longer than a frame are rejected
+        assert_eq!(Frame::from_payload(&[9; FRAME_SIZE + 1]), Frame([9; FRAME_SIZE]));
+        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&Frame([9; FRAME_SIZE]).encode_with_key(1000, 1, FULL_FRAME_LENGTH + 1, &secrets, &signing_key)).unwrap();
+        let archived = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacket>(&bytes) };
+        assert!(matches!(archived.decode(&subscription_key, 0, &verifying_key), Err(DecodeError::BadLength(65))));
+    }
+
+    #[test]
+    fn test_parse_frame_packet() {
+        let secrets = test_secrets();
+        let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+        let frame = Frame(core::array::from_fn(|i| i as u8));
+
+        let mut bytes = AlignedVec::<16>::new();
+        frame.encode_into::<_, rkyv::rancor::Error>(12345, 3, FULL_FRAME_LENGTH, &secrets, &signing_key, &mut bytes).unwrap();
+
+        // Both parse to the packet that was encoded
+        let expected = rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode_with_key(12345, 3, FULL_FRAME_LENGTH, &secrets, &signing_key)).unwrap();
+        let parsed = EncodedFramePacket::try_from_bytes(&bytes).unwrap();
+        assert_eq!(rkyv::to_bytes::<rkyv::rancor::Error>(&parsed).unwrap().as_slice(), expected.as_slice());
+        let archived = ArchivedEncodedFramePacket::from_bytes(&bytes).unwrap();
+        assert_eq!((archived.header.timestamp(), archived.header.channel()), (12345, 3));
+        assert!(archived.keys.iter().zip(&parsed.keys).all(|(a, p)| a.0 == p.0));
+
+        // A packet with the wrong version, size, or alignment is rejected
+        let mut future = bytes.clone();
+        future[0] += 1;
+        assert_eq!(EncodedFramePacket::try_from_bytes(&future).unwrap_err(), ParseError::UnsupportedVersion(FRAME_FORMAT_VERSION + 1));
+
+        let size = bytes.len() - FRAME_PREFIX_SIZE;
+        assert_eq!(EncodedFramePacket::try_from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(), ParseError::WrongSize { expected: size, actual: size - 1 });
+        assert_eq!(ArchivedEncodedFramePacket::from_bytes(&bytes[..4]).err(), Some(ParseError::MissingPrefix));
+
+        let mut shifted = AlignedVec::<16>::new();
+        shifted.push(0);
+        shifted.extend_from_slice(&bytes);
+        assert_eq!(ArchivedEncodedFramePacket::from_bytes(&shifted[1..]).err(), Some(ParseError::Misaligned));
+        assert!(EncodedFramePacket::try_from_bytes(&shifted[1..]).is_ok());
+    }
+
+    #[test]
+    fn test_encoded_frame_packet_size() {
+        let secrets = test_secrets();
+        let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+        let frame = Frame([0x42; FRAME_SIZE]);
+
+        // Written in place and serialized with rkyv, signed and not, the packet is the same size
+        for channel in [0, 1] {
+            let mut bytes = AlignedVec::<16>::new();
+            frame.encode_into::<_, rkyv::rancor::Error>(1000, channel, FULL_FRAME_LENGTH, &secrets, &signing_key, &mut bytes).unwrap();
+            assert_eq!(bytes.len(), ENCODED_FRAME_PACKET_SIZE);
+
+            let serialized = rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode_with_key(1000, channel, FULL_FRAME_LENGTH, &secrets, &signing_key)).unwrap();
+            assert_eq!(FRAME_PREFIX_SIZE + serialized.len(), ENCODED_FRAME_PACKET_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_compat_hash() {
+        let masks: Vec<u8> = (0..=60).step_by(3).collect();
+        let hash = compat_hash_for(FRAME_SIZE, &masks, KEY_SIZE_BYTES, SIGNATURE_SIZE);
+        assert_eq!(hash, compat_hash_for(FRAME_SIZE, &masks, KEY_SIZE_BYTES, SIGNATURE_SIZE));
+        assert_eq!(compat_hash(), compat_hash_for(FRAME_SIZE, MASKS, KEY_SIZE_BYTES, SIGNATURE_SIZE));
+
+        // Different mask tables, including ones with a different number of masks, and any other
+        // difference in the format all change it
+        let fewer: Vec<u8> = (0..=56).step_by(4).collect();
+        let shifted: Vec<u8> = masks.iter().map(|&m| if m == 0 { 0 } else { m + 1 }).collect();
+        for other in [
+            compat_hash_for(FRAME_SIZE, &fewer, KEY_SIZE_BYTES, SIGNATURE_SIZE),
+            compat_hash_for(FRAME_SIZE, &shifted, KEY_SIZE_BYTES, SIGNATURE_SIZE),
+            compat_hash_for(FRAME_SIZE, &masks[..masks.len() - 1], KEY_SIZE_BYTES, SIGNATURE_SIZE),
+            compat_hash_for(FRAME_SIZE * 2, &masks, KEY_SIZE_BYTES, SIGNATURE_SIZE),
+            compat_hash_for(FRAME_SIZE, &masks, KEY_SIZE_BYTES * 2, SIGNATURE_SIZE),
+            compat_hash_for(FRAME_SIZE, &masks, KEY_SIZE_BYTES, SIGNATURE_SIZE * 2),
+        ] {
+            assert_ne!(other, hash);
+        }
+    }
+
+    #[test]
+    fn test_parse_frame_packet_random_bytes() {
+        let size = ENCODED_FRAME_PACKET_SIZE;
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..1000 {
+            let length = rng.gen_range(0..size * 2);
+            let mut bytes = AlignedVec::<16>::new();
+            bytes.extend_from_slice(&(0..length).map(|_| rng.gen()).collect::<Vec<u8>>());
+
+            // Packets that are the right size with a valid prefix parse whatever the rest is, and
+            // nothing panics
+            if length == size && rng.gen() {
+                bytes[..FRAME_PREFIX_SIZE].copy_from_slice(&frame_prefix((size - FRAME_PREFIX_SIZE) as u32));
+            }
+            let valid = length == size && bytes[..FRAME_PREFIX_SIZE] == frame_prefix((size - FRAME_PREFIX_SIZE) as u32);
+            assert_eq!(EncodedFramePacket::try_from_bytes(&bytes).is_ok(), valid);
+            assert_eq!(ArchivedEncodedFramePacket::from_bytes(&bytes).is_ok(), valid);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_json_round_trip() {
+        use crate::subscription::{ChannelInfo, EncodedSubscriptionKey, SubscriptionDataHeader};
+
+        let header = SubscriptionDataHeader { start_timestamp: 10, end_timestamp: u64::MAX, channel: 3, mac_hash: core::array::from_fn(|i| i as u8) };
+        let json = serde_json::to_string(&header).unwrap();
+        assert_eq!(json, r#"{"start_timestamp":10,"end_timestamp":18446744073709551615,"channel":3,"mac_hash":"000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"}"#);
+        let parsed: SubscriptionDataHeader = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+
+        let key = EncodedSubscriptionKey { key: Key([0xab; 16]) };
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(json, r#"{"key":"abababababababababababababababab"}"#);
+        assert_eq!(serde_json::from_str::<EncodedSubscriptionKey>(&json).unwrap().key.0, key.key.0);
+
+        let info = ChannelInfo { channel: 1, start: 2, end: 3 };
+        let parsed: ChannelInfo = serde_json::from_str(&serde_json::to_string(&info).unwrap()).unwrap();
+        assert_eq!((parsed.channel, parsed.start, parsed.end), (1, 2, 3));
+
+        let frame_header = EncodedFramePacketHeader { timestamp: 5, channel: 6, length: FULL_FRAME_LENGTH, signature: [0x5a; SIGNATURE_SIZE], frame: Frame([7; FRAME_SIZE]) };
+        let json = serde_json::to_string(&frame_header).unwrap();
+        assert!(json.contains(&"5a".repeat(SIGNATURE_SIZE)));
+        let parsed: EncodedFramePacketHeader = serde_json::from_str(&json).unwrap();
+        assert_eq!((parsed.timestamp, parsed.channel, parsed.signature, parsed.frame), (5, 6, frame_header.signature, frame_header.frame));
+
+        // Hex strings of the wrong length or with non-hex digits are rejected
+        assert!(serde_json::from_str::<EncodedSubscriptionKey>(r#"{"key":"abab"}"#).is_err());
+        assert!(serde_json::from_str::<EncodedSubscriptionKey>(r#"{"key":"zzababababababababababababababab"}"#).is_err());
+    }
+
+    #[test]
+    fn test_rsa_2048_signatures() {
+        let private_key = RsaPrivateKey::new(&mut OsRng, 2048).unwrap();
+        let secrets = private_key.to_pkcs1_der().unwrap().as_bytes().to_vec();
+
+        let mut signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+        let signature: Box<[u8]> = signing_key.sign(b"message").into();
+        assert_eq!(signature.len(), 2048 / 8);
+
+        // Only keys of the size this build signs frames with are accepted
+        let bytes = Secrets { channels: Some(vec![1]), masks: None, key: secrets.clone() }.to_bytes();
+        if RSA_KEY_BITS == 2048 {
+            assert_eq!(SIGNATURE_SIZE, 256);
+            // Channel 0 frames are signed even when other channels are sealed with `aead`
+            let frame = Frame([1; FRAME_SIZE]).encode(5, 0, FULL_FRAME_LENGTH, &parse_secrets(&bytes).unwrap().key);
+            let signature = Signature::try_from(frame.header.signature.as_slice()).unwrap();
+            assert!(signing_key.verifying_key().verify(&Frame([1; FRAME_SIZE]).signed_message(5, 0, FULL_FRAME_LENGTH), &signature).is_ok());
+        } else {
+            assert_eq!(parse_secrets(&bytes), Err(SecretsError::WrongKeySize(2048)));
+        }
+
+        // Keys below the minimum are refused no matter what size this build signs with
+        let small_key = RsaPrivateKey::new(&mut OsRng, 512).unwrap().to_pkcs1_der().unwrap().as_bytes().to_vec();
+        let bytes = Secrets { channels: Some(vec![1]), masks: None, key: small_key }.to_bytes();
+        assert_eq!(parse_secrets(&bytes), Err(SecretsError::KeyTooSmall(512)));
+    }
+
+    #[test]
+    #[cfg(feature = "aead")]
+    fn test_sealed_frames() {
+        use crate::{frame::{ASSOCIATED_DATA_SIZE, FRAME_NONCE}, key::TAG_SIZE};
+
+        let secrets = test_secrets();
+        let frame = Frame([7; FRAME_SIZE]);
+
+        // Channel 0 frames are still signed, the rest only carry a tag
+        assert!(is_signed(0) && !is_signed(1));
+        let encoded_frame = frame.encode(1000, 1, FULL_FRAME_LENGTH, &secrets);
+        assert!(encoded_frame.header.signature[TAG_SIZE..].iter().all(|&b| b == 0));
+
+        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&encoded_frame).unwrap();
+        let archived = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacket>(&bytes) };
+        let tag = *archived.header.tag();
+        let mut cipher = Key::for_frame(1000, 1, &secrets).cipher();
+
+        let mut f = encoded_frame.header.frame.0;
+        assert!(cipher.open_frame(&mut f, 1000, 1, FULL_FRAME_LENGTH, &tag).is_ok());
+        assert_eq!(f, frame.0);
+
+        // Changing the ciphertext, the tag, or either header field fails without decrypting
+        let mut tampered = encoded_frame.header.frame.0;
+        tampered[FRAME_SIZE - 1] ^= 1;
+        let before = tampered;
+        assert!(cipher.open_frame(&mut tampered, 1000, 1, FULL_FRAME_LENGTH, &tag).is_err());
+        assert_eq!(tampered, before);
+
+        let mut bad_tag = tag;
+        bad_tag[0] ^= 1;
+        for (timestamp, channel, tag) in [(1000, 1, &bad_tag), (1001, 1, &tag), (1000, 2, &tag)] {
+            let mut f = encoded_frame.header.frame.0;
+            assert!(cipher.open_frame(&mut f, timestamp, channel, FULL_FRAME_LENGTH, tag).is_err());
+            assert_eq!(f, encoded_frame.header.frame.0);
+        }
+
+        // The associated data is just the header fields
+        let mut data = encoded_frame.header.frame.0;
+        assert!(cipher.open(&FRAME_NONCE, &[0; ASSOCIATED_DATA_SIZE], &mut data, &tag).is_err());
+    }
+
+    #[test]
+    fn test_rekey() {
+        let secrets = b"test secrets";
+        let current = Key::for_device(7, secrets);
+        let new = Key([0x5a; KEY_SIZE_BYTES]);
+
+        let body = wrap_device_key(&current, &new);
+        assert_eq!(unwrap_device_key(&current, &body).unwrap().0, new.0);
+
+        // The new key isn't sent in the clear
+        assert_ne!(body[..KEY_SIZE_BYTES], new.0);
 
-        println!("{:?}", encoded_frame);
+        // Any flipped bit, the wrong current key, or the wrong size is rejected
+        for bit in 0..REKEY_SIZE * 8 {
+            let mut flipped = body;
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            assert!(unwrap_device_key(&current, &flipped).is_none());
+        }
+        assert!(unwrap_device_key(&Key::for_device(8, secrets), &body).is_none());
+        assert!(unwrap_device_key(&current, &body[1..]).is_none());
 
-        assert!(true == false);
+        // Subscriptions for the new key authenticate under it and not the old one
+        let data = SubscriptionData::generate_for_key(secrets, 1000, 5000, 3, Some(&new)).unwrap();
+        assert!(data.would_authenticate(&new));
+        assert!(!data.would_authenticate(&current));
     }
 }
@@ -0,0 +1,41 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::key::Key;
+
+/// Compare two MACs in constant time. Every byte is examined no matter where the first difference
+/// is, so the time taken doesn't reveal how much of a forged MAC was correct.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// The MAC that authenticates a subscription: a full HMAC-SHA256, keyed with the device key, over
+/// the start timestamp, end timestamp, channel, and then every unencrypted subscription key. This is
+/// the only place it is computed so generating and checking subscriptions can't drift apart.
+pub struct SubscriptionMac(Hmac<Sha256>);
+
+impl SubscriptionMac {
+    /// Start the MAC for a subscription's header.
+    pub fn new(device_key: &Key, start_timestamp: u64, end_timestamp: u64, channel: u32) -> Self {
+        let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(&device_key.0).unwrap();
+        hasher.update(&start_timestamp.to_le_bytes());
+        hasher.update(&end_timestamp.to_le_bytes());
+        hasher.update(&channel.to_le_bytes());
+        Self(hasher)
+    }
+
+    /// Add the next unencrypted subscription key.
+    pub fn update_key(&mut self, key: &[u8]) {
+        self.0.update(key);
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        self.0.finalize().into_bytes().into()
+    }
+
+    /// Check the MAC against the one sent with a subscription, in constant time.
+    pub fn verify(self, mac_hash: &[u8; 32]) -> bool {
+        ct_eq(&self.finalize(), mac_hash)
+    }
+}
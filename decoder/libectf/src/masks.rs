@@ -2,26 +2,52 @@ use alloc::vec::Vec;
 
 /// Mask widths that are used to encode packets and generate subscription keys. More mask widths
 /// means encoded packets are larger and subscriptions are smaller, and less mask widths means vice
-/// versa.
-pub const MASKS: &[u8] = &[0, 3, 6, 9, 12, 15, 18, 21, 24, 27, 30, 33, 36, 39, 42, 45, 48, 51, 54, 57, 60];
+/// versa. Set at build time with the `ECTF_MASKS` environment variable.
+pub const MASKS: &[u8] = &include!(concat!(env!("OUT_DIR"), "/masks.rs"));
+
+/// The most bitranges [`characterize_range`] can produce for any range. Each mask width needs at
+/// most `2^(next_width - width) - 1` bitranges at either end of the range, and the widest mask needs
+/// at most `2^(64 - width)` bitranges to cover everything in between.
+pub const MAX_BITRANGES: usize = {
+    let mut total = 1 << (64 - MASKS[MASKS.len() - 1]);
+    let mut i = 0;
+    while i < MASKS.len() - 1 {
+        total += 2 * ((1 << (MASKS[i + 1] - MASKS[i])) - 1);
+        i += 1;
+    }
+    total
+};
+
+/// The offsets within a bitrange of mask width `mask`, i.e. its low `mask` bits set. A width of 64
+/// covers every timestamp, which a plain shift would overflow on.
+pub(crate) const fn block_span(mask: u8) -> u64 {
+    match 1u64.checked_shl(mask as u32) {
+        Some(size) => size - 1,
+        None => u64::MAX,
+    }
+}
 
 /// Turn a range of timestamps into a list of bitranges `(start_timestamp, mask_idx)`
-pub(crate) fn characterize_range(mut a: u64, b: u64) -> Vec<(u64, u8)> {
+pub(crate) fn characterize_range(a: u64, b: u64) -> Vec<(u64, u8)> {
+    characterize_range_with(MASKS, a, b)
+}
+
+/// [`characterize_range`] with a different mask table.
+pub(crate) fn characterize_range_with(masks: &[u8], mut a: u64, b: u64) -> Vec<(u64, u8)> {
     let mut res = Vec::new();
 
     let mut mask_idx = 0;
 
     while a <= b {
-        if mask_idx < MASKS.len() - 1 {
-            let next_block_span = (1 << MASKS[mask_idx + 1]) - 1;
+        if mask_idx < masks.len() - 1 {
+            let next_block_span = block_span(masks[mask_idx + 1]);
             if a & next_block_span == 0 && a | next_block_span <= b {
                 mask_idx += 1;
                 continue;
             } 
         }
-        let block_span = (1 << MASKS[mask_idx]) - 1;
         res.push((a, mask_idx as u8));
-        a = (a | block_span).wrapping_add(1);
+        a = (a | block_span(masks[mask_idx])).wrapping_add(1);
         if a == 0 {  // Overflow
             return res;
         }
@@ -0,0 +1,49 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{key::{Key, KEY_SIZE_BYTES}, mac::ct_eq};
+
+/// Size of a REKEY packet body: the new device key encrypted with the current one, then the MAC
+/// that authenticates it.
+pub const REKEY_SIZE: usize = KEY_SIZE_BYTES + 32;
+
+/// Domain separation label for the rekey MAC, so it can't be mistaken for a subscription MAC
+/// under the same device key.
+pub const REKEY_LABEL: &[u8] = b"ectf25 rekey";
+
+/// The REKEY body that moves a decoder from device key `current` to `new`. The new key is
+/// encrypted with the current one, and an HMAC-SHA256 keyed with the current key over
+/// [`REKEY_LABEL`] and the encrypted key follows it. Only the host, which knows the current key,
+/// can make one.
+pub fn wrap_device_key(current: &Key, new: &Key) -> [u8; REKEY_SIZE] {
+    let mut encrypted = new.0;
+    current.cipher().encrypt(&mut encrypted);
+
+    let mut body = [0u8; REKEY_SIZE];
+    body[..KEY_SIZE_BYTES].copy_from_slice(&encrypted);
+    body[KEY_SIZE_BYTES..].copy_from_slice(&rekey_mac(current, &encrypted));
+    body
+}
+
+/// The new device key in a REKEY body, or `None` unless it's the right size and its MAC checks out
+/// under `current`. The MAC is checked in constant time before anything is decrypted.
+pub fn unwrap_device_key(current: &Key, body: &[u8]) -> Option<Key> {
+    if body.len() != REKEY_SIZE {
+        return None;
+    }
+
+    let mut encrypted: [u8; KEY_SIZE_BYTES] = body[..KEY_SIZE_BYTES].try_into().unwrap();
+    if !ct_eq(&rekey_mac(current, &encrypted), &body[KEY_SIZE_BYTES..]) {
+        return None;
+    }
+
+    current.cipher().decrypt(&mut encrypted);
+    Some(Key(encrypted))
+}
+
+fn rekey_mac(current: &Key, encrypted: &[u8; KEY_SIZE_BYTES]) -> [u8; 32] {
+    let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(&current.0).unwrap();
+    hasher.update(REKEY_LABEL);
+    hasher.update(encrypted);
+    hasher.finalize().into_bytes().into()
+}
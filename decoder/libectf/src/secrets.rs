@@ -0,0 +1,188 @@
+use core::fmt::Display;
+
+use alloc::vec::Vec;
+use rsa::{pkcs1::DecodeRsaPrivateKey, traits::PublicKeyParts, RsaPrivateKey};
+
+use crate::{frame::{MIN_RSA_KEY_BITS, RSA_KEY_BITS}, masks::MASKS};
+
+/// Magic at the front of framed secrets.
+pub const SECRETS_MAGIC: [u8; 4] = *b"ESEC";
+
+/// Version of the framed secrets format that [`Secrets::to_bytes`] writes. Version 2 secrets,
+/// which don't record the mask widths, are still read.
+pub const SECRETS_VERSION: u8 = 3;
+
+/// Size of the framed secrets header: the magic, version, payload length, and payload CRC32.
+const HEADER_SIZE: usize = 4 + 1 + 4 + 4;
+
+/// DER tag that starts legacy secrets, which are only the PKCS#1 key.
+const DER_SEQUENCE: u8 = 0x30;
+
+/// Global secrets generated by `gen_secrets`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Secrets {
+    /// Channels that exist, not including the broadcast channel. `None` for legacy secrets, which
+    /// don't record any channels.
+    pub channels: Option<Vec<u32>>,
+    /// Mask widths the secrets were generated for, which set how many keys are in each encoded
+    /// frame packet and how many are in a subscription. `None` for secrets from before they were
+    /// recorded, which are taken to be for this build's [`MASKS`].
+    pub masks: Option<Vec<u8>>,
+    /// PKCS#1 DER of the RSA signing key. Subscription and frame keys are derived from these bytes.
+    pub key: Vec<u8>,
+}
+
+/// Reasons secrets can fail to parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretsError {
+    /// The secrets don't start with anything we recognize.
+    UnknownFormat,
+    /// The framed secrets are from a version we don't support.
+    UnsupportedVersion(u8),
+    /// The secrets end before the header says they should.
+    Truncated,
+    /// The payload doesn't match the CRC32 in the header.
+    CrcMismatch { expected: u32, actual: u32 },
+    /// The key isn't a PKCS#1 DER RSA private key.
+    InvalidKey,
+    /// The key is this many bits rather than [`RSA_KEY_BITS`], so its signatures won't fit in a
+    /// frame packet.
+    WrongKeySize(usize),
+    /// The key is this many bits, less than [`MIN_RSA_KEY_BITS`].
+    KeyTooSmall(usize),
+    /// The secrets were generated for these mask widths rather than [`MASKS`], so frames and
+    /// subscriptions made with them wouldn't match decoders built with them.
+    WrongMasks(Vec<u8>),
+}
+
+/// Parse secrets written by [`Secrets::to_bytes`]. Legacy secrets that are only a key are still
+/// accepted. Either way the key is checked, so code using parsed secrets can expect it to load.
+pub fn parse_secrets(bytes: &[u8]) -> Result<Secrets, SecretsError> {
+    let secrets = parse_unchecked(bytes)?;
+    let key = RsaPrivateKey::from_pkcs1_der(&secrets.key).map_err(|_| SecretsError::InvalidKey)?;
+    if key.size() * 8 < MIN_RSA_KEY_BITS {
+        return Err(SecretsError::KeyTooSmall(key.size() * 8));
+    }
+    if key.size() * 8 != RSA_KEY_BITS {
+        return Err(SecretsError::WrongKeySize(key.size() * 8));
+    }
+    if let Some(masks) = secrets.masks.as_ref().filter(|&m| m != MASKS) {
+        return Err(SecretsError::WrongMasks(masks.clone()));
+    }
+    Ok(secrets)
+}
+
+/// Parse secrets without checking the key.
+fn parse_unchecked(bytes: &[u8]) -> Result<Secrets, SecretsError> {
+    if bytes.starts_with(&SECRETS_MAGIC) {
+        let header = bytes.get(..HEADER_SIZE).ok_or(SecretsError::Truncated)?;
+        let version = header[4];
+        let len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+        let expected = u32::from_le_bytes(header[9..13].try_into().unwrap());
+
+        if version != SECRETS_VERSION && version != 2 {
+            return Err(SecretsError::UnsupportedVersion(version));
+        }
+
+        let payload = bytes.get(HEADER_SIZE..).filter(|p| p.len() == len).ok_or(SecretsError::Truncated)?;
+        let actual = crc32(payload);
+        if actual != expected {
+            return Err(SecretsError::CrcMismatch { expected, actual });
+        }
+
+        return parse_payload(payload, version);
+    }
+
+    match bytes.first() {
+        Some(&DER_SEQUENCE) => Ok(Secrets { channels: None, masks: None, key: bytes.to_vec() }),
+        _ => Err(SecretsError::UnknownFormat),
+    }
+}
+
+/// Parse the number of channels and each channel, then for version 3 the number of masks and each
+/// mask, and then the key.
+fn parse_payload(bytes: &[u8], version: u8) -> Result<Secrets, SecretsError> {
+    let (channels, rest) = parse_list(bytes, 4)?;
+    let channels = channels.chunks_exact(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect();
+
+    let (masks, key) = match version {
+        2 => (None, rest),
+        _ => parse_list(rest, 1).map(|(masks, key)| (Some(masks.to_vec()), key))?,
+    };
+
+    Ok(Secrets { channels: Some(channels), masks, key: key.to_vec() })
+}
+
+/// Split a u32 count followed by that many `size` byte items off the front of `bytes`.
+fn parse_list(bytes: &[u8], size: usize) -> Result<(&[u8], &[u8]), SecretsError> {
+    let count = u32::from_le_bytes(bytes.get(..4).ok_or(SecretsError::Truncated)?.try_into().unwrap()) as usize;
+    let end = count.checked_mul(size).and_then(|n| n.checked_add(4)).ok_or(SecretsError::Truncated)?;
+    let items = bytes.get(4..end).ok_or(SecretsError::Truncated)?;
+    Ok((items, &bytes[end..]))
+}
+
+/// Bitwise CRC32 (IEEE). Secrets are only parsed on the host so this doesn't need to be fast.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+impl Secrets {
+    /// Serialize the secrets as the magic, version, payload length, payload CRC32, and then the
+    /// payload: the number of channels, each channel, the number of masks, each mask, and the key.
+    /// Secrets that don't record their masks are written with this build's. Legacy secrets are
+    /// written back out as only the key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let Some(channels) = &self.channels else {
+            return self.key.clone();
+        };
+
+        let masks = self.masks.as_deref().unwrap_or(MASKS);
+
+        let mut payload = Vec::with_capacity(4 + channels.len() * 4 + 4 + masks.len() + self.key.len());
+        payload.extend_from_slice(&(channels.len() as u32).to_le_bytes());
+        for channel in channels {
+            payload.extend_from_slice(&channel.to_le_bytes());
+        }
+        payload.extend_from_slice(&(masks.len() as u32).to_le_bytes());
+        payload.extend_from_slice(masks);
+        payload.extend_from_slice(&self.key);
+
+        let mut res = Vec::with_capacity(HEADER_SIZE + payload.len());
+        res.extend_from_slice(&SECRETS_MAGIC);
+        res.push(SECRETS_VERSION);
+        res.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        res.extend_from_slice(&crc32(&payload).to_le_bytes());
+        res.extend_from_slice(&payload);
+        res
+    }
+
+    /// Checks if a channel exists. The broadcast channel always exists, and every channel exists
+    /// for legacy secrets.
+    pub fn has_channel(&self, channel: u32) -> bool {
+        channel == 0 || self.channels.as_ref().is_none_or(|c| c.contains(&channel))
+    }
+}
+
+impl Display for SecretsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SecretsError::UnknownFormat => write!(f, "not a secrets file"),
+            SecretsError::UnsupportedVersion(v) => write!(f, "unsupported secrets version {}", v),
+            SecretsError::Truncated => write!(f, "secrets are truncated"),
+            SecretsError::CrcMismatch { expected, actual } => {
+                write!(f, "secrets CRC mismatch (expected {:#010x}, got {:#010x})", expected, actual)
+            },
+            SecretsError::InvalidKey => write!(f, "secrets key isn't a PKCS#1 RSA private key"),
+            SecretsError::WrongKeySize(bits) => write!(f, "secrets key is {} bits, but frames are signed with {} bit keys", bits, RSA_KEY_BITS),
+            SecretsError::KeyTooSmall(bits) => write!(f, "secrets key is {} bits, but keys must be at least {} bits", bits, MIN_RSA_KEY_BITS),
+            SecretsError::WrongMasks(masks) => write!(f, "secrets are for mask widths {:?}, but this build uses {:?}", masks, MASKS),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Serializes byte arrays as hex strings, for `#[serde(with = "crate::serde_hex")]`. Arrays of
+//! integers are unreadable in JSON, and serde can't derive arrays longer than 32 anyway.
+
+use core::fmt::Write;
+
+use alloc::string::String;
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S: Serializer, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+    let mut hex = String::with_capacity(N * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    serializer.serialize_str(&hex)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    if hex.len() != N * 2 {
+        return Err(D::Error::custom(format_args!("expected {} hex digits, got {}", N * 2, hex.len())));
+    }
+
+    let mut bytes = [0u8; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        let digits = core::str::from_utf8(digits).map_err(D::Error::custom)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(D::Error::custom)?;
+    }
+    Ok(bytes)
+}
@@ -1,12 +1,19 @@
+use core::fmt::Display;
+
 use alloc::vec::Vec;
-use hmac::{Hmac, Mac};
 use rkyv::{Archive, Deserialize, Serialize};
-use sha2::Sha256;
+#[cfg(feature = "ct")]
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
 
-use crate::{frame::ArchivedEncodedFramePacketHeader, key::Key, masks::{characterize_range, MASKS}};
+use crate::{frame::ArchivedEncodedFramePacketHeader, key::Key, mac::SubscriptionMac, masks::{characterize_range, MASKS, MAX_BITRANGES}};
 
-/// Channel information that is sent in response to a list subscription command.
-#[derive(Debug, Archive, Serialize, Deserialize)]
+/// The most keys a valid subscription can have, no matter its time range.
+pub const MAX_SUBSCRIPTION_KEYS: usize = MAX_BITRANGES;
+
+/// Channel information that is sent in response to a list subscription command. These order by
+/// channel, then start, then end, which is the order the decoder lists them in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Archive, Serialize, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChannelInfo {
     pub channel: u32,
     pub start: u64,
@@ -21,8 +28,11 @@ pub struct SubscriptionData {
     pub keys: Vec<EncodedSubscriptionKey>
 }
 
-/// Subscription channel, time range, and a mac_hash for data authentication.
+/// Subscription channel, time range, and a mac_hash for data authentication. The range includes
+/// both ends, so a subscription with `start_timestamp == end_timestamp` covers exactly one
+/// timestamp.
 #[derive(Debug, Archive, Serialize, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[rkyv(derive(Debug))]
 pub struct SubscriptionDataHeader {
     pub start_timestamp: u64,
@@ -30,21 +40,57 @@ pub struct SubscriptionDataHeader {
     pub channel: u32,
     /// SHA256 of the entire contents of the subscription data packet. Calculated like this:
     /// `SHA256(start_timestamp, end_timestamp, channel, UNENCRYPTED_KEY for each key)`
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_hex"))]
     pub mac_hash: [u8; 32]
 }
 
 /// An encoded subscription key valid for a bitrange. The start_timestamp isn't encoded with the
 /// key because they are all adjacent.
 #[derive(Debug, Archive, Serialize, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[rkyv(derive(Debug))]
 pub struct EncodedSubscriptionKey {
     pub key: Key
 }
 
+impl SubscriptionDataHeader {
+    /// The `(start_timestamp, mask_idx)` bitrange each key of this subscription is valid for, in the
+    /// same order as the keys.
+    pub fn bitranges(&self) -> Vec<(u64, u8)> {
+        characterize_range(self.start_timestamp, self.end_timestamp)
+    }
+}
+
 impl ArchivedSubscriptionDataHeader {
+    /// The channel this subscription is for.
+    pub fn channel(&self) -> u32 {
+        self.channel.to_native()
+    }
+
+    /// First timestamp this subscription covers.
+    pub fn start_timestamp(&self) -> u64 {
+        self.start_timestamp.to_native()
+    }
+
+    /// Last timestamp this subscription covers.
+    pub fn end_timestamp(&self) -> u64 {
+        self.end_timestamp.to_native()
+    }
+
+    /// Start the MAC for this header. The keys still need to be added.
+    pub fn mac(&self, device_key: &Key) -> SubscriptionMac {
+        SubscriptionMac::new(device_key, self.start_timestamp(), self.end_timestamp(), self.channel())
+    }
+
     /// Checks if we can use this subscription to decode a frame.
     pub fn contains_frame(&self, frame: &ArchivedEncodedFramePacketHeader) -> bool {
-        self.channel == frame.channel && self.start_timestamp <= frame.timestamp && self.end_timestamp >= frame.timestamp
+        self.channel() == frame.channel() && (self.start_timestamp()..=self.end_timestamp()).contains(&frame.timestamp())
+    }
+
+    /// The `(start_timestamp, mask_idx)` bitrange each key of this subscription is valid for, in the
+    /// same order as the keys.
+    pub fn bitranges(&self) -> Vec<(u64, u8)> {
+        characterize_range(self.start_timestamp(), self.end_timestamp())
     }
 
     /// Finds a key we can use to decode a frame.
@@ -53,35 +99,137 @@ impl ArchivedSubscriptionDataHeader {
             return None;
         }
 
-        for (key, (start_timestamp, mask_idx)) in keys.iter().zip(characterize_range(self.start_timestamp.to_native(), self.end_timestamp.to_native()).into_iter()) {
+        for (key, (start_timestamp, mask_idx)) in keys.iter().zip(self.bitranges()) {
             let mask = MASKS[mask_idx as usize];
-            if (start_timestamp ^ header.timestamp) >> mask == 0 {
+            if (start_timestamp ^ header.timestamp()) >> mask == 0 {
                 return Some((key, mask_idx));
             }
         }
 
         None
     }
+
+    /// [`ArchivedSubscriptionDataHeader::key_for_frame`] in constant time, see
+    /// [`ArchivedSubscriptionDataHeader::bitrange_for_frame_ct`]. Only built with the `ct` feature.
+    #[cfg(feature = "ct")]
+    pub fn key_for_frame_ct<'k>(&self, header: &ArchivedEncodedFramePacketHeader, keys: &'k [ArchivedEncodedSubscriptionKey]) -> Option<(&'k ArchivedEncodedSubscriptionKey, u8)> {
+        let (i, mask_idx) = self.bitrange_for_frame_ct(header, &self.bitranges())?;
+        Some((keys.get(i)?, mask_idx))
+    }
+
+    /// [`ArchivedSubscriptionDataHeader::key_for_frame`] using bitranges from
+    /// [`ArchivedSubscriptionDataHeader::bitranges`] that were computed ahead of time. The bitranges
+    /// are sorted, so this is a binary search instead of a scan.
+    pub fn key_for_frame_cached<'k>(&self, header: &ArchivedEncodedFramePacketHeader, keys: &'k [ArchivedEncodedSubscriptionKey], bitranges: &[(u64, u8)]) -> Option<(&'k ArchivedEncodedSubscriptionKey, u8)> {
+        let (i, mask_idx) = self.bitrange_for_frame(header, bitranges)?;
+        Some((keys.get(i)?, mask_idx))
+    }
+
+    /// The index into `bitranges` of the bitrange containing a frame, and its mask. Like
+    /// [`ArchivedSubscriptionDataHeader::key_for_frame_cached`], but the index lets callers keep
+    /// state for each key.
+    pub fn bitrange_for_frame(&self, header: &ArchivedEncodedFramePacketHeader, bitranges: &[(u64, u8)]) -> Option<(usize, u8)> {
+        if !self.contains_frame(header) {
+            return None;
+        }
+
+        // Last bitrange that starts at or before the frame
+        let timestamp = header.timestamp();
+        let i = bitranges.partition_point(|&(start_timestamp, _)| start_timestamp <= timestamp).checked_sub(1)?;
+        let (start_timestamp, mask_idx) = bitranges[i];
+
+        if (start_timestamp ^ timestamp) >> MASKS[mask_idx as usize] == 0 {
+            Some((i, mask_idx))
+        } else {
+            None
+        }
+    }
+
+    /// [`ArchivedSubscriptionDataHeader::bitrange_for_frame`] without the early exit. Every bitrange
+    /// is checked and the match is picked out with constant time selects, so how long it takes
+    /// doesn't show which key a frame needed. Only built with the `ct` feature.
+    #[cfg(feature = "ct")]
+    pub fn bitrange_for_frame_ct(&self, header: &ArchivedEncodedFramePacketHeader, bitranges: &[(u64, u8)]) -> Option<(usize, u8)> {
+        if !self.contains_frame(header) {
+            return None;
+        }
+
+        let timestamp = header.timestamp();
+        let mut found = Choice::from(0);
+        let mut index = 0u64;
+        let mut found_mask_idx = 0u8;
+
+        // Bitranges don't overlap, so at most one matches
+        for (i, &(start_timestamp, mask_idx)) in bitranges.iter().enumerate() {
+            let matches = ((start_timestamp ^ timestamp) >> MASKS[mask_idx as usize]).ct_eq(&0);
+            index.conditional_assign(&(i as u64), matches);
+            found_mask_idx.conditional_assign(&mask_idx, matches);
+            found |= matches;
+        }
+
+        bool::from(found).then_some((index as usize, found_mask_idx))
+    }
+}
+
+/// Reasons a subscription can't be generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscriptionError {
+    /// The range starts after it ends, so it wouldn't cover any timestamps.
+    InvertedRange { start: u64, end: u64 },
+    /// Channel 0 can't be subscribed to. Every decoder is built with its keys, see
+    /// [`SubscriptionData::generate_channel_0`], and decoders that take refreshed keys get them
+    /// from [`SubscriptionData::generate_channel_0_window`].
+    Channel0,
 }
 
 impl SubscriptionData {
-    /// Generate a subscription key.
-    pub fn generate(secrets: &[u8], start: u64, end: u64, channel: u32, device_id: Option<u32>) -> SubscriptionData {
-        let mut key_and_hasher = device_id.map(|d| {
-            let k = Key::for_device(d, secrets);
-            let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(&k.0).unwrap();
-            hasher.update(&start.to_le_bytes());
-            hasher.update(&end.to_le_bytes());
-            hasher.update(&channel.to_le_bytes());
-
-            (k.cipher(), hasher)
-        });
+    /// Generate a subscription for `start..=end`. `start == end` is a subscription to that one
+    /// timestamp, and `start > end` is an error rather than a subscription without any keys.
+    /// Decoders refuse subscriptions to channel 0, so that's an error too.
+    pub fn generate(secrets: &[u8], start: u64, end: u64, channel: u32, device_id: Option<u32>) -> Result<SubscriptionData, SubscriptionError> {
+        let device_key = device_id.map(|d| Key::for_device(d, secrets));
+        Self::generate_for_key(secrets, start, end, channel, device_key.as_ref())
+    }
+
+    /// Like [`SubscriptionData::generate`], but for a decoder whose device key is `device_key`
+    /// rather than the one derived from its ID, such as one that's been rekeyed.
+    pub fn generate_for_key(secrets: &[u8], start: u64, end: u64, channel: u32, device_key: Option<&Key>) -> Result<SubscriptionData, SubscriptionError> {
+        if channel == 0 {
+            return Err(SubscriptionError::Channel0);
+        }
+        if start > end {
+            return Err(SubscriptionError::InvertedRange { start, end });
+        }
+
+        Ok(Self::generate_unchecked(secrets, start, end, channel, device_key))
+    }
+
+    /// The channel 0 keys for every timestamp, which are built into each decoder instead of being
+    /// sent to it. They aren't encrypted with a device key, and the MAC is left zeroed.
+    pub fn generate_channel_0(secrets: &[u8]) -> SubscriptionData {
+        Self::generate_unchecked(secrets, 0, u64::MAX, 0, None)
+    }
+
+    /// The channel 0 keys for `start..=end` only, sent like a subscription to decoders built to
+    /// take refreshed channel 0 keys. They use these in place of their built in keys, so channel 0
+    /// frames outside the window stop decoding.
+    pub fn generate_channel_0_window(secrets: &[u8], start: u64, end: u64, device_id: Option<u32>) -> Result<SubscriptionData, SubscriptionError> {
+        if start > end {
+            return Err(SubscriptionError::InvertedRange { start, end });
+        }
+
+        let device_key = device_id.map(|d| Key::for_device(d, secrets));
+        Ok(Self::generate_unchecked(secrets, start, end, 0, device_key.as_ref()))
+    }
+
+    fn generate_unchecked(secrets: &[u8], start: u64, end: u64, channel: u32, device_key: Option<&Key>) -> SubscriptionData {
+        let mut key_and_hasher = device_key.map(|k| (k.cipher(), SubscriptionMac::new(k, start, end, channel)));
 
         let keys = characterize_range(start, end).into_iter().map(|(t, mask_idx)| {
             let mut key = Key::for_bitrange(t, mask_idx, channel, secrets);
 
             if let Some((device_key_cipher, hasher)) = &mut key_and_hasher {
-                hasher.update(&key.0);
+                hasher.update_key(&key.0);
                 device_key_cipher.encrypt(&mut key.0);
             }
 
@@ -94,10 +242,32 @@ impl SubscriptionData {
             channel,
             start_timestamp: start,
             end_timestamp: end,
-            mac_hash: key_and_hasher.map(|(_, hasher)| hasher.finalize().into_bytes().into()).unwrap_or([0; 32])
+            mac_hash: key_and_hasher.map(|(_, hasher)| hasher.finalize()).unwrap_or([0; 32])
         };
 
         SubscriptionData { header, keys }
     }
+
+    /// Checks the MAC of a subscription whose keys are still encrypted with `device_key`, the way
+    /// the decoder does when it's subscribed. The keys are decrypted into a scratch buffer, so the
+    /// subscription is left as it was sent.
+    pub fn would_authenticate(&self, device_key: &Key) -> bool {
+        let mut cipher = device_key.cipher();
+        let mut hasher = SubscriptionMac::new(device_key, self.header.start_timestamp, self.header.end_timestamp, self.header.channel);
+        for k in &self.keys {
+            let mut key = k.key.0;
+            cipher.decrypt(&mut key);
+            hasher.update_key(&key);
+        }
+        hasher.verify(&self.header.mac_hash)
+    }
 }
 
+impl Display for SubscriptionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SubscriptionError::InvertedRange { start, end } => write!(f, "subscription start {} is after end {}", start, end),
+            SubscriptionError::Channel0 => write!(f, "can't subscribe to channel 0"),
+        }
+    }
+}
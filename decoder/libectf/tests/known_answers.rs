@@ -0,0 +1,83 @@
+//! Frozen outputs of everything that has to match between the encoder and deployed decoders, in
+//! `known_answers.txt`. A change to a key derivation, the masks, the MAC, or a packet layout fails
+//! here, and the assertion message has the new value to paste in once the change is deliberate.
+#![cfg(not(feature = "rsa-2048"))]
+
+use libectf::{key::Key, masks::MASKS, secrets::parse_secrets, subscription::SubscriptionData};
+
+const VECTORS: &str = include_str!("known_answers.txt");
+
+const DEVICE_ID: u32 = 0xdeadbeef;
+const CHANNEL: u32 = 1;
+const SUBSCRIPTION_START: u64 = 100;
+const SUBSCRIPTION_END: u64 = 1000;
+const TIMESTAMP: u64 = 0x1234_5678;
+
+/// Masks the vectors were generated with, the default for `ECTF_MASKS`.
+const DEFAULT_MASKS: [u8; 21] = [0, 3, 6, 9, 12, 15, 18, 21, 24, 27, 30, 33, 36, 39, 42, 45, 48, 51, 54, 57, 60];
+
+/// The vector called `name`.
+fn vector(name: &str) -> &'static str {
+    VECTORS.lines()
+        .filter_map(|line| line.split_once(" = "))
+        .find(|(n, _)| *n == name)
+        .unwrap_or_else(|| panic!("no vector named {}", name))
+        .1
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+fn assert_vector(name: &str, actual: &[u8]) {
+    assert_eq!(MASKS, DEFAULT_MASKS, "known answers are for the default ECTF_MASKS");
+    assert_eq!(hex(actual), vector(name), "{} changed. If that's deliberate, bump the format version and update known_answers.txt", name);
+}
+
+/// The key from the secrets vector.
+fn secrets() -> Vec<u8> {
+    parse_secrets(&unhex(vector("secrets"))).unwrap().key
+}
+
+#[test]
+fn test_key_derivation() {
+    let secrets = secrets();
+    assert_vector("device_key", &Key::for_device(DEVICE_ID, &secrets).0);
+    assert_vector("bitrange_key", &Key::for_bitrange(TIMESTAMP & !0x1ff, 3, CHANNEL, &secrets).0);
+    assert_vector("frame_key", &Key::for_frame(TIMESTAMP, CHANNEL, &secrets).0);
+}
+
+#[test]
+fn test_subscription() {
+    let data = SubscriptionData::generate(&secrets(), SUBSCRIPTION_START, SUBSCRIPTION_END, CHANNEL, Some(DEVICE_ID)).unwrap();
+
+    // The mask schedule decides which keys a subscription gets
+    let bitranges: Vec<u8> = data.header.bitranges().iter().flat_map(|&(start, mask_idx)| [&start.to_le_bytes()[..], &[mask_idx]].concat()).collect();
+    assert_vector("subscription_bitranges", &bitranges);
+    assert_vector("subscription_mac", &data.header.mac_hash);
+
+    let mut packet = rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap().into_vec();
+    for key in &data.keys {
+        packet.extend_from_slice(&rkyv::to_bytes::<rkyv::rancor::Error>(key).unwrap());
+    }
+    assert_vector("subscription_packet", &packet);
+}
+
+#[test]
+#[cfg(not(feature = "aead"))]
+fn test_frame_packet() {
+    use libectf::frame::{Frame, FRAME_SIZE, FULL_FRAME_LENGTH};
+    use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs1v15::SigningKey, sha2::Sha256};
+
+    let secrets = secrets();
+    let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap();
+
+    // PKCS#1 v1.5 signatures are deterministic, so the whole packet is
+    let mut packet = Vec::new();
+    Frame([0x42; FRAME_SIZE]).encode_into::<_, rkyv::rancor::Error>(TIMESTAMP, CHANNEL, FULL_FRAME_LENGTH, &secrets, &signing_key, &mut packet).unwrap();
+    assert_vector("frame_packet", &packet);
+}
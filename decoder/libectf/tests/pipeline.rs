@@ -0,0 +1,95 @@
+//! The whole path a frame takes, from generating secrets to decoding it, done the way the encoder
+//! and decoder each do their half. Catches the two sides drifting apart, which the unit tests of
+//! either side can't.
+
+use std::{mem, slice};
+
+use libectf::{frame::{ArchivedEncodedFramePacket, Frame, FRAME_SIZE, FULL_FRAME_LENGTH, RSA_KEY_BITS}, key::{Key, KEY_SIZE_BYTES}, masks::MASKS, secrets::{parse_secrets, Secrets}, subscription::{ArchivedEncodedSubscriptionKey, ArchivedSubscriptionDataHeader, SubscriptionData}};
+use rand::rngs::OsRng;
+use rkyv::util::AlignedVec;
+use rsa::{pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey}, pkcs1v15::SigningKey, sha2::Sha256, signature::Keypair, RsaPrivateKey};
+
+const DEVICE_ID: u32 = 0xdeadbeef;
+
+/// Secrets for `channels`, generated like `gen_secrets` does.
+fn gen_secrets(channels: Vec<u32>) -> Vec<u8> {
+    let private_key = RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS).unwrap();
+    let key = private_key.to_pkcs1_der().unwrap().as_bytes().to_vec();
+    Secrets { channels: Some(channels), masks: Some(MASKS.to_vec()), key }.to_bytes()
+}
+
+/// A subscription packet body, generated and serialized like `gen_subscription` does.
+fn gen_subscription(secrets: &[u8], start: u64, end: u64, channel: u32) -> AlignedVec {
+    let data = SubscriptionData::generate(&parse_secrets(secrets).unwrap().key, start, end, channel, Some(DEVICE_ID)).unwrap();
+
+    let mut bytes = AlignedVec::new();
+    bytes.extend_from_slice(&rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap());
+    for key in &data.keys {
+        bytes.extend_from_slice(&rkyv::to_bytes::<rkyv::rancor::Error>(key).unwrap());
+    }
+    bytes
+}
+
+/// A frame packet body, encoded like `Encoder::encode` does.
+fn encode(secrets: &[u8], frame: &Frame, timestamp: u64, channel: u32) -> AlignedVec {
+    let key = parse_secrets(secrets).unwrap().key;
+    let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&key).unwrap();
+
+    let mut bytes = AlignedVec::new();
+    frame.encode_into::<_, rkyv::rancor::Error>(timestamp, channel, FULL_FRAME_LENGTH, &key, &signing_key, &mut bytes).unwrap();
+    bytes
+}
+
+/// Decodes a frame packet with a subscription packet the way the decoder does, returning `None`
+/// if the subscription doesn't cover the frame. Panics if the frame or subscription don't
+/// authenticate, since neither is tampered with here.
+fn decode(secrets: &[u8], subscription: &mut AlignedVec, packet: &AlignedVec) -> Option<[u8; FRAME_SIZE]> {
+    let secrets = parse_secrets(secrets).unwrap();
+    let header_size = mem::size_of::<ArchivedSubscriptionDataHeader>();
+    let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
+
+    // Decrypt the subscription keys with the device key and check the MAC over them
+    let (header, keys) = subscription.split_at_mut(header_size);
+    let header = unsafe { rkyv::access_unchecked::<ArchivedSubscriptionDataHeader>(header) };
+    let device_key = Key::for_device(DEVICE_ID, &secrets.key);
+    let mut cipher = device_key.cipher();
+    let mut mac = header.mac(&device_key);
+    for key in keys.chunks_exact_mut(key_size) {
+        cipher.decrypt(<&mut [u8; KEY_SIZE_BYTES]>::try_from(&mut *key).unwrap());
+        mac.update_key(key);
+    }
+    assert!(mac.verify(&header.mac_hash), "subscription MAC doesn't match");
+    let keys = unsafe { slice::from_raw_parts(keys.as_ptr() as *const ArchivedEncodedSubscriptionKey, keys.len() / key_size) };
+
+    // Check the prefix and size, then find the key for the frame
+    let encoded_frame = ArchivedEncodedFramePacket::from_bytes(packet).unwrap();
+    let (key, mask_idx) = header.key_for_frame(&encoded_frame.header, keys)?;
+
+    // Decrypt the frame key, then the frame, and check its signature or tag
+    let verifying_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets.key).unwrap().verifying_key();
+    let frame = encoded_frame.decode(&Key(key.key.0), mask_idx, &verifying_key).expect("frame doesn't authenticate");
+
+    Some(frame.0)
+}
+
+#[test]
+fn test_pipeline_round_trip() {
+    let secrets = gen_secrets(vec![1, 2]);
+    let mut subscription = gen_subscription(&secrets, 100, 1000, 1);
+    let frame = Frame(*b"abcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcd");
+
+    for timestamp in [100, 101, 555, 999, 1000] {
+        let packet = encode(&secrets, &frame, timestamp, 1);
+        assert_eq!(decode(&secrets, &mut subscription.clone(), &packet), Some(frame.0), "frame at {}", timestamp);
+    }
+
+    // Frames just outside the subscription's range have no key
+    for timestamp in [0, 99, 1001, u64::MAX] {
+        let packet = encode(&secrets, &frame, timestamp, 1);
+        assert_eq!(decode(&secrets, &mut subscription.clone(), &packet), None, "frame at {}", timestamp);
+    }
+
+    // Neither do frames on a channel the subscription isn't for
+    let packet = encode(&secrets, &frame, 500, 2);
+    assert_eq!(decode(&secrets, &mut subscription, &packet), None);
+}
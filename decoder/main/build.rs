@@ -8,14 +8,19 @@
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
 //!
-//! The build script also sets the linker flags to tell it which link script to use.
+//! The build script also sets the linker flags to tell it which link script to use, and passes
+//! the flash storage region and RAM size from `memory.x` on to the firmware. The UART baud rate is
+//! read from `ECTF_BAUD`, see `src/baud.rs`.
 
 use std::{env, fs};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use libectf::key::Key;
+use libectf::frame::{compat_hash_for, FRAME_SIZE};
+use libectf::key::{Key, KEY_SIZE_BYTES};
+use libectf::masks::MASKS;
+use libectf::secrets::{parse_secrets, Secrets};
 use libectf::subscription::SubscriptionData;
 use quote::quote;
 use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPublicKey};
@@ -23,8 +28,21 @@ use rsa::pkcs1v15::SigningKey;
 use rsa::sha2::{Digest, Sha256};
 use rsa::signature::Keypair;
 
+#[path = "src/baud.rs"]
+#[allow(dead_code)]
+mod baud;
+
 const DEFAULT_DECODER_ID: u32 = 0xdeadbeef;
 const SECRETS_FILE: &str = "../../global.secrets";
+const MEMORY_FILE: &str = "../memory.x";
+
+/// Flash on the MAX78000, which the storage region has to be in. Flash is erased a page at a time.
+const DEVICE_FLASH: (u32, u32) = (0x1000_0000, 0x0008_0000);
+const FLASH_PAGE_SIZE: u32 = 0x2000;
+
+/// Version of the subscription layout in flash. This goes into the flash magic, so bump it whenever
+/// the layout in `src/flash.rs` changes and decoders will erase flash they can't read.
+const FLASH_LAYOUT_VERSION: u32 = 6;
 
 fn main() -> anyhow::Result<()> {
     let decoder_id: u32 = match env::var("DECODER_ID") {
@@ -38,18 +56,39 @@ fn main() -> anyhow::Result<()> {
         Err(_) => { DEFAULT_DECODER_ID },
     };
 
-    let secrets: Vec<u8> = fs::read(SECRETS_FILE)?;
+    println!("cargo:rerun-if-env-changed=ECTF_BAUD");
+    let baud_rate = baud::parse_baud_rate(env::var("ECTF_BAUD").ok().as_deref()).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    // The libectf this script is linked with is built for the host, and doesn't get the features
+    // the firmware turns on in its own, so the signature size comes from our features. `ECTF_MASKS`
+    // is read by libectf's build script for both.
+    let rsa_key_bits = if env::var_os("CARGO_FEATURE_RSA_2048").is_some() { 2048 } else { 1024 };
+    let compat_hash = compat_hash_for(FRAME_SIZE, MASKS, KEY_SIZE_BYTES, rsa_key_bits / 8);
+
+    let secrets_file: Vec<u8> = fs::read(SECRETS_FILE)?;
+    let secrets = parse_secrets(&secrets_file).map_err(|e| anyhow::anyhow!("Invalid secrets file {}: {}", SECRETS_FILE, e))?;
     
-    // Hash the secrets and take the first 4 bytes as the flash magic so that when we generate new
-    // secrets it'll erase the old subscriptions
+    // Hash the secrets and the flash layout version and take the first 4 bytes as the flash magic
+    // so that when we generate new secrets or change the layout it'll erase the old subscriptions.
+    // The secrets are reframed first so the magic only depends on their contents.
     let mut hasher: Sha256 = Digest::new();
-    hasher.update(&secrets);
+    hasher.update(secrets.to_bytes());
+    hasher.update(FLASH_LAYOUT_VERSION.to_le_bytes());
     let secrets_hash: [u8; 32] = hasher.finalize().into();
     let flash_magic: u32 = u32::from_le_bytes(secrets_hash[..4].try_into().unwrap());
 
+    let Secrets { channels, key: secrets, .. } = secrets;
+
+    // Channels that we will accept subscriptions for. Legacy secrets don't record any channels so
+    // every channel is allowed.
+    let channels_code = match channels {
+        Some(channels) => quote! { Some(&[#(#channels),*]) },
+        None => quote! { None },
+    };
+
     let decoder_key = Key::for_device(decoder_id, &secrets).0;
 
-    let s = SubscriptionData::generate(&secrets, 0, u64::MAX, 0, None);
+    let s = SubscriptionData::generate_channel_0(&secrets);
 
     let keys_code = s.keys.iter().map(|k| {
         let key = k.key.0;
@@ -61,7 +100,15 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
-    let verifying_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets).unwrap().verifying_key().to_pkcs1_der().unwrap();
+    let bitranges_code = s.header.bitranges().into_iter().map(|(start_timestamp, mask_idx)| {
+        quote! { (#start_timestamp, #mask_idx) }
+    });
+
+    let verifying_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets)
+        .map_err(|e| anyhow::anyhow!("Invalid signing key in secrets file {}: {}", SECRETS_FILE, e))?
+        .verifying_key()
+        .to_pkcs1_der()
+        .unwrap();
     let verifying_key_bytes = verifying_key.as_bytes();
 
     let code = quote! {
@@ -71,8 +118,12 @@ fn main() -> anyhow::Result<()> {
         pub static DECODER_ID: u32 = #decoder_id;
         pub static DECODER_KEY: Key = Key([#(#decoder_key),*]);
         pub static CHANNEL_0_KEYS: &[ArchivedEncodedSubscriptionKey] = &[#(#keys_code),*];
+        pub static CHANNEL_0_BITRANGES: &[(u64, u8)] = &[#(#bitranges_code),*];
         pub static VERIFYING_KEY: &[u8] = &[#(#verifying_key_bytes),*];
         pub static FLASH_MAGIC: u32 = #flash_magic;
+        pub static CHANNELS: Option<&[u32]> = #channels_code;
+        pub static BAUD_RATE: u32 = #baud_rate;
+        pub static COMPAT_HASH: u32 = #compat_hash;
     };
 
     let dest_path = Path::new("src/keys.rs");
@@ -84,27 +135,33 @@ fn main() -> anyhow::Result<()> {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory_x = fs::read_to_string(MEMORY_FILE)?;
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("../memory.x"))
+        .write_all(memory_x.as_bytes())
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    fs::write(out.join("memory.rs"), memory_code(&memory_x)?).expect("Failed to write memory.rs");
+
     // By default, Cargo will re-run a build script whenever
     // any file in the project changes. By specifying `memory.x`
     // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
-
-    // Specify linker arguments.
+    // `memory.x` is changed. The flash layout and heap size come
+    // from it too, so they're regenerated along with it.
+    println!("cargo:rerun-if-changed={}", MEMORY_FILE);
 
-    // `--nmagic` is required if memory section addresses are not aligned to 0x10000,
-    // for example the FLASH and RAM sections in your `memory.x`.
-    // See https://github.com/rust-embedded/cortex-m-quickstart/pull/95
-    println!("cargo:rustc-link-arg=--nmagic");
+    // Specify linker arguments. These only apply to the firmware, not to unit tests built for the
+    // host.
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        // `--nmagic` is required if memory section addresses are not aligned to 0x10000,
+        // for example the FLASH and RAM sections in your `memory.x`.
+        // See https://github.com/rust-embedded/cortex-m-quickstart/pull/95
+        println!("cargo:rustc-link-arg=--nmagic");
 
-    // Set the linker script to the one provided by cortex-m-rt.
-    println!("cargo:rustc-link-arg=-Tlink.x");
+        // Set the linker script to the one provided by cortex-m-rt.
+        println!("cargo:rustc-link-arg=-Tlink.x");
+    }
 
     // Optimizations
     println!("cargo:rustc-cfg=target_cpu=\"cortex-m4\"");
@@ -112,3 +169,112 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// A region from the `MEMORY` block of a linker script.
+struct Region {
+    name: String,
+    origin: u32,
+    length: u32,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.origin as u64 + self.length as u64
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        (self.origin as u64) < other.end() && (other.origin as u64) < self.end()
+    }
+}
+
+/// Parses the `MEMORY` block of a linker script. Only handles the `NAME (attrs) : ORIGIN = x,
+/// LENGTH = y` lines that ours uses.
+fn parse_memory_regions(script: &str) -> anyhow::Result<Vec<Region>> {
+    // Drop comments, including the commented out DEV layout
+    let mut text = String::new();
+    let mut rest = script;
+    while let Some(start) = rest.find("/*") {
+        text.push_str(&rest[..start]);
+        let end = rest[start..].find("*/").ok_or_else(|| anyhow::anyhow!("Unterminated comment in {}", MEMORY_FILE))?;
+        rest = &rest[start + end + 2..];
+    }
+    text.push_str(rest);
+
+    let block = text.find("MEMORY")
+        .and_then(|start| {
+            let open = start + text[start..].find('{')? + 1;
+            let close = open + text[open..].find('}')?;
+            Some(&text[open..close])
+        })
+        .ok_or_else(|| anyhow::anyhow!("No MEMORY block in {}", MEMORY_FILE))?;
+
+    let parse_number = |s: &str| -> anyhow::Result<u32> {
+        let s = s.trim();
+        let (digits, scale) = match s.strip_suffix('K').or(s.strip_suffix('M')) {
+            Some(digits) if s.ends_with('K') => (digits, 1 << 10),
+            Some(digits) => (digits, 1 << 20),
+            None => (s, 1),
+        };
+        let value = match digits.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16)?,
+            None => digits.parse()?,
+        };
+        value.checked_mul(scale).ok_or_else(|| anyhow::anyhow!("{} is too big", s))
+    };
+
+    block.lines().map(str::trim).filter(|line| !line.is_empty()).map(|line| {
+        let (name, attrs) = line.split_once(':').ok_or_else(|| anyhow::anyhow!("Can't parse memory region {:?}", line))?;
+        let name = name.split_whitespace().next().unwrap_or_default().to_string();
+
+        let mut origin = None;
+        let mut length = None;
+        for attr in attrs.split(',') {
+            let (key, value) = attr.split_once('=').ok_or_else(|| anyhow::anyhow!("Can't parse memory region {:?}", line))?;
+            match key.trim() {
+                "ORIGIN" | "org" | "o" => origin = Some(parse_number(value)?),
+                "LENGTH" | "len" | "l" => length = Some(parse_number(value)?),
+                key => anyhow::bail!("Unknown memory region attribute {} in {:?}", key, line),
+            }
+        }
+
+        match (origin, length) {
+            (Some(origin), Some(length)) => Ok(Region { name, origin, length }),
+            _ => anyhow::bail!("Memory region {} needs an ORIGIN and LENGTH", name),
+        }
+    }).collect()
+}
+
+/// Consts for the regions of `memory.x` that the firmware uses. This fails the build if the
+/// storage region isn't whole pages of flash, or if it overlaps the program or another region.
+fn memory_code(script: &str) -> anyhow::Result<String> {
+    let regions = parse_memory_regions(script)?;
+    let region = |name: &str| {
+        regions.iter().find(|r| r.name == name).ok_or_else(|| anyhow::anyhow!("No {} region in {}", name, MEMORY_FILE))
+    };
+    let program = region("FLASH")?;
+    let storage = region("STORAGE")?;
+    let ram = region("RAM")?;
+
+    let device_flash = Region { name: "device flash".into(), origin: DEVICE_FLASH.0, length: DEVICE_FLASH.1 };
+    if storage.origin < device_flash.origin || storage.end() > device_flash.end() {
+        anyhow::bail!("STORAGE region {:#x}..{:#x} isn't in flash", storage.origin, storage.end());
+    }
+    if !storage.origin.is_multiple_of(FLASH_PAGE_SIZE) || !storage.length.is_multiple_of(FLASH_PAGE_SIZE) || storage.length == 0 {
+        anyhow::bail!("STORAGE region {:#x}..{:#x} isn't whole flash pages", storage.origin, storage.end());
+    }
+    if let Some(other) = regions.iter().find(|r| r.name != storage.name && r.overlaps(storage)) {
+        anyhow::bail!("STORAGE region {:#x}..{:#x} overlaps {}", storage.origin, storage.end(), other.name);
+    }
+
+    let (program_start, program_end) = (program.origin, program.origin + program.length);
+    let (storage_start, storage_end) = (storage.origin, storage.origin + storage.length);
+    let ram_size = ram.length as usize;
+
+    Ok(quote! {
+        pub const PROGRAM_START: u32 = #program_start;
+        pub const PROGRAM_END: u32 = #program_end;
+        pub const STORAGE_START: u32 = #storage_start;
+        pub const STORAGE_END: u32 = #storage_end;
+        pub const RAM_SIZE: usize = #ram_size;
+    }.to_string())
+}
@@ -0,0 +1,98 @@
+//! The UART baud rate, picked at build time with the `ECTF_BAUD` environment variable. `build.rs`
+//! includes this file as well, so a rate the UART can't run at fails the build instead of leaving
+//! the decoder unable to talk to the host.
+
+use core::fmt;
+
+/// The clock the UART divides down to its baud rate: PCLK, which is half the 100 MHz IPO.
+pub const UART_CLOCK: u32 = 50_000_000;
+
+/// Baud rate used when `ECTF_BAUD` isn't set. It's what the eCTF host tools expect.
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Slowest and fastest baud rates we accept. Below this a frame packet takes seconds to send, and
+/// above it the divider is too coarse to land near most rates.
+pub const MIN_BAUD_RATE: u32 = 1_200;
+pub const MAX_BAUD_RATE: u32 = 1_000_000;
+
+/// Most the rate the UART actually runs at can be off from the one asked for, in parts per
+/// thousand. Both ends of a link together can only be off by a few percent.
+const MAX_ERROR_PER_MILLE: u64 = 10;
+
+/// Reasons an `ECTF_BAUD` value can be rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BaudError {
+    /// The value isn't a decimal number.
+    NotANumber,
+    /// The rate is outside [`MIN_BAUD_RATE`]..=[`MAX_BAUD_RATE`].
+    OutOfRange(u32),
+    /// Dividing [`UART_CLOCK`] down to the rate leaves the UART running at this rate instead,
+    /// which is too far off.
+    Inexact { requested: u32, actual: u32 },
+}
+
+/// The baud rate for an `ECTF_BAUD` value, or [`DEFAULT_BAUD_RATE`] if it isn't set.
+pub fn parse_baud_rate(value: Option<&str>) -> Result<u32, BaudError> {
+    let Some(value) = value else { return Ok(DEFAULT_BAUD_RATE) };
+    let requested: u32 = value.trim().parse().map_err(|_| BaudError::NotANumber)?;
+
+    if !(MIN_BAUD_RATE..=MAX_BAUD_RATE).contains(&requested) {
+        return Err(BaudError::OutOfRange(requested));
+    }
+
+    // The UART runs at the clock divided by a whole number, rounded down like the HAL does
+    let actual = actual_baud_rate(requested);
+    if (actual.abs_diff(requested) as u64) * 1000 > requested as u64 * MAX_ERROR_PER_MILLE {
+        return Err(BaudError::Inexact { requested, actual });
+    }
+
+    Ok(requested)
+}
+
+/// The rate the UART runs at when it's set to `baud`.
+pub const fn actual_baud_rate(baud: u32) -> u32 {
+    UART_CLOCK / (UART_CLOCK / baud)
+}
+
+impl fmt::Display for BaudError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BaudError::NotANumber => write!(f, "ECTF_BAUD isn't a number"),
+            BaudError::OutOfRange(baud) => write!(f, "ECTF_BAUD {} isn't between {} and {}", baud, MIN_BAUD_RATE, MAX_BAUD_RATE),
+            BaudError::Inexact { requested, actual } => {
+                write!(f, "ECTF_BAUD {} can't be divided from the {} Hz UART clock, the closest is {}", requested, UART_CLOCK, actual)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_baud_rate() {
+        assert_eq!(parse_baud_rate(None), Ok(DEFAULT_BAUD_RATE));
+        assert_eq!(parse_baud_rate(Some("115200")), Ok(115_200));
+        assert_eq!(parse_baud_rate(Some(" 921600\n")), Ok(921_600));
+        assert_eq!(parse_baud_rate(Some("9600")), Ok(9_600));
+
+        assert_eq!(parse_baud_rate(Some("fast")), Err(BaudError::NotANumber));
+        assert_eq!(parse_baud_rate(Some("-9600")), Err(BaudError::NotANumber));
+        assert_eq!(parse_baud_rate(Some("")), Err(BaudError::NotANumber));
+    }
+
+    #[test]
+    fn test_baud_rate_limits() {
+        assert_eq!(parse_baud_rate(Some("1200")), Ok(MIN_BAUD_RATE));
+        assert_eq!(parse_baud_rate(Some("1000000")), Ok(MAX_BAUD_RATE));
+        assert_eq!(parse_baud_rate(Some("1199")), Err(BaudError::OutOfRange(1_199)));
+        assert_eq!(parse_baud_rate(Some("0")), Err(BaudError::OutOfRange(0)));
+        assert_eq!(parse_baud_rate(Some("50000000")), Err(BaudError::OutOfRange(50_000_000)));
+
+        // 700000 needs a divider of 71.4, and 71 runs the UART 0.6% fast. 900000 needs 55.6, and
+        // 55 runs it 1% fast.
+        assert_eq!(parse_baud_rate(Some("700000")), Ok(700_000));
+        assert_eq!(parse_baud_rate(Some("900000")), Err(BaudError::Inexact { requested: 900_000, actual: 909_090 }));
+    }
+}
@@ -1,37 +1,105 @@
 use core::mem;
 
-use alloc::{format, string::{String, ToString}};
-use libectf::{frame::{ArchivedEncodedFramePacket, ArchivedEncodedFramePacketHeader}, key::{ArchivedKey, Key}, subscription::ArchivedSubscriptionDataHeader};
+use libectf::{frame::{parse_frame_prefix, ArchivedEncodedFramePacket, ArchivedEncodedFramePacketHeader, ENCODED_FRAME_PACKET_SIZE, FRAME_FORMAT_VERSION, FRAME_PREFIX_SIZE}, key::{ArchivedKey, Key}, subscription::ArchivedSubscriptionDataHeader};
 use rkyv::{access_unchecked_mut, util::AlignedVec};
-use rsa::pkcs1v15::{Signature, VerifyingKey};
-use rsa::signature::Verifier;
+use rsa::pkcs1v15::VerifyingKey;
 use sha2::Sha256;
 
-use crate::{flash::Flash, keys::CHANNEL_0_KEYS, uart::{body_rw::BodyRW, packet::{MessageHeader, Opcode}, raw_rw::RawRW}};
+use crate::{error::DecoderError, flash::{Flash, FlashStorage}, keys::{CHANNEL_0_BITRANGES, CHANNEL_0_KEYS}, uart::{body_rw::BodyRW, dma::{RxDma, TxDma}, packet::{MessageHeader, Opcode}, raw_rw::{RawRW, UartError}}};
+
+/// How [`decode_frame`] treats the anti-replay state.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeMode {
+    /// Refuse replayed frames, and make each frame the newest on its channel once it's decoded.
+    Decode,
+    /// Decode the frame without checking or touching the anti-replay state, so tools can check a
+    /// frame would decode without the real one being rejected as a replay afterwards. Only built
+    /// with the `diagnostics` feature.
+    #[cfg(feature = "diagnostics")]
+    DryRun,
+}
+
+/// Status in a DECODE_DRY_RUN response for a frame that would also be decoded for real.
+#[cfg(feature = "diagnostics")]
+pub const DRY_RUN_FRESH: u8 = 0;
+/// Status in a DECODE_DRY_RUN response for a frame that a real decode would reject as a replay.
+#[cfg(feature = "diagnostics")]
+pub const DRY_RUN_REPLAYED: u8 = 1;
+
+/// Decodes an encoded frame packet and sends back its payload. In [`DecodeMode::DryRun`] the
+/// response is a DECODE_DRY_RUN with a [`DRY_RUN_FRESH`] or [`DRY_RUN_REPLAYED`] status byte in
+/// front of the payload.
+///
+/// Most failures are found before the whole frame has arrived. Whichever it is, the rest of the
+/// body is read before the error is returned, so the packet after this one is read from its header.
+pub fn decode_frame<RW: RawRW, D: RxDma<RW> + TxDma<RW>, F: FlashStorage>(header: &MessageHeader, packet: &mut AlignedVec, verifying_key: &VerifyingKey<Sha256>, body_rw: &mut BodyRW<RW, D>, flash: &mut Flash<F>, mode: DecodeMode) -> Result<(), DecoderError> {
+    let result = decode_and_respond(header, packet, verifying_key, body_rw, flash, mode);
+
+    // After an overrun the body is short, so waiting for it would only time out. What's left of it
+    // is thrown away instead.
+    if let Err(e) = &result {
+        if matches!(e, DecoderError::Uart(UartError::Overrun)) {
+            body_rw.discard_after_overrun();
+        } else {
+            let _ = body_rw.wait_for_dma(header.length as usize);
+        }
+    }
+
+    result
+}
 
-pub fn decode_frame<RW: RawRW>(header: &MessageHeader, mut packet: AlignedVec, verifying_key: &VerifyingKey<Sha256>, most_recent_timestamp: &mut Option<u64>, body_rw: &mut BodyRW<RW>, flash: &Flash) -> Result<(), String> {
+fn decode_and_respond<RW: RawRW, D: RxDma<RW> + TxDma<RW>, F: FlashStorage>(header: &MessageHeader, packet: &mut AlignedVec, verifying_key: &VerifyingKey<Sha256>, body_rw: &mut BodyRW<RW, D>, flash: &mut Flash<F>, mode: DecodeMode) -> Result<(), DecoderError> {
     // All encoded frame packets have the same size
-    if packet.len() != mem::size_of::<ArchivedEncodedFramePacket>() {
-        return Err("Unexpected frame packet size".to_string());
+    if packet.len() != ENCODED_FRAME_PACKET_SIZE {
+        return Err(DecoderError::BadSize);
     }
 
-    let header_size = mem::size_of::<ArchivedEncodedFramePacketHeader>();
+    // Check the prefix says this is a packet in our format before reading anything else from it
+    body_rw.wait_for_dma(FRAME_PREFIX_SIZE)?;
+    let (version, length) = parse_frame_prefix(packet).ok_or(DecoderError::BadSize)?;
+    if version != FRAME_FORMAT_VERSION {
+        return Err(DecoderError::FrameVersion(version));
+    }
+    if length as usize != mem::size_of::<ArchivedEncodedFramePacket>() {
+        return Err(DecoderError::BadSize);
+    }
+
+    // The frame header ends this far into the packet body, and the keys follow it
+    let header_size = FRAME_PREFIX_SIZE + mem::size_of::<ArchivedEncodedFramePacketHeader>();
     let key_size = mem::size_of::<ArchivedKey>();
 
-    // "cast" the AlignedVec to an encoded frame packet
-    let encoded_frame = unsafe { access_unchecked_mut::<ArchivedEncodedFramePacket>(&mut packet) };
+    // "cast" the rest of the AlignedVec to an encoded frame packet. The size was checked above, and
+    // libectf checks the prefix keeps the packet aligned in an AlignedVec.
+    debug_assert!(packet[FRAME_PREFIX_SIZE..].as_ptr().cast::<ArchivedEncodedFramePacket>().is_aligned());
+    let encoded_frame = unsafe { access_unchecked_mut::<ArchivedEncodedFramePacket>(&mut packet[FRAME_PREFIX_SIZE..]) };
 
     // Wait for header
-    while body_rw.dma_poll_for_ack() < header_size { }
+    body_rw.wait_for_dma(header_size)?;
 
     // Subscription key we will use to decrypt the frame key (if we have one)
     let mut key = None;
-
-    if encoded_frame.header.channel != 0 {
-        // Check each subscription in the flash for a key to decrypt our frame
-        for subscription in flash.subscriptions() {
-            key = subscription.header.key_for_frame(&encoded_frame.header, subscription.keys);
-            if key.is_some() { break; }
+    // Index of the key in the subscription it came from, so the subscription's cipher can be used
+    let mut key_index = None;
+
+    // Channel 0 frames use the keys built in for every timestamp, unless refreshed keys for a
+    // window were loaded in their place
+    let channel = encoded_frame.header.channel();
+    let refreshed_channel_0 = cfg!(feature = "channel-0-refresh") && flash.subscription_for_channel(0).is_some();
+
+    if channel != 0 || refreshed_channel_0 {
+        // Check the subscription for the frame's channel for a key to decrypt our frame. It's the
+        // last one the host sent for the channel, see `Flash::subscription_for_channel`.
+        if let Some(subscription) = flash.subscription_for_channel(channel) {
+            // Check the frame is in the subscription's time range ourselves, rather than relying on
+            // none of its keys' bitranges reaching past either end
+            if !(subscription.start_timestamp()..=subscription.end_timestamp()).contains(&encoded_frame.header.timestamp()) {
+                return Err(DecoderError::Expired);
+            }
+
+            if let Some((i, mask_idx)) = find_bitrange(subscription.header, &encoded_frame.header, &subscription.bitranges) {
+                key = subscription.keys.get(i).map(|k| (k, mask_idx));
+                key_index = Some(i);
+            }
         }
     } else {
         // Dummy header so we can use the same subscription key for frame code
@@ -42,48 +110,64 @@ pub fn decode_frame<RW: RawRW>(header: &MessageHeader, mut packet: AlignedVec, v
             mac_hash: [0; 32]
         };
 
-        key = subscription_header.key_for_frame(&encoded_frame.header, CHANNEL_0_KEYS);
+        key = find_bitrange(&subscription_header, &encoded_frame.header, CHANNEL_0_BITRANGES)
+            .and_then(|(i, mask_idx)| CHANNEL_0_KEYS.get(i).map(|k| (k, mask_idx)));
     }
 
     // Error if we don't have a key
-    let (key, mask_idx) = key.ok_or("No subscription for frame".to_string())?;    
+    let (key, mask_idx) = key.ok_or(DecoderError::NoSubscription)?;    
 
     // Wait for the key to be transferred
-    while body_rw.dma_poll_for_ack() < header_size + (mask_idx as usize + 1) * key_size { }
-
-    // Encrypted frame key
-    let mut frame_key = encoded_frame.keys[mask_idx as usize].0;
+    body_rw.wait_for_dma(header_size + (mask_idx as usize + 1) * key_size)?;
 
-    // Decrypt the frame key with our subscription key
-    key.key.cipher().decrypt(&mut frame_key);
-
-    // Decrypt the frame with our decrypted frame key
-    let mut f = encoded_frame.header.frame.0;
-    Key(frame_key).cipher().decrypt(&mut f);
-
-    // Makes sure timestamp is valid and globally increasing
-    if most_recent_timestamp.map(|t| encoded_frame.header.timestamp <= t).unwrap_or(false) {
-        return Err("Frame is from the past".to_string());
+    // Makes sure the frame is newer than, or close behind, the newest one on its channel and not a
+    // replay. A dry run only reports it.
+    let fresh = flash.is_fresh_timestamp(channel, encoded_frame.header.timestamp());
+    if !fresh && mode == DecodeMode::Decode {
+        return Err(DecoderError::Replayed);
     }
 
-    // Parse the signature bytes from the frame header
-    let signature = Signature::try_from(encoded_frame.header.signature.as_slice())
-        .map_err(|e| format!("Signature invalid: {:?}", e))?;
-
-    // Verify that the signature matches our decrypted frame
-    if verifying_key.verify(&f, &signature).is_err() {
-        return Err("Frame validation failed".to_string());
-    }
+    // Decrypt the frame and check it's the one the encoder sent. Subscriptions keep a cipher for
+    // the key they last used, so frames in the same bitrange don't redo its key schedule.
+    let f = match key_index {
+        Some(i) => {
+            let subscription = flash.subscription_for_channel_mut(channel).ok_or(DecoderError::NoSubscription)?;
+            encoded_frame.decode_with_cipher(subscription.cipher(i), mask_idx, verifying_key)?
+        }
+        None => encoded_frame.decode(&Key(key.key.0), mask_idx, verifying_key)?,
+    };
 
     // Update the most recent timestamp now that we know the frame is valid
-    *most_recent_timestamp = Some(encoded_frame.header.timestamp.to_native());
+    if mode == DecodeMode::Decode {
+        flash.set_most_recent_timestamp(channel, encoded_frame.header.timestamp())?;
+    }
 
     // Wait until the whole message is transferred
-    while body_rw.dma_poll_for_ack() < header.length as usize { }
+    body_rw.wait_for_dma(header.length as usize)?;
 
-    // Write decode response
-    body_rw.rw.write_header(Opcode::DECODE, f.len() as u16);
-    body_rw.write_bytes(&f);
+    // Write decode response, leaving off any padding after the payload
+    let payload = f.payload(encoded_frame.header.length());
+    match mode {
+        DecodeMode::Decode => {
+            body_rw.rw.write_header(Opcode::DECODE, payload.len() as u16);
+        }
+        #[cfg(feature = "diagnostics")]
+        DecodeMode::DryRun => {
+            body_rw.rw.write_header(Opcode::DECODE_DRY_RUN, 1 + payload.len() as u16);
+            body_rw.dma_write_bytes(&[if fresh { DRY_RUN_FRESH } else { DRY_RUN_REPLAYED }])?;
+        }
+    }
+    body_rw.dma_write_bytes(payload)?;
 
     Ok(())
 }
+
+/// The index and mask of the bitrange in `bitranges` that a frame is in, see
+/// [`ArchivedSubscriptionDataHeader::bitrange_for_frame`]. With the `ct` feature every bitrange is
+/// checked, so the time taken doesn't depend on which one it is.
+fn find_bitrange(subscription: &ArchivedSubscriptionDataHeader, header: &ArchivedEncodedFramePacketHeader, bitranges: &[(u64, u8)]) -> Option<(usize, u8)> {
+    #[cfg(feature = "ct")]
+    return subscription.bitrange_for_frame_ct(header, bitranges);
+    #[cfg(not(feature = "ct"))]
+    subscription.bitrange_for_frame(header, bitranges)
+}
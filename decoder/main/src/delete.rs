@@ -0,0 +1,41 @@
+use rkyv::util::AlignedVec;
+
+use crate::{error::DecoderError, flash::{Flash, FlashStorage}, uart::{body_rw::BodyRW, dma::RxDma, packet::Opcode, raw_rw::RawRW}};
+
+/// Channel number in a delete packet that removes every subscription.
+pub const DELETE_ALL: u32 = u32::MAX;
+
+/// Remove the subscription for the channel in the packet body, or every subscription if the channel
+/// is [`DELETE_ALL`].
+pub fn delete_subscription<RW: RawRW, D: RxDma<RW>, F: FlashStorage>(packet: &AlignedVec, body_rw: &mut BodyRW<RW, D>, flash: &mut Flash<F>) -> Result<(), DecoderError> {
+    // The body is just the channel number
+    if packet.len() != 4 {
+        return Err(DecoderError::BadDeleteSize);
+    }
+
+    body_rw.wait_for_dma(packet.len())?;
+
+    let channel = u32::from_le_bytes(packet[..4].try_into().unwrap());
+    if channel == DELETE_ALL {
+        return clear_subscriptions(body_rw.rw, flash);
+    }
+
+    if !flash.remove_subscription(channel)? {
+        return Err(DecoderError::NoSubscriptionForChannel);
+    }
+
+    // Respond
+    body_rw.rw.write_header(Opcode::DELETE, 0);
+
+    Ok(())
+}
+
+/// Remove every subscription.
+pub fn clear_subscriptions<F: FlashStorage>(rw: &mut impl RawRW, flash: &mut Flash<F>) -> Result<(), DecoderError> {
+    flash.clear_subscriptions()?;
+
+    // Respond
+    rw.write_header(Opcode::DELETE, 0);
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use alloc::vec::Vec;
+use rkyv::util::AlignedVec;
+#[cfg(feature = "diagnostics")]
+use sha2::{Digest, Sha256};
+
+use crate::{error::DecoderError, flash::{Flash, FlashStorage, StaticSubscription}, uart::{body_rw::BodyRW, dma::{RxDma, TxDma}, packet::Opcode, raw_rw::RawRW}};
+
+/// Size of the key fingerprints in a detail response.
+#[cfg(feature = "diagnostics")]
+pub const FINGERPRINT_SIZE: usize = 4;
+
+/// Describe the subscription for the channel in the packet body, including the bitrange of each of
+/// its keys. With the `diagnostics` feature each key also gets a fingerprint, which is enough to
+/// tell keys apart without revealing them.
+pub fn subscription_detail<RW: RawRW, D: RxDma<RW> + TxDma<RW>, F: FlashStorage>(packet: &AlignedVec, body_rw: &mut BodyRW<RW, D>, flash: &Flash<F>) -> Result<(), DecoderError> {
+    // The body is just the channel number
+    if packet.len() != 4 {
+        return Err(DecoderError::BadDetailSize);
+    }
+
+    body_rw.wait_for_dma(packet.len())?;
+
+    let channel = u32::from_le_bytes(packet[..4].try_into().unwrap());
+    let subscription = flash.subscription_for_channel(channel)
+        .ok_or(DecoderError::NoSubscriptionForChannel)?;
+
+    let output = detail_body(subscription);
+
+    body_rw.rw.write_header(Opcode::DETAIL, output.len() as u16);
+    body_rw.dma_write_bytes(&output)?;
+    Ok(body_rw.finish_write()?)
+}
+
+/// The body of a detail response for a subscription.
+fn detail_body(subscription: &StaticSubscription) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::new();
+
+    // (channel_u32, start_timestamp_u64, end_timestamp_u64, num_keys_u32)
+    output.extend_from_slice(&subscription.channel().to_le_bytes());
+    output.extend_from_slice(&subscription.start_timestamp().to_le_bytes());
+    output.extend_from_slice(&subscription.end_timestamp().to_le_bytes());
+    output.extend_from_slice(&(subscription.keys.len() as u32).to_le_bytes());
+
+    // Add (bitrange_start_u64, mask_idx_u8) for every key, followed by the first bytes of the
+    // SHA256 of the key with the `diagnostics` feature
+    #[cfg_attr(not(feature = "diagnostics"), allow(unused_variables))]
+    for (key, &(start_timestamp, mask_idx)) in subscription.keys.iter().zip(&subscription.bitranges) {
+        output.extend_from_slice(&start_timestamp.to_le_bytes());
+        output.push(mask_idx);
+
+        #[cfg(feature = "diagnostics")]
+        output.extend_from_slice(&Sha256::digest(key.key.0)[..FINGERPRINT_SIZE]);
+    }
+
+    output
+}
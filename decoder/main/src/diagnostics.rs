@@ -0,0 +1,28 @@
+use alloc::vec::Vec;
+
+use crate::{flash::{Flash, FlashStorage}, uart::{body_rw::BodyRW, dma::{RxDma, TxDma}, packet::Opcode, raw_rw::{RawRW, UartError}}};
+
+/// Sent in place of the most recent timestamp when the decoder hasn't accepted a frame yet. No frame
+/// can be newer than it, so it can't be mistaken for a real one that leaves room for more.
+pub const NO_TIMESTAMP: u64 = u64::MAX;
+
+/// Tells the host how much of the heap and the subscription flash is in use, given the heap's
+/// `(used, free)` bytes, and the newest frame timestamp it has accepted. Only built with the
+/// `diagnostics` feature.
+pub fn report_diagnostics<RW: RawRW, D: RxDma<RW> + TxDma<RW>, F: FlashStorage>(body_rw: &mut BodyRW<RW, D>, flash: &Flash<F>, (heap_used, heap_free): (usize, usize)) -> Result<(), UartError> {
+    let mut output: Vec<u8> = Vec::new();
+
+    // (heap_used_u32, heap_free_u32, subscriptions_u32, flash_free_u32, most_recent_timestamp_u64)
+    output.extend_from_slice(&(heap_used as u32).to_le_bytes());
+    output.extend_from_slice(&(heap_free as u32).to_le_bytes());
+    output.extend_from_slice(&(flash.subscriptions().len() as u32).to_le_bytes());
+    output.extend_from_slice(&flash.free_space().to_le_bytes());
+
+    // Frames have to be newer than this (or within the replay window of it) to be accepted. It's
+    // NO_TIMESTAMP before any frame has been decoded, including any from before a reset.
+    output.extend_from_slice(&flash.most_recent_timestamp().unwrap_or(NO_TIMESTAMP).to_le_bytes());
+
+    body_rw.rw.write_header(Opcode::DIAGNOSTICS, output.len() as u16);
+    body_rw.dma_write_bytes(&output)?;
+    body_rw.finish_write()
+}
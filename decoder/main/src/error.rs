@@ -0,0 +1,149 @@
+use core::fmt;
+
+use libectf::frame::DecodeError;
+use rsa::signature;
+
+use crate::{flash::StorageError, uart::raw_rw::UartError};
+
+/// Everything that can go wrong while handling a packet. Each one is reported to the host in an
+/// ERROR packet.
+#[derive(Debug)]
+pub enum DecoderError {
+    /// Reading from or writing to the host failed.
+    Uart(UartError),
+    /// Reading, writing, or erasing flash failed.
+    Flash(StorageError),
+    /// Flash couldn't be set up when the first packet arrived.
+    FlashInit(StorageError),
+    /// A frame packet isn't the size every frame packet is.
+    BadSize,
+    /// A frame packet is in a format version we don't understand.
+    FrameVersion(u8),
+    /// A delete packet isn't just a channel number.
+    BadDeleteSize,
+    /// A list packet has a body that isn't just a channel number.
+    BadListSize,
+    /// A detail packet isn't just a channel number.
+    BadDetailSize,
+    /// A version packet has a body that isn't a single byte of flags we know.
+    BadVersionRequest,
+    /// None of our subscriptions have a key for the frame.
+    NoSubscription,
+    /// The subscription for the frame's channel doesn't cover the frame's timestamp.
+    Expired,
+    /// The frame isn't newer than the last one we decoded.
+    Replayed,
+    /// The frame's signature couldn't be parsed.
+    InvalidSignature(signature::Error),
+    /// The frame's signature, or its tag if it was sealed, doesn't match the frame.
+    BadSignature,
+    /// The frame's payload length is longer than a frame.
+    BadPayloadLength(u8),
+    /// Subscriptions to the broadcast channel aren't allowed.
+    Channel0,
+    /// The channel wasn't in the secrets the decoder was built with.
+    UnknownChannel,
+    /// The subscription's MAC doesn't match its contents.
+    AuthFailed,
+    /// There's no subscription for the channel to delete.
+    NoSubscriptionForChannel,
+    /// A packet that needs a body was sent without one.
+    MissingBody,
+    /// A packet the host shouldn't send was sent.
+    UnexpectedPacket,
+    /// A packet that doesn't have a body was sent with one.
+    UnexpectedBody,
+    /// The opcode isn't one we know.
+    UnknownOpcode(u8),
+    /// The subscription has more keys than any valid subscription does.
+    SubscriptionTooLarge,
+    /// A subscription packet isn't a header followed by a whole number of keys.
+    BadSubscriptionSize,
+    /// We already have as many subscriptions as we can store, none for the new one's channel.
+    SubscriptionLimit,
+    /// A subscription batch isn't a count followed by that many subscriptions, or is bigger than
+    /// any batch we accept.
+    BadBatchSize,
+    /// A rekey packet isn't the size of a wrapped device key.
+    BadRekeySize,
+    /// A rekey packet wasn't wrapped with the current device key.
+    RekeyAuthFailed,
+}
+
+impl DecoderError {
+    /// Description of the error sent to the host. Errors that wrap another error are followed by
+    /// that error's details when displayed.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Uart(_) => "UART error",
+            Self::Flash(_) => "Flash error",
+            Self::FlashInit(_) => "Flash Error",
+            Self::BadSize => "Unexpected frame packet size",
+            Self::FrameVersion(_) => "Unsupported frame format version",
+            Self::BadDeleteSize => "Unexpected delete packet size",
+            Self::BadListSize => "Unexpected list packet size",
+            Self::BadDetailSize => "Unexpected detail packet size",
+            Self::BadVersionRequest => "Unsupported version request",
+            Self::NoSubscription => "No subscription for frame",
+            Self::Expired => "Subscription expired",
+            Self::Replayed => "Frame is from the past",
+            Self::InvalidSignature(_) => "Signature invalid",
+            Self::BadSignature => "Frame validation failed",
+            Self::BadPayloadLength(_) => "Frame payload too long",
+            Self::Channel0 => "Cannot subscribe to channel 0",
+            Self::UnknownChannel => "Unknown channel",
+            Self::AuthFailed => "Authentication Failed",
+            Self::NoSubscriptionForChannel => "No subscription for channel",
+            Self::MissingBody => "Missing packet body",
+            Self::UnexpectedPacket => "Unexpected packet",
+            Self::UnexpectedBody => "Unexpected packet body",
+            Self::UnknownOpcode(_) => "Unknown opcode",
+            Self::SubscriptionTooLarge => "Subscription too large",
+            Self::BadSubscriptionSize => "Unexpected subscription packet size",
+            Self::SubscriptionLimit => "Subscription limit reached",
+            Self::BadBatchSize => "Unexpected subscription batch size",
+            Self::BadRekeySize => "Unexpected rekey packet size",
+            Self::RekeyAuthFailed => "Rekey authentication failed",
+        }
+    }
+}
+
+impl fmt::Display for DecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())?;
+        match self {
+            Self::Uart(e) => write!(f, ": {:?}", e),
+            Self::Flash(e) | Self::FlashInit(e) => write!(f, ": {}", e),
+            Self::InvalidSignature(e) => write!(f, ": {:?}", e),
+            Self::FrameVersion(version) => write!(f, ": {}", version),
+            Self::BadPayloadLength(len) => write!(f, ": {}", len),
+            Self::UnknownOpcode(opcode) => write!(f, ": {:#04x}", opcode),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<UartError> for DecoderError {
+    fn from(e: UartError) -> Self {
+        match e {
+            UartError::UnknownOpcode(opcode) => Self::UnknownOpcode(opcode),
+            e => Self::Uart(e),
+        }
+    }
+}
+
+impl From<StorageError> for DecoderError {
+    fn from(e: StorageError) -> Self {
+        Self::Flash(e)
+    }
+}
+
+impl From<DecodeError> for DecoderError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::InvalidSignature(e) => Self::InvalidSignature(e),
+            DecodeError::BadSignature => Self::BadSignature,
+            DecodeError::BadLength(len) => Self::BadPayloadLength(len),
+        }
+    }
+}
@@ -1,20 +1,198 @@
-use core::{mem, ptr::{slice_from_raw_parts, slice_from_raw_parts_mut}};
+use core::{fmt, mem, ptr::{slice_from_raw_parts, slice_from_raw_parts_mut}};
 
 use alloc::vec::Vec;
-use libectf::subscription::{ArchivedEncodedSubscriptionKey, ArchivedSubscriptionDataHeader};
-use max7800x_hal::flc::{FlashError, Flc, FLASH_PAGE_SIZE};
+use libectf::{key::{Cipher, Key, KEY_SIZE_BYTES}, subscription::{ArchivedEncodedSubscriptionKey, ArchivedSubscriptionDataHeader}};
+use max7800x_hal::flc::{FlashError, Flc, FLASH_BASE, FLASH_END, FLASH_PAGE_SIZE};
 use rkyv::util::AlignedVec;
 
-use crate::{keys::FLASH_MAGIC, uart::raw_rw::RawRW};
+use crate::{error::DecoderError, keys::{DECODER_KEY, FLASH_MAGIC}, memory::{PROGRAM_END, PROGRAM_START, STORAGE_END, STORAGE_START}, subscribe::{MAX_SUBSCRIPTION_SIZE, MIN_SUBSCRIPTIONS}, uart::raw_rw::RawRW};
 
-const START_ADDR: u32 = 0x1006_0000;  // Should be at the start of a page
-const NUM_PAGES: u32 = 4;
+/// The `STORAGE` region of `memory.x`
+const START_ADDR: u32 = STORAGE_START;
+const NUM_PAGES: u32 = (STORAGE_END - STORAGE_START) / FLASH_PAGE_SIZE;
 const ALIGNMENT: u32 = 16;
 
+// `build.rs` already checks this, but the flash code relies on it so check again here
+const _: () = assert!(
+    START_ADDR >= FLASH_BASE && STORAGE_END <= FLASH_END
+        && START_ADDR.is_multiple_of(FLASH_PAGE_SIZE) && STORAGE_END.is_multiple_of(FLASH_PAGE_SIZE)
+        && (STORAGE_END <= PROGRAM_START || START_ADDR >= PROGRAM_END),
+    "The storage region has to be whole pages of flash outside the program"
+);
+
+/// The last pages of the region are a log of accepted frame timestamps for each channel so that
+/// anti-replay survives a reboot. The page before them holds the device key the decoder was last
+/// rekeyed to, and subscriptions use every page before that.
+const TIMESTAMP_LOG_PAGES: u32 = 2;
+const TIMESTAMP_LOG_ADDR: u32 = START_ADDR + (NUM_PAGES - TIMESTAMP_LOG_PAGES) * FLASH_PAGE_SIZE;
+const DEVICE_KEY_ADDR: u32 = TIMESTAMP_LOG_ADDR - FLASH_PAGE_SIZE;
+const SUBSCRIPTIONS_END: u32 = DEVICE_KEY_ADDR;
+
+/// The subscription pages are split into two banks, and subscriptions are stored in one of them at
+/// a time. Compaction copies the live ones to the other bank and commits it before the old one is
+/// erased, so losing power partway through always leaves a complete bank to load.
+const BANK_SIZE: u32 = (SUBSCRIPTIONS_END - START_ADDR) / FLASH_PAGE_SIZE / 2 * FLASH_PAGE_SIZE;
+const BANKS: [u32; 2] = [START_ADDR, START_ADDR + BANK_SIZE];
+
+/// A bank starts with a 128-bit header, `[FLASH_MAGIC, generation, !generation, 0]`, that's written
+/// once every entry has been copied to it. If both banks have one because we lost power before the
+/// old bank was erased, the one with the later generation is used.
+const BANK_HEADER_SIZE: u32 = ALIGNMENT;
+
+// The region has to fit the timestamp log, the device key page and at least a page for each bank
+const _: () = assert!(NUM_PAGES >= TIMESTAMP_LOG_PAGES + 3, "The storage region is too small");
+
+// A bank has to fit the subscriptions the eCTF rules require even if every one is as big as they
+// get. Entries are sized as in `Flash::entry_size`.
+const _: () = assert!(
+    (BANK_SIZE - BANK_HEADER_SIZE - ALIGNMENT) / (4 + MAX_SUBSCRIPTION_SIZE as u32).next_multiple_of(ALIGNMENT) >= MIN_SUBSCRIPTIONS as u32,
+    "A bank doesn't fit the smallest number of subscriptions we have to store"
+);
+
+/// Granularity of the timestamps written to the timestamp log. Frame timestamps are in
+/// microseconds so this is about a second.
+const TIMESTAMP_STEP: u64 = 1 << 20;
+
+/// Size of a record in the timestamp log: two 128-bit words, `[channel, !channel, lo, !lo]` and
+/// `[hi, !hi, 0, 0]`. Every value is next to its complement, so a record that was only partially
+/// written, including one where only the first word made it, is detected and ignored.
+const TIMESTAMP_RECORD_SIZE: u32 = 2 * ALIGNMENT;
+
+/// Size of a record on the device key page: two 128-bit words, the key and then its complement.
+/// Each rekey appends a record and the last valid one is the device key, so a record that was only
+/// partially written leaves the decoder on the key it had before.
+const DEVICE_KEY_RECORD_SIZE: u32 = 2 * ALIGNMENT;
+
+const _: () = assert!(KEY_SIZE_BYTES as u32 == ALIGNMENT, "A device key record is a key and its complement");
+
+/// How far behind the newest frame a frame's timestamp can be and still be accepted, so that
+/// frames delivered slightly out of order aren't dropped. Each timestamp in the window is only
+/// accepted once. Can be at most 64, the size of the bitset tracking them.
+pub const REPLAY_WINDOW: u64 = 64;
+
+/// The low bits of an entry's length word hold the length of the entry.
+const ENTRY_LEN_MASK: u32 = 0x00FF_FFFF;
+/// Set in an entry's length word when it's written and cleared once the entry is superseded. Since
+/// flash bits can be cleared without an erase, this lets us tombstone entries in place.
+const ENTRY_LIVE: u32 = 1 << 31;
+/// Set in an entry's length word when it's written and cleared once the whole entry has been
+/// written and read back. `init` skips an entry that still has it set, since we lost power before
+/// it was finished, and its body could be anything.
+const ENTRY_PENDING: u32 = 1 << 30;
+
+/// Why storing or loading from flash failed, for the host to diagnose flash problems with.
+#[derive(Debug, PartialEq)]
+pub enum StorageError {
+    /// The flash controller refused a read, write, or erase.
+    Controller(FlashError),
+    /// There's no room left in the subscription pages for an entry, even after reclaiming space,
+    /// or on the device key page for another rekey.
+    Full,
+    /// A word read back after it was written didn't match, so the entry was skipped over.
+    VerifyFailed,
+    /// An entry's length word doesn't describe a subscription inside the subscription pages.
+    Corrupt,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Controller(FlashError::InvalidAddress) => "invalid address",
+            Self::Controller(FlashError::AccessViolation) => "flash controller busy or locked",
+            Self::Controller(FlashError::NeedsErase) => "write to flash that wasn't erased",
+            Self::Full => "flash region full",
+            Self::VerifyFailed => "write verify failed",
+            Self::Corrupt => "corrupt subscription entry",
+        })
+    }
+}
+
+impl From<FlashError> for StorageError {
+    fn from(e: FlashError) -> Self {
+        Self::Controller(e)
+    }
+}
+
+/// The flash controller operations that we use. This lets the flash logic run against RAM in tests.
+pub trait FlashStorage {
+    fn read_32(&self, addr: u32) -> Result<u32, FlashError>;
+    fn read_128(&self, addr: u32) -> Result<[u32; 4], FlashError>;
+    fn write_32(&self, addr: u32, data: u32) -> Result<(), FlashError>;
+    fn write_128(&self, addr: u32, data: &[u32; 4]) -> Result<(), FlashError>;
+
+    /// # Safety
+    ///
+    /// Nothing else can be stored in the page being erased.
+    unsafe fn erase_page(&self, addr: u32) -> Result<(), FlashError>;
+
+    /// Pointer that the flash at `addr` can be read through in place.
+    fn as_ptr(&self, addr: u32) -> *const u8;
+}
+
+impl FlashStorage for Flc {
+    fn read_32(&self, addr: u32) -> Result<u32, FlashError> {
+        Flc::read_32(self, addr)
+    }
+
+    fn read_128(&self, addr: u32) -> Result<[u32; 4], FlashError> {
+        Flc::read_128(self, addr)
+    }
+
+    fn write_32(&self, addr: u32, data: u32) -> Result<(), FlashError> {
+        Flc::write_32(self, addr, data)
+    }
+
+    fn write_128(&self, addr: u32, data: &[u32; 4]) -> Result<(), FlashError> {
+        Flc::write_128(self, addr, data)
+    }
+
+    unsafe fn erase_page(&self, addr: u32) -> Result<(), FlashError> {
+        Flc::erase_page(self, addr)
+    }
+
+    /// Flash is memory mapped.
+    fn as_ptr(&self, addr: u32) -> *const u8 {
+        addr as *const u8
+    }
+}
+
 /// Static reference to a subscription stored in flash
 pub struct StaticSubscription {
+    /// Address of the length word in front of this subscription
+    len_addr: u32,
     pub header: &'static ArchivedSubscriptionDataHeader,
-    pub keys: &'static [ArchivedEncodedSubscriptionKey]
+    pub keys: &'static [ArchivedEncodedSubscriptionKey],
+    /// Bitrange of each key, so that finding the key for a frame doesn't need to recompute them
+    pub bitranges: Vec<(u64, u8)>,
+    /// Index of the key that last decoded a frame, and a cipher for it. Frames mostly arrive in
+    /// order, so the next one is almost always in the same bitrange, and only its frame key needs
+    /// an AES key schedule.
+    cipher: Option<(usize, Cipher)>
+}
+
+impl StaticSubscription {
+    /// The channel this subscription is for
+    pub fn channel(&self) -> u32 {
+        self.header.channel()
+    }
+
+    /// First timestamp this subscription covers
+    pub fn start_timestamp(&self) -> u64 {
+        self.header.start_timestamp()
+    }
+
+    /// Last timestamp this subscription covers
+    pub fn end_timestamp(&self) -> u64 {
+        self.header.end_timestamp()
+    }
+
+    /// A cipher for key `key_index`, reusing the last one if it was for the same key.
+    pub fn cipher(&mut self, key_index: usize) -> &mut Cipher {
+        if self.cipher.as_ref().is_none_or(|&(i, _)| i != key_index) {
+            self.cipher = Some((key_index, self.keys[key_index].key.cipher()));
+        }
+        &mut self.cipher.as_mut().unwrap().1
+    }
 }
 
 /// Mutable reference to a subscription stored in RAM
@@ -24,64 +202,128 @@ pub struct MutSubscription {
 }
 
 /// Flash storage for subscriptions
-pub struct Flash {
-    flc: Flc,
+pub struct Flash<F: FlashStorage = Flc> {
+    flc: F,
     subscriptions: Vec<StaticSubscription>,
-    next_entry_addr: u32
+    /// `(channel, index into subscriptions)` sorted by channel, so that the subscriptions for a
+    /// channel can be found without scanning all of them
+    channel_index: Vec<(u32, usize)>,
+    /// Start of the bank the subscriptions are stored in, and its generation
+    bank: u32,
+    generation: u32,
+    next_entry_addr: u32,
+    /// `(channel, anti-replay state)` sorted by channel, for every channel a frame has been
+    /// accepted on
+    timestamps: Vec<(u32, ChannelTimestamps)>,
+    next_timestamp_addr: u32,
+    /// The key the decoder was last rekeyed to, or `None` if it still uses the one it was built with
+    device_key: Option<Key>,
+    next_key_addr: u32
 }
 
-impl Flash {
+/// Anti-replay state for one channel. Frames on different channels don't affect each other, so
+/// channels sent at different rates can be decoded side by side.
+#[derive(Clone, Copy, Default)]
+struct ChannelTimestamps {
+    most_recent: Option<u64>,
+    /// Bit `i` is set if the frame `i` before the most recent one has been accepted
+    seen: u64,
+    /// Latest timestamp in the timestamp log for the channel
+    logged: Option<u64>,
+}
+
+impl<F: FlashStorage> Flash<F> {
     /// Creates a new (uninitialized) flash
-    pub fn new(flc: Flc) -> Self {
+    pub fn new(flc: F) -> Self {
         Self {
             flc,
             subscriptions: Vec::new(),
-            next_entry_addr: 0
+            channel_index: Vec::new(),
+            bank: BANKS[0],
+            generation: 0,
+            next_entry_addr: 0,
+            timestamps: Vec::new(),
+            next_timestamp_addr: TIMESTAMP_LOG_ADDR,
+            device_key: None,
+            next_key_addr: DEVICE_KEY_ADDR
         }
     }
 
     // Initialize the flash and fetch all current subscriptions
     #[allow(unused_variables)]
-    pub fn init(&mut self, rw: &mut impl RawRW) -> Result<(), FlashError> {
-        // Check if the flash has valid data in it, otherwise erase
-        if self.flc.read_32(START_ADDR)? != FLASH_MAGIC {
-            // Erase all pages
-            let mut addr = START_ADDR;
-            for _ in 0..NUM_PAGES {
-                unsafe { self.flc.erase_page(addr)?; }
-                addr += FLASH_PAGE_SIZE;
+    pub fn init(&mut self, rw: &mut impl RawRW) -> Result<(), StorageError> {
+        // Find the bank in use. If neither bank has a header the flash doesn't have valid data in
+        // it, so erase it.
+        (self.bank, self.generation) = match [self.bank_generation(BANKS[0])?, self.bank_generation(BANKS[1])?] {
+            [Some(a), Some(b)] => {
+                // We lost power before the old bank was erased, so finish switching banks
+                let (newer, older) = if b.wrapping_sub(a) as i32 > 0 { (1, 0) } else { (0, 1) };
+                self.erase_bank(BANKS[older])?;
+                (BANKS[newer], [a, b][newer])
             }
-            
-            // Write magic to the start address
-            self.flc.write_32(START_ADDR, FLASH_MAGIC)?;
-        }
+            [Some(a), None] => (BANKS[0], a),
+            [None, Some(b)] => (BANKS[1], b),
+            [None, None] => {
+                // Erase all pages
+                let mut addr = START_ADDR;
+                for _ in 0..NUM_PAGES {
+                    unsafe { self.flc.erase_page(addr)?; }
+                    addr += FLASH_PAGE_SIZE;
+                }
+
+                self.write_bank_header(BANKS[0], 0)?;
+                (BANKS[0], 0)
+            }
+        };
 
         self.subscriptions = Vec::new();
 
         // First possible subscription address (if it's aligned)
-        let mut addr = START_ADDR + 4;
+        let mut addr = self.bank + BANK_HEADER_SIZE;
 
         loop {
             // We want the length specifier to be right before our aligned vec
             addr = Self::addr_before_aligned(addr);
 
-            Self::check_addr(addr)?;
+            // An entry can end right at the end of the bank, leaving no room for another length
+            if self.check_span(addr, 4).is_err() { break }
 
             // rw.write_debug(&format!("Checking for len at {:#x}", addr));
 
             // Read the length of the subscription packet
-            let len = self.flc.read_32(addr)?;
+            let len_word = self.flc.read_32(addr)?;
             
             // If the length specifier is blank (all 1s) we are done
-            if len == 0xFFFFFFFF { break }
+            if len_word == 0xFFFFFFFF { break }
+            let len = len_word & ENTRY_LEN_MASK;
 
             // Actual packet is after length u32
             addr += 4;
             // rw.write_debug(&format!("len={}, start={:#x}", len, addr));
-            Self::check_addr(addr + len)?;
 
-            // Add this subscription to the subscriptions list
-            self.subscriptions.push(Self::access_subscription(addr, len));
+            // A length running past the end of the bank is one we never wrote, like a torn
+            // write. Nothing after it can be found, so the bank is treated as full and the next
+            // subscription added compacts the ones before it.
+            if self.check_span(addr, len).is_err() {
+                addr = self.bank + BANK_SIZE;
+                break;
+            }
+
+            // Add this subscription to the subscriptions list unless it has been superseded or was
+            // never finished. An entry that can't hold a subscription is skipped the same way,
+            // rather than failing to start over one corrupted length
+            if len_word & (ENTRY_LIVE | ENTRY_PENDING) == ENTRY_LIVE {
+                if let Ok(subscription) = self.access_subscription(addr, len) {
+                    // A channel only has one live subscription. If we lost power before the one
+                    // this replaced was tombstoned, finish replacing it now.
+                    if let Some(i) = self.subscriptions.iter().position(|s| s.channel() == subscription.channel()) {
+                        let old = self.subscriptions.remove(i);
+                        let old_len_word = self.flc.read_32(old.len_addr)?;
+                        self.flc.write_32(old.len_addr, old_len_word & !ENTRY_LIVE)?;
+                    }
+                    self.subscriptions.push(subscription);
+                }
+            }
 
             // Increment addr so we can continue our search
             addr += len;
@@ -89,21 +331,356 @@ impl Flash {
 
         // Address that the next subscription will be stored
         self.next_entry_addr = Self::addr_before_aligned(addr);
+        self.rebuild_channel_index();
+
+        self.load_timestamps()?;
+        self.load_device_key()?;
+
+        Ok(())
+    }
+
+    /// Find the most recent timestamp for each channel in the timestamp log. Records are appended
+    /// to one log page at a time, and when it fills up every channel's latest record is carried
+    /// over to the other page before the full one is erased. Taking the max of all valid records on
+    /// both pages means a channel's timestamp can never go backwards, even if we lost power while
+    /// switching pages.
+    fn load_timestamps(&mut self) -> Result<(), StorageError> {
+        self.timestamps = Vec::new();
+        let mut next_addr = None;
+
+        for page in 0..TIMESTAMP_LOG_PAGES {
+            let page_addr = TIMESTAMP_LOG_ADDR + page * FLASH_PAGE_SIZE;
+            let page_end = page_addr + FLASH_PAGE_SIZE;
+
+            let mut addr = page_addr;
+            while addr < page_end {
+                let [channel, not_channel, lo, not_lo] = self.flc.read_128(addr)?;
+                let [hi, not_hi, _, _] = self.flc.read_128(addr + ALIGNMENT)?;
+
+                // A blank record is where the next timestamp on this page will be written
+                if [channel, not_channel, lo, not_lo, hi, not_hi] == [0xFFFFFFFF; 6] { break }
+
+                if channel == !not_channel && lo == !not_lo && hi == !not_hi {
+                    let timestamp = (hi as u64) << 32 | lo as u64;
+                    let state = self.channel_timestamps_mut(channel);
+                    state.logged = Some(state.logged.map_or(timestamp, |t| t.max(timestamp)));
+                }
+
+                addr += TIMESTAMP_RECORD_SIZE;
+            }
+
+            // Keep appending to a page that has records and room for more. If the only one with
+            // records is full, the next record switches pages.
+            if addr > page_addr && (addr < page_end || next_addr.is_none()) {
+                next_addr = Some(addr);
+            }
+        }
+
+        self.next_timestamp_addr = next_addr.unwrap_or(TIMESTAMP_LOG_ADDR);
+
+        // We don't know which frames before the logged one were accepted, so treat them all as seen
+        for (_, state) in &mut self.timestamps {
+            state.most_recent = state.logged;
+            state.seen = u64::MAX;
+        }
+
+        Ok(())
+    }
+
+    /// The anti-replay state for a channel, added if no frame has been accepted on it yet.
+    fn channel_timestamps_mut(&mut self, channel: u32) -> &mut ChannelTimestamps {
+        let i = match self.timestamps.binary_search_by_key(&channel, |&(c, _)| c) {
+            Ok(i) => i,
+            Err(i) => {
+                self.timestamps.insert(i, (channel, ChannelTimestamps::default()));
+                i
+            }
+        };
+        &mut self.timestamps[i].1
+    }
+
+    /// The anti-replay state for a channel, if a frame has been accepted on it.
+    fn channel_timestamps(&self, channel: u32) -> Option<&ChannelTimestamps> {
+        let i = self.timestamps.binary_search_by_key(&channel, |&(c, _)| c).ok()?;
+        Some(&self.timestamps[i].1)
+    }
+
+    /// The timestamp of the most recently accepted frame on any channel, if there has been one
+    #[allow(dead_code)]
+    pub fn most_recent_timestamp(&self) -> Option<u64> {
+        self.timestamps.iter().filter_map(|(_, state)| state.most_recent).max()
+    }
+
+    /// The timestamp of the most recently accepted frame on `channel`, if there has been one
+    pub fn channel_timestamp(&self, channel: u32) -> Option<u64> {
+        self.channel_timestamps(channel).and_then(|state| state.most_recent)
+    }
+
+    /// Whether a frame on `channel` with this timestamp can be accepted: it's newer than the most
+    /// recent frame on the channel, or within [`REPLAY_WINDOW`] of it and not accepted before.
+    pub fn is_fresh_timestamp(&self, channel: u32, timestamp: u64) -> bool {
+        let Some(state) = self.channel_timestamps(channel) else { return true };
+        match state.most_recent {
+            None => true,
+            Some(t) if timestamp > t => true,
+            Some(t) => t - timestamp < REPLAY_WINDOW && state.seen & (1 << (t - timestamp)) == 0,
+        }
+    }
+
+    /// Record the timestamp of a frame accepted on `channel`. Only timestamps newer than the
+    /// channel's current one move it forward, older ones are just marked as seen.
+    ///
+    /// To limit flash wear, the log isn't written for every frame. Instead, when a timestamp passes
+    /// the one in the log for its channel, the end of its [`TIMESTAMP_STEP`] is written, so at most
+    /// one record is written per step on each channel. After a reboot frames up to the end of that
+    /// step are rejected, which keeps the timestamp from ever going backwards at the cost of
+    /// dropping up to a step of frames.
+    ///
+    /// When a log page fills up, every channel's latest record is written to the other page before
+    /// the full one is erased, so losing power partway through never loses any of them.
+    pub fn set_most_recent_timestamp(&mut self, channel: u32, timestamp: u64) -> Result<(), StorageError> {
+        let state = self.channel_timestamps_mut(channel);
+        match state.most_recent {
+            Some(t) if timestamp <= t => {
+                if t - timestamp < REPLAY_WINDOW {
+                    state.seen |= 1 << (t - timestamp);
+                }
+                return Ok(());
+            }
+            Some(t) => {
+                state.seen = state.seen.checked_shl((timestamp - t).min(64) as u32).unwrap_or(0) | 1;
+            }
+            None => state.seen = 1,
+        }
+        state.most_recent = Some(timestamp);
+
+        if state.logged.is_some_and(|t| timestamp <= t) {
+            return Ok(());
+        }
+        let record = timestamp | (TIMESTAMP_STEP - 1);
+
+        // The record is what's carried over if this switches pages. If it isn't written, the next
+        // frame on the channel tries again.
+        let logged = state.logged.replace(record);
+        let result = self.log_timestamp(channel, record);
+        if result.is_err() {
+            self.channel_timestamps_mut(channel).logged = logged;
+        }
+        result
+    }
+
+    /// Append a record to the timestamp log, switching pages if the current one is full.
+    fn log_timestamp(&mut self, channel: u32, record: u64) -> Result<(), StorageError> {
+        // Records fill a page from the start, so the page the last record was written to is the one
+        // just before the next record. An empty log starts on the first page.
+        let page_addr = TIMESTAMP_LOG_ADDR + (self.next_timestamp_addr - TIMESTAMP_LOG_ADDR).saturating_sub(1) / FLASH_PAGE_SIZE * FLASH_PAGE_SIZE;
+
+        if self.next_timestamp_addr < page_addr + FLASH_PAGE_SIZE {
+            self.write_timestamp(self.next_timestamp_addr, channel, record)?;
+            self.next_timestamp_addr += TIMESTAMP_RECORD_SIZE;
+            return Ok(());
+        }
+
+        let other_page_addr = TIMESTAMP_LOG_ADDR + (page_addr - TIMESTAMP_LOG_ADDR + FLASH_PAGE_SIZE) % (TIMESTAMP_LOG_PAGES * FLASH_PAGE_SIZE);
+
+        // Every channel's latest record has to fit on the new page with room to spare
+        if self.timestamps.len() as u32 * TIMESTAMP_RECORD_SIZE >= FLASH_PAGE_SIZE {
+            return Err(StorageError::Full);
+        }
+
+        // The other page is normally already erased, but might not be if we lost power while
+        // switching pages last time
+        if !self.page_is_blank(other_page_addr)? {
+            unsafe { self.flc.erase_page(other_page_addr)?; }
+        }
+
+        self.next_timestamp_addr = other_page_addr;
+        for i in 0..self.timestamps.len() {
+            if let (c, ChannelTimestamps { logged: Some(logged), .. }) = self.timestamps[i] {
+                self.write_timestamp(self.next_timestamp_addr, c, logged)?;
+                self.next_timestamp_addr += TIMESTAMP_RECORD_SIZE;
+            }
+        }
+
+        unsafe { self.flc.erase_page(page_addr)?; }
 
         Ok(())
     }
 
+    /// Write a timestamp record for `channel` to the timestamp log.
+    fn write_timestamp(&mut self, addr: u32, channel: u32, timestamp: u64) -> Result<(), StorageError> {
+        let (lo, hi) = (timestamp as u32, (timestamp >> 32) as u32);
+        self.flc.write_128(addr, &[channel, !channel, lo, !lo])?;
+        Ok(self.flc.write_128(addr + ALIGNMENT, &[hi, !hi, 0, 0])?)
+    }
+
+    /// Checks if a page has been erased.
+    fn page_is_blank(&self, page_addr: u32) -> Result<bool, StorageError> {
+        for addr in (page_addr..page_addr + FLASH_PAGE_SIZE).step_by(4) {
+            if self.flc.read_32(addr)? != 0xFFFFFFFF {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Find the last valid record on the device key page, which is the key the decoder was last
+    /// rekeyed to.
+    fn load_device_key(&mut self) -> Result<(), StorageError> {
+        self.device_key = None;
+
+        let mut addr = DEVICE_KEY_ADDR;
+        while addr < DEVICE_KEY_ADDR + FLASH_PAGE_SIZE {
+            let key = self.flc.read_128(addr)?;
+            let not_key = self.flc.read_128(addr + ALIGNMENT)?;
+
+            // A blank record is where the next rekey will be written
+            if key == [0xFFFFFFFF; 4] && not_key == [0xFFFFFFFF; 4] { break }
+
+            if key.iter().zip(&not_key).all(|(&k, &n)| k == !n) {
+                self.device_key = Some(Self::key_from_words(key));
+            }
+
+            addr += DEVICE_KEY_RECORD_SIZE;
+        }
+
+        self.next_key_addr = addr;
+        Ok(())
+    }
+
+    /// The key subscriptions are encrypted and authenticated with: the one the decoder was last
+    /// rekeyed to, or the one it was built with if it hasn't been.
+    pub fn device_key(&self) -> &Key {
+        self.device_key.as_ref().unwrap_or(&DECODER_KEY)
+    }
+
+    /// Replace the device key. The new key is appended to the device key page and read back
+    /// before it's used, so the decoder keeps the old key if this fails. The page is never erased,
+    /// which would risk losing the key, so a decoder can only be rekeyed until it fills up.
+    pub fn set_device_key(&mut self, key: &Key) -> Result<(), StorageError> {
+        if self.next_key_addr >= DEVICE_KEY_ADDR + FLASH_PAGE_SIZE {
+            return Err(StorageError::Full);
+        }
+
+        let addr = self.next_key_addr;
+        let words = Self::key_words(key);
+        self.next_key_addr += DEVICE_KEY_RECORD_SIZE;
+        self.flc.write_128(addr, &words)?;
+        self.flc.write_128(addr + ALIGNMENT, &words.map(|w| !w))?;
+
+        if self.flc.read_128(addr)? != words || self.flc.read_128(addr + ALIGNMENT)? != words.map(|w| !w) {
+            return Err(StorageError::VerifyFailed);
+        }
+
+        self.device_key = Some(key.clone());
+        Ok(())
+    }
+
+    fn key_words(key: &Key) -> [u32; 4] {
+        core::array::from_fn(|i| u32::from_le_bytes(key.0[4 * i..4 * i + 4].try_into().unwrap()))
+    }
+
+    fn key_from_words(words: [u32; 4]) -> Key {
+        Key(core::array::from_fn(|i| words[i / 4].to_le_bytes()[i % 4]))
+    }
+
+    /// Number of bytes left for subscription entries before the store has to be compacted
+    #[cfg(any(test, feature = "diagnostics"))]
+    pub fn free_space(&self) -> u32 {
+        (self.bank + BANK_SIZE).saturating_sub(self.next_entry_addr)
+    }
+
     /// Immutable reference to the subscriptions list
     pub fn subscriptions(&self) -> &Vec<StaticSubscription> {
         &self.subscriptions
     }
 
-    /// Add a subscription to the flash memory and the subscriptions vec
+    /// The subscription for a channel, found with a binary search of the channel index. Adding a
+    /// subscription replaces the one for its channel, so there's never more than one. When
+    /// subscriptions overlap, the one the host sent last is the one used, even for frames only the
+    /// older one covered, so a range that's been replaced can't be used to decode anything.
+    ///
+    /// That's whichever was sent last, not whichever has the latest `start_timestamp`. A
+    /// subscription that starts earlier than the one it replaces still replaces it, so the host can
+    /// move a channel's range back as well as forward.
+    pub fn subscription_for_channel(&self, channel: u32) -> Option<&StaticSubscription> {
+        let i = self.channel_index.binary_search_by_key(&channel, |&(c, _)| c).ok()?;
+        Some(&self.subscriptions[self.channel_index[i].1])
+    }
+
+    /// [`Flash::subscription_for_channel`], but mutable so its cached cipher can be used.
+    pub fn subscription_for_channel_mut(&mut self, channel: u32) -> Option<&mut StaticSubscription> {
+        let i = self.channel_index.binary_search_by_key(&channel, |&(c, _)| c).ok()?;
+        Some(&mut self.subscriptions[self.channel_index[i].1])
+    }
+
+    /// Every channel we have a subscription for, in ascending order
+    pub fn channels(&self) -> impl Iterator<Item = u32> + '_ {
+        self.channel_index.iter().map(|&(c, _)| c)
+    }
+
+    /// Whether a subscription with the same channel and time range as `header` is stored
+    pub fn has_subscription(&self, header: &ArchivedSubscriptionDataHeader) -> bool {
+        self.subscription_for_channel(header.channel()).is_some_and(|s| {
+            s.header.start_timestamp == header.start_timestamp && s.header.end_timestamp == header.end_timestamp
+        })
+    }
+
+    /// Rebuild the channel index after the subscriptions list changes
+    fn rebuild_channel_index(&mut self) {
+        self.channel_index = self.subscriptions.iter()
+            .enumerate()
+            .map(|(i, s)| (s.channel(), i))
+            .collect();
+        self.channel_index.sort_unstable();
+    }
+
+    /// Add a subscription to the flash memory and the subscriptions vec. Any existing subscription
+    /// for the same channel is superseded by the new one.
     #[allow(unused_variables)]
-    pub fn add_subscription(&mut self, data: AlignedVec, rw: &mut impl RawRW) -> Result<(), FlashError> {
-        Self::check_addr(self.next_entry_addr + 4 + data.len() as u32)?;
+    pub fn add_subscription(&mut self, data: &[u8], rw: &mut impl RawRW) -> Result<(), StorageError> {
+        // If we're out of room, reclaim space from superseded and expired subscriptions as long as
+        // that makes enough room. Each channel's timestamps move forward on their own, so a
+        // subscription has only expired once its own channel is past it.
+        let len = data.len() as u32;
+        if self.check_span(self.next_entry_addr, Self::entry_span(len)).is_err() {
+            self.prune_expired()?;
+            if self.live_size()? + Self::entry_size(len) <= BANK_SIZE - BANK_HEADER_SIZE - ALIGNMENT {
+                self.compact()?;
+            }
+        }
+
+        let subscription = self.write_entry(data)?;
+
+        // Tombstone older subscriptions for this channel now that the new one is fully written
+        self.remove_subscription(subscription.channel())?;
+
+        self.subscriptions.push(subscription);
+        self.rebuild_channel_index();
+
+        Ok(())
+    }
+
+    /// Write an entry at the end of the subscriptions in flash. Every word is read back after it's
+    /// written, and if one didn't stick the entry is tombstoned and skipped over, and this returns
+    /// [`StorageError::VerifyFailed`]. The entry is only committed by clearing [`ENTRY_PENDING`]
+    /// once all of it is written, so losing power partway through leaves an entry `init` skips.
+    fn write_entry(&mut self, data: &[u8]) -> Result<StaticSubscription, StorageError> {
+        // The whole entry has to fit, including the padding on its last 128-bit write
+        self.check_span(self.next_entry_addr, Self::entry_span(data.len() as u32)).map_err(|_| StorageError::Full)?;
         // rw.write_debug(&format!("Writing len={} to {:#x}", data.len(), self.next_entry_addr));
-        self.flc.write_32(self.next_entry_addr, data.len() as u32)?;
+        // All flag bits start set so they can be cleared later
+        let len_addr = self.next_entry_addr;
+        let len_word = data.len() as u32 | !ENTRY_LEN_MASK;
+        self.flc.write_32(len_addr, len_word)?;
+
+        if self.flc.read_32(len_addr)? != len_word {
+            // We can't trust the length, so clear the whole word. `init` reads that as an empty
+            // tombstoned entry and carries on right after it.
+            self.flc.write_32(len_addr, 0)?;
+            self.next_entry_addr = Self::addr_before_aligned(len_addr + 4);
+            return Err(StorageError::VerifyFailed);
+        }
 
         self.next_entry_addr += 4;
 
@@ -112,19 +689,208 @@ impl Flash {
         for chunk in data.chunks(16) {
             let mut buf = [0xFFu8; 16];
             buf[..chunk.len()].copy_from_slice(chunk);
-            let buf = unsafe { &*(buf.as_ptr() as *const [u32; 4]) };
+            let buf: [u32; 4] = core::array::from_fn(|i| u32::from_ne_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap()));
             self.flc.write_128(self.next_entry_addr, &buf)?;
+
+            if self.flc.read_128(self.next_entry_addr)? != buf {
+                // The length is fine, so `init` can skip the entry once it's tombstoned
+                self.flc.write_32(len_addr, len_word & !ENTRY_LIVE)?;
+                self.next_entry_addr = Self::addr_before_aligned(entry_addr + data.len() as u32);
+                return Err(StorageError::VerifyFailed);
+            }
+
             self.next_entry_addr += chunk.len() as u32;
         }
 
         self.next_entry_addr = Self::addr_before_aligned(self.next_entry_addr);
+
+        // Commit the entry now that all of it is in flash
+        let committed = len_word & !ENTRY_PENDING;
+        self.flc.write_32(len_addr, committed)?;
+        if self.flc.read_32(len_addr)? != committed {
+            self.flc.write_32(len_addr, committed & !ENTRY_LIVE)?;
+            return Err(StorageError::VerifyFailed);
+        }
         // rw.write_debug(&format!("Next subscription will be at {:#x}", self.next_entry_addr));
 
-        self.subscriptions.push(Self::access_subscription(entry_addr, data.len() as u32));
+        self.access_subscription(entry_addr, data.len() as u32)
+    }
+
+    /// Copy the live subscriptions to the other bank, reclaiming the space used by superseded and
+    /// deleted ones. The old bank is only erased once the new one is committed, so one of them
+    /// always holds every live subscription.
+    pub fn compact(&mut self) -> Result<(), StorageError> {
+        self.switch_bank(|_| true)
+    }
+
+    /// Copy the subscriptions that `keep` says to keep to the other bank, commit it with the next
+    /// generation, and then erase the old bank. If anything fails before the commit we stay on the
+    /// old bank.
+    fn switch_bank(&mut self, keep: impl Fn(&StaticSubscription) -> bool) -> Result<(), StorageError> {
+        let (old_bank, old_next_entry_addr) = (self.bank, self.next_entry_addr);
+        let new_bank = BANKS[(old_bank == BANKS[0]) as usize];
+        let kept: Vec<u32> = self.subscriptions.iter().filter(|s| keep(s)).map(|s| s.len_addr).collect();
+
+        // The other bank is normally already erased, but might not be if we lost power while
+        // switching banks last time
+        self.erase_bank(new_bank)?;
+
+        self.bank = new_bank;
+        self.next_entry_addr = Self::addr_before_aligned(new_bank + BANK_HEADER_SIZE);
+        let copied = self.copy_entries(&kept).and_then(|copied| {
+            self.write_bank_header(new_bank, self.generation.wrapping_add(1))?;
+            Ok(copied)
+        });
+        let copied = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                (self.bank, self.next_entry_addr) = (old_bank, old_next_entry_addr);
+                return Err(e);
+            }
+        };
+
+        self.generation = self.generation.wrapping_add(1);
+        self.subscriptions = copied;
+        self.rebuild_channel_index();
+
+        self.erase_bank(old_bank)
+    }
+
+    /// Write a copy of each entry in `len_addrs` to the current bank.
+    fn copy_entries(&mut self, len_addrs: &[u32]) -> Result<Vec<StaticSubscription>, StorageError> {
+        let mut copied = Vec::with_capacity(len_addrs.len());
+        for &len_addr in len_addrs {
+            let len = self.flc.read_32(len_addr)? & ENTRY_LEN_MASK;
+            let data = unsafe { &*slice_from_raw_parts(self.flc.as_ptr(len_addr + 4), len as usize) };
+            copied.push(self.write_entry(data)?);
+        }
+        Ok(copied)
+    }
+
+    /// The generation in a bank's header, or `None` if it doesn't have a whole one.
+    fn bank_generation(&self, bank: u32) -> Result<Option<u32>, StorageError> {
+        let [magic, generation, not_generation, _] = self.flc.read_128(bank)?;
+        Ok((magic == FLASH_MAGIC && generation == !not_generation).then_some(generation))
+    }
+
+    /// Commit a bank by writing its header.
+    fn write_bank_header(&self, bank: u32, generation: u32) -> Result<(), StorageError> {
+        let header = [FLASH_MAGIC, generation, !generation, 0];
+        self.flc.write_128(bank, &header)?;
+        if self.flc.read_128(bank)? != header {
+            return Err(StorageError::VerifyFailed);
+        }
+        Ok(())
+    }
 
+    /// Erase every page of a bank that isn't blank already.
+    fn erase_bank(&self, bank: u32) -> Result<(), StorageError> {
+        for page_addr in (bank..bank + BANK_SIZE).step_by(FLASH_PAGE_SIZE as usize) {
+            if !self.page_is_blank(page_addr)? {
+                unsafe { self.flc.erase_page(page_addr)?; }
+            }
+        }
         Ok(())
     }
 
+    /// Number of bytes of flash used by live subscriptions.
+    fn live_size(&self) -> Result<u32, StorageError> {
+        let mut size = 0;
+        for subscription in &self.subscriptions {
+            size += Self::entry_size(self.flc.read_32(subscription.len_addr)? & ENTRY_LEN_MASK);
+        }
+        Ok(size)
+    }
+
+    /// Number of bytes of flash taken up by an entry of length `len`, from its length word to the
+    /// next entry's. A bank fits entries adding up to [`ALIGNMENT`] less than the space after its
+    /// header, since the first length word is padded out to just before an aligned address and the
+    /// last entry's final 128-bit write can run past where the next length word would go.
+    const fn entry_size(len: u32) -> u32 {
+        (4 + len).next_multiple_of(ALIGNMENT)
+    }
+
+    /// Number of bytes [`Flash::write_entry`] writes for an entry of length `len`, starting at its
+    /// length word. This has to fit before the end of the bank for the entry to be written.
+    const fn entry_span(len: u32) -> u32 {
+        4 + len.next_multiple_of(ALIGNMENT)
+    }
+
+    /// Tombstone every subscription for a channel and remove them from the subscriptions vec.
+    /// Returns whether there were any.
+    pub fn remove_subscription(&mut self, channel: u32) -> Result<bool, StorageError> {
+        let mut found = false;
+
+        for old in self.subscriptions.iter().filter(|s| s.channel() == channel) {
+            let len_word = self.flc.read_32(old.len_addr)?;
+            self.flc.write_32(old.len_addr, len_word & !ENTRY_LIVE)?;
+            found = true;
+        }
+        self.subscriptions.retain(|s| s.channel() != channel);
+        self.rebuild_channel_index();
+
+        Ok(found)
+    }
+
+    /// Tombstone every subscription that ended before `now`, so its slot is free for another
+    /// channel and compaction can reclaim its space. Channel 0 frames are decoded with keys built
+    /// into the decoder rather than a stored subscription, but one for channel 0 is never pruned
+    /// either way. Returns how many were removed.
+    ///
+    /// `now` is compared against every channel's subscription, but each channel only moves forward
+    /// on its own, so this is only safe with a time every channel is known to have reached. Use
+    /// [`Flash::prune_expired`] to go by each channel's own timestamps.
+    #[allow(dead_code)]
+    pub fn clear_expired(&mut self, now: u64) -> Result<usize, StorageError> {
+        self.clear_where(|s| s.end_timestamp() < now)
+    }
+
+    /// Prune the subscriptions that can't decode any frame we'd still accept on their channel,
+    /// because they ended before the oldest timestamp in its replay window.
+    pub fn prune_expired(&mut self) -> Result<usize, StorageError> {
+        let expired: Vec<u32> = self.subscriptions.iter()
+            .filter(|s| self.channel_timestamp(s.channel()).is_some_and(|t| s.end_timestamp() < t.saturating_sub(REPLAY_WINDOW - 1)))
+            .map(|s| s.channel())
+            .collect();
+        self.clear_where(|s| expired.contains(&s.channel()))
+    }
+
+    /// Tombstone every subscription other than channel 0's that `expired` says has expired.
+    fn clear_where(&mut self, expired: impl Fn(&StaticSubscription) -> bool) -> Result<usize, StorageError> {
+        let expired = |s: &StaticSubscription| s.channel() != 0 && expired(s);
+        let mut removed = 0;
+
+        for old in self.subscriptions.iter().filter(|s| expired(s)) {
+            let len_word = self.flc.read_32(old.len_addr)?;
+            self.flc.write_32(old.len_addr, len_word & !ENTRY_LIVE)?;
+            removed += 1;
+        }
+        self.subscriptions.retain(|s| !expired(s));
+        self.rebuild_channel_index();
+
+        Ok(removed)
+    }
+
+    /// Erase every subscription by switching to the other bank without any of them. The timestamp
+    /// log is left alone so this can't be used to replay old frames, and so is the device key.
+    pub fn clear_subscriptions(&mut self) -> Result<(), StorageError> {
+        self.switch_bank(|_| false)
+    }
+
+    /// Erase the whole storage region, including the timestamp log and device key, and start over
+    /// as if the decoder had just been flashed. This forgets which frames have been accepted, so it's only
+    /// built with the `test-reset` feature.
+    #[cfg(feature = "test-reset")]
+    pub fn reset(&mut self, rw: &mut impl RawRW) -> Result<(), StorageError> {
+        let mut addr = START_ADDR;
+        for _ in 0..NUM_PAGES {
+            unsafe { self.flc.erase_page(addr)?; }
+            addr += FLASH_PAGE_SIZE;
+        }
+
+        self.init(rw)
+    }
+
     /// Address of the next u32 before an aligned chunk of memory (where a subscription's packet
     /// length will be stored)
     #[inline]
@@ -132,61 +898,864 @@ impl Flash {
         ((current + 3) & !(ALIGNMENT - 1)) + ALIGNMENT - 4
     }
 
-    /// This MUST be called on a RAM address and not flash
-    pub fn access_subscription_mut(packet: &mut AlignedVec) -> MutSubscription {
-        let addr: usize = packet.as_ptr() as usize;
-        let len: usize = packet.len();
-        
+    /// Access a subscription that has been stored into flash. Fails if the entry can't hold a
+    /// subscription, so a corrupted length word is never cast into one.
+    fn access_subscription(&self, addr: u32, len: u32) -> Result<StaticSubscription, StorageError> {
+        let ptr = self.flc.as_ptr(addr);
+        let num_keys = subscription_num_keys(ptr, len as usize).ok_or(StorageError::Corrupt)?;
+
         // Split the header off of the packet
         let header_size = mem::size_of::<ArchivedSubscriptionDataHeader>();
-        let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
 
-        let header: &'static ArchivedSubscriptionDataHeader = unsafe { &*(addr as *const ArchivedSubscriptionDataHeader) };
+        // Safety: `subscription_num_keys` checked the header fits and is aligned
+        let header: &'static ArchivedSubscriptionDataHeader = unsafe { &*(ptr as *const ArchivedSubscriptionDataHeader) };
         
         // Cast the keys that are stored inline
         // Safety: The alignment of the encoded keys is 1 since we just store a bunch
-        // of u8s
-        let keys: &'static mut [ArchivedEncodedSubscriptionKey] = unsafe {
-            &mut *slice_from_raw_parts_mut(
-                (addr as usize + header_size) as *mut ArchivedEncodedSubscriptionKey,
-                (len as usize - header_size) / key_size
-            )
+        // of u8s, and there are exactly `num_keys` of them after the header
+        let keys: &'static [ArchivedEncodedSubscriptionKey] = unsafe {
+            &*slice_from_raw_parts(ptr.add(header_size) as *const ArchivedEncodedSubscriptionKey, num_keys)
         };
 
-        MutSubscription {
-            header, keys
+        Ok(StaticSubscription {
+            len_addr: addr - 4, header, keys, bitranges: header.bitranges(), cipher: None
+        })
+    }
+
+    /// Make sure the `len` bytes starting at `addr` are all within the bank in use
+    fn check_span(&self, addr: u32, len: u32) -> Result<(), FlashError> {
+        match addr.checked_add(len) {
+            Some(end) if addr >= self.bank && end <= self.bank + BANK_SIZE => Ok(()),
+            _ => Err(FlashError::InvalidAddress)
         }
     }
+}
+
+impl Flash {
+    /// This MUST be called on a RAM address and not flash. Fails if the packet isn't a header
+    /// followed by a whole number of keys, since the keys we'd read wouldn't line up with the ones
+    /// the host sent.
+    pub fn access_subscription_mut(packet: &mut AlignedVec) -> Result<MutSubscription, DecoderError> {
+        let ptr = packet.as_mut_ptr();
+        let num_keys = subscription_num_keys(ptr, packet.len()).ok_or(DecoderError::BadSubscriptionSize)?;
 
-    /// Access a subscription that has been stored into flash
-    fn access_subscription(addr: u32, len: u32) -> StaticSubscription {
         // Split the header off of the packet
         let header_size = mem::size_of::<ArchivedSubscriptionDataHeader>();
-        let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
 
-        let header: &'static ArchivedSubscriptionDataHeader = unsafe { &*(addr as *const ArchivedSubscriptionDataHeader) };
+        // Safety: `subscription_num_keys` checked the header fits and is aligned
+        let header: &'static ArchivedSubscriptionDataHeader = unsafe { &*(ptr as *const ArchivedSubscriptionDataHeader) };
         
         // Cast the keys that are stored inline
         // Safety: The alignment of the encoded keys is 1 since we just store a bunch
-        // of u8s
-        let keys: &'static [ArchivedEncodedSubscriptionKey] = unsafe {
-            &*slice_from_raw_parts(
-                (addr as usize + header_size) as *const ArchivedEncodedSubscriptionKey,
-                (len as usize - header_size) / key_size
-            )
+        // of u8s, and there are exactly `num_keys` of them after the header
+        let keys: &'static mut [ArchivedEncodedSubscriptionKey] = unsafe {
+            &mut *slice_from_raw_parts_mut(ptr.add(header_size) as *mut ArchivedEncodedSubscriptionKey, num_keys)
         };
 
-        StaticSubscription {
+        Ok(MutSubscription {
             header, keys
+        })
+    }
+}
+
+/// Number of keys in the `len` byte subscription at `ptr`, or `None` if it isn't a header followed
+/// by a whole number of keys, or the header at `ptr` wouldn't be aligned. Checked before every
+/// cast to a subscription so a bad length can't make us read past the end of it.
+fn subscription_num_keys(ptr: *const u8, len: usize) -> Option<usize> {
+    let header_size = mem::size_of::<ArchivedSubscriptionDataHeader>();
+    let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
+
+    let keys_size = len.checked_sub(header_size)?;
+    if !keys_size.is_multiple_of(key_size) || !ptr.cast::<ArchivedSubscriptionDataHeader>().is_aligned() {
+        return None;
+    }
+    Some(keys_size / key_size)
+}
+
+/// Flash backed by RAM for testing. Like real flash, writes can only clear bits and erases set a
+/// whole page back to all 1s.
+#[cfg(test)]
+pub struct MemFlc {
+    mem: *mut u32,
+    /// Address of a word that doesn't store what's written to it, like a worn out flash cell
+    bad_word: Option<u32>,
+    /// How many more writes and erases go through before power is lost. Every one after that fails
+    /// without changing anything, like it would if the decoder had turned off.
+    writes_left: core::cell::Cell<Option<u32>>,
+}
+
+#[cfg(test)]
+impl MemFlc {
+    const LEN: usize = (NUM_PAGES * FLASH_PAGE_SIZE) as usize;
+
+    /// Creates a fully erased flash. The memory is leaked so that subscriptions can refer to it
+    /// statically, just like on the device.
+    pub fn new() -> Self {
+        let mem = alloc::vec![u32::MAX; Self::LEN / 4].leak();
+        Self { mem: mem.as_mut_ptr(), bad_word: None, writes_left: core::cell::Cell::new(None) }
+    }
+
+    /// Index of the word at `addr`.
+    fn index(addr: u32, len: u32) -> Result<usize, FlashError> {
+        match addr.checked_sub(START_ADDR) {
+            Some(offset) if addr.is_multiple_of(4) && (offset + len) as usize <= Self::LEN => Ok(offset as usize / 4),
+            _ => Err(FlashError::InvalidAddress)
+        }
+    }
+
+    /// Uses up one of the writes left before power is lost.
+    fn use_write(&self) -> Result<(), FlashError> {
+        match self.writes_left.get() {
+            Some(0) => return Err(FlashError::AccessViolation),
+            left => self.writes_left.set(left.map(|n| n - 1)),
         }
+        Ok(())
     }
 
-    /// Make sure an address is within our flash storage area
-    fn check_addr(addr: u32) -> Result<(), FlashError> {
-        if addr > START_ADDR + NUM_PAGES * FLASH_PAGE_SIZE {
-            Err(FlashError::InvalidAddress)
-        } else {
-            Ok(())
+    fn write(&self, addr: u32, data: &[u32]) -> Result<(), FlashError> {
+        self.use_write()?;
+
+        let index = Self::index(addr, data.len() as u32 * 4)?;
+        for (i, &word) in data.iter().enumerate() {
+            let old = unsafe { self.mem.add(index + i).read() };
+            if old & word != word {
+                return Err(FlashError::NeedsErase);
+            }
         }
+        for (i, &word) in data.iter().enumerate() {
+            let word = if self.bad_word == Some(addr + i as u32 * 4) { word ^ 1 } else { word };
+            unsafe { self.mem.add(index + i).write(word) };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl FlashStorage for MemFlc {
+    fn read_32(&self, addr: u32) -> Result<u32, FlashError> {
+        Ok(unsafe { self.mem.add(Self::index(addr, 4)?).read() })
+    }
+
+    fn read_128(&self, addr: u32) -> Result<[u32; 4], FlashError> {
+        let index = Self::index(addr, 16)?;
+        Ok(core::array::from_fn(|i| unsafe { self.mem.add(index + i).read() }))
+    }
+
+    fn write_32(&self, addr: u32, data: u32) -> Result<(), FlashError> {
+        self.write(addr, &[data])
+    }
+
+    fn write_128(&self, addr: u32, data: &[u32; 4]) -> Result<(), FlashError> {
+        if !addr.is_multiple_of(ALIGNMENT) {
+            return Err(FlashError::InvalidAddress);
+        }
+        self.write(addr, data)
+    }
+
+    unsafe fn erase_page(&self, addr: u32) -> Result<(), FlashError> {
+        self.use_write()?;
+        let page = addr & !(FLASH_PAGE_SIZE - 1);
+        let index = Self::index(page, FLASH_PAGE_SIZE)?;
+        for i in 0..(FLASH_PAGE_SIZE / 4) as usize {
+            self.mem.add(index + i).write(u32::MAX);
+        }
+        Ok(())
+    }
+
+    fn as_ptr(&self, addr: u32) -> *const u8 {
+        unsafe { (self.mem as *const u8).add((addr - START_ADDR) as usize) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libectf::frame::{ArchivedEncodedFramePacketHeader, EncodedFramePacketHeader, Frame, FRAME_SIZE, FULL_FRAME_LENGTH, SIGNATURE_SIZE};
+    use libectf::subscription::SubscriptionData;
+
+    use crate::uart::mem_rw::MemRW;
+
+    use super::*;
+
+    /// Serialize a subscription the same way `gen_subscription` does. The keys aren't valid, but
+    /// the flash doesn't care.
+    pub fn subscription_bytes(channel: u32, start: u64, end: u64) -> AlignedVec {
+        let data = SubscriptionData::generate(b"test secrets", start, end, channel, None).unwrap();
+
+        let mut res: AlignedVec = AlignedVec::new();
+        res.extend_from_slice(&rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap());
+        for key in &data.keys {
+            res.extend_from_slice(&rkyv::to_bytes::<rkyv::rancor::Error>(key).unwrap());
+        }
+        res
+    }
+
+    fn init_flash() -> Flash<MemFlc> {
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut MemRW::new(b"")).unwrap();
+        flash
+    }
+
+    #[test]
+    fn test_resubscribe_supersedes() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        flash.add_subscription(&subscription_bytes(3, 0, 100), &mut rw).unwrap();
+        flash.add_subscription(&subscription_bytes(3, 50, 500), &mut rw).unwrap();
+
+        assert_eq!(flash.subscriptions().len(), 1);
+        assert_eq!(flash.subscriptions()[0].header.start_timestamp, 50);
+        assert_eq!(flash.subscriptions()[0].header.end_timestamp, 500);
+
+        // The lookup finds the new one
+        let subscription = flash.subscription_for_channel(3).unwrap();
+        assert_eq!((subscription.start_timestamp(), subscription.end_timestamp()), (50, 500));
+        assert_eq!(flash.channels().collect::<Vec<u32>>(), [3]);
+
+        // The superseded subscription stays gone after a reboot
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        assert_eq!(rebooted.subscriptions().len(), 1);
+        assert_eq!(rebooted.subscriptions()[0].header.start_timestamp, 50);
+    }
+
+    #[test]
+    fn test_timestamp_survives_reboot() {
+        let mut flash = init_flash();
+        assert_eq!(flash.most_recent_timestamp(), None);
+
+        flash.set_most_recent_timestamp(1, 5).unwrap();
+        flash.set_most_recent_timestamp(1, 3).unwrap();
+        assert_eq!(flash.most_recent_timestamp(), Some(5));
+
+        // Frames up to the end of the step are rejected after a reboot
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut MemRW::new(b"")).unwrap();
+        assert_eq!(rebooted.most_recent_timestamp(), Some(TIMESTAMP_STEP - 1));
+    }
+
+    #[test]
+    fn test_device_key() {
+        let mut flash = init_flash();
+        assert_eq!(flash.device_key().0, DECODER_KEY.0);
+
+        flash.set_device_key(&Key([1; KEY_SIZE_BYTES])).unwrap();
+        flash.set_device_key(&Key([2; KEY_SIZE_BYTES])).unwrap();
+        assert_eq!(flash.device_key().0, [2; KEY_SIZE_BYTES]);
+
+        // The latest key survives a reboot, and clearing subscriptions doesn't touch it
+        flash.clear_subscriptions().unwrap();
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut MemRW::new(b"")).unwrap();
+        assert_eq!(rebooted.device_key().0, [2; KEY_SIZE_BYTES]);
+
+        // Losing power after the key but before its complement keeps the previous key
+        rebooted.flc.write_128(rebooted.next_key_addr, &[3; 4]).unwrap();
+        rebooted.init(&mut MemRW::new(b"")).unwrap();
+        assert_eq!(rebooted.device_key().0, [2; KEY_SIZE_BYTES]);
+
+        // Once the page is full the decoder can't be rekeyed again
+        while rebooted.set_device_key(&Key([4; KEY_SIZE_BYTES])).is_ok() {}
+        assert_eq!(rebooted.set_device_key(&Key([5; KEY_SIZE_BYTES])), Err(StorageError::Full));
+        assert_eq!(rebooted.device_key().0, [4; KEY_SIZE_BYTES]);
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut flash = init_flash();
+        assert!(flash.is_fresh_timestamp(1, 0));
+
+        flash.set_most_recent_timestamp(1, 1000).unwrap();
+        flash.set_most_recent_timestamp(1, 1010).unwrap();
+
+        // Reordered frames inside the window are accepted once
+        assert!(flash.is_fresh_timestamp(1, 1005));
+        flash.set_most_recent_timestamp(1, 1005).unwrap();
+        assert!(!flash.is_fresh_timestamp(1, 1005));
+        assert_eq!(flash.most_recent_timestamp(), Some(1010));
+
+        // True duplicates are rejected, including of frames that have fallen behind the newest
+        assert!(!flash.is_fresh_timestamp(1, 1010));
+        assert!(!flash.is_fresh_timestamp(1, 1000));
+
+        // Frames too far behind are rejected even if they haven't been seen
+        assert!(!flash.is_fresh_timestamp(1, 1010 - REPLAY_WINDOW));
+        assert!(flash.is_fresh_timestamp(1, 1010 - REPLAY_WINDOW + 1));
+
+        // Jumping further than the window forgets everything before it
+        flash.set_most_recent_timestamp(1, 5000).unwrap();
+        assert!(!flash.is_fresh_timestamp(1, 5000));
+        assert!(flash.is_fresh_timestamp(1, 4999));
+        assert!(!flash.is_fresh_timestamp(1, 1010));
+
+        // After a reboot nothing at or before the logged timestamp is accepted
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut MemRW::new(b"")).unwrap();
+        assert!(!rebooted.is_fresh_timestamp(1, TIMESTAMP_STEP - 2));
+        assert!(rebooted.is_fresh_timestamp(1, TIMESTAMP_STEP));
+    }
+
+    #[test]
+    fn test_timestamp_log_switches_pages() {
+        let mut flash = init_flash();
+        let records_per_page = (FLASH_PAGE_SIZE / TIMESTAMP_RECORD_SIZE) as u64;
+
+        // Each of these is in a new step, so each one is written to the log. This goes around both
+        // pages a few times.
+        for i in 0..records_per_page * 5 + 3 {
+            let timestamp = i * TIMESTAMP_STEP;
+            flash.set_most_recent_timestamp(1, timestamp).unwrap();
+
+            if i % 97 == 0 || i % records_per_page == 0 {
+                let mut rebooted = Flash::new(MemFlc { mem: flash.flc.mem, bad_word: None, writes_left: core::cell::Cell::new(None) });
+                rebooted.init(&mut MemRW::new(b"")).unwrap();
+                assert_eq!(rebooted.most_recent_timestamp(), Some(timestamp | (TIMESTAMP_STEP - 1)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_channel_timestamps_independent() {
+        let mut flash = init_flash();
+
+        // Channel 1 running far ahead doesn't hold channel 2 back, and the other way around
+        for (a, b) in [(10 * TIMESTAMP_STEP, 100), (10 * TIMESTAMP_STEP + 1, 101), (10 * TIMESTAMP_STEP + 2, 200)] {
+            assert!(flash.is_fresh_timestamp(1, a));
+            assert!(flash.is_fresh_timestamp(2, b));
+            flash.set_most_recent_timestamp(1, a).unwrap();
+            flash.set_most_recent_timestamp(2, b).unwrap();
+        }
+        assert_eq!(flash.channel_timestamp(1), Some(10 * TIMESTAMP_STEP + 2));
+        assert_eq!(flash.channel_timestamp(2), Some(200));
+        assert_eq!(flash.channel_timestamp(3), None);
+        assert_eq!(flash.most_recent_timestamp(), Some(10 * TIMESTAMP_STEP + 2));
+
+        // Replays are still caught within each channel
+        assert!(!flash.is_fresh_timestamp(1, 10 * TIMESTAMP_STEP + 1));
+        assert!(!flash.is_fresh_timestamp(2, 101));
+        assert!(flash.is_fresh_timestamp(3, 101));
+
+        // Each channel's timestamp survives a reboot on its own
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut MemRW::new(b"")).unwrap();
+        assert_eq!(rebooted.channel_timestamp(1), Some(11 * TIMESTAMP_STEP - 1));
+        assert_eq!(rebooted.channel_timestamp(2), Some(TIMESTAMP_STEP - 1));
+        assert!(rebooted.is_fresh_timestamp(2, TIMESTAMP_STEP));
+    }
+
+    #[test]
+    fn test_timestamp_log_carries_channels_over() {
+        let mut flash = init_flash();
+        let records_per_page = (FLASH_PAGE_SIZE / TIMESTAMP_RECORD_SIZE) as u64;
+        flash.set_most_recent_timestamp(7, 1234).unwrap();
+
+        // Channel 7's only record is carried over each time channel 1 fills a page
+        for i in 0..records_per_page * 3 {
+            flash.set_most_recent_timestamp(1, i * TIMESTAMP_STEP).unwrap();
+        }
+
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut MemRW::new(b"")).unwrap();
+        assert_eq!(rebooted.channel_timestamp(7), Some(TIMESTAMP_STEP - 1));
+        assert_eq!(rebooted.channel_timestamp(1), Some(records_per_page * 3 * TIMESTAMP_STEP - 1));
+    }
+
+    #[test]
+    fn test_timestamp_log_power_loss_while_switching() {
+        let mut flash = init_flash();
+        let records_per_page = FLASH_PAGE_SIZE / TIMESTAMP_RECORD_SIZE;
+
+        // Fill the first page, then write the first record of the second page without erasing the
+        // first, like we lost power partway through switching pages
+        for i in 0..records_per_page {
+            flash.write_timestamp(TIMESTAMP_LOG_ADDR + i * TIMESTAMP_RECORD_SIZE, 1, i as u64).unwrap();
+        }
+        flash.write_timestamp(TIMESTAMP_LOG_ADDR + FLASH_PAGE_SIZE, 1, 1000).unwrap();
+
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut MemRW::new(b"")).unwrap();
+        assert_eq!(rebooted.most_recent_timestamp(), Some(1000));
+
+        // The next record carries on from the second page
+        rebooted.set_most_recent_timestamp(1, TIMESTAMP_STEP * 4).unwrap();
+        assert_eq!(rebooted.next_timestamp_addr, TIMESTAMP_LOG_ADDR + FLASH_PAGE_SIZE + 2 * TIMESTAMP_RECORD_SIZE);
+    }
+
+    #[test]
+    fn test_subscription_for_channel() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        for channel in [5, 1, 4, 2, 3] {
+            let start = channel as u64 * 1000;
+            flash.add_subscription(&subscription_bytes(channel, start, start + 5000), &mut rw).unwrap();
+        }
+        flash.remove_subscription(4).unwrap();
+
+        for (channel, timestamp) in (0..7).flat_map(|c| (0..12_000).step_by(97).map(move |t| (c, t))) {
+            let frame_bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&EncodedFramePacketHeader { timestamp, channel, length: FULL_FRAME_LENGTH, signature: [0; SIGNATURE_SIZE], frame: Frame([0; FRAME_SIZE]) }).unwrap();
+            let frame = unsafe { rkyv::access_unchecked::<ArchivedEncodedFramePacketHeader>(&frame_bytes) };
+
+            let scanned = flash.subscriptions().iter()
+                .find_map(|s| s.header.key_for_frame(frame, s.keys))
+                .map(|(k, mask_idx)| (k.key.0, mask_idx));
+            let indexed = flash.subscription_for_channel(channel)
+                .and_then(|s| s.header.key_for_frame_cached(frame, s.keys, &s.bitranges))
+                .map(|(k, mask_idx)| (k.key.0, mask_idx));
+            assert_eq!(indexed, scanned, "keys differ for channel {} at {}", channel, timestamp);
+        }
+    }
+
+    #[test]
+    fn test_fill_subscriptions() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        // Add subscriptions to distinct channels until there is no room left
+        let mut channel = 1;
+        let err = loop {
+            match flash.add_subscription(&subscription_bytes(channel, 0, 1000), &mut rw) {
+                Ok(()) => channel += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, StorageError::Full);
+        assert_eq!(flash.subscriptions().len(), channel as usize - 1);
+        assert!(flash.next_entry_addr + 4 + subscription_bytes(channel, 0, 1000).len() as u32 > flash.bank + BANK_SIZE);
+
+        // The rejected subscription didn't leave anything behind
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        assert_eq!(rebooted.subscriptions().len(), channel as usize - 1);
+    }
+
+    #[test]
+    fn test_entries_span_pages() {
+        // The banks are sized by `memory.x`, and have to have room past the first page to test this
+        const { assert!(BANK_SIZE > FLASH_PAGE_SIZE) };
+
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        // Add subscriptions until one starts on the second page, so the one before it straddles
+        // the boundary or ends right at it
+        let mut channel = 1;
+        while flash.subscriptions().iter().all(|s| s.len_addr < flash.bank + FLASH_PAGE_SIZE) {
+            flash.add_subscription(&subscription_bytes(channel, 0, 1000), &mut rw).unwrap();
+            channel += 1;
+        }
+
+        // Everything, including the entries on later pages, is found again after a reboot
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        let live: Vec<u32> = rebooted.subscriptions().iter().map(|s| s.header.channel()).collect();
+        assert_eq!(live, (1..channel).collect::<Vec<u32>>());
+        assert_eq!(rebooted.next_entry_addr, flash.next_entry_addr);
+    }
+
+    #[test]
+    fn test_init_erases_every_page() {
+        let flash = MemFlc::new();
+
+        // Leftovers from something else on the last page of the region, without our magic
+        let last_page = START_ADDR + (NUM_PAGES - 1) * FLASH_PAGE_SIZE;
+        flash.write_32(last_page + 8, 0).unwrap();
+
+        let mut flash = Flash::new(flash);
+        flash.init(&mut MemRW::new(b"")).unwrap();
+        assert_eq!(flash.flc.read_32(last_page + 8), Ok(0xFFFFFFFF));
+        assert_eq!(flash.flc.read_32(START_ADDR), Ok(FLASH_MAGIC));
+    }
+
+    #[test]
+    fn test_compaction() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        // Fill the bank
+        let mut channels = 0;
+        while flash.add_subscription(&subscription_bytes(channels + 1, 0, 1000), &mut rw).is_ok() {
+            channels += 1;
+        }
+
+        // Deleting half the subscriptions only tombstones them, so new entries still don't fit
+        // without compacting
+        for channel in (1..=channels).step_by(2) {
+            assert!(flash.remove_subscription(channel).unwrap());
+        }
+        let next = subscription_bytes(channels + 1, 0, 1000);
+        assert!(flash.check_span(flash.next_entry_addr, Flash::<MemFlc>::entry_span(next.len() as u32)).is_err());
+
+        flash.add_subscription(&next, &mut rw).unwrap();
+
+        let mut expected: Vec<u32> = (2..=channels).step_by(2).collect();
+        expected.push(channels + 1);
+        let live: Vec<u32> = flash.subscriptions().iter().map(|s| s.header.channel()).collect();
+        assert_eq!(live, expected);
+
+        // The compacted subscriptions survive a reboot
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        let live: Vec<u32> = rebooted.subscriptions().iter().map(|s| s.header.channel()).collect();
+        assert_eq!(live, expected);
+    }
+
+    #[test]
+    fn test_compaction_reclaims_one_entry() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        // Fill the bank, then free just enough room for one more subscription like the others
+        let mut channels = 0;
+        while flash.add_subscription(&subscription_bytes(channels + 1, 0, 1000), &mut rw).is_ok() {
+            channels += 1;
+        }
+        flash.remove_subscription(1).unwrap();
+
+        flash.add_subscription(&subscription_bytes(channels + 1, 0, 1000), &mut rw).unwrap();
+        assert_eq!(flash.channels().collect::<Vec<u32>>(), (2..=channels + 1).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_min_subscriptions_at_max_size() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        // Pad each out with copies of its last key to as many keys as we'd ever take
+        let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
+        let subscriptions: Vec<AlignedVec> = (1..=MIN_SUBSCRIPTIONS as u32).map(|channel| {
+            let mut data = subscription_bytes(channel, 1, u64::MAX - 1);
+            let last_key = data[data.len() - key_size..].to_vec();
+            while data.len() < MAX_SUBSCRIPTION_SIZE {
+                data.extend_from_slice(&last_key);
+            }
+            data
+        }).collect();
+        assert!(subscriptions.iter().all(|data| data.len() == MAX_SUBSCRIPTION_SIZE));
+        for data in &subscriptions {
+            flash.add_subscription(data, &mut rw).unwrap();
+        }
+
+        // They all still fit in the other bank, and are there after a reboot
+        flash.compact().unwrap();
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        assert_eq!(rebooted.channels().collect::<Vec<u32>>(), (1..=MIN_SUBSCRIPTIONS as u32).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_power_loss_while_compacting() {
+        let mut rw = MemRW::new(b"");
+        let subscriptions: Vec<AlignedVec> = (1..=4).map(|channel| subscription_bytes(channel, 0, 1000)).collect();
+        let setup = || {
+            let mut flash = init_flash();
+            for data in &subscriptions {
+                flash.add_subscription(data, &mut MemRW::new(b"")).unwrap();
+            }
+            flash.remove_subscription(2).unwrap();
+            flash
+        };
+
+        // Power is lost at every point of a compaction in turn, including while erasing the old
+        // bank, until there's enough for all of it
+        for writes_left in 0.. {
+            let mut flash = setup();
+            let bank = flash.bank;
+            flash.flc.writes_left.set(Some(writes_left));
+            let compacted = flash.compact().is_ok();
+
+            // Either bank has every live subscription after a reboot
+            flash.flc.writes_left.set(None);
+            let mut rebooted = Flash::new(flash.flc);
+            rebooted.init(&mut rw).unwrap();
+            assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [1, 3, 4], "power lost after {} writes", writes_left);
+
+            // And it can still be written to
+            rebooted.add_subscription(&subscriptions[1], &mut rw).unwrap();
+            assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [1, 2, 3, 4]);
+
+            if compacted {
+                assert_ne!(rebooted.bank, bank);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_verify_failure() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        flash.add_subscription(&subscription_bytes(1, 0, 100), &mut rw).unwrap();
+        flash.add_subscription(&subscription_bytes(2, 0, 100), &mut rw).unwrap();
+
+        // A word in the middle of the next entry's data doesn't stick, then the next entry's length
+        // word doesn't
+        for offset in [4 + 2 * ALIGNMENT + 8, 0] {
+            flash.flc.bad_word = Some(flash.next_entry_addr + offset);
+            let err = flash.add_subscription(&subscription_bytes(2, 50, 500), &mut rw).unwrap_err();
+            assert_eq!(err, StorageError::VerifyFailed);
+
+            // The subscription it would have replaced is still there
+            let live: Vec<(u32, u64)> = flash.subscriptions().iter().map(|s| (s.header.channel(), s.header.start_timestamp())).collect();
+            assert_eq!(live, [(1, 0), (2, 0)]);
+        }
+
+        // Later entries go after the failed ones and everything survives a reboot
+        flash.flc.bad_word = None;
+        flash.add_subscription(&subscription_bytes(3, 0, 100), &mut rw).unwrap();
+
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        let live: Vec<(u32, u64)> = rebooted.subscriptions().iter().map(|s| (s.header.channel(), s.header.start_timestamp())).collect();
+        assert_eq!(live, [(1, 0), (2, 0), (3, 0)]);
+        assert_eq!(rebooted.next_entry_addr, flash.next_entry_addr);
+    }
+
+    #[test]
+    fn test_power_loss_while_writing_entry() {
+        let mut rw = MemRW::new(b"");
+        let data = subscription_bytes(2, 0, 100);
+        // The length word, every 128-bit chunk, then the commit
+        let writes = 1 + data.len().div_ceil(16) as u32 + 1;
+
+        // Power is lost after the length word, partway through the body, or right before the commit
+        for writes_left in [1, writes / 2, writes - 1] {
+            let mut flash = init_flash();
+            flash.add_subscription(&subscription_bytes(1, 0, 100), &mut rw).unwrap();
+
+            flash.flc.writes_left.set(Some(writes_left));
+            assert!(flash.add_subscription(&data, &mut rw).is_err());
+
+            // The unfinished entry is skipped after a reboot, and the next one goes after it
+            flash.flc.writes_left.set(None);
+            let mut rebooted = Flash::new(flash.flc);
+            rebooted.init(&mut rw).unwrap();
+            assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [1]);
+
+            rebooted.add_subscription(&subscription_bytes(3, 0, 100), &mut rw).unwrap();
+            let mut rebooted = Flash::new(rebooted.flc);
+            rebooted.init(&mut rw).unwrap();
+            assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [1, 3]);
+        }
+
+        // With enough power for every write the entry is committed
+        let mut flash = init_flash();
+        flash.flc.writes_left.set(Some(writes));
+        flash.add_subscription(&data, &mut rw).unwrap();
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [2]);
+    }
+
+    #[test]
+    fn test_power_loss_before_superseding() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+        flash.add_subscription(&subscription_bytes(1, 0, 100), &mut rw).unwrap();
+        flash.add_subscription(&subscription_bytes(2, 0, 100), &mut rw).unwrap();
+
+        // Power is lost once the new subscription is committed, before the old one is tombstoned
+        let data = subscription_bytes(1, 50, 500);
+        flash.flc.writes_left.set(Some(1 + data.len().div_ceil(16) as u32 + 1));
+        assert!(flash.add_subscription(&data, &mut rw).is_err());
+
+        // Only the new one is live after a reboot, and that sticks
+        flash.flc.writes_left.set(None);
+        for _ in 0..2 {
+            let mut rebooted = Flash::new(flash.flc);
+            rebooted.init(&mut rw).unwrap();
+            let live: Vec<(u32, u64)> = rebooted.subscriptions().iter().map(|s| (s.channel(), s.start_timestamp())).collect();
+            assert_eq!(live, [(2, 0), (1, 50)]);
+            flash = rebooted;
+        }
+    }
+
+    #[test]
+    fn test_init_entry_ends_at_region_end() {
+        let mut flash = init_flash();
+
+        // A superseded entry that takes up the whole bank
+        let len_addr = flash.next_entry_addr;
+        let len = flash.bank + BANK_SIZE - (len_addr + 4);
+        flash.flc.write_32(len_addr, len | (!ENTRY_LEN_MASK & !ENTRY_LIVE)).unwrap();
+
+        flash.init(&mut MemRW::new(b"")).unwrap();
+        assert!(flash.subscriptions().is_empty());
+    }
+
+    #[test]
+    fn test_init_length_past_region_end() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+        flash.add_subscription(&subscription_bytes(1, 0, 100), &mut rw).unwrap();
+
+        // A torn length write after it that says the entry runs past the end of the bank
+        flash.flc.write_32(flash.next_entry_addr, ENTRY_LEN_MASK).unwrap();
+
+        // The entries before it still load, and the bank is full until it's compacted
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [1]);
+        assert_eq!(rebooted.free_space(), 0);
+
+        rebooted.add_subscription(&subscription_bytes(2, 0, 100), &mut rw).unwrap();
+        let mut rebooted = Flash::new(rebooted.flc);
+        rebooted.init(&mut rw).unwrap();
+        assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [1, 2]);
+        assert!(rebooted.free_space() > 0);
+    }
+
+    #[test]
+    fn test_clear_expired() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        for (channel, end) in [(1, 99), (2, 100), (3, 1000), (4, 50)] {
+            flash.add_subscription(&subscription_bytes(channel, 0, end), &mut rw).unwrap();
+        }
+
+        // Only the ones that ended before now are removed
+        assert_eq!(flash.clear_expired(100).unwrap(), 2);
+        assert_eq!(flash.channels().collect::<Vec<u32>>(), [2, 3]);
+        assert!(flash.subscription_for_channel(1).is_none());
+
+        // They stay gone after a reboot
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [2, 3]);
+        assert_eq!(rebooted.clear_expired(100).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_full_flash_prunes_expired() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        // Fill the bank, with every other subscription ending long before the rest
+        let mut channel = 1;
+        while flash.add_subscription(&subscription_bytes(channel, 0, if channel % 2 == 1 { 100 } else { 10_000 }), &mut rw).is_ok() {
+            channel += 1;
+        }
+        let next = subscription_bytes(channel, 0, 10_000);
+        assert_eq!(flash.add_subscription(&next, &mut rw), Err(StorageError::Full));
+
+        // Channel 2 is far ahead, past the end of its own subscription and everyone else's.
+        // Channel 3 is past its own, but channel 1 can still decode frames.
+        flash.set_most_recent_timestamp(2, 1_000_000_000).unwrap();
+        flash.set_most_recent_timestamp(3, 1000).unwrap();
+        flash.set_most_recent_timestamp(1, 50).unwrap();
+        flash.add_subscription(&next, &mut rw).unwrap();
+
+        // Only the subscriptions that ended on their own channel are cleared to make room
+        let expected: Vec<u32> = (1..=channel).filter(|c| !matches!(c, 2 | 3)).collect();
+        assert_eq!(flash.channels().collect::<Vec<u32>>(), expected);
+    }
+
+    #[test]
+    fn test_prune_expired_keeps_replay_window() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+        assert_eq!(flash.prune_expired().unwrap(), 0);
+
+        flash.add_subscription(&subscription_bytes(1, 0, 1000 - REPLAY_WINDOW), &mut rw).unwrap();
+        flash.add_subscription(&subscription_bytes(2, 0, 1000 - REPLAY_WINDOW + 1), &mut rw).unwrap();
+        flash.add_subscription(&subscription_bytes(3, 0, 100), &mut rw).unwrap();
+        flash.set_most_recent_timestamp(1, 1000).unwrap();
+        flash.set_most_recent_timestamp(2, 1000).unwrap();
+        flash.set_most_recent_timestamp(3, 50).unwrap();
+
+        // Channel 2 can still decode a reordered frame at the back of the window, and channel 3
+        // hasn't got near the end of its subscription
+        assert!(flash.is_fresh_timestamp(2, 1000 - REPLAY_WINDOW + 1));
+        assert_eq!(flash.prune_expired().unwrap(), 1);
+        assert_eq!(flash.channels().collect::<Vec<u32>>(), [2, 3]);
+    }
+
+    #[test]
+    fn test_init_skips_malformed_entry() {
+        let mut flash = init_flash();
+        let mut rw = MemRW::new(b"");
+
+        // A live entry whose length is a header and part of a key, followed by a good one
+        let len_addr = flash.next_entry_addr;
+        let len = (mem::size_of::<ArchivedSubscriptionDataHeader>() + 3) as u32;
+        flash.flc.write_32(len_addr, len | (!ENTRY_LEN_MASK & !ENTRY_PENDING)).unwrap();
+        flash.next_entry_addr = Flash::<MemFlc>::addr_before_aligned(len_addr + 4 + len);
+        flash.add_subscription(&subscription_bytes(2, 0, 100), &mut rw).unwrap();
+
+        // Only the good one is loaded after a reboot
+        let mut rebooted = Flash::new(flash.flc);
+        rebooted.init(&mut rw).unwrap();
+        assert_eq!(rebooted.channels().collect::<Vec<u32>>(), [2]);
+    }
+
+    #[test]
+    fn test_subscription_layout() {
+        let header_size = mem::size_of::<ArchivedSubscriptionDataHeader>();
+        let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
+        let packet = subscription_bytes(1, 0, 100);
+        let num_keys = (packet.len() - header_size) / key_size;
+
+        assert_eq!(subscription_num_keys(packet.as_ptr(), packet.len()), Some(num_keys));
+        assert_eq!(subscription_num_keys(packet.as_ptr(), header_size), Some(0));
+
+        // Too short for a header, or with part of a key left over
+        assert_eq!(subscription_num_keys(packet.as_ptr(), header_size - 1), None);
+        assert_eq!(subscription_num_keys(packet.as_ptr(), packet.len() - 1), None);
+
+        // A header that isn't aligned
+        assert_eq!(subscription_num_keys(packet[1..].as_ptr(), header_size), None);
+    }
+
+    #[test]
+    fn test_access_subscription_mut_checks_size() {
+        let mut packet = subscription_bytes(1, 0, 100);
+        let num_keys = (packet.len() - mem::size_of::<ArchivedSubscriptionDataHeader>()) / mem::size_of::<ArchivedEncodedSubscriptionKey>();
+        assert_eq!(Flash::access_subscription_mut(&mut packet).unwrap().keys.len(), num_keys);
+
+        packet.extend_from_slice(&[0]);
+        assert!(matches!(Flash::access_subscription_mut(&mut packet), Err(DecoderError::BadSubscriptionSize)));
+
+        let mut packet: AlignedVec = AlignedVec::new();
+        packet.extend_from_slice(&[0; 8]);
+        assert!(matches!(Flash::access_subscription_mut(&mut packet), Err(DecoderError::BadSubscriptionSize)));
+    }
+
+    #[test]
+    fn test_check_span() {
+        let mut flash = init_flash();
+        for bank in [BANKS[0], BANKS[1]] {
+            let end = bank + BANK_SIZE;
+            assert!(flash.check_span(bank, 4).is_ok());
+            assert!(flash.check_span(end - 16, 16).is_ok());
+            assert!(flash.check_span(end, 0).is_ok());
+
+            // Spans that run past the end, even by a byte, are rejected
+            assert_eq!(flash.check_span(end - 16, 17), Err(FlashError::InvalidAddress));
+            assert_eq!(flash.check_span(end, 1), Err(FlashError::InvalidAddress));
+            assert_eq!(flash.check_span(bank - 4, 4), Err(FlashError::InvalidAddress));
+            assert_eq!(flash.check_span(u32::MAX, 2), Err(FlashError::InvalidAddress));
+
+            flash.compact().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_storage_error_messages() {
+        let messages = [
+            (StorageError::Controller(FlashError::InvalidAddress), "invalid address"),
+            (StorageError::Controller(FlashError::AccessViolation), "flash controller busy or locked"),
+            (StorageError::Controller(FlashError::NeedsErase), "write to flash that wasn't erased"),
+            (StorageError::Full, "flash region full"),
+            (StorageError::VerifyFailed, "write verify failed"),
+            (StorageError::Corrupt, "corrupt subscription entry"),
+        ];
+        for (e, message) in messages {
+            assert_eq!(e.to_string(), message);
+        }
+
+        // ERROR packets say what went wrong instead of the variant name
+        assert_eq!(DecoderError::Flash(StorageError::Full).to_string(), "Flash error: flash region full");
     }
 }
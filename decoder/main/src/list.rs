@@ -1,29 +1,63 @@
 use alloc::vec::Vec;
-use max7800x_hal::pac::dma::Ch;
+use libectf::subscription::ChannelInfo;
+use rkyv::util::AlignedVec;
 
-use crate::{flash::Flash, uart::{body_rw::BodyRW, packet::{MessageHeader, Opcode}, raw_rw::RawRW}};
+use crate::{error::DecoderError, flash::{Flash, FlashStorage}, subscribe::MAX_SUBSCRIPTIONS, uart::{body_rw::BodyRW, dma::{RxDma, TxDma}, packet::{Opcode, MAX_BODY_SIZE}, raw_rw::{RawRW, UartError}}};
 
-pub fn list_subscriptions(header: &MessageHeader, rw: &mut impl RawRW, flash: &Flash, dma: &Ch) {
-    let mut output: Vec<u8> = Vec::new();
+/// Size of a list response with every subscription slot used: the count, then a channel, start,
+/// and end for each subscription.
+const MAX_LIST_SIZE: usize = 4 + MAX_SUBSCRIPTIONS * (4 + 8 + 8);
+
+// The whole list is sent in one packet, so its length has to fit in the header
+const _: () = assert!(MAX_LIST_SIZE <= MAX_BODY_SIZE, "A full subscription list doesn't fit in a packet");
+
+/// List every subscription, for a LIST packet with no body.
+pub fn list_subscriptions<RW: RawRW, D: RxDma<RW> + TxDma<RW>, F: FlashStorage>(body_rw: &mut BodyRW<RW, D>, flash: &Flash<F>) -> Result<(), UartError> {
+    write_list(body_rw, flash, None)
+}
+
+/// List the subscriptions for the channel in the packet body.
+pub fn list_channel<RW: RawRW, D: RxDma<RW> + TxDma<RW>, F: FlashStorage>(packet: &AlignedVec, body_rw: &mut BodyRW<RW, D>, flash: &Flash<F>) -> Result<(), DecoderError> {
+    // The body is just the channel number
+    if packet.len() != 4 {
+        return Err(DecoderError::BadListSize);
+    }
 
-    let subscriptions = flash.subscriptions();
+    body_rw.wait_for_dma(packet.len())?;
+
+    let channel = u32::from_le_bytes(packet[..4].try_into().unwrap());
+    Ok(write_list(body_rw, flash, Some(channel))?)
+}
+
+/// Write the subscriptions, or only the one for `channel`, sorted like [`ChannelInfo`] so the
+/// response doesn't depend on the order they were added in. Refreshed channel 0 keys are only
+/// listed when channel 0 is asked for, since the host tools don't expect it in a full list.
+fn write_list<RW: RawRW, D: RxDma<RW> + TxDma<RW>, F: FlashStorage>(body_rw: &mut BodyRW<RW, D>, flash: &Flash<F>, channel: Option<u32>) -> Result<(), UartError> {
+    let mut subscriptions: Vec<ChannelInfo> = flash.channels()
+        .filter(|&c| channel.map_or(c != 0, |channel| c == channel))
+        .filter_map(|c| flash.subscription_for_channel(c))
+        .map(|s| ChannelInfo { channel: s.channel(), start: s.start_timestamp(), end: s.end_timestamp() })
+        .collect();
+    subscriptions.sort();
+
+    let mut output: Vec<u8> = Vec::new();
 
     // 32-bit number of subscriptions
     output.extend_from_slice(&(subscriptions.len() as u32).to_le_bytes());
 
     // Add (channel_u32, start_timestamp_u64, end_timestamp_u64) for all
     // subscriptions
-    for subscription in subscriptions {
-        output.extend_from_slice(&subscription.header.channel.to_native().to_le_bytes());
-        output.extend_from_slice(&subscription.header.start_timestamp.to_native().to_le_bytes());
-        output.extend_from_slice(&subscription.header.end_timestamp.to_native().to_le_bytes());
+    for ChannelInfo { channel, start, end } in subscriptions {
+        output.extend_from_slice(&channel.to_le_bytes());
+        output.extend_from_slice(&start.to_le_bytes());
+        output.extend_from_slice(&end.to_le_bytes());
     }
 
-    // Write list packet header
-    rw.write_header(Opcode::LIST, output.len() as u16);
+    // Write list packet header. There are never more than MAX_SUBSCRIPTIONS, so this fits.
+    debug_assert!(output.len() <= MAX_LIST_SIZE);
+    body_rw.rw.write_header(Opcode::LIST, output.len() as u16);
 
     // Write list packet body
-    let mut body_rw = BodyRW::new(header.opcode.should_ack(), rw, dma);
-    body_rw.write_bytes(&output);
-    body_rw.finish_write();
+    body_rw.dma_write_bytes(&output)?;
+    body_rw.finish_write()
 }
@@ -1,25 +1,43 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Unit tests run on the host, where nothing is reachable from the firmware entry point
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 extern crate alloc;
 
-use alloc::format;
-use alloc::string::ToString;
-use decode::decode_frame;
+use decode::{decode_frame, DecodeMode};
+use delete::delete_subscription;
+use detail::subscription_detail;
 use embedded_alloc::LlffHeap as Heap;
-use flash::Flash;
+use error::DecoderError;
+use flash::{Flash, FlashStorage};
 use keys::VERIFYING_KEY;
-use list::list_subscriptions;
+use libectf::frame::ENCODED_FRAME_PACKET_SIZE;
+use list::{list_channel, list_subscriptions};
 use max7800x_hal::flc::Flc;
 use max7800x_hal::gcr::ClockForPeripheral;
 use max7800x_hal as hal;
 use rsa::pkcs1::DecodeRsaPublicKey;
+use rkyv::util::AlignedVec;
 use rsa::pkcs1v15::VerifyingKey;
 use sha2::Sha256;
-use subscribe::add_subscription;
+use rekey::rekey_device;
+use subscribe::{add_subscription, add_subscriptions, MAX_BATCH_SIZE, MAX_SUBSCRIPTION_SIZE};
 use uart::body_rw::BodyRW;
-use uart::packet::Opcode;
-use uart::raw_rw::RawRW;
+use uart::dma::{RxDma, TxDma, UartDma, DEFAULT_BURST_SIZE};
+use uart::packet::{crc16, AckMode, MessageHeader, Opcode};
+use uart::raw_rw::{RawRW, UartError};
+use version::{negotiate_version, report_version};
+#[cfg(feature = "diagnostics")]
+use diagnostics::report_diagnostics;
+#[cfg(feature = "test-reset")]
+use reset::reset_decoder;
+#[cfg(feature = "self-test")]
+use self_test::run_self_test;
+#[cfg(feature = "watchdog")]
+use watchdog::Watchdog;
+#[cfg(feature = "watchdog")]
+use embedded_io::ReadReady;
 use core::mem;
 use core::mem::MaybeUninit;
 
@@ -27,6 +45,7 @@ pub use hal::pac;
 pub use hal::entry;
 
 // pick a panicking behavior
+#[cfg(not(any(test, feature = "panic-uart")))]
 use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch panics
 // use panic_abort as _; // requires nightly
 // use panic_itm as _; // logs messages over ITM; requires ITM support
@@ -34,17 +53,45 @@ use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch
 // use cortex_m_semihosting::heprintln; // uncomment to use this for printing through semihosting
 
 mod uart;
+mod baud;
 mod keys;
+mod memory;
 mod flash;
 mod list;
 mod subscribe;
 mod decode;
+mod delete;
+mod detail;
+mod error;
+mod version;
+mod rekey;
+#[cfg(feature = "diagnostics")]
+mod diagnostics;
+#[cfg(feature = "test-reset")]
+mod reset;
+#[cfg(feature = "self-test")]
+mod self_test;
+#[cfg(feature = "watchdog")]
+mod watchdog;
+#[cfg(feature = "panic-uart")]
+mod panic_uart;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static HEAP: Heap = Heap::empty();
-const HEAP_SIZE: usize = 0x10000;  // Half of our RAM
+const HEAP_SIZE: usize = memory::RAM_SIZE / 2;  // Half of our RAM
 static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
 
+/// Bytes of the heap that are used and free. Unit tests allocate from the host instead, so there's
+/// nothing to report.
+#[cfg(feature = "diagnostics")]
+fn heap_usage() -> (usize, usize) {
+    #[cfg(not(test))]
+    return (HEAP.used(), HEAP.free());
+    #[cfg(test)]
+    (0, 0)
+}
+
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     // Initialize the Heap
@@ -54,7 +101,10 @@ fn main() -> ! {
 
     // Enable DMA
     unsafe { p.dma.enable_clock(&mut p.gcr); }
-    let dma = p.dma.ch(0);
+
+    // Enable the watchdog's clock. It isn't started until we're about to handle packets.
+    #[cfg(feature = "watchdog")]
+    unsafe { p.wdt0.enable_clock(&mut p.gcr); }
 
     // Initialize clock
     let mut gcr = hal::gcr::Gcr::new(p.gcr, p.lpgcr);
@@ -67,7 +117,7 @@ fn main() -> ! {
     // Initialize GPIO for UART
     let gpio0_pins = hal::gpio::Gpio0::new(p.gpio0, &mut gcr.reg).split();
 
-    // Configure UART to host computer with 115200 8N1 settings
+    // Configure UART to host computer with 8N1 settings, at the baud rate picked at build time
     let rx_pin = gpio0_pins.p0_0.into_af1();
     let tx_pin = gpio0_pins.p0_1.into_af1();
     let mut rw = hal::uart::UartPeripheral::uart0(
@@ -76,31 +126,66 @@ fn main() -> ! {
         rx_pin,
         tx_pin
     )
-        .baud(115200)
+        .baud(keys::BAUD_RATE)
         .clock_pclk(&clks.pclk)
         .parity(hal::uart::ParityBit::None)
         .build();
 
-    let mut flash = Flash::new(Flc::new(p.flc, clks.sys_clk));
+    // The HAL doesn't support flow control pins yet, so set them up by hand. With hardware flow
+    // control the UART drives RTS from its RX FIFO level, so the host is held off if DMA falls
+    // behind, and we hold off transmitting while the host deasserts CTS.
+    #[cfg(feature = "flow-control")]
+    let _flow_control_pins = {
+        let cts_pin = gpio0_pins.p0_2.into_af1();
+        let rts_pin = gpio0_pins.p0_3.into_af1();
+        p.uart0.ctrl().modify(|_, w| w.hfc_en().set_bit().cts_dis().clear_bit());
+        (cts_pin, rts_pin)
+    };
 
-    // Init flash during startup (no debug messages)
-    let mut flash_init = true;
-    flash.init(&mut rw).unwrap();
+    // Catch decoders built with broken keys before the first packet
+    #[cfg(feature = "self-test")]
+    run_self_test(&mut rw);
 
-    // Init flash on first command
-    // let mut flash_init = false;
+    let mut flash = Flash::new(Flc::new(p.flc, clks.sys_clk));
 
-    let mut most_recent_timestamp: Option<u64> = None;
+    // Init flash during startup (no debug messages). If that fails it's tried again on the first
+    // command, which reports the error to the host instead of panicking before it can hear about it.
+    let mut flash_init = flash.init(&mut rw).is_ok();
 
     // PKCS1v15 Verifying key used to validate frame packets
     let verifying_key = VerifyingKey::<Sha256>::from_pkcs1_der(VERIFYING_KEY).unwrap();
+
+    // DMA channels used to read packet bodies from and write responses to the UART
+    let dma = UartDma::new(p.dma.ch(0), p.dma.ch(1), &p.uart0, DEFAULT_BURST_SIZE);
+
+    // How bodies are ACKed, until the host negotiates something else
+    let mut ack_mode = AckMode::default();
+
+    // Every packet body is read into this buffer, so handling packets doesn't allocate for them
+    let mut packet = AlignedVec::with_capacity(MAX_PACKET_SIZE);
+
+    // Reset if the loop stalls. See `Watchdog` for where it's kicked.
+    #[cfg(feature = "watchdog")]
+    let mut watchdog = Watchdog::start(p.wdt0);
     
     loop {
-        // Disable UART DMA
-        p.uart0.dma().modify(|_, w| w.rx_en().clear_bit());
+        // Kick the watchdog for each packet, and keep kicking it while we're idle
+        #[cfg(feature = "watchdog")]
+        {
+            watchdog.kick();
+            while !rw.read_ready().unwrap_or(true) {
+                watchdog.kick();
+            }
+        }
 
-        // Read header and ack if needed
-        let header = rw.read_header();
+        // Read header and ack if needed. On error, report it and resync on the next header.
+        let header = match rw.read_header() {
+            Ok(header) => header,
+            Err(e) => {
+                rw.write_error(DecoderError::from(e));
+                continue;
+            }
+        };
         if header.opcode.should_ack() {
             rw.write_ack();
         }
@@ -108,55 +193,1409 @@ fn main() -> ! {
         // Init flash if we haven't 
         if !flash_init { 
             if let Err(e) = flash.init(&mut rw) {
-                rw.write_error(&format!("Flash Error: {:?}", e));
+                rw.write_error(DecoderError::FlashInit(e));
             }
 
             flash_init = true;
         }
 
-        if header.length == 0 {
-            match header.opcode {
-                Opcode::LIST => { 
-                    list_subscriptions(&header, &mut rw, &flash, &dma);
-                },
-                Opcode::ACK => {
-                    // Do nothing when we get an ACK
+        handle_packet(&header, &mut rw, dma, &mut flash, &verifying_key, &mut ack_mode, &mut packet);
+    }
+}
+
+/// Largest packet body we read, which is what the packet buffer is allocated for up front so that
+/// it never grows. Frame packets are all the same size, subscriptions are limited to
+/// [`MAX_SUBSCRIPTION_SIZE`], and subscription batches to [`MAX_BATCH_SIZE`]. Every other packet
+/// with a body is only a few bytes.
+const MAX_PACKET_SIZE: usize = {
+    let max = if ENCODED_FRAME_PACKET_SIZE > MAX_SUBSCRIPTION_SIZE { ENCODED_FRAME_PACKET_SIZE } else { MAX_SUBSCRIPTION_SIZE };
+    if MAX_BATCH_SIZE > max { MAX_BATCH_SIZE } else { max }
+};
+
+/// Responds to a single packet from the host, reading its body if it has one into `packet`, which
+/// is reused for every packet. `ack_mode` is changed if the packet negotiates a new one.
+fn handle_packet<RW: RawRW, D: RxDma<RW> + TxDma<RW> + Copy, F: FlashStorage>(header: &MessageHeader, rw: &mut RW, dma: D, flash: &mut Flash<F>, verifying_key: &VerifyingKey<Sha256>, ack_mode: &mut AckMode, packet: &mut AlignedVec) {
+    let should_ack = header.opcode.should_ack_chunks(*ack_mode);
+
+    if header.length == 0 {
+        match header.opcode {
+            Opcode::LIST => { 
+                let result = list_subscriptions(&mut BodyRW::new(should_ack, rw, dma), flash);
+                if let Err(e) = result {
+                    rw.write_error(DecoderError::Uart(e));
                 }
-                _ => { 
-                    // Undefined behavior, no other zero-length commands
-                    rw.write_error("Unrecognized zero-length command");
+            },
+            Opcode::VERSION => {
+                // A host that doesn't ask for a mode gets the default
+                *ack_mode = AckMode::default();
+                let result = report_version(&mut BodyRW::new(header.opcode.should_ack_chunks(*ack_mode), rw, dma));
+                if let Err(e) = result {
+                    rw.write_error(DecoderError::Uart(e));
                 }
             }
-        } else {
-            // Enable DMA from the UART side
-            p.uart0.dma().modify(|_, w| unsafe { w
-                .rx_en().set_bit()
-                .rx_thd_val().bits(1)
-            });
-
-            // Start reding packet body
-            let mut body_rw = BodyRW::new(header.opcode.should_ack(), &mut rw, dma);
-            let packet = body_rw.start_dma_read(header.length as usize);
-
-            let result = match header.opcode {
-                Opcode::SUBSCRIBE => {
-                    add_subscription(packet, &mut body_rw, &mut flash)
+            #[cfg(feature = "diagnostics")]
+            Opcode::DIAGNOSTICS => {
+                let result = report_diagnostics(&mut BodyRW::new(should_ack, rw, dma), flash, heap_usage());
+                if let Err(e) = result {
+                    rw.write_error(DecoderError::Uart(e));
                 }
-                Opcode::DECODE => {
-                    decode_frame(&header, packet, &verifying_key, &mut most_recent_timestamp, &mut body_rw, &flash)
+            }
+            #[cfg(feature = "test-reset")]
+            Opcode::RESET => {
+                if let Err(e) = reset_decoder(rw, flash) {
+                    rw.write_error(e);
                 }
-                _ => {
-                    Err("Unrecognized command".to_string())
+            }
+            Opcode::ACK => {
+                // Do nothing when we get an ACK
+            }
+            Opcode::DECODE | Opcode::SUBSCRIBE | Opcode::SUBSCRIBE_BATCH | Opcode::DELETE | Opcode::DETAIL | Opcode::REKEY => {
+                rw.write_error(DecoderError::MissingBody);
+            }
+            #[cfg(feature = "diagnostics")]
+            Opcode::DECODE_DRY_RUN => {
+                rw.write_error(DecoderError::MissingBody);
+            }
+            // ERROR, DEBUG and NACK are only ours to send. Unknown opcodes never get this far,
+            // since `read_header` rejects them.
+            _ => {
+                rw.write_error(DecoderError::UnexpectedPacket);
+            }
+        }
+    } else if !header.opcode.is_decode() && !matches!(header.opcode, Opcode::SUBSCRIBE | Opcode::SUBSCRIBE_BATCH | Opcode::DELETE | Opcode::LIST | Opcode::DETAIL | Opcode::VERSION | Opcode::REKEY) {
+        // Skip the body so that the next packet is still in frame
+        let mut body_rw = BodyRW::new(should_ack, rw, dma);
+        let _ = body_rw.discard(header.length as usize);
+
+        rw.write_error(DecoderError::UnexpectedBody);
+    } else if header.opcode == Opcode::SUBSCRIBE && header.length as usize > MAX_SUBSCRIPTION_SIZE {
+        // Don't allocate space for a subscription with more keys than any valid one has
+        let mut body_rw = BodyRW::new(should_ack, rw, dma);
+        let _ = body_rw.discard(header.length as usize);
+        rw.write_error(DecoderError::SubscriptionTooLarge);
+    } else if header.opcode == Opcode::SUBSCRIBE_BATCH && header.length as usize > MAX_BATCH_SIZE {
+        // Batches fill the packet buffer at most
+        let mut body_rw = BodyRW::new(should_ack, rw, dma);
+        let _ = body_rw.discard(header.length as usize);
+        rw.write_error(DecoderError::BadBatchSize);
+    } else if header.length as usize > MAX_PACKET_SIZE {
+        // Too big for the packet buffer, so too big to be valid
+        let mut body_rw = BodyRW::new(should_ack, rw, dma);
+        let _ = body_rw.discard(header.length as usize);
+        rw.write_error(match header.opcode {
+            Opcode::DECODE => DecoderError::BadSize,
+            #[cfg(feature = "diagnostics")]
+            Opcode::DECODE_DRY_RUN => DecoderError::BadSize,
+            Opcode::DELETE => DecoderError::BadDeleteSize,
+            Opcode::LIST => DecoderError::BadListSize,
+            Opcode::DETAIL => DecoderError::BadDetailSize,
+            Opcode::REKEY => DecoderError::BadRekeySize,
+            _ => DecoderError::BadVersionRequest,
+        });
+    } else {
+        // Start reding packet body
+        let mut body_rw = BodyRW::new(should_ack, rw, dma);
+        body_rw.start_dma_read(packet, header.length as usize);
+
+        // A sequenced packet is checked before anything is done with it, so one that was damaged
+        // on the way can be sent again instead of being acted on. That means it isn't handled
+        // while it's still arriving, like other packets are.
+        if let Some(sequence) = header.sequence {
+            let received = body_rw.wait_for_dma(header.length as usize);
+            if received.is_err() || crc16(packet) != sequence.body_crc {
+                if received == Err(UartError::Overrun) {
+                    body_rw.discard_after_overrun();
+                } else {
+                    body_rw.stop_dma();
+                }
+                rw.write_nack(sequence.number);
+                return;
+            }
+        }
+
+        let result = match header.opcode {
+            Opcode::SUBSCRIBE => {
+                add_subscription(packet, &mut body_rw, flash)
+            }
+            Opcode::SUBSCRIBE_BATCH => {
+                add_subscriptions(packet, &mut body_rw, flash)
+            }
+            Opcode::DECODE => {
+                decode_frame(header, packet, verifying_key, &mut body_rw, flash, DecodeMode::Decode)
+            }
+            #[cfg(feature = "diagnostics")]
+            Opcode::DECODE_DRY_RUN => {
+                decode_frame(header, packet, verifying_key, &mut body_rw, flash, DecodeMode::DryRun)
+            }
+            Opcode::DELETE => {
+                delete_subscription(packet, &mut body_rw, flash)
+            }
+            Opcode::LIST => {
+                list_channel(packet, &mut body_rw, flash)
+            }
+            Opcode::DETAIL => {
+                subscription_detail(packet, &mut body_rw, flash)
+            }
+            Opcode::VERSION => {
+                negotiate_version(packet, &mut body_rw, ack_mode)
+            }
+            Opcode::REKEY => {
+                rekey_device(packet, &mut body_rw, flash)
+            }
+            // Every other opcode was handled above
+            _ => {
+                Err(DecoderError::UnexpectedPacket)
+            }
+        };
+
+        // If an error was generated, print it
+        if let Err(e) = result {
+            // Wait until the whole message is transferred. If the host stopped sending we
+            // give up on the packet and resync on the next header. After an overrun the body
+            // is short, so what's left of it is thrown away instead.
+            if matches!(e, DecoderError::Uart(UartError::Overrun)) {
+                body_rw.discard_after_overrun();
+            } else {
+                let _ = body_rw.wait_for_dma(header.length as usize);
+                body_rw.stop_dma();
+            }
+
+            rw.write_error(e);
+        } else {
+            body_rw.stop_dma();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use libectf::{frame::{Frame, FULL_FRAME_LENGTH}, key::{Key, KEY_SIZE_BYTES}, mac::SubscriptionMac, rekey::wrap_device_key, subscription::SubscriptionData};
+
+    use crate::delete::DELETE_ALL;
+    use crate::flash::MemFlc;
+    use crate::keys::DECODER_KEY;
+    use crate::uart::mem_rw::{MemDma, MemRW};
+    use crate::uart::packet::HEADER_SIZE;
+
+    use super::*;
+
+    /// Runs every packet in `input` through the decoder like the main loop does, returning what
+    /// was written back and the flash.
+    fn run(input: &[u8]) -> (MemRW, Flash<MemFlc>) {
+        let mut rw = MemRW::new(input);
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut rw).unwrap();
+        process(&mut rw, &mut flash);
+
+        (rw, flash)
+    }
+
+    /// Handles packets until the input runs out.
+    fn process(rw: &mut MemRW, flash: &mut Flash<MemFlc>) {
+        let verifying_key = VerifyingKey::<Sha256>::from_pkcs1_der(VERIFYING_KEY).unwrap();
+        let mut packet = AlignedVec::with_capacity(MAX_PACKET_SIZE);
+
+        while !rw.input.is_empty() {
+            let header = match rw.read_header() {
+                Ok(header) => header,
+                Err(e) => {
+                    rw.write_error(DecoderError::from(e));
+                    continue;
                 }
             };
+            if header.opcode.should_ack() {
+                rw.write_ack();
+            }
+            // The host switches modes along with the decoder
+            let mut ack_mode = rw.ack_mode;
+            handle_packet(&header, rw, MemDma::default(), flash, &verifying_key, &mut ack_mode, &mut packet);
+            rw.ack_mode = ack_mode;
+        }
+    }
+
+    /// Splits the decoder's output into (opcode, body) pairs.
+    fn packets(mut output: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut res = Vec::new();
+        while !output.is_empty() {
+            assert_eq!(output[0], uart::packet::MAGIC);
+            let length = u16::from_le_bytes([output[2], output[3]]) as usize;
+            assert_eq!(output[..HEADER_SIZE], uart::packet::header_bytes(Opcode(output[1]), length as u16));
+            res.push((output[1], output[HEADER_SIZE..HEADER_SIZE + length].to_vec()));
+            output = &output[HEADER_SIZE + length..];
+        }
+        res
+    }
+
+    fn header(opcode: Opcode, length: u16) -> Vec<u8> {
+        uart::packet::header_bytes(opcode, length).to_vec()
+    }
 
-            // If an error was generated, print it
-            if let Err(e) = result {
-                // Wait until the whole message is transferred
-                while body_rw.dma_poll_for_ack() < header.length as usize { }
+    /// A header followed by `body`.
+    fn packet(opcode: Opcode, body: &[u8]) -> Vec<u8> {
+        let mut res = header(opcode, body.len() as u16);
+        res.extend_from_slice(body);
+        res
+    }
 
-                rw.write_error(&e);
+    /// The secrets the decoder was built with.
+    fn secrets() -> Vec<u8> {
+        libectf::secrets::parse_secrets(include_bytes!("../../../global.secrets")).unwrap().key
+    }
+
+    /// Serializes a subscription for this decoder, encrypted and authenticated with its key.
+    fn subscription_packet(channel: u32, start: u64, end: u64) -> Vec<u8> {
+        packet(Opcode::SUBSCRIBE, &subscription_body(channel, start, end))
+    }
+
+    /// A subscription as it's sent in a SUBSCRIBE body.
+    fn subscription_body(channel: u32, start: u64, end: u64) -> Vec<u8> {
+        subscription_body_for_key(&DECODER_KEY, channel, start, end)
+    }
+
+    /// A subscription encrypted and authenticated with `device_key` instead of the decoder's key.
+    fn subscription_body_for_key(device_key: &Key, channel: u32, start: u64, end: u64) -> Vec<u8> {
+        // Channel 0 keys are only ever sent as a refreshed window
+        let mut data = match channel {
+            0 => SubscriptionData::generate_channel_0_window(&secrets(), start, end, None),
+            _ => SubscriptionData::generate(&secrets(), start, end, channel, None),
+        }.unwrap();
+
+        let mut hasher = SubscriptionMac::new(device_key, start, end, channel);
+
+        let mut cipher = device_key.cipher();
+        for k in &mut data.keys {
+            hasher.update_key(&k.key.0);
+            cipher.encrypt(&mut k.key.0);
+        }
+        data.header.mac_hash = hasher.finalize();
+
+        let mut res = Vec::new();
+        res.extend_from_slice(&rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap());
+        for key in &data.keys {
+            res.extend_from_slice(&rkyv::to_bytes::<rkyv::rancor::Error>(key).unwrap());
+        }
+        res
+    }
+
+    /// A SUBSCRIBE_BATCH packet carrying `subscriptions`, followed by the ACK for the decoder's
+    /// response.
+    fn batch_packet(subscriptions: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = vec![subscriptions.len() as u8];
+        for subscription in subscriptions {
+            body.extend_from_slice(&(subscription.len() as u16).to_le_bytes());
+            body.extend_from_slice(subscription);
+        }
+        let mut res = packet(Opcode::SUBSCRIBE_BATCH, &body);
+        res.extend(header(Opcode::ACK, 0));
+        res
+    }
+
+    /// Encodes `frame` like the encoder does.
+    fn frame_packet(frame: &Frame, timestamp: u64, channel: u32) -> Vec<u8> {
+        encoded_packet(frame, timestamp, channel, FULL_FRAME_LENGTH)
+    }
+
+    /// Encodes a payload shorter than a frame like the encoder does.
+    fn payload_packet(payload: &[u8], timestamp: u64, channel: u32) -> Vec<u8> {
+        encoded_packet(&Frame::from_payload(payload), timestamp, channel, payload.len() as u8)
+    }
+
+    fn encoded_packet(frame: &Frame, timestamp: u64, channel: u32, length: u8) -> Vec<u8> {
+        let encoded = rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode(timestamp, channel, length, &secrets())).unwrap();
+        packet(Opcode::DECODE, &[&libectf::frame::frame_prefix(encoded.len() as u32)[..], &encoded].concat())
+    }
+
+    /// `body` in a sequenced packet, with `damage` applied to it after its CRC is taken.
+    fn sequenced_packet(opcode: Opcode, number: u8, body: &[u8], damage: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut res = uart::packet::sequenced_header_bytes(opcode, number, body).to_vec();
+        let mut body = body.to_vec();
+        damage(&mut body);
+        res.extend(body);
+        res
+    }
+
+    fn delete_packet(channel: u32) -> Vec<u8> {
+        let mut res = header(Opcode::DELETE, 4);
+        res.extend_from_slice(&channel.to_le_bytes());
+        res
+    }
+
+    /// A LIST packet followed by the ACK for the decoder's response.
+    fn list_packet() -> Vec<u8> {
+        let mut res = header(Opcode::LIST, 0);
+        res.extend(header(Opcode::ACK, 0));
+        res
+    }
+
+    fn list_body(subscriptions: &[(u32, u64, u64)]) -> Vec<u8> {
+        let mut res = (subscriptions.len() as u32).to_le_bytes().to_vec();
+        for (channel, start, end) in subscriptions {
+            res.extend_from_slice(&channel.to_le_bytes());
+            res.extend_from_slice(&start.to_le_bytes());
+            res.extend_from_slice(&end.to_le_bytes());
+        }
+        res
+    }
+
+    /// Everything the decoder sent other than ACKs.
+    fn responses(output: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        packets(output).into_iter().filter(|(opcode, _)| *opcode != Opcode::ACK.0).collect()
+    }
+
+    #[test]
+    fn test_delete_subscription() {
+        let mut input = subscription_packet(3, 100, 200);
+        input.extend(list_packet());
+        input.extend(delete_packet(3));
+        input.extend(list_packet());
+        input.extend(delete_packet(3));
+
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::LIST.0, list_body(&[(3, 100, 200)])),
+            (Opcode::DELETE.0, Vec::new()),
+            (Opcode::LIST.0, list_body(&[])),
+            (Opcode::ERROR.0, b"No subscription for channel".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_one_subscription_per_channel() {
+        let mut input = subscription_packet(3, 100, 200);
+        input.extend(subscription_packet(3, 150, 900));
+        input.extend(list_packet());
+
+        let (rw, flash) = run(&input);
+        assert_eq!(responses(&rw.output)[2..], [(Opcode::LIST.0, list_body(&[(3, 150, 900)]))]);
+        assert_eq!(flash.subscriptions().len(), 1);
+        assert_eq!(flash.subscription_for_channel(3).unwrap().start_timestamp(), 150);
+    }
+
+    #[test]
+    fn test_subscription_limit() {
+        let mut input = Vec::new();
+        for channel in 1..=subscribe::MAX_SUBSCRIPTIONS as u32 + 1 {
+            input.extend(subscription_packet(channel, 100, 200));
+        }
+        // At the limit a channel we have can still be renewed
+        input.extend(subscription_packet(1, 300, 400));
+
+        let (rw, flash) = run(&input);
+        let responses = responses(&rw.output);
+        assert!(responses[..subscribe::MAX_SUBSCRIPTIONS].iter().all(|r| *r == (Opcode::SUBSCRIBE.0, Vec::new())));
+        assert_eq!(responses[subscribe::MAX_SUBSCRIPTIONS..], [
+            (Opcode::ERROR.0, b"Subscription limit reached".to_vec()),
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+        ]);
+        assert_eq!(flash.subscriptions().len(), subscribe::MAX_SUBSCRIPTIONS);
+        assert!(flash.subscription_for_channel(subscribe::MAX_SUBSCRIPTIONS as u32 + 1).is_none());
+    }
+
+    #[test]
+    fn test_subscription_detail() {
+        let mut input = subscription_packet(3, 100, 200);
+        // The response fits in one chunk, so the host only ACKs it once
+        input.extend(packet(Opcode::DETAIL, &3u32.to_le_bytes()));
+        input.extend(header(Opcode::ACK, 0));
+        input.extend(packet(Opcode::DETAIL, &4u32.to_le_bytes()));
+        input.extend(packet(Opcode::DETAIL, &[3, 0]));
+
+        let (rw, _) = run(&input);
+        let responses = responses(&rw.output);
+        assert_eq!(responses[2..], [
+            (Opcode::ERROR.0, b"No subscription for channel".to_vec()),
+            (Opcode::ERROR.0, b"Unexpected detail packet size".to_vec()),
+        ]);
+
+        // Read the response back field by field
+        let (opcode, body) = &responses[1];
+        assert_eq!(*opcode, Opcode::DETAIL.0);
+        let mut body = body.as_slice();
+        let mut take = |n: usize| { let (field, rest) = body.split_at(n); body = rest; field.to_vec() };
+        assert_eq!(u32::from_le_bytes(take(4).try_into().unwrap()), 3);
+        assert_eq!(u64::from_le_bytes(take(8).try_into().unwrap()), 100);
+        assert_eq!(u64::from_le_bytes(take(8).try_into().unwrap()), 200);
+
+        let bitranges = SubscriptionData::generate(&secrets(), 100, 200, 3, None).unwrap().header.bitranges();
+        assert_eq!(u32::from_le_bytes(take(4).try_into().unwrap()) as usize, bitranges.len());
+        for (start_timestamp, mask_idx) in bitranges {
+            assert_eq!(u64::from_le_bytes(take(8).try_into().unwrap()), start_timestamp);
+            assert_eq!(take(1), [mask_idx]);
+
+            // The fingerprint is of the decrypted key, but isn't the key
+            #[cfg(feature = "diagnostics")]
+            {
+                use sha2::Digest;
+                let key = libectf::key::Key::for_bitrange(start_timestamp, mask_idx, 3, &secrets());
+                assert_eq!(take(detail::FINGERPRINT_SIZE), Sha256::digest(key.0)[..detail::FINGERPRINT_SIZE]);
             }
         }
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_misaligned_subscription_length() {
+        // A subscription with a few extra bytes, as if the header had grown
+        let mut misaligned = subscription_packet(3, 100, 200);
+        misaligned.extend_from_slice(&[0; 3]);
+        let length = (misaligned.len() - HEADER_SIZE) as u16;
+        misaligned[..HEADER_SIZE].copy_from_slice(&uart::packet::header_bytes(Opcode::SUBSCRIBE, length));
+
+        let mut input = misaligned;
+        input.extend(list_packet());
+
+        // The whole body is still consumed, so the next packet is read correctly
+        let (rw, flash) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::ERROR.0, b"Unexpected subscription packet size".to_vec()),
+            (Opcode::LIST.0, list_body(&[])),
+        ]);
+        assert!(flash.subscriptions().is_empty());
+    }
+
+    #[test]
+    fn test_subscription_shorter_than_header() {
+        let mut input = packet(Opcode::SUBSCRIBE, &[1, 2, 3, 4]);
+        input.extend(list_packet());
+
+        let (rw, flash) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::ERROR.0, b"Unexpected subscription packet size".to_vec()),
+            (Opcode::LIST.0, list_body(&[])),
+        ]);
+        assert!(flash.subscriptions().is_empty());
+    }
+
+    #[test]
+    fn test_identical_subscription_not_rewritten() {
+        let (_, mut flash) = run(&subscription_packet(3, 100, 200));
+        let free_space = flash.free_space();
+
+        let mut input = subscription_packet(3, 100, 200);
+        input.extend(list_packet());
+        let mut rw = MemRW::new(&input);
+        process(&mut rw, &mut flash);
+
+        // Still a success, but nothing more is written to flash
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::LIST.0, list_body(&[(3, 100, 200)])),
+        ]);
+        assert_eq!(flash.free_space(), free_space);
+        assert_eq!(flash.subscriptions().len(), 1);
+    }
+
+    #[test]
+    fn test_delete_all_subscriptions() {
+        let mut input = subscription_packet(3, 100, 200);
+        input.extend(subscription_packet(4, 100, 200));
+        input.extend(header(Opcode::DELETE, 0));
+        input.extend(delete_packet(DELETE_ALL));
+        input.extend(list_packet());
+
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::ERROR.0, b"Missing packet body".to_vec()),
+            (Opcode::DELETE.0, Vec::new()),
+            (Opcode::LIST.0, list_body(&[])),
+        ]);
+    }
+
+    /// A LIST packet for one channel followed by the ACK for the decoder's response.
+    fn list_channel_packet(channel: u32) -> Vec<u8> {
+        let mut res = packet(Opcode::LIST, &channel.to_le_bytes());
+        res.extend(header(Opcode::ACK, 0));
+        res
+    }
+
+    #[test]
+    fn test_list_sorted_and_filtered() {
+        let mut input = Vec::new();
+        for (channel, start, end) in [(5, 500, 600), (9, 900, 1000), (2, 200, 300), (9, 100, 2000)] {
+            input.extend(subscription_packet(channel, start, end));
+        }
+        input.extend(list_packet());
+        input.extend(list_channel_packet(5));
+        input.extend(list_channel_packet(7));
+        input.extend(packet(Opcode::LIST, &[5, 0]));
+
+        // Channel 9 was resubscribed, which replaces it rather than adding to the end of the list
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output)[4..], [
+            (Opcode::LIST.0, list_body(&[(2, 200, 300), (5, 500, 600), (9, 100, 2000)])),
+            (Opcode::LIST.0, list_body(&[(5, 500, 600)])),
+            (Opcode::LIST.0, list_body(&[])),
+            (Opcode::ERROR.0, b"Unexpected list packet size".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_list_multiple_blocks() {
+        // The host ACKs everything as it is written, so only one command can be queued at a time
+        let mut rw = MemRW::with_host_acks(b"");
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut rw).unwrap();
+
+        // Enough subscriptions that the list response is more than one block
+        let subscriptions: Vec<(u32, u64, u64)> = (1..=20).map(|c| (c, c as u64 * 100, c as u64 * 200)).collect();
+        for &(channel, start, end) in &subscriptions {
+            rw.input.extend(subscription_packet(channel, start, end));
+            process(&mut rw, &mut flash);
+        }
+
+        let start = rw.output.len();
+        rw.input.extend(header(Opcode::LIST, 0));
+        process(&mut rw, &mut flash);
+
+        assert!(list_body(&subscriptions).len() > 256);
+        assert_eq!(responses(&rw.output[start..]), [(Opcode::LIST.0, list_body(&subscriptions))]);
+    }
+
+    #[test]
+    fn test_ack_modes() {
+        let subscriptions: Vec<(u32, u64, u64)> = (1..=20).map(|c| (c, c as u64 * 100, c as u64 * 200)).collect();
+        let chunks: usize = subscriptions.iter().map(|&(c, s, e)| (subscription_packet(c, s, e).len() - HEADER_SIZE).div_ceil(256)).sum();
+
+        for (flags, mode) in [(0, AckMode::Ack), (version::VERSION_FLAG_BULK, AckMode::Bulk)] {
+            let mut rw = MemRW::with_host_acks(b"");
+            let mut flash = Flash::new(MemFlc::new());
+            flash.init(&mut rw).unwrap();
+
+            rw.input.extend(packet(Opcode::VERSION, &[flags]));
+            process(&mut rw, &mut flash);
+            assert_eq!(rw.ack_mode, mode);
+
+            for &(channel, start, end) in &subscriptions {
+                rw.input.extend(subscription_packet(channel, start, end));
+                process(&mut rw, &mut flash);
+            }
+            let start = rw.output.len();
+            rw.input.extend(header(Opcode::LIST, 0));
+            process(&mut rw, &mut flash);
+            assert_eq!(responses(&rw.output[start..]), [(Opcode::LIST.0, list_body(&subscriptions))]);
+
+            // Every header is ACKed, and so is the VERSION body since it was sent before the
+            // switch. Only in ACK mode are the subscription bodies.
+            let acks = packets(&rw.output).iter().filter(|(opcode, _)| *opcode == Opcode::ACK.0).count();
+            let headers = 1 + subscriptions.len() + 1;
+            assert_eq!(acks, headers + 1 + if mode == AckMode::Ack { chunks } else { 0 });
+        }
+    }
+
+    #[test]
+    fn test_version_falls_back_to_acks() {
+        let mut rw = MemRW::with_host_acks(b"");
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut rw).unwrap();
+
+        rw.input.extend(packet(Opcode::VERSION, &[version::VERSION_FLAG_BULK]));
+        process(&mut rw, &mut flash);
+        assert_eq!(rw.ack_mode, AckMode::Bulk);
+
+        // Flags we don't know are rejected without changing modes
+        rw.input.extend(packet(Opcode::VERSION, &[0x80]));
+        process(&mut rw, &mut flash);
+        assert_eq!(rw.ack_mode, AckMode::Bulk);
+
+        // A host that doesn't negotiate gets ACKs
+        rw.input.extend(header(Opcode::VERSION, 0));
+        process(&mut rw, &mut flash);
+        assert_eq!(rw.ack_mode, AckMode::Ack);
+
+        let responses = responses(&rw.output);
+        assert_eq!(responses.iter().map(|(opcode, _)| *opcode).collect::<Vec<u8>>(), [Opcode::VERSION.0, Opcode::ERROR.0, Opcode::VERSION.0]);
+        assert_eq!(responses[1].1, b"Unsupported version request");
+    }
+
+    #[test]
+    fn test_oversized_subscribe() {
+        let length = MAX_SUBSCRIPTION_SIZE + 16;
+        let mut input = header(Opcode::SUBSCRIBE, length as u16);
+        input.resize(input.len() + length, 0xAA);
+        // A LIST afterwards should still be read as a packet. The ACK is for its body.
+        input.extend(header(Opcode::LIST, 0));
+        input.extend(header(Opcode::ACK, 0));
+
+        let (rw, _) = run(&input);
+        let packets = packets(&rw.output);
+
+        // The header ACK, an ACK for every chunk of the body, then the error
+        let chunks = length.div_ceil(256);
+        assert!(packets[..=chunks].iter().all(|p| *p == (Opcode::ACK.0, Vec::new())));
+        assert_eq!(packets[chunks + 1], (Opcode::ERROR.0, b"Subscription too large".to_vec()));
+
+        // Followed by the LIST response
+        assert_eq!(packets[chunks + 2], (Opcode::ACK.0, Vec::new()));
+        assert_eq!(packets[chunks + 3], (Opcode::LIST.0, 0u32.to_le_bytes().to_vec()));
+        assert_eq!(packets.len(), chunks + 4);
+    }
+
+    #[test]
+    fn test_oversized_packet() {
+        let length = MAX_PACKET_SIZE + 1;
+        let mut input = header(Opcode::DELETE, length as u16);
+        input.resize(input.len() + length, 0xAA);
+        input.extend(list_packet());
+
+        // The body is skipped without being read into the packet buffer
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::ERROR.0, b"Unexpected delete packet size".to_vec()),
+            (Opcode::LIST.0, list_body(&[])),
+        ]);
+    }
+
+    #[test]
+    fn test_packet_buffer_reused() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+        let mut input = subscription_packet(3, 100, 1000);
+        input.extend(frame_packet(&frame, 500, 3));
+        input.extend(subscription_packet(4, 100, 1000));
+        input.extend(payload_packet(b"hi", 501, 3));
+        input.extend(delete_packet(4));
+        input.extend(frame_packet(&frame, 502, 3));
+        // The largest batch we take is read in whole before it's found to be malformed
+        input.extend(packet(Opcode::SUBSCRIBE_BATCH, &vec![0; MAX_BATCH_SIZE]));
+
+        let mut rw = MemRW::new(&input);
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut rw).unwrap();
+        let verifying_key = VerifyingKey::<Sha256>::from_pkcs1_der(VERIFYING_KEY).unwrap();
+
+        // Every body fits in the buffer allocated up front, so it's never reallocated
+        let mut packet = AlignedVec::with_capacity(MAX_PACKET_SIZE);
+        let (ptr, capacity) = (packet.as_ptr(), packet.capacity());
+        while !rw.input.is_empty() {
+            let header = rw.read_header().unwrap();
+            if header.opcode.should_ack() {
+                rw.write_ack();
+            }
+            handle_packet(&header, &mut rw, MemDma::default(), &mut flash, &verifying_key, &mut AckMode::Ack, &mut packet);
+            assert_eq!((packet.as_ptr(), packet.capacity()), (ptr, capacity));
+        }
+
+        let responses = responses(&rw.output);
+        assert_eq!(responses.len(), 7);
+        assert!(responses[..6].iter().all(|(opcode, _)| *opcode != Opcode::ERROR.0));
+        assert_eq!(responses[6], (Opcode::ERROR.0, b"Unexpected subscription batch size".to_vec()));
+    }
+
+    #[test]
+    fn test_decode_until_subscription_end() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+
+        // The subscription ends partway through a bitrange
+        let mut input = subscription_packet(3, 100, 1000);
+        input.extend(frame_packet(&frame, 1000, 3));
+        input.extend(frame_packet(&frame, 1001, 3));
+
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::ERROR.0, b"Subscription expired".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_decode_across_bitranges() {
+        // Frames in the same bitrange share a cached cipher, and moving to another bitrange
+        // replaces it. Each subscription has its own.
+        let timestamps = [512, 514, 600, 1000, 1002, 2000, 2046];
+        let frames: Vec<Frame> = timestamps.iter().map(|&t| Frame(core::array::from_fn(|i| (i as u64 + t) as u8))).collect();
+        let mut input = subscription_packet(3, 100, 2047);
+        input.extend(subscription_packet(4, 0, 5000));
+        for (frame, &timestamp) in frames.iter().zip(&timestamps) {
+            input.extend(frame_packet(frame, timestamp, 3));
+            input.extend(frame_packet(frame, timestamp + 1, 4));
+        }
+
+        let (rw, _) = run(&input);
+        let decoded: Vec<_> = responses(&rw.output)[2..].iter().map(|(opcode, body)| (*opcode, body.clone())).collect();
+        let expected: Vec<_> = frames.iter().flat_map(|f| [(Opcode::DECODE.0, f.0.to_vec()), (Opcode::DECODE.0, f.0.to_vec())]).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_channel_0_keys() {
+        // The build script generates the built in keys the same way the host tools do
+        let data = SubscriptionData::generate_channel_0(&secrets());
+        assert_eq!(keys::CHANNEL_0_BITRANGES, data.header.bitranges());
+        assert!(keys::CHANNEL_0_KEYS.iter().map(|k| k.key.0).eq(data.keys.iter().map(|k| k.key.0)));
+    }
+
+    #[test]
+    #[cfg(not(feature = "channel-0-refresh"))]
+    fn test_channel_0_subscription_refused() {
+        let frame = Frame([9; libectf::frame::FRAME_SIZE]);
+        let mut input = subscription_packet(0, 1000, 2000);
+        input.extend(frame_packet(&frame, 5000, 0));
+
+        // The built in keys stay in use
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::ERROR.0, b"Cannot subscribe to channel 0".to_vec()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+        ]);
+    }
+
+    #[test]
+    #[cfg(feature = "channel-0-refresh")]
+    fn test_channel_0_refresh() {
+        let frame = Frame([9; libectf::frame::FRAME_SIZE]);
+
+        // Before a refresh every channel 0 frame decodes, and after it only those in the window do
+        let mut input = frame_packet(&frame, 500, 0);
+        input.extend(subscription_packet(0, 1000, 2000));
+        input.extend(frame_packet(&frame, 1000, 0));
+        input.extend(frame_packet(&frame, 1500, 0));
+        input.extend(frame_packet(&frame, 2001, 0));
+        input.extend(list_packet());
+        input.extend(list_channel_packet(0));
+
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::ERROR.0, b"Subscription expired".to_vec()),
+            // The refresh isn't a subscription the host tools would list
+            (Opcode::LIST.0, list_body(&[])),
+            (Opcode::LIST.0, list_body(&[(0, 1000, 2000)])),
+        ]);
+    }
+
+    #[test]
+    fn test_subscribe_batch() {
+        // The middle subscription's MAC doesn't match, which only rejects that one
+        let mut bad = subscription_body(4, 0, 1000);
+        *bad.last_mut().unwrap() ^= 1;
+        let mut input = batch_packet(&[subscription_body(3, 0, 1000), bad, subscription_body(5, 100, 200)]);
+        input.extend(list_packet());
+
+        let (rw, flash) = run(&input);
+        let mut statuses = vec![3];
+        for (channel, status) in [(3u32, subscribe::BATCH_STORED), (4, subscribe::BATCH_REJECTED), (5, subscribe::BATCH_STORED)] {
+            statuses.extend_from_slice(&channel.to_le_bytes());
+            statuses.push(status);
+        }
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE_BATCH.0, statuses),
+            (Opcode::LIST.0, list_body(&[(3, 0, 1000), (5, 100, 200)])),
+        ]);
+        assert_eq!(flash.subscriptions().len(), 2);
+    }
+
+    #[test]
+    fn test_subscribe_batch_malformed() {
+        let subscription = subscription_body(3, 0, 1000);
+
+        // A batch has to be exactly the subscriptions its count says it has, or none are stored
+        let mut short = batch_packet(&[subscription.clone(), subscription.clone()]);
+        short[HEADER_SIZE] = 3;
+        let mut long = batch_packet(core::slice::from_ref(&subscription));
+        long[HEADER_SIZE] = 0;
+        let mut torn = subscription.clone();
+        torn.pop();
+
+        for mut input in [short, long, batch_packet(&[torn])] {
+            input.extend(list_packet());
+            let (rw, _) = run(&input);
+            assert_eq!(responses(&rw.output), [
+                (Opcode::ERROR.0, b"Unexpected subscription batch size".to_vec()),
+                (Opcode::LIST.0, list_body(&[])),
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_channels_replay_independently() {
+        let frame = Frame([5; libectf::frame::FRAME_SIZE]);
+        let mut input = subscription_packet(3, 0, 100_000);
+        input.extend(subscription_packet(4, 0, 100_000));
+
+        // Channel 3 is well ahead of channel 4, but each only has to move forward on its own
+        for (timestamp_3, timestamp_4) in [(50_000, 100), (50_001, 101), (50_002, 102)] {
+            input.extend(frame_packet(&frame, timestamp_3, 3));
+            input.extend(frame_packet(&frame, timestamp_4, 4));
+        }
+        input.extend(frame_packet(&frame, 101, 4));
+        input.extend(frame_packet(&frame, 50_001, 3));
+
+        let (rw, _) = run(&input);
+        let mut expected = vec![(Opcode::DECODE.0, frame.0.to_vec()); 6];
+        expected.extend(vec![(Opcode::ERROR.0, b"Frame is from the past".to_vec()); 2]);
+        assert_eq!(responses(&rw.output)[2..], expected);
+    }
+
+    #[test]
+    fn test_decode_short_payload() {
+        let mut input = subscription_packet(3, 100, 1000);
+        input.extend(payload_packet(b"hi", 500, 3));
+        input.extend(payload_packet(&[], 501, 3));
+
+        // Only the payload is sent back, not the padding
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output)[1..], [
+            (Opcode::DECODE.0, b"hi".to_vec()),
+            (Opcode::DECODE.0, Vec::new()),
+        ]);
+    }
+
+    #[test]
+    fn test_single_timestamp_subscription() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+
+        // A subscription that starts and ends at 500 only decodes 500
+        let mut input = subscription_packet(3, 500, 500);
+        input.extend(frame_packet(&frame, 499, 3));
+        input.extend(frame_packet(&frame, 500, 3));
+        input.extend(frame_packet(&frame, 501, 3));
+
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output)[1..], [
+            (Opcode::ERROR.0, b"Subscription expired".to_vec()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::ERROR.0, b"Subscription expired".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_overlapping_subscriptions_use_newest() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+
+        // A short subscription sent after a long one that covers it
+        let mut input = subscription_packet(3, 0, 10_000);
+        input.extend(subscription_packet(3, 500, 600));
+        input.extend(frame_packet(&frame, 550, 3));
+        input.extend(frame_packet(&frame, 5000, 3));
+
+        // Frames are decoded with the newer one, and the rest of the older range is gone
+        let (rw, flash) = run(&input);
+        assert_eq!(responses(&rw.output)[2..], [
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::ERROR.0, b"Subscription expired".to_vec()),
+        ]);
+        let subscription = flash.subscription_for_channel(3).unwrap();
+        assert_eq!((subscription.start_timestamp(), subscription.end_timestamp()), (500, 600));
+    }
+
+    #[test]
+    fn test_overlapping_subscriptions_use_last_sent() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+
+        // A subscription that starts earlier than the one it's sent after
+        let mut input = subscription_packet(3, 500, 10_000);
+        input.extend(subscription_packet(3, 0, 1000));
+        input.extend(frame_packet(&frame, 100, 3));
+        input.extend(frame_packet(&frame, 5000, 3));
+
+        // It's used even though the older one starts later, and the older one's range is gone
+        let (rw, flash) = run(&input);
+        assert_eq!(responses(&rw.output)[2..], [
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::ERROR.0, b"Subscription expired".to_vec()),
+        ]);
+        let subscription = flash.subscription_for_channel(3).unwrap();
+        assert_eq!((subscription.start_timestamp(), subscription.end_timestamp()), (0, 1000));
+    }
+
+    #[test]
+    fn test_decode_failure_reasons() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+        let signature_offset = HEADER_SIZE + libectf::frame::FRAME_PREFIX_SIZE + mem::offset_of!(libectf::frame::ArchivedEncodedFramePacketHeader, signature);
+
+        // A frame for a channel we aren't subscribed to
+        let unsubscribed = frame_packet(&frame, 500, 4);
+
+        // A frame that was corrupted on the way
+        let mut corrupted = frame_packet(&frame, 500, 3);
+        corrupted[signature_offset] ^= 1;
+
+        // A frame packet that's been cut short
+        let mut truncated = frame_packet(&frame, 500, 3);
+        truncated.truncate(truncated.len() - 1);
+        let length = (truncated.len() - HEADER_SIZE) as u16;
+        truncated[..HEADER_SIZE].copy_from_slice(&uart::packet::header_bytes(Opcode::DECODE, length));
+
+        let mut input = subscription_packet(3, 100, 1000);
+        input.extend(unsubscribed);
+        input.extend(corrupted);
+        input.extend(truncated);
+        input.extend(frame_packet(&frame, 500, 3));
+
+        // Each failure is reported with its own reason, and none of them stop a good frame
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::ERROR.0, b"No subscription for frame".to_vec()),
+            (Opcode::ERROR.0, b"Frame validation failed".to_vec()),
+            (Opcode::ERROR.0, b"Unexpected frame packet size".to_vec()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_decode_errors_drain_body() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+        let signature_offset = HEADER_SIZE + libectf::frame::FRAME_PREFIX_SIZE + mem::offset_of!(libectf::frame::ArchivedEncodedFramePacketHeader, signature);
+        let verifying_key = VerifyingKey::<Sha256>::from_pkcs1_der(VERIFYING_KEY).unwrap();
+
+        let mut corrupted = frame_packet(&frame, 600, 3);
+        corrupted[signature_offset] ^= 1;
+
+        let mut future = frame_packet(&frame, 600, 3);
+        future[HEADER_SIZE] += 1;
+
+        let mut truncated = frame_packet(&frame, 600, 3);
+        truncated.truncate(truncated.len() - 1);
+        let length = (truncated.len() - HEADER_SIZE) as u16;
+        truncated[..HEADER_SIZE].copy_from_slice(&uart::packet::header_bytes(Opcode::DECODE, length));
+
+        let (_, mut flash) = run(&[subscription_packet(3, 100, 1000), frame_packet(&frame, 500, 3)].concat());
+
+        // Each failure is found at a different point in the body, but all of them are returned
+        // with the body read, without relying on the main loop to skip what's left
+        for (input, message) in [
+            (frame_packet(&frame, 600, 4), "No subscription for frame"),
+            (frame_packet(&frame, 400, 3), "Frame is from the past"),
+            (corrupted, "Frame validation failed"),
+            (future, "Unsupported frame format version"),
+            (truncated, "Unexpected frame packet size"),
+        ] {
+            let mut rw = MemRW::new(&[input, list_packet()].concat());
+            let header = rw.read_header().unwrap();
+            let mut packet = AlignedVec::new();
+
+            let mut body_rw = BodyRW::new(false, &mut rw, MemDma::default());
+            body_rw.start_dma_read(&mut packet, header.length as usize);
+            let e = decode_frame(&header, &mut packet, &verifying_key, &mut body_rw, &mut flash, DecodeMode::Decode).unwrap_err();
+            assert_eq!(e.message(), message);
+
+            // Only the next packet is left. Reading headers resyncs on garbage, so checking the next
+            // one parses wouldn't catch a body that was left behind.
+            assert_eq!(rw.input.len(), list_packet().len(), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_frame_format_version() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+
+        let mut future = frame_packet(&frame, 500, 3);
+        future[HEADER_SIZE] += 1;
+        let mut input = subscription_packet(3, 100, 1000);
+        input.extend(future);
+        input.extend(frame_packet(&frame, 500, 3));
+
+        let (rw, _) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::ERROR.0, alloc::format!("Unsupported frame format version: {}", libectf::frame::FRAME_FORMAT_VERSION + 1).into_bytes()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_version() {
+        let mut input = header(Opcode::VERSION, 0);
+        input.extend(header(Opcode::ACK, 0));
+
+        let (rw, _) = run(&input);
+
+        let mut body = crate::keys::DECODER_ID.to_le_bytes().to_vec();
+        body.push(version::PROTOCOL_VERSION);
+        body.extend_from_slice(&crate::keys::FLASH_MAGIC.to_le_bytes());
+        body.extend_from_slice(&crate::keys::BAUD_RATE.to_le_bytes());
+        body.extend_from_slice(&crate::keys::COMPAT_HASH.to_le_bytes());
+        assert_eq!(body.len(), 17);
+
+        // build.rs works out the hash without our libectf features, so check it agrees
+        assert_eq!(crate::keys::COMPAT_HASH, libectf::frame::compat_hash());
+        assert_eq!(packets(&rw.output), [
+            (Opcode::ACK.0, Vec::new()),
+            (Opcode::VERSION.0, body),
+        ]);
+    }
+
+    #[test]
+    #[cfg(feature = "diagnostics")]
+    fn test_diagnostics() {
+        let mut input = subscription_packet(3, 100, 200);
+        input.extend(header(Opcode::DIAGNOSTICS, 0));
+        input.extend(header(Opcode::ACK, 0));
+
+        let (rw, flash) = run(&input);
+
+        // Tests don't use the firmware's heap, so it reports nothing used or free
+        let mut body = [0u32, 0, 1].map(u32::to_le_bytes).concat();
+        body.extend_from_slice(&flash.free_space().to_le_bytes());
+        body.extend_from_slice(&diagnostics::NO_TIMESTAMP.to_le_bytes());
+        assert!(flash.free_space() > 0);
+        assert_eq!(responses(&rw.output)[1..], [(Opcode::DIAGNOSTICS.0, body)]);
+    }
+
+    #[test]
+    #[cfg(feature = "diagnostics")]
+    fn test_diagnostics_timestamp() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+        let diagnostics = [header(Opcode::DIAGNOSTICS, 0), header(Opcode::ACK, 0)].concat();
+
+        // Nothing before the first frame, then updated by a successful decode but not by one that
+        // was rejected
+        let mut input = subscription_packet(3, 100, 200);
+        input.extend(&diagnostics);
+        input.extend(frame_packet(&frame, 150, 3));
+        input.extend(&diagnostics);
+        input.extend(frame_packet(&frame, 250, 3));
+        input.extend(&diagnostics);
+
+        let (rw, _) = run(&input);
+        let timestamps: Vec<_> = responses(&rw.output).into_iter()
+            .filter(|(opcode, _)| *opcode == Opcode::DIAGNOSTICS.0)
+            .map(|(_, body)| u64::from_le_bytes(body[16..].try_into().unwrap()))
+            .collect();
+        assert_eq!(timestamps, [diagnostics::NO_TIMESTAMP, 150, 150]);
+    }
+
+    #[test]
+    #[cfg(feature = "diagnostics")]
+    fn test_decode_dry_run() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+        let dry_run = packet(Opcode::DECODE_DRY_RUN, &frame_packet(&frame, 500, 3)[HEADER_SIZE..]);
+        let with_status = |status: u8| [&[status][..], &frame.0].concat();
+
+        // Dry runs of the same frame all decode, and don't stop the real one from decoding. After
+        // it, a dry run still decodes the frame but reports it would be rejected.
+        let mut input = subscription_packet(3, 100, 1000);
+        input.extend(&dry_run);
+        input.extend(&dry_run);
+        input.extend(frame_packet(&frame, 500, 3));
+        input.extend(frame_packet(&frame, 500, 3));
+        input.extend(&dry_run);
+
+        let (rw, flash) = run(&input);
+        assert_eq!(responses(&rw.output)[1..], [
+            (Opcode::DECODE_DRY_RUN.0, with_status(decode::DRY_RUN_FRESH)),
+            (Opcode::DECODE_DRY_RUN.0, with_status(decode::DRY_RUN_FRESH)),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::ERROR.0, b"Frame is from the past".to_vec()),
+            (Opcode::DECODE_DRY_RUN.0, with_status(decode::DRY_RUN_REPLAYED)),
+        ]);
+        assert_eq!(flash.channel_timestamp(3), Some(500));
+
+        // Everything else a real decode checks, a dry run checks too
+        let outside = packet(Opcode::DECODE_DRY_RUN, &frame_packet(&frame, 2000, 3)[HEADER_SIZE..]);
+        let (rw, flash) = run(&[subscription_packet(3, 100, 1000), outside].concat());
+        assert_eq!(responses(&rw.output)[1..], [(Opcode::ERROR.0, b"Subscription expired".to_vec())]);
+        assert_eq!(flash.channel_timestamp(3), None);
+    }
+
+    #[test]
+    #[cfg(feature = "test-reset")]
+    fn test_reset() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+
+        let mut input = subscription_packet(3, 100, 1000);
+        input.extend(frame_packet(&frame, 500, 3));
+        input.extend(frame_packet(&frame, 400, 3));
+        input.extend(header(Opcode::RESET, 0));
+
+        let (mut rw, mut flash) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::ERROR.0, b"Frame is from the past".to_vec()),
+        ]);
+        // The reset is acked when it arrives and again once it's done
+        let output = packets(&rw.output);
+        assert_eq!(output[output.len() - 2..], [(Opcode::ACK.0, Vec::new()), (Opcode::ACK.0, Vec::new())]);
+        assert_eq!(flash.most_recent_timestamp(), None);
+
+        // The subscription is gone and the old frame decodes once it's subscribed to again
+        let mut input = list_packet();
+        input.extend(subscription_packet(3, 100, 1000));
+        input.extend(frame_packet(&frame, 400, 3));
+
+        rw = MemRW::new(&input);
+        process(&mut rw, &mut flash);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::LIST.0, list_body(&[])),
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_rekey() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+        let new_key = Key([0x5a; KEY_SIZE_BYTES]);
+
+        let mut input = packet(Opcode::REKEY, &wrap_device_key(&DECODER_KEY, &new_key));
+        input.extend(subscription_packet(3, 100, 1000));
+        input.extend(packet(Opcode::SUBSCRIBE, &subscription_body_for_key(&new_key, 3, 100, 1000)));
+        input.extend(frame_packet(&frame, 500, 3));
+
+        let (rw, mut flash) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::REKEY.0, Vec::new()),
+            (Opcode::ERROR.0, b"Authentication Failed".to_vec()),
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+        ]);
+
+        // The new key is still used after a reboot, and the next rekey has to be wrapped with it
+        let mut input = packet(Opcode::REKEY, &wrap_device_key(&DECODER_KEY, &DECODER_KEY));
+        input.extend(packet(Opcode::REKEY, &wrap_device_key(&new_key, &DECODER_KEY)));
+        input.extend(subscription_packet(4, 100, 1000));
+
+        let mut rw = MemRW::new(&input);
+        flash.init(&mut rw).unwrap();
+        process(&mut rw, &mut flash);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::ERROR.0, b"Rekey authentication failed".to_vec()),
+            (Opcode::REKEY.0, Vec::new()),
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+        ]);
+    }
+
+    #[test]
+    fn test_rekey_rejected() {
+        let new_key = Key([0x5a; KEY_SIZE_BYTES]);
+        let mut tampered = wrap_device_key(&DECODER_KEY, &new_key);
+        tampered[0] ^= 1;
+
+        let mut input = packet(Opcode::REKEY, &tampered);
+        input.extend(packet(Opcode::REKEY, &wrap_device_key(&new_key, &new_key)));
+        input.extend(packet(Opcode::REKEY, &wrap_device_key(&DECODER_KEY, &new_key)[1..]));
+        input.extend(header(Opcode::REKEY, 0));
+        input.extend(subscription_packet(3, 100, 1000));
+
+        // The decoder keeps its key, so subscriptions for it are still accepted
+        let (rw, flash) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::ERROR.0, b"Rekey authentication failed".to_vec()),
+            (Opcode::ERROR.0, b"Rekey authentication failed".to_vec()),
+            (Opcode::ERROR.0, b"Unexpected rekey packet size".to_vec()),
+            (Opcode::ERROR.0, b"Missing packet body".to_vec()),
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+        ]);
+        assert_eq!(flash.device_key().0, DECODER_KEY.0);
+    }
+
+    #[test]
+    fn test_dma_error() {
+        let length = ENCODED_FRAME_PACKET_SIZE;
+        let mut rw = MemRW::new(&alloc::vec![0; length]);
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut rw).unwrap();
+        let verifying_key = VerifyingKey::<Sha256>::from_pkcs1_der(VERIFYING_KEY).unwrap();
+
+        let dma = MemDma::failing_at(4, uart::dma::DmaError::BusError);
+        let header = MessageHeader { magic: uart::packet::MAGIC, opcode: Opcode::DECODE, length: length as u16, sequence: None };
+        handle_packet(&header, &mut rw, dma, &mut flash, &verifying_key, &mut AckMode::Ack, &mut AlignedVec::new());
+
+        assert_eq!(responses(&rw.output), [(Opcode::ERROR.0, b"UART error: Dma(BusError)".to_vec())]);
+    }
+
+    #[test]
+    fn test_overrun_resyncs() {
+        let mut rw = MemRW::new(&frame_packet(&Frame([7; libectf::frame::FRAME_SIZE]), 500, 3));
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut rw).unwrap();
+        let verifying_key = VerifyingKey::<Sha256>::from_pkcs1_der(VERIFYING_KEY).unwrap();
+
+        // The frame loses a byte partway through, so it's abandoned and the rest of it is thrown
+        // away. The host sends the next packet once it has the error.
+        let header = rw.read_header().unwrap();
+        handle_packet(&header, &mut rw, MemDma::overrun_at(100), &mut flash, &verifying_key, &mut AckMode::Ack, &mut AlignedVec::new());
+        assert!(rw.input.is_empty());
+        rw.input.extend(list_packet());
+        process(&mut rw, &mut flash);
+
+        assert_eq!(responses(&rw.output), [
+            (Opcode::ERROR.0, b"UART error: Overrun".to_vec()),
+            (Opcode::LIST.0, list_body(&[])),
+        ]);
+    }
+
+    #[test]
+    fn test_sequenced_packet_resent() {
+        let subscription = &subscription_packet(3, 100, 1000)[HEADER_SIZE..];
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+
+        // Each damaged packet is NACKed without being acted on, then handled when it's resent
+        let mut input = sequenced_packet(Opcode::SUBSCRIBE, 1, subscription, |body| body[40] ^= 1);
+        input.extend(sequenced_packet(Opcode::SUBSCRIBE, 1, subscription, |_| ()));
+        input.extend(sequenced_packet(Opcode::DECODE, 2, &frame_packet(&frame, 500, 3)[HEADER_SIZE..], |body| body[100] = 0));
+        input.extend(sequenced_packet(Opcode::DECODE, 2, &frame_packet(&frame, 500, 3)[HEADER_SIZE..], |_| ()));
+        input.extend(sequenced_packet(Opcode::DELETE, 3, &3u32.to_le_bytes(), |body| body[0] = 4));
+        input.extend(list_packet());
+
+        let (rw, flash) = run(&input);
+        assert_eq!(responses(&rw.output), [
+            (Opcode::NACK.0, alloc::vec![1]),
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::NACK.0, alloc::vec![2]),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+            (Opcode::NACK.0, alloc::vec![3]),
+            (Opcode::LIST.0, list_body(&[(3, 100, 1000)])),
+        ]);
+        assert_eq!(flash.subscriptions().len(), 1);
+
+        // Errors that aren't from damage in transit are reported like they are for other packets
+        let (rw, _) = run(&sequenced_packet(Opcode::DELETE, 4, &[3, 0], |_| ()));
+        assert_eq!(responses(&rw.output), [(Opcode::ERROR.0, b"Unexpected delete packet size".to_vec())]);
+    }
+
+    #[test]
+    fn test_sequenced_packet_overrun() {
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+        let body = &frame_packet(&frame, 500, 3)[HEADER_SIZE..];
+        let sequenced = sequenced_packet(Opcode::DECODE, 200, body, |_| ());
+        let mut rw = MemRW::new(b"");
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut rw).unwrap();
+        let verifying_key = VerifyingKey::<Sha256>::from_pkcs1_der(VERIFYING_KEY).unwrap();
+        let mut packet = AlignedVec::new();
+
+        // Bytes are lost partway through the frame, so it's NACKed and the resent copy decodes
+        for (input, dma) in [(subscription_packet(3, 100, 1000), MemDma::default()), (sequenced.clone(), MemDma::overrun_at(100)), (sequenced, MemDma::default())] {
+            rw.input.extend(input);
+            let header = rw.read_header().unwrap();
+            handle_packet(&header, &mut rw, dma, &mut flash, &verifying_key, &mut AckMode::Ack, &mut packet);
+        }
+
+        assert_eq!(responses(&rw.output), [
+            (Opcode::SUBSCRIBE.0, Vec::new()),
+            (Opcode::NACK.0, alloc::vec![200]),
+            (Opcode::DECODE.0, frame.0.to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut input = header(Opcode(b'Z'), 0);
+        input.extend(header(Opcode::LIST, 0));
+        input.extend(header(Opcode::ACK, 0));
+
+        // Rejected with the header, so it isn't ACKed
+        let (rw, _) = run(&input);
+        assert_eq!(packets(&rw.output), [
+            (Opcode::ERROR.0, b"Unknown opcode: 0x5a".to_vec()),
+            (Opcode::ACK.0, Vec::new()),
+            (Opcode::LIST.0, 0u32.to_le_bytes().to_vec()),
+        ]);
+
+        // A LIST in its body is skipped along with the rest of it, not handled
+        let body = header(Opcode::LIST, 0);
+        let mut input = header(Opcode(b'Z'), body.len() as u16);
+        input.extend(body);
+        let (rw, _) = run(&input);
+        assert_eq!(packets(&rw.output), [(Opcode::ERROR.0, b"Unknown opcode: 0x5a".to_vec())]);
+    }
+
+    #[test]
+    fn test_unexpected_packet() {
+        let mut input = header(Opcode::ERROR, 0);
+        input.extend(header(Opcode::DEBUG, 0));
+
+        let (rw, _) = run(&input);
+        assert_eq!(packets(&rw.output), [
+            (Opcode::ACK.0, Vec::new()),
+            (Opcode::ERROR.0, b"Unexpected packet".to_vec()),
+            (Opcode::ERROR.0, b"Unexpected packet".to_vec()),
+        ]);
+    }
+
+    /// Packets built from mutated valid packets, with headers that are good enough to be read.
+    /// `seed` picks the packets and mutations, so a failure can be reproduced.
+    fn mutated_packets(seed: u64) -> Vec<u8> {
+        // xorshift64, to keep the mutations the same from run to run
+        let mut state = seed.max(1);
+        let mut next = move |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+
+        let frame = Frame([7; libectf::frame::FRAME_SIZE]);
+        let bodies = [
+            (Opcode::SUBSCRIBE, subscription_packet(3, 100, 1000)[HEADER_SIZE..].to_vec()),
+            (Opcode::DECODE, frame_packet(&frame, 500, 3)[HEADER_SIZE..].to_vec()),
+            (Opcode::DECODE, payload_packet(b"hi", 501, 3)[HEADER_SIZE..].to_vec()),
+            (Opcode::DELETE, 3u32.to_le_bytes().to_vec()),
+            (Opcode::DETAIL, 3u32.to_le_bytes().to_vec()),
+            (Opcode::VERSION, alloc::vec![1]),
+            (Opcode::LIST, Vec::new()),
+            (Opcode::ACK, Vec::new()),
+            (Opcode(next(256) as u8), alloc::vec![0; next(16)]),
+        ];
+
+        let mut input = Vec::new();
+        for _ in 0..8 {
+            let (Opcode(opcode), body) = &bodies[next(bodies.len())];
+            let mut body = body.clone();
+            for _ in 0..next(8) {
+                match next(4) {
+                    0 if !body.is_empty() => {
+                        let i = next(body.len());
+                        body[i] ^= 1 << next(8);
+                    }
+                    1 if !body.is_empty() => {
+                        let i = next(body.len());
+                        body[i] = next(256) as u8;
+                    }
+                    2 => body.truncate(next(body.len() + 1)),
+                    _ => body.extend((0..next(64)).map(|_| next(256) as u8)),
+                }
+            }
+
+            // Sometimes claim a different length than the body has
+            let length = if next(4) == 0 { next(u16::MAX as usize + 1) } else { body.len() };
+            input.extend(header(Opcode(*opcode), length as u16));
+            input.extend(body);
+        }
+        input
+    }
+
+    #[test]
+    fn test_mutated_packets() {
+        let verifying_key = VerifyingKey::<Sha256>::from_pkcs1_der(VERIFYING_KEY).unwrap();
+        let mut packet = AlignedVec::with_capacity(MAX_PACKET_SIZE);
+        let mut flash = Flash::new(MemFlc::new());
+        flash.init(&mut MemRW::new(b"")).unwrap();
+
+        // None of these should panic. Errors are reported and the decoder moves on like the main
+        // loop does.
+        for seed in 0..100 {
+            let mut rw = MemRW::new(&mutated_packets(seed));
+            let mut ack_mode = AckMode::Ack;
+            while !rw.input.is_empty() {
+                let header = match rw.read_header() {
+                    Ok(header) => header,
+                    Err(e) => {
+                        rw.write_error(DecoderError::from(e));
+                        continue;
+                    }
+                };
+                if header.opcode.should_ack() {
+                    rw.write_ack();
+                }
+                handle_packet(&header, &mut rw, MemDma::default(), &mut flash, &verifying_key, &mut ack_mode, &mut packet);
+            }
+
+            // Whatever got through, the decoder still answers
+            let mut rw = MemRW::new(&list_packet());
+            handle_packet(&rw.read_header().unwrap(), &mut rw, MemDma::default(), &mut flash, &verifying_key, &mut AckMode::Ack, &mut packet);
+            assert_eq!(packets(&rw.output).last().unwrap().0, Opcode::LIST.0, "seed {}", seed);
+        }
     }
 }
@@ -0,0 +1,4 @@
+//! Where things are in memory, from the regions in `memory.x`. Generated by `build.rs`, which checks
+//! that the storage region is whole pages of flash and doesn't overlap the program.
+
+include!(concat!(env!("OUT_DIR"), "/memory.rs"));
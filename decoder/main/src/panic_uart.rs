@@ -0,0 +1,94 @@
+use core::{fmt, panic::Location};
+
+use crate::{pac, uart::raw_rw::RawRW};
+
+/// What the host sees when the decoder panics, in an ERROR packet.
+pub struct PanicReport<'a, M: fmt::Display> {
+    pub location: Option<&'a Location<'a>>,
+    pub message: M,
+}
+
+impl<M: fmt::Display> fmt::Display for PanicReport<'_, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Panicked")?;
+        if let Some(location) = self.location {
+            write!(f, " at {}:{}:{}", location.file(), location.line(), location.column())?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Sends the panic to the host as an ERROR packet, then halts like `panic_halt` does. The UART is
+/// written through its registers, since whatever was using it is stuck in the middle of the panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    // Safety: nothing else runs after this, so we're the only one using the UART
+    let mut uart = PanicUart(unsafe { &*pac::Uart0::ptr() });
+    uart.write_error(PanicReport { location: info.location(), message: info.message() });
+    let _ = embedded_io::Write::flush(&mut uart);
+
+    loop {
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// UART0 driven straight through its registers. It only writes, reads never have anything.
+struct PanicUart(&'static pac::uart0::RegisterBlock);
+
+impl embedded_io::ErrorType for PanicUart {
+    type Error = embedded_io::ErrorKind;
+}
+
+impl embedded_io::Read for PanicUart {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+impl embedded_io::ReadReady for PanicUart {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+}
+
+impl embedded_io::Write for PanicUart {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        for &b in buf {
+            while self.0.status().read().tx_full().bit_is_set() {}
+            self.0.fifo().write(|w| unsafe { w.data().bits(b) });
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        while self.0.status().read().tx_busy().bit_is_set() {}
+        Ok(())
+    }
+}
+
+impl RawRW for PanicUart { }
+
+#[cfg(test)]
+mod tests {
+    use crate::uart::{mem_rw::MemRW, packet::{header_bytes, Opcode}};
+
+    use super::*;
+
+    #[test]
+    fn test_panic_report() {
+        let location = Location::caller();
+        let mut rw = MemRW::new(b"");
+        rw.write_error(PanicReport { location: Some(location), message: format_args!("index {} out of range", 3) });
+
+        let expected = format!("Panicked at {}:{}:{}: index 3 out of range", location.file(), location.line(), location.column());
+        assert_eq!(rw.output, [&header_bytes(Opcode::ERROR, expected.len() as u16)[..], expected.as_bytes()].concat());
+
+        // Panics without a location still say what happened
+        let mut rw = MemRW::new(b"");
+        rw.write_error(PanicReport { location: None, message: "oops" });
+        assert_eq!(rw.output, [&header_bytes(Opcode::ERROR, 14)[..], b"Panicked: oops"].concat());
+    }
+}
@@ -0,0 +1,24 @@
+use libectf::rekey::{unwrap_device_key, REKEY_SIZE};
+use rkyv::util::AlignedVec;
+
+use crate::{error::DecoderError, flash::{Flash, FlashStorage}, uart::{body_rw::BodyRW, dma::RxDma, packet::Opcode, raw_rw::RawRW}};
+
+/// Replace the device key with the one in a REKEY packet. The body is the new key wrapped by
+/// [`libectf::rekey::wrap_device_key`] under the current device key, so only the host can rekey a
+/// decoder. Subscriptions sent afterwards have to be encrypted with the new key, and the ones
+/// already stored keep working.
+pub fn rekey_device<RW: RawRW, D: RxDma<RW>, F: FlashStorage>(packet: &AlignedVec, body_rw: &mut BodyRW<RW, D>, flash: &mut Flash<F>) -> Result<(), DecoderError> {
+    if packet.len() != REKEY_SIZE {
+        return Err(DecoderError::BadRekeySize);
+    }
+
+    body_rw.wait_for_dma(packet.len())?;
+
+    let key = unwrap_device_key(flash.device_key(), packet).ok_or(DecoderError::RekeyAuthFailed)?;
+    flash.set_device_key(&key)?;
+
+    // Respond
+    body_rw.rw.write_header(Opcode::REKEY, 0);
+
+    Ok(())
+}
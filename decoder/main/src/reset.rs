@@ -0,0 +1,12 @@
+use crate::{error::DecoderError, flash::{Flash, FlashStorage}, uart::raw_rw::RawRW};
+
+/// Put the decoder back in the state it was flashed in: no subscriptions, and no frames decoded.
+/// Only built with the `test-reset` feature, since it lets old frames be decoded again.
+pub fn reset_decoder<F: FlashStorage>(rw: &mut impl RawRW, flash: &mut Flash<F>) -> Result<(), DecoderError> {
+    flash.reset(rw)?;
+
+    // Respond
+    rw.write_ack();
+
+    Ok(())
+}
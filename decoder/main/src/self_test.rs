@@ -0,0 +1,96 @@
+use libectf::{key::Key, subscription::ArchivedEncodedSubscriptionKey};
+use rsa::{pkcs1::DecodeRsaPublicKey, pkcs1v15::VerifyingKey};
+use sha2::Sha256;
+
+use crate::{keys::{CHANNEL_0_BITRANGES, CHANNEL_0_KEYS, DECODER_KEY, VERIFYING_KEY}, uart::raw_rw::RawRW};
+
+/// Ways the keys baked in by `build.rs` can be broken.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// There are no channel 0 keys, so no broadcast frame can be decoded.
+    NoChannel0Keys,
+    /// There isn't a bitrange for every channel 0 key.
+    Channel0Bitranges,
+    /// The decoder key is all zeros, so it wasn't derived from the secrets.
+    ZeroDecoderKey,
+    /// The verifying key doesn't parse, so no frame can be verified.
+    BadVerifyingKey,
+}
+
+impl SelfTestError {
+    /// Description of the failure sent to the host in a DEBUG packet.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::NoChannel0Keys => "Self test failed: no channel 0 keys",
+            Self::Channel0Bitranges => "Self test failed: channel 0 keys and bitranges don't match",
+            Self::ZeroDecoderKey => "Self test failed: decoder key is zero",
+            Self::BadVerifyingKey => "Self test failed: verifying key doesn't parse",
+        }
+    }
+}
+
+/// Sanity checks a set of baked in keys. Frames can't be encoded on the device, so this can't
+/// check the keys actually work together, only that none of them are obviously broken.
+pub fn check_keys(channel_0_keys: &[ArchivedEncodedSubscriptionKey], channel_0_bitranges: &[(u64, u8)], decoder_key: &Key, verifying_key: &[u8]) -> Result<(), SelfTestError> {
+    if channel_0_keys.is_empty() {
+        return Err(SelfTestError::NoChannel0Keys);
+    }
+
+    if channel_0_keys.len() != channel_0_bitranges.len() {
+        return Err(SelfTestError::Channel0Bitranges);
+    }
+
+    if decoder_key.0.iter().all(|&b| b == 0) {
+        return Err(SelfTestError::ZeroDecoderKey);
+    }
+
+    if VerifyingKey::<Sha256>::from_pkcs1_der(verifying_key).is_err() {
+        return Err(SelfTestError::BadVerifyingKey);
+    }
+
+    Ok(())
+}
+
+/// Checks the keys this decoder was built with, writing a DEBUG packet if they're broken. Only
+/// built with the `self-test` feature, for bring-up.
+pub fn run_self_test(rw: &mut impl RawRW) {
+    if let Err(e) = check_keys(CHANNEL_0_KEYS, CHANNEL_0_BITRANGES, &DECODER_KEY, VERIFYING_KEY) {
+        rw.write_debug(e.message());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libectf::key::ArchivedKey;
+
+    use crate::uart::{mem_rw::MemRW, packet::HEADER_SIZE};
+
+    use super::*;
+
+    #[test]
+    fn test_built_keys_pass() {
+        assert_eq!(check_keys(CHANNEL_0_KEYS, CHANNEL_0_BITRANGES, &DECODER_KEY, VERIFYING_KEY), Ok(()));
+
+        let mut rw = MemRW::new(b"");
+        run_self_test(&mut rw);
+        assert!(rw.output.is_empty());
+    }
+
+    #[test]
+    fn test_broken_keys_fail() {
+        let key = [ArchivedEncodedSubscriptionKey { key: ArchivedKey([1; 16]) }];
+
+        assert_eq!(check_keys(&[], &[], &DECODER_KEY, VERIFYING_KEY), Err(SelfTestError::NoChannel0Keys));
+        assert_eq!(check_keys(&key, &[], &DECODER_KEY, VERIFYING_KEY), Err(SelfTestError::Channel0Bitranges));
+        assert_eq!(check_keys(&key, &[(0, 0)], &Key([0; 16]), VERIFYING_KEY), Err(SelfTestError::ZeroDecoderKey));
+        assert_eq!(check_keys(&key, &[(0, 0)], &DECODER_KEY, &VERIFYING_KEY[1..]), Err(SelfTestError::BadVerifyingKey));
+        assert_eq!(check_keys(&key, &[(0, 0)], &DECODER_KEY, VERIFYING_KEY), Ok(()));
+    }
+
+    #[test]
+    fn test_failure_message() {
+        let mut rw = MemRW::new(b"");
+        rw.write_debug(SelfTestError::ZeroDecoderKey.message());
+        assert_eq!(rw.output[HEADER_SIZE..], *b"Self test failed: decoder key is zero");
+    }
+}
@@ -1,60 +1,175 @@
 use core::mem;
 
-use alloc::{format, string::{String, ToString}};
-use libectf::subscription::{ArchivedEncodedSubscriptionKey, ArchivedSubscriptionDataHeader};
+use alloc::vec::Vec;
+use libectf::{key::Key, subscription::{ArchivedEncodedSubscriptionKey, ArchivedSubscriptionDataHeader, MAX_SUBSCRIPTION_KEYS}};
 use rkyv::util::AlignedVec;
-use hmac::{Hmac, Mac};
-use sha2::Sha256;
 
-use crate::{flash::Flash, keys::DECODER_KEY, uart::{body_rw::BodyRW, packet::Opcode, raw_rw::RawRW}};
+use crate::{error::DecoderError, flash::{Flash, FlashStorage}, keys::CHANNELS, uart::{body_rw::BodyRW, dma::{RxDma, TxDma}, packet::{Opcode, MAX_BODY_SIZE}, raw_rw::{RawRW, UartError}}};
 
-pub fn add_subscription<RW: RawRW>(mut packet: AlignedVec, body_rw: &mut BodyRW<RW>, flash: &mut Flash) -> Result<(), String> {
+/// Largest subscription packet we will accept. Anything bigger is rejected before we allocate
+/// space for it.
+pub const MAX_SUBSCRIPTION_SIZE: usize = mem::size_of::<ArchivedSubscriptionDataHeader>() + MAX_SUBSCRIPTION_KEYS * mem::size_of::<ArchivedEncodedSubscriptionKey>();
+
+/// Most subscriptions we will store at once. There's at most one per channel, so this is also the
+/// number of channels a decoder can be subscribed to.
+pub const MAX_SUBSCRIPTIONS: usize = 32;
+
+/// Subscriptions of [`MAX_SUBSCRIPTION_SIZE`] that flash has to have room for, since the eCTF rules
+/// require decoders to take subscriptions to at least 8 channels.
+pub const MIN_SUBSCRIPTIONS: usize = 8;
+
+const _: () = assert!(MIN_SUBSCRIPTIONS <= MAX_SUBSCRIPTIONS);
+
+/// Largest SUBSCRIBE_BATCH body we will accept. It's read in whole before any of it is stored, so
+/// this bounds how much of the heap a batch can take.
+pub const MAX_BATCH_SIZE: usize = 4 * MAX_SUBSCRIPTION_SIZE;
+
+const _: () = assert!(MAX_BATCH_SIZE <= MAX_BODY_SIZE, "A full subscription batch doesn't fit in a packet");
+
+/// Status in a SUBSCRIBE_BATCH response for a subscription that was stored.
+pub const BATCH_STORED: u8 = 0;
+/// Status in a SUBSCRIBE_BATCH response for a subscription that was rejected.
+pub const BATCH_REJECTED: u8 = 1;
+
+pub fn add_subscription<RW: RawRW, D: RxDma<RW>, F: FlashStorage>(packet: &mut AlignedVec, body_rw: &mut BodyRW<RW, D>, flash: &mut Flash<F>) -> Result<(), DecoderError> {
+    // Check the subscription as it arrives
+    verify_subscription(packet, flash.device_key(), |length| body_rw.wait_for_dma(length))?;
+    store_subscription(packet, body_rw.rw, flash)?;
+
+    // Respond
+    body_rw.rw.write_header(Opcode::SUBSCRIBE, 0);
+
+    Ok(())
+}
+
+/// Add every subscription in a SUBSCRIBE_BATCH packet. The body is a u8 count, then for each
+/// subscription a u16 length followed by the subscription as it would be sent in a SUBSCRIBE. Each
+/// is checked and stored on its own, so one that's rejected doesn't stop the others. The response
+/// is the count, then each subscription's channel and a [`BATCH_STORED`] or [`BATCH_REJECTED`]
+/// status, in the order they were sent.
+pub fn add_subscriptions<RW: RawRW, D: RxDma<RW> + TxDma<RW>, F: FlashStorage>(packet: &AlignedVec, body_rw: &mut BodyRW<RW, D>, flash: &mut Flash<F>) -> Result<(), DecoderError> {
+    body_rw.wait_for_dma(packet.len())?;
+
+    // Find every subscription before storing any, so a batch that's cut short doesn't get
+    // partially stored
+    let spans = batch_spans(packet).ok_or(DecoderError::BadBatchSize)?;
+
+    // Subscriptions are copied out so they're aligned and can be decrypted in place
+    let mut subscription = AlignedVec::with_capacity(MAX_SUBSCRIPTION_SIZE);
+    let mut output: Vec<u8> = Vec::with_capacity(1 + spans.len() * 5);
+    output.push(spans.len() as u8);
+
+    for span in spans {
+        subscription.clear();
+        subscription.extend_from_slice(&packet[span]);
+
+        let channel = Flash::access_subscription_mut(&mut subscription)?.header.channel();
+        let result = verify_subscription(&mut subscription, flash.device_key(), |_| Ok(()))
+            .and_then(|()| store_subscription(&subscription, body_rw.rw, flash));
+
+        output.extend_from_slice(&channel.to_le_bytes());
+        output.push(if result.is_ok() { BATCH_STORED } else { BATCH_REJECTED });
+    }
+
+    body_rw.rw.write_header(Opcode::SUBSCRIBE_BATCH, output.len() as u16);
+    body_rw.dma_write_bytes(&output)?;
+    Ok(body_rw.finish_write()?)
+}
+
+/// Where each subscription in a SUBSCRIBE_BATCH body is, or `None` unless the body is exactly the
+/// count and that many subscriptions, each a header followed by a whole number of keys and no
+/// bigger than [`MAX_SUBSCRIPTION_SIZE`].
+fn batch_spans(body: &[u8]) -> Option<Vec<core::ops::Range<usize>>> {
     let header_size = mem::size_of::<ArchivedSubscriptionDataHeader>();
     let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
 
-    // "cast" the AlignedVec to subscription data
-    let subscription = Flash::access_subscription_mut(&mut packet);
+    let (&count, mut rest) = body.split_first()?;
+    let mut spans = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let length = u16::from_le_bytes(rest.get(..2)?.try_into().unwrap()) as usize;
+        let keys_size = length.checked_sub(header_size)?;
+        if length > MAX_SUBSCRIPTION_SIZE || !keys_size.is_multiple_of(key_size) || rest.len() < 2 + length {
+            return None;
+        }
+
+        let start = body.len() - rest.len() + 2;
+        spans.push(start..start + length);
+        rest = &rest[2 + length..];
+    }
+
+    rest.is_empty().then_some(spans)
+}
+
+/// Check that the subscription in `packet` is one we can take, decrypting its keys in place with
+/// `device_key`. `wait_for` is called with how much of the packet has to have arrived before
+/// reading on.
+fn verify_subscription(packet: &mut AlignedVec, device_key: &Key, mut wait_for: impl FnMut(usize) -> Result<(), UartError>) -> Result<(), DecoderError> {
+    let header_size = mem::size_of::<ArchivedSubscriptionDataHeader>();
+    let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
+
+    // "cast" the AlignedVec to subscription data. This fails unless the body is a header followed
+    // by a whole number of keys.
+    let subscription = Flash::access_subscription_mut(packet)?;
 
-    // Initialize hasher to verify MAC
-    let mut hasher = <Hmac::<Sha256> as Mac>::new_from_slice(&DECODER_KEY.0).unwrap();
-     
     // Wait until header has been transferred by DMA
-    while body_rw.dma_poll_for_ack() < header_size { }
+    wait_for(header_size)?;
 
-    // Disallow channel 0 subscriptions
-    if subscription.header.channel == 0 {
-        return Err("Cannot subscribe to channel 0".to_string())
-    } 
+    // Disallow channel 0 subscriptions, unless they're refreshed channel 0 keys we can take
+    let channel = subscription.header.channel();
+    if channel == 0 && !cfg!(feature = "channel-0-refresh") {
+        return Err(DecoderError::Channel0)
+    }
 
-    // Hash the header components
-    hasher.update(&subscription.header.start_timestamp.to_native().to_le_bytes());
-    hasher.update(&subscription.header.end_timestamp.to_native().to_le_bytes());
-    hasher.update(&subscription.header.channel.to_native().to_le_bytes());
+    // Only allow channels that were in the secrets. Channel 0 never is, but is always valid.
+    if channel != 0 && CHANNELS.is_some_and(|c| !c.contains(&channel)) {
+        return Err(DecoderError::UnknownChannel);
+    }
+
+    // Start the MAC with the header components
+    let mut hasher = subscription.header.mac(device_key);
 
-    // All subscription keys are encrypted with the decoder key
-    let mut cipher = DECODER_KEY.cipher();
+    // All subscription keys are encrypted with the device key
+    let mut cipher = device_key.cipher();
 
     for (i, k) in subscription.keys.iter_mut().enumerate() {
         // Wait till this key has been transferred by DMA
-        while body_rw.dma_poll_for_ack() < header_size + (i + 1) * key_size { }
+        wait_for(header_size + (i + 1) * key_size)?;
 
         // Decrypt the key in-place and then update the hasher with the decrypted key
         cipher.decrypt(&mut k.key.0);
-        hasher.update(&k.key.0);
+        hasher.update_key(&k.key.0);
     }
 
     // Ensure that the MAC matches what we got from the hasher
-    if <[u8; 32]>::from(hasher.finalize().into_bytes()) != subscription.header.mac_hash {
-        return Err("Authentication Failed".to_string());
-    } 
+    if !hasher.verify(&subscription.header.mac_hash) {
+        return Err(DecoderError::AuthFailed);
+    }
+
+    Ok(())
+}
+
+/// Store a subscription that [`verify_subscription`] accepted.
+fn store_subscription<F: FlashStorage>(packet: &AlignedVec, rw: &mut impl RawRW, flash: &mut Flash<F>) -> Result<(), DecoderError> {
+    // Safety: `verify_subscription` checked the packet is a header followed by whole keys
+    let header = unsafe { rkyv::access_unchecked::<ArchivedSubscriptionDataHeader>(&packet[..mem::size_of::<ArchivedSubscriptionDataHeader>()]) };
 
-    // Write subscription to the flash
-    if let Err(e) = flash.add_subscription(packet, body_rw.rw) {
-        return Err(format!("Flash error: {:?}", e));
+    // A subscription for a new channel needs a free slot, but one for a channel we already have
+    // replaces the old one. Expired subscriptions are pruned to make room.
+    let channel = header.channel();
+    let needs_slot = |flash: &Flash<F>| flash.subscriptions().len() >= MAX_SUBSCRIPTIONS && flash.subscription_for_channel(channel).is_none();
+    if needs_slot(flash) {
+        flash.prune_expired()?;
+        if needs_slot(flash) {
+            return Err(DecoderError::SubscriptionLimit);
+        }
     }
 
-    // Respond
-    body_rw.rw.write_header(Opcode::SUBSCRIBE, 0);
+    // Write subscription to the flash, unless it's the same as one we already have. The keys are
+    // derived from the channel and time range, so it would be a byte for byte copy.
+    if !flash.has_subscription(header) {
+        flash.add_subscription(packet, rw)?;
+    }
 
     Ok(())
 }
@@ -1,119 +1,403 @@
-use max7800x_hal::pac::dma;
 use rkyv::util::AlignedVec;
 
-use super::raw_rw::RawRW;
+use super::{dma::{RxDma, TxDma}, raw_rw::{RawRW, UartError}};
 
 const ALIGNMENT: usize = 16;
 
 /// A wrapper around a raw reader/writer that handles reading/writing the body of 
-/// packets. This is needed because the encoder expects ACKs every 256 bytes.
-pub struct BodyRW<'l, RW: RawRW> {
+/// packets. This is needed because the encoder expects ACKs every 256 bytes, unless the host
+/// negotiated [`AckMode::Bulk`](super::packet::AckMode::Bulk) and `should_ack` is false.
+pub struct BodyRW<'l, RW: RawRW, D: RxDma<RW>> {
     pub rw: &'l mut RW,
     should_ack: bool,
-    dma: &'l dma::Ch,
+    dma: D,
     cursor: usize,
     last_ack_write: usize,
     dma_read_length: usize,
 }
 
-impl<'l, RW: RawRW> BodyRW<'l, RW> {
+impl<'l, RW: RawRW, D: RxDma<RW>> BodyRW<'l, RW, D> {
     const CHUNK_SIZE: usize = 256;
     
     /// Creates a new BodyRW object.
-    pub fn new(should_ack: bool, rw: &'l mut RW, dma: &'l dma::Ch) -> Self {
+    pub fn new(should_ack: bool, rw: &'l mut RW, dma: D) -> Self {
         Self { rw, should_ack, dma, cursor: 0, dma_read_length: 0, last_ack_write: 0 }
     }
     
-    pub fn start_dma_read(&mut self, length: usize) -> AlignedVec<ALIGNMENT> {
-        let mut res = AlignedVec::with_capacity(length);
-        unsafe { res.set_len(length); }
+    /// Starts reading a packet body of `length` bytes with DMA into `buffer`, replacing what was in
+    /// it. The buffer is meant to be reused for every packet, so it only allocates if `length` is
+    /// more than its capacity. It has to outlive the transfer, and can't be touched again until
+    /// [`BodyRW::wait_for_dma`] has returned for the whole body or [`BodyRW::stop_dma`] has been
+    /// called.
+    pub fn start_dma_read(&mut self, buffer: &mut AlignedVec<ALIGNMENT>, length: usize) {
+        buffer.clear();
+        buffer.reserve(length);
+        unsafe { buffer.set_len(length); }
 
         self.dma_read_length = length;
         self.last_ack_write = 0;
 
-        // 1. Ensure DMA_CHn_CTRL.en, DMA_CHn_CTRL.rlden = 0, and DMA_CHn_STATUS.ctz_if = 0.
-        self.dma.ctrl().modify(|_, w| w.en().clear_bit().rlden().clear_bit());
-        self.dma.status().write(|w| w.ctz_if().clear_bit_by_one());
-
-        // 2. If using memory for the destination of the DMA transfer, configure DMA_CHn_DST to the starting 
-        // address of the destination in memory.
-        self.dma.dst().write(|w| unsafe { w.bits(res.as_ptr() as u32) } );
+        unsafe { self.dma.start(buffer.as_mut_ptr(), length); }
+    }
+    
+    /// Reads and throws away a packet body without DMA, sending ACKs as if it were being read.
+    pub fn discard(&mut self, length: usize) -> Result<(), UartError> {
+        for i in 1..=length {
+            self.rw.read_u8()?;
+            if self.should_ack && (i.is_multiple_of(Self::CHUNK_SIZE) || i == length) {
+                self.rw.write_ack();
+            }
+        }
 
-        // 4. Write the number of bytes to transfer to the DMA_CHn_CNT register.
-        self.dma.cnt().write(|w| unsafe { w.bits(length as u32) });
+        Ok(())
+    }
 
-        // 5. Configure the following DMA_CHn_CTRL register fields in one or more instructions. Do not set DMA_CHn_CTRL.en
-        // to 1 or DMA_CHn_CTRL.rlden to 1 in this step:
-        self.dma.ctrl().modify(|_, w| unsafe { w
-            // 5a. Configure DMA_CHn_CTRL.request to select the transfer operation associated with the DMA channel.
-            .request().uart0rx()
-            
-            // 5b. Configure DMA_CHn_CTRL.burst_size for the desired burst size.
-            .burst_size().bits(0)  // 1 byte (TODO can we increase this?)
+    /// Checks how far the DMA read has got, sending an ACK whenever it reaches the end of a chunk.
+    /// Fails with [`UartError::Overrun`] if bytes were dropped, since the body can't be trusted and
+    /// won't all arrive.
+    pub fn dma_poll_for_ack(&mut self) -> Result<usize, UartError> {
+        let bytes_read = self.dma.transferred(self.rw)?;
+        if self.dma.take_overrun(self.rw) {
+            return Err(UartError::Overrun);
+        }
+        if (bytes_read.is_multiple_of(Self::CHUNK_SIZE) || bytes_read == self.dma_read_length) && bytes_read != self.last_ack_write {
+            self.last_ack_write = bytes_read;
+            if self.should_ack {
+                self.rw.write_ack();
+            }
+        }
+        Ok(bytes_read)
+    }
 
-            // 5c. Configure DMA_CHn_CTRL.pri to set the channel priority relative to other DMA channels.
-            .pri().set(0)
+    /// Waits until at least `length` bytes have been transferred by DMA. Gives up and stops the
+    /// transfer if the DMA fails or no new bytes arrive within the reader's timeout.
+    pub fn wait_for_dma(&mut self, length: usize) -> Result<(), UartError> {
+        let result = self.poll_dma_until(length);
+        if result.is_err() {
+            self.dma.stop();
+        }
+        result
+    }
 
-            // 5d. Configure DMA_CHn_CTRL.dstwd to set the width of the data written in each transaction.
-            .dstwd().word()
+    fn poll_dma_until(&mut self, length: usize) -> Result<(), UartError> {
+        let mut bytes_read = self.dma_poll_for_ack()?;
+        let mut polls = 0;
 
-            // 5e. If desired, set DMA_CHn_CTRL.dstinc to 1 to enable automatic incrementing of the DMA_CHn_DST register
-            // upon every AHB transaction.
-            .dstinc().set_bit()
+        while bytes_read < length {
+            let new_bytes_read = self.dma_poll_for_ack()?;
+            if new_bytes_read != bytes_read {
+                bytes_read = new_bytes_read;
+                polls = 0;
+            } else {
+                polls += 1;
+                if polls >= self.rw.read_timeout() {
+                    return Err(UartError::Timeout);
+                }
+            }
+        }
 
-            // 5f. Configure DMA_CHn_CTRL.srcwd to set the width of the data read in each transaction.
-            .srcwd().word()
+        Ok(())
+    }
 
-            // 5h. If desired, set DMA_CHn_CTRL.dis_ie = 1 to generate an interrupt when the channel becomes disabled. The
-            // channel becomes disabled when the DMA transfer completes, or a bus error occurs.
-            // TODO
+    /// Stops the DMA transfer, if it hasn't finished already.
+    pub fn stop_dma(&mut self) {
+        self.dma.stop();
+    }
 
-            // 5i. If desired, set DMA_CHn_CTRL.ctz_ie 1 to generate an interrupt when the DMA_CHn_CNT register is
-            // decremented to zero.
-            // TODO
+    /// Stops the DMA transfer after an [`UartError::Overrun`] and throws away the rest of the body.
+    /// There's no telling how many bytes were lost, so it's read until the host stops sending,
+    /// which it does until we answer the packet.
+    pub fn discard_after_overrun(&mut self) {
+        self.dma.stop();
+        for _ in 0..self.dma_read_length {
+            if self.rw.read_u8().is_err() {
+                break;
+            }
+        }
+    }
 
-            // 5j. If using the reload feature, configure the reload registers to set the destination, source, and count for the
-            // following DMA transaction.
-            // 1) Load the DMA_CHn_SRCRLD register with the source address reload value.
-            // 2) Load the DMA_CHn_DSTRLD register with the destination address reload value.
-            // 3) Load the DMA_CHn_CNTRLD register with the count reload value.
-            // Not using reload for now
+    /// Writes `bytes` one at a time. Responses go through [`BodyRW::dma_write_bytes`] instead.
+    #[allow(dead_code)]
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), UartError> {
+        for byte in bytes {
+            self.rw.write_u8(*byte);
+            self.cursor += 1;
+            if self.should_ack && self.cursor.is_multiple_of(Self::CHUNK_SIZE) {
+                self.rw.wait_for_ack()?;
+            }
+        }
 
-            // 5k. If desired, enable the channel timeout feature described in Channel Timeout Detect. Clear
-            // DMA_CHn_CTRL.to_clkdiv to 0 to disable the channel timeout feature.
-            .to_clkdiv().set(0)
-        });
+        Ok(())
+    }
 
-        // 7. Set DMA_CHn_CTRL.en = 1 to start the DMA transfer immediately.
-        self.dma.ctrl().modify(|_, w| w.en().set_bit());
+    /// Writes `bytes` with DMA, one transfer per chunk, waiting for the host's ACK at every chunk
+    /// boundary like [`BodyRW::write_bytes`] does.
+    pub fn dma_write_bytes(&mut self, bytes: &[u8]) -> Result<(), UartError> where D: TxDma<RW> {
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            // Only write up to the next chunk boundary
+            let length = bytes.len().min(Self::CHUNK_SIZE - self.cursor % Self::CHUNK_SIZE);
+            let (chunk, rest) = bytes.split_at(length);
+            self.start_dma_write(chunk)?;
+            self.cursor += length;
+            if self.should_ack && self.cursor.is_multiple_of(Self::CHUNK_SIZE) {
+                self.rw.wait_for_ack()?;
+            }
+            bytes = rest;
+        }
 
-        res
+        Ok(())
     }
-    
-    pub fn dma_poll_for_ack(&mut self) -> usize {
-        let bytes_read = self.dma_read_length - self.dma.cnt().read().bits() as usize;
-        if (bytes_read % Self::CHUNK_SIZE == 0 || bytes_read == self.dma_read_length) && bytes_read != self.last_ack_write {
-            self.last_ack_write = bytes_read;
-            self.rw.write_ack();
-        }
-        bytes_read
+
+    /// Writes `chunk` with DMA and waits until all of it has been handed to the UART. Stops the
+    /// transfer if it makes no progress within the reader's timeout.
+    fn start_dma_write(&mut self, chunk: &[u8]) -> Result<(), UartError> where D: TxDma<RW> {
+        // The chunk outlives the transfer since we don't return until it's finished or stopped
+        unsafe { self.dma.start_write(chunk.as_ptr(), chunk.len()); }
+
+        let result = self.poll_dma_write(chunk.len());
+        self.dma.stop_write();
+        result
     }
 
-    pub fn write_bytes(&mut self, bytes: &[u8]) {
-        for byte in bytes {
-            self.rw.write_u8(*byte);
-            self.cursor += 1;
-            if self.cursor % Self::CHUNK_SIZE == 0 {
-                self.rw.wait_for_ack();
+    fn poll_dma_write(&mut self, length: usize) -> Result<(), UartError> where D: TxDma<RW> {
+        let mut written = self.dma.written(self.rw)?;
+        let mut polls = 0;
+        while written < length {
+            let new_written = self.dma.written(self.rw)?;
+            if new_written != written {
+                written = new_written;
+                polls = 0;
+            } else {
+                polls += 1;
+                if polls >= self.rw.read_timeout() {
+                    return Err(UartError::Timeout);
+                }
             }
         }
+
+        Ok(())
     }
 
-    /// Recieve the final ACK once an entire packet has been transmitted.
-    pub fn finish_write(&mut self) {
-        if self.should_ack && self.cursor % Self::CHUNK_SIZE != 0 {
-            self.rw.wait_for_ack();
+    /// Recieve the final ACK once an entire packet has been transmitted. A body that ends on a chunk
+    /// boundary already had its last ACK waited for with the rest of the chunk, so this only waits
+    /// for the ACK of a partial last chunk. That's the same number of ACKs the reader sends, see
+    /// [`BodyRW::dma_poll_for_ack`].
+    pub fn finish_write(&mut self) -> Result<(), UartError> {
+        if self.should_ack && !self.cursor.is_multiple_of(Self::CHUNK_SIZE) {
+            self.rw.wait_for_ack()?;
         }
+
+        Ok(())
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::uart::{dma::DmaError, mem_rw::{MemDma, MemRW}, packet::{header_bytes, Opcode, HEADER_SIZE}};
+
+    use super::*;
+
+    const ACK: [u8; HEADER_SIZE] = header_bytes(Opcode::ACK, 0);
+
+    #[test]
+    fn test_write_multiple_chunks() {
+        for length in [1, 255, 256, 257, 512, 600] {
+            let body: Vec<u8> = (0..length).map(|i| i as u8).collect();
+
+            let mut rw = MemRW::with_host_acks(b"");
+            rw.write_header(Opcode::LIST, length as u16);
+            let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+            body_rw.write_bytes(&body).unwrap();
+            body_rw.finish_write().unwrap();
+
+            assert_eq!(&rw.output[HEADER_SIZE..], body.as_slice());
+            // We wait for an ACK for every chunk, so only the ACK the host sends for the header is
+            // left over
+            assert_eq!(rw.input.iter().copied().collect::<Vec<u8>>(), ACK);
+        }
+    }
+
+    #[test]
+    fn test_write_without_acks() {
+        let mut rw = MemRW::new(b"");
+        rw.timeout = 1;
+        let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+
+        // Nothing to wait for until a whole chunk has been written
+        body_rw.write_bytes(&[0; 255]).unwrap();
+        assert_eq!(body_rw.write_bytes(&[0]), Err(UartError::Timeout));
+    }
+
+    #[test]
+    fn test_dma_write_matches_write_bytes() {
+        for length in [1, 255, 256, 257, 512, 600] {
+            let body: Vec<u8> = (0..length).map(|i| i as u8).collect();
+
+            let mut rw = MemRW::with_host_acks(b"");
+            rw.write_header(Opcode::LIST, length as u16);
+            let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+            body_rw.write_bytes(&body).unwrap();
+            body_rw.finish_write().unwrap();
+            let expected = rw.output;
+
+            let mut rw = MemRW::with_host_acks(b"");
+            rw.write_header(Opcode::LIST, length as u16);
+            let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+            body_rw.dma_write_bytes(&body).unwrap();
+            body_rw.finish_write().unwrap();
+
+            // Same bytes on the wire as writing byte by byte. On hardware the CPU only sets up one
+            // transfer per 256 byte chunk instead of polling the TX FIFO for every byte.
+            assert_eq!(rw.output.len(), HEADER_SIZE + length);
+            assert_eq!(rw.output, expected);
+            assert_eq!(rw.input.iter().copied().collect::<Vec<u8>>(), ACK);
+        }
+    }
+
+    #[test]
+    fn test_dma_write_without_acks() {
+        let mut rw = MemRW::new(b"");
+        rw.timeout = 1;
+        let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+
+        body_rw.dma_write_bytes(&[0; 255]).unwrap();
+        assert_eq!(body_rw.dma_write_bytes(&[0]), Err(UartError::Timeout));
+    }
+
+    #[test]
+    fn test_dma_read_acks() {
+        let body: Vec<u8> = (0..600).map(|i| i as u8).collect();
+
+        let mut rw = MemRW::new(&body);
+        let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+        let mut packet = AlignedVec::new();
+        body_rw.start_dma_read(&mut packet, body.len());
+        body_rw.wait_for_dma(body.len()).unwrap();
+
+        assert_eq!(packet.as_slice(), body.as_slice());
+        // One ACK per chunk, including the partial one at the end
+        assert_eq!(rw.output, ACK.repeat(3));
+    }
+
+    #[test]
+    fn test_dma_read_reuses_buffer() {
+        let mut packet = AlignedVec::with_capacity(600);
+        let (ptr, capacity) = (packet.as_ptr(), packet.capacity());
+
+        // Reading bodies of any size up to the capacity never reallocates the buffer
+        for length in [600, 1, 256, 0, 599] {
+            let body: Vec<u8> = (0..length).map(|i| (i * 7) as u8).collect();
+            let mut rw = MemRW::new(&body);
+            let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+            body_rw.start_dma_read(&mut packet, length);
+            body_rw.wait_for_dma(length).unwrap();
+
+            assert_eq!(packet.as_slice(), body.as_slice());
+            assert_eq!((packet.as_ptr(), packet.capacity()), (ptr, capacity));
+        }
+    }
+
+    #[test]
+    fn test_dma_read_timeout() {
+        let mut rw = MemRW::new(&[0; 300]);
+        let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+        let mut packet = AlignedVec::new();
+        body_rw.start_dma_read(&mut packet, 400);
+
+        assert_eq!(body_rw.wait_for_dma(400), Err(UartError::Timeout));
+        assert_eq!(rw.output, ACK);
+    }
+
+    #[test]
+    fn test_dma_read_bus_error() {
+        let mut rw = MemRW::new(&[0; 600]);
+        let dma = MemDma::failing_at(300, DmaError::BusError);
+        let mut body_rw = BodyRW::new(true, &mut rw, dma);
+        let mut packet = AlignedVec::new();
+        body_rw.start_dma_read(&mut packet, 600);
+
+        assert_eq!(body_rw.wait_for_dma(256), Ok(()));
+        assert_eq!(body_rw.wait_for_dma(600), Err(UartError::Dma(DmaError::BusError)));
+    }
+
+    #[test]
+    fn test_dma_read_overrun() {
+        let mut rw = MemRW::new(&[0; 600]);
+        let mut body_rw = BodyRW::new(true, &mut rw, MemDma::overrun_at(300));
+        let mut packet = AlignedVec::new();
+        body_rw.start_dma_read(&mut packet, 600);
+
+        assert_eq!(body_rw.wait_for_dma(256), Ok(()));
+        assert_eq!(body_rw.wait_for_dma(600), Err(UartError::Overrun));
+        body_rw.discard_after_overrun();
+        // The chunk with the dropped byte is never acked, and the rest of the body is thrown away
+        assert_eq!(rw.output, ACK);
+        assert!(rw.input.is_empty());
+    }
+
+    #[test]
+    fn test_discard_acks() {
+        let mut rw = MemRW::new(&[0; 600]);
+        BodyRW::new(true, &mut rw, MemDma::default()).discard(600).unwrap();
+        assert_eq!(rw.output, ACK.repeat(3));
+
+        let mut rw = MemRW::new(&[0; 600]);
+        BodyRW::new(false, &mut rw, MemDma::default()).discard(600).unwrap();
+        assert!(rw.output.is_empty());
+    }
+
+    #[test]
+    fn test_no_chunk_acks() {
+        // Reading doesn't send any ACKs
+        let body: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let mut rw = MemRW::new(&body);
+        let mut body_rw = BodyRW::new(false, &mut rw, MemDma::default());
+        let mut packet = AlignedVec::new();
+        body_rw.start_dma_read(&mut packet, body.len());
+        body_rw.wait_for_dma(body.len()).unwrap();
+        assert_eq!(packet.as_slice(), body.as_slice());
+        assert!(rw.output.is_empty());
+
+        // Writing doesn't wait for any
+        let mut rw = MemRW::new(b"");
+        rw.timeout = 1;
+        let mut body_rw = BodyRW::new(false, &mut rw, MemDma::default());
+        body_rw.dma_write_bytes(&body).unwrap();
+        body_rw.write_bytes(&body).unwrap();
+        body_rw.finish_write().unwrap();
+        assert_eq!(rw.output, body.repeat(2));
+    }
+
+    #[test]
+    fn test_ack_counts_on_chunk_boundaries() {
+        for length in [255usize, 256, 257, 511, 512, 513] {
+            let body: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let chunks = length.div_ceil(256);
+
+            // The reader sends an ACK for every chunk, including a partial last one
+            let mut rw = MemRW::new(&body);
+            let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+            let mut packet = AlignedVec::new();
+            body_rw.start_dma_read(&mut packet, length);
+            body_rw.wait_for_dma(length).unwrap();
+            assert_eq!(rw.output, ACK.repeat(chunks), "reading {} bytes", length);
+
+            // The writer waits for exactly that many, whether the body ends on a boundary or not
+            let mut rw = MemRW::new(&ACK.repeat(chunks));
+            rw.timeout = 1;
+            let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+            body_rw.dma_write_bytes(&body).unwrap();
+            body_rw.finish_write().unwrap();
+            assert!(rw.input.is_empty(), "writing {} bytes", length);
+
+            // and fails without the last one
+            let mut rw = MemRW::new(&ACK.repeat(chunks - 1));
+            rw.timeout = 1;
+            let mut body_rw = BodyRW::new(true, &mut rw, MemDma::default());
+            let result = body_rw.dma_write_bytes(&body).and_then(|_| body_rw.finish_write());
+            assert_eq!(result, Err(UartError::Timeout), "writing {} bytes", length);
+        }
+    }
+}
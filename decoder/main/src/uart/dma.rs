@@ -0,0 +1,276 @@
+use max7800x_hal::pac::{dma, uart0};
+
+/// Number of bytes moved per DMA burst unless configured otherwise
+pub const DEFAULT_BURST_SIZE: u8 = 4;
+
+/// Depth of the UART RX FIFO, which bounds how large a burst can be
+const RX_FIFO_DEPTH: u8 = 8;
+
+/// Largest burst of at most `max_burst` bytes that evenly divides `length`. Every burst has to be
+/// full, since the UART only requests a burst once that many bytes are waiting in its FIFO.
+/// `max_burst` is a power of two no larger than the FIFO, so the bursts also line up with the 256
+/// byte chunks the host waits for ACKs between.
+pub fn burst_size_for(length: usize, max_burst: u8) -> u8 {
+    let mut burst = max_burst;
+    while !length.is_multiple_of(burst as usize) {
+        burst /= 2;
+    }
+    burst
+}
+
+/// Number of bytes a transfer of `length` bytes has moved, given the count register. The count is
+/// in bytes regardless of the burst size.
+fn bytes_transferred(length: usize, cnt: u32) -> usize {
+    length - cnt as usize
+}
+
+/// Errors reported by a DMA channel.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DmaError {
+    /// The channel got an AHB abort and disabled itself.
+    BusError,
+    /// No bytes were requested by the UART for the channel's timeout period.
+    Timeout,
+}
+
+/// Checks a channel's status flags for errors.
+fn check_status(ch: &dma::Ch) -> Result<(), DmaError> {
+    let status = ch.status().read();
+    if status.bus_err().bit_is_set() {
+        Err(DmaError::BusError)
+    } else if status.to_if().bit_is_set() {
+        Err(DmaError::Timeout)
+    } else {
+        Ok(())
+    }
+}
+
+/// A DMA transfer of a packet body from the UART into memory.
+pub trait RxDma<RW> {
+    /// Starts reading `length` bytes into `dst`.
+    ///
+    /// # Safety
+    ///
+    /// `dst` has to stay valid for `length` bytes until the transfer finishes or [`RxDma::stop`] is
+    /// called.
+    unsafe fn start(&mut self, dst: *mut u8, length: usize);
+
+    /// Number of bytes that have been transferred so far, or why the transfer failed.
+    fn transferred(&mut self, rw: &mut RW) -> Result<usize, DmaError>;
+
+    /// Whether the UART's RX FIFO has overflowed since the transfer started, meaning bytes of the
+    /// packet were dropped. The overrun is cleared and the FIFO flushed before returning `true`.
+    fn take_overrun(&mut self, rw: &mut RW) -> bool;
+
+    /// Stops the transfer. Nothing more is written to the destination once this returns.
+    fn stop(&mut self);
+}
+
+/// A DMA transfer of a response body from memory out to the UART.
+pub trait TxDma<RW> {
+    /// Starts writing `length` bytes from `src`.
+    ///
+    /// # Safety
+    ///
+    /// `src` has to stay valid for `length` bytes until the transfer finishes or
+    /// [`TxDma::stop_write`] is called.
+    unsafe fn start_write(&mut self, src: *const u8, length: usize);
+
+    /// Number of bytes that have been handed to the UART so far, or why the transfer failed.
+    fn written(&mut self, rw: &mut RW) -> Result<usize, DmaError>;
+
+    /// Stops the transfer. Nothing more is read from the source once this returns.
+    fn stop_write(&mut self);
+}
+
+/// DMA channels reading from and writing to UART0.
+#[derive(Clone, Copy)]
+pub struct UartDma<'l> {
+    ch: &'l dma::Ch,
+    tx_ch: &'l dma::Ch,
+    uart: &'l uart0::RegisterBlock,
+    burst_size: u8,
+    length: usize,
+    tx_length: usize,
+}
+
+impl<'l> UartDma<'l> {
+    /// `burst_size` is the largest burst used when reading, and has to be a power of two no larger
+    /// than the UART RX FIFO.
+    pub fn new(ch: &'l dma::Ch, tx_ch: &'l dma::Ch, uart: &'l uart0::RegisterBlock, burst_size: u8) -> Self {
+        assert!(burst_size.is_power_of_two() && burst_size <= RX_FIFO_DEPTH, "Invalid DMA burst size");
+        Self { ch, tx_ch, uart, burst_size, length: 0, tx_length: 0 }
+    }
+}
+
+impl<RW> RxDma<RW> for UartDma<'_> {
+    unsafe fn start(&mut self, dst: *mut u8, length: usize) {
+        self.length = length;
+        let burst = burst_size_for(length, self.burst_size);
+
+        // Enable DMA from the UART side, asking for a burst once there's a whole one in the FIFO
+        self.uart.dma().modify(|_, w| unsafe { w
+            .rx_en().set_bit()
+            .rx_thd_val().bits(burst)
+        });
+
+        // 1. Ensure DMA_CHn_CTRL.en, DMA_CHn_CTRL.rlden = 0, and DMA_CHn_STATUS.ctz_if = 0.
+        self.ch.ctrl().modify(|_, w| w.en().clear_bit().rlden().clear_bit());
+        self.ch.status().write(|w| w.ctz_if().clear_bit_by_one().bus_err().clear_bit_by_one().to_if().clear_bit_by_one());
+
+        // The header was read intact, so an overrun before now didn't lose any of this packet
+        self.uart.int_fl().write(|w| w.rx_ov().set_bit());
+
+        // 2. If using memory for the destination of the DMA transfer, configure DMA_CHn_DST to the starting
+        // address of the destination in memory.
+        self.ch.dst().write(|w| unsafe { w.bits(dst as u32) } );
+
+        // 4. Write the number of bytes to transfer to the DMA_CHn_CNT register.
+        self.ch.cnt().write(|w| unsafe { w.bits(length as u32) });
+
+        // 5. Configure the following DMA_CHn_CTRL register fields in one or more instructions. Do not set DMA_CHn_CTRL.en
+        // to 1 or DMA_CHn_CTRL.rlden to 1 in this step:
+        self.ch.ctrl().modify(|_, w| unsafe { w
+            // 5a. Configure DMA_CHn_CTRL.request to select the transfer operation associated with the DMA channel.
+            .request().uart0rx()
+
+            // 5b. Configure DMA_CHn_CTRL.burst_size for the desired burst size.
+            .burst_size().bits(burst - 1)
+
+            // 5c. Configure DMA_CHn_CTRL.pri to set the channel priority relative to other DMA channels.
+            .pri().set(0)
+
+            // 5d. Configure DMA_CHn_CTRL.dstwd to set the width of the data written in each transaction.
+            .dstwd().word()
+
+            // 5e. If desired, set DMA_CHn_CTRL.dstinc to 1 to enable automatic incrementing of the DMA_CHn_DST register
+            // upon every AHB transaction.
+            .dstinc().set_bit()
+
+            // 5f. Configure DMA_CHn_CTRL.srcwd to set the width of the data read in each transaction.
+            // The width shrinks on its own for bursts smaller than a word.
+            .srcwd().word()
+
+            // 5h. If desired, set DMA_CHn_CTRL.dis_ie = 1 to generate an interrupt when the channel becomes disabled. The
+            // channel becomes disabled when the DMA transfer completes, or a bus error occurs.
+            // We poll DMA_CHn_STATUS.bus_err instead of using interrupts
+
+            // 5i. If desired, set DMA_CHn_CTRL.ctz_ie 1 to generate an interrupt when the DMA_CHn_CNT register is
+            // decremented to zero.
+            // We poll DMA_CHn_CNT instead of using interrupts
+
+            // 5j. If using the reload feature, configure the reload registers to set the destination, source, and count for the
+            // following DMA transaction.
+            // 1) Load the DMA_CHn_SRCRLD register with the source address reload value.
+            // 2) Load the DMA_CHn_DSTRLD register with the destination address reload value.
+            // 3) Load the DMA_CHn_CNTRLD register with the count reload value.
+            // Not using reload for now
+
+            // 5k. If desired, enable the channel timeout feature described in Channel Timeout Detect. Clear
+            // DMA_CHn_CTRL.to_clkdiv to 0 to disable the channel timeout feature.
+            // PCLK / 2^24 is about 3 ticks a second, so this times out after a few seconds without data
+            .to_clkdiv().div16m()
+            .to_per().to8()
+        });
+
+        // 7. Set DMA_CHn_CTRL.en = 1 to start the DMA transfer immediately.
+        self.ch.ctrl().modify(|_, w| w.en().set_bit());
+    }
+
+    fn transferred(&mut self, _rw: &mut RW) -> Result<usize, DmaError> {
+        check_status(self.ch)?;
+        Ok(bytes_transferred(self.length, self.ch.cnt().read().bits()))
+    }
+
+    fn take_overrun(&mut self, _rw: &mut RW) -> bool {
+        if self.uart.int_fl().read().rx_ov().bit_is_clear() {
+            return false;
+        }
+
+        // The interrupt flags are cleared by writing a 1
+        self.uart.int_fl().write(|w| w.rx_ov().set_bit());
+        self.uart.ctrl().modify(|_, w| w.rx_flush().set_bit());
+        true
+    }
+
+    fn stop(&mut self) {
+        self.ch.ctrl().modify(|_, w| w.en().clear_bit());
+        self.uart.dma().modify(|_, w| w.rx_en().clear_bit());
+    }
+}
+
+impl<RW> TxDma<RW> for UartDma<'_> {
+    unsafe fn start_write(&mut self, src: *const u8, length: usize) {
+        self.tx_length = length;
+
+        // Request more bytes while there's room in the TX FIFO
+        self.uart.dma().modify(|_, w| unsafe { w
+            .tx_en().set_bit()
+            .tx_thd_val().bits(2)
+        });
+
+        // Same steps as for reading, but with memory as the source and the UART as the destination
+        self.tx_ch.ctrl().modify(|_, w| w.en().clear_bit().rlden().clear_bit());
+        self.tx_ch.status().write(|w| w.ctz_if().clear_bit_by_one().bus_err().clear_bit_by_one().to_if().clear_bit_by_one());
+
+        self.tx_ch.src().write(|w| unsafe { w.bits(src as u32) });
+        self.tx_ch.cnt().write(|w| unsafe { w.bits(length as u32) });
+
+        self.tx_ch.ctrl().modify(|_, w| unsafe { w
+            .request().uart0tx()
+            .burst_size().bits(0)
+            .pri().set(0)
+            .srcwd().byte()
+            .srcinc().set_bit()
+            .dstwd().byte()
+            .dstinc().clear_bit()
+            .to_clkdiv().set(0)
+        });
+
+        self.tx_ch.ctrl().modify(|_, w| w.en().set_bit());
+    }
+
+    fn written(&mut self, _rw: &mut RW) -> Result<usize, DmaError> {
+        check_status(self.tx_ch)?;
+        Ok(bytes_transferred(self.tx_length, self.tx_ch.cnt().read().bits()))
+    }
+
+    fn stop_write(&mut self) {
+        self.tx_ch.ctrl().modify(|_, w| w.en().clear_bit());
+        self.uart.dma().modify(|_, w| w.tx_en().clear_bit());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn test_burst_size_for() {
+        assert_eq!(burst_size_for(600, 4), 4);
+        assert_eq!(burst_size_for(602, 4), 2);
+        assert_eq!(burst_size_for(601, 8), 1);
+        assert_eq!(burst_size_for(0, 8), 8);
+    }
+
+    #[test]
+    fn test_bursts_hit_every_ack_boundary() {
+        for max_burst in [1, 2, 4, 8] {
+            for length in [1, 255, 256, 257, 600, 1024, 1030] {
+                let burst = burst_size_for(length, max_burst) as usize;
+
+                // Count register after every burst of the transfer
+                let seen: Vec<usize> = (0..=length / burst)
+                    .map(|i| bytes_transferred(length, (length - i * burst) as u32))
+                    .collect();
+
+                assert_eq!(seen.last(), Some(&length));
+                for boundary in (256..length).step_by(256) {
+                    assert!(seen.contains(&boundary), "burst {burst} skips {boundary} of {length}");
+                }
+            }
+        }
+    }
+}
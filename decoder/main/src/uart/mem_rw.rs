@@ -0,0 +1,232 @@
+use alloc::{collections::VecDeque, vec::Vec};
+
+use super::{dma::{DmaError, RxDma, TxDma}, packet::{header_bytes, AckMode, Opcode, HEADER_SIZE, MAGIC}, raw_rw::RawRW};
+
+/// Reader/writer backed by in-memory buffers
+pub struct MemRW {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+    pub timeout: u32,
+    /// ACK the packets we write like the host tools do: once after the header and once after every
+    /// block of the body, unless the packet is an ACK or DEBUG
+    pub host_acks: bool,
+    /// Which body ACKs the host sends. Tests keep it in step with the mode the decoder negotiated.
+    pub ack_mode: AckMode,
+    /// How much of the output has been looked at for sending host ACKs
+    acked_to: usize,
+    /// Opcode and remaining body length of the packet being written
+    body: Option<(Opcode, usize)>,
+    /// Number of bytes of the current block of the body that have been written
+    block_len: usize,
+}
+
+impl MemRW {
+    /// Block size the host tools send ACKs for
+    const HOST_BLOCK_LEN: usize = 256;
+
+    pub fn new(input: &[u8]) -> Self {
+        Self {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+            timeout: 10,
+            host_acks: false,
+            ack_mode: AckMode::Ack,
+            acked_to: 0,
+            body: None,
+            block_len: 0,
+        }
+    }
+
+    /// Creates a reader/writer that ACKs what's written to it like the host does.
+    pub fn with_host_acks(input: &[u8]) -> Self {
+        Self { host_acks: true, ..Self::new(input) }
+    }
+
+    /// Queue an ACK from the host.
+    fn push_ack(&mut self) {
+        self.input.extend(header_bytes(Opcode::ACK, 0));
+    }
+
+    /// Go through newly written output and queue up the ACKs the host would send for it.
+    fn send_host_acks(&mut self) {
+        while self.acked_to < self.output.len() {
+            match self.body.take() {
+                None => {
+                    // Wait for a whole header
+                    let Some(header) = self.output.get(self.acked_to..self.acked_to + HEADER_SIZE) else { return };
+                    if header[0] != MAGIC {
+                        self.acked_to += 1;
+                        continue;
+                    }
+
+                    let opcode = Opcode(header[1]);
+                    let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+                    self.acked_to += HEADER_SIZE;
+                    if opcode.should_ack() {
+                        self.push_ack();
+                    }
+                    if length > 0 {
+                        self.body = Some((opcode, length));
+                        self.block_len = 0;
+                    }
+                }
+                Some((opcode, remaining)) => {
+                    let n = (self.output.len() - self.acked_to).min(remaining).min(Self::HOST_BLOCK_LEN - self.block_len);
+                    self.acked_to += n;
+                    self.block_len += n;
+
+                    let remaining = remaining - n;
+                    if self.block_len == Self::HOST_BLOCK_LEN || remaining == 0 {
+                        if opcode.should_ack_chunks(self.ack_mode) {
+                            self.push_ack();
+                        }
+                        self.block_len = 0;
+                    }
+                    if remaining > 0 {
+                        self.body = Some((opcode, remaining));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl embedded_io::ErrorType for MemRW {
+    type Error = embedded_io::ErrorKind;
+}
+
+impl embedded_io::Read for MemRW {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = buf.len().min(self.input.len());
+        for (b, i) in buf.iter_mut().zip(self.input.drain(..n)) {
+            *b = i;
+        }
+        Ok(n)
+    }
+}
+
+impl embedded_io::ReadReady for MemRW {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.input.is_empty())
+    }
+}
+
+impl embedded_io::Write for MemRW {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.output.extend_from_slice(buf);
+        if self.host_acks {
+            self.send_host_acks();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl RawRW for MemRW {
+    fn read_timeout(&self) -> u32 {
+        self.timeout
+    }
+}
+
+/// DMA that moves a byte from a [`MemRW`]'s input into the destination, or from the source into
+/// its output, every time it is polled
+#[derive(Clone, Copy)]
+pub struct MemDma {
+    dst: *mut u8,
+    length: usize,
+    transferred: usize,
+    src: *const u8,
+    tx_length: usize,
+    written: usize,
+    /// Error to report once this many bytes have been transferred
+    error: Option<(usize, DmaError)>,
+    /// Drop a byte of input once this many bytes have been transferred, like an RX FIFO overrun
+    overrun_at: Option<usize>,
+    overrun: bool,
+}
+
+impl Default for MemDma {
+    fn default() -> Self {
+        Self {
+            dst: core::ptr::null_mut(),
+            length: 0,
+            transferred: 0,
+            src: core::ptr::null(),
+            tx_length: 0,
+            written: 0,
+            error: None,
+            overrun_at: None,
+            overrun: false,
+        }
+    }
+}
+
+impl MemDma {
+    /// A DMA that reports `error` once `at` bytes have been read.
+    pub fn failing_at(at: usize, error: DmaError) -> Self {
+        Self { error: Some((at, error)), ..Self::default() }
+    }
+
+    /// A DMA that loses a byte to an RX FIFO overrun once `at` bytes have been read.
+    pub fn overrun_at(at: usize) -> Self {
+        Self { overrun_at: Some(at), ..Self::default() }
+    }
+}
+
+impl RxDma<MemRW> for MemDma {
+    unsafe fn start(&mut self, dst: *mut u8, length: usize) {
+        *self = Self { dst, length, transferred: 0, ..*self };
+    }
+
+    fn transferred(&mut self, rw: &mut MemRW) -> Result<usize, DmaError> {
+        if let Some((at, e)) = self.error {
+            if self.transferred >= at {
+                return Err(e);
+            }
+        }
+
+        if self.overrun_at == Some(self.transferred) {
+            rw.input.pop_front();
+            self.overrun_at = None;
+            self.overrun = true;
+        }
+
+        if self.transferred < self.length {
+            if let Some(b) = rw.input.pop_front() {
+                unsafe { self.dst.add(self.transferred).write(b); }
+                self.transferred += 1;
+            }
+        }
+        Ok(self.transferred)
+    }
+
+    fn take_overrun(&mut self, _rw: &mut MemRW) -> bool {
+        core::mem::take(&mut self.overrun)
+    }
+
+    fn stop(&mut self) {
+        self.length = self.transferred;
+    }
+}
+
+impl TxDma<MemRW> for MemDma {
+    unsafe fn start_write(&mut self, src: *const u8, length: usize) {
+        *self = Self { src, tx_length: length, written: 0, ..*self };
+    }
+
+    fn written(&mut self, rw: &mut MemRW) -> Result<usize, DmaError> {
+        if self.written < self.tx_length {
+            let b = unsafe { self.src.add(self.written).read() };
+            rw.write_u8(b);
+            self.written += 1;
+        }
+        Ok(self.written)
+    }
+
+    fn stop_write(&mut self) {
+        self.tx_length = self.written;
+    }
+}
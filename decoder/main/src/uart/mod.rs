@@ -1,4 +1,6 @@
 pub mod raw_rw;
 pub mod packet;
 pub mod body_rw;
+pub mod dma;
 
+#[cfg(test)] pub mod mem_rw;
@@ -3,6 +3,20 @@ use rkyv::{Archive, Deserialize, Serialize};
 /// The magic character indicating the start of a packet
 pub const MAGIC: u8 = b'%';
 
+/// The magic character starting a packet with a [`Sequence`] in its header
+pub const SEQUENCED_MAGIC: u8 = b'&';
+
+/// Size of a packet header on the wire: the magic, opcode, and length. This is the eCTF host
+/// protocol's header, so it can't grow; hosts that want headers checked send sequenced ones.
+pub const HEADER_SIZE: usize = 4;
+
+/// Size of a sequenced packet header on the wire: the magic, opcode, sequence number, length, CRC16
+/// of the body, and a CRC16 of everything between the magic and it
+pub const SEQUENCED_HEADER_SIZE: usize = 9;
+
+/// Longest body a packet can have, since the header's length is a u16
+pub const MAX_BODY_SIZE: usize = u16::MAX as usize;
+
 /// The opcode indicating the type of packet being sent
 #[derive(Serialize, Deserialize, Archive, PartialEq, Eq, Debug)]
 pub struct Opcode(pub u8);
@@ -11,14 +25,87 @@ impl Opcode {
     pub const DECODE: Opcode = Opcode(b'D');
     pub const SUBSCRIBE: Opcode = Opcode(b'S');
     pub const LIST: Opcode = Opcode(b'L');
+    pub const DELETE: Opcode = Opcode(b'X');
     pub const ACK: Opcode = Opcode(b'A');
     pub const ERROR: Opcode = Opcode(b'E');
     pub const DEBUG: Opcode = Opcode(b'G');
+    pub const VERSION: Opcode = Opcode(b'V');
+    pub const DETAIL: Opcode = Opcode(b'I');
+    pub const NACK: Opcode = Opcode(b'N');
+    pub const SUBSCRIBE_BATCH: Opcode = Opcode(b'B');
+    pub const REKEY: Opcode = Opcode(b'K');
+    #[cfg(feature = "diagnostics")]
+    pub const DIAGNOSTICS: Opcode = Opcode(b'M');
+    #[cfg(feature = "diagnostics")]
+    pub const DECODE_DRY_RUN: Opcode = Opcode(b'T');
+    #[cfg(feature = "test-reset")]
+    pub const RESET: Opcode = Opcode(b'R');
+
+    /// Every opcode this build knows. The ones behind features are only known when they're built in.
+    pub const KNOWN: &[Opcode] = &[
+        Opcode::DECODE, Opcode::SUBSCRIBE, Opcode::LIST, Opcode::DELETE, Opcode::ACK, Opcode::ERROR,
+        Opcode::DEBUG, Opcode::VERSION, Opcode::DETAIL, Opcode::NACK, Opcode::SUBSCRIBE_BATCH,
+        Opcode::REKEY,
+        #[cfg(feature = "diagnostics")]
+        Opcode::DIAGNOSTICS,
+        #[cfg(feature = "diagnostics")]
+        Opcode::DECODE_DRY_RUN,
+        #[cfg(feature = "test-reset")]
+        Opcode::RESET,
+    ];
+
+    /// Does a packet with this opcode carry an encoded frame? That's a DECODE, or a DECODE_DRY_RUN
+    /// with the `diagnostics` feature.
+    pub fn is_decode(&self) -> bool {
+        #[cfg(feature = "diagnostics")]
+        if *self == Opcode::DECODE_DRY_RUN {
+            return true;
+        }
+        *self == Opcode::DECODE
+    }
 
     /// Do we need to send/recieve ACKs for this opcode?
     pub fn should_ack(&self) -> bool {
         !matches!(self.0, b'G' | b'A')
     }
+
+    /// Do we need to send/recieve an ACK for every chunk of this opcode's body? Headers are still
+    /// ACKed per [`Opcode::should_ack`] in [`AckMode::Bulk`].
+    pub fn should_ack_chunks(&self, mode: AckMode) -> bool {
+        mode == AckMode::Ack && self.should_ack()
+    }
+}
+
+/// A byte that isn't in [`Opcode::KNOWN`].
+#[derive(PartialEq, Eq, Debug)]
+pub struct UnknownOpcode(pub u8);
+
+impl TryFrom<u8> for Opcode {
+    type Error = UnknownOpcode;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        let opcode = Opcode(byte);
+        if Opcode::KNOWN.contains(&opcode) { Ok(opcode) } else { Err(UnknownOpcode(byte)) }
+    }
+}
+
+/// An intact header with an opcode that isn't in [`Opcode::KNOWN`], and the length of the body
+/// after it, which still has to be read past to stay in frame.
+#[derive(PartialEq, Eq, Debug)]
+pub struct UnknownHeader {
+    pub opcode: UnknownOpcode,
+    pub length: u16,
+}
+
+/// How packet bodies are ACKed for the rest of a session. The host picks one in the VERSION
+/// handshake, and it's [`AckMode::Ack`] until it does.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum AckMode {
+    /// An ACK for every 256 byte chunk of a body, which the host tools expect.
+    #[default]
+    Ack,
+    /// No ACKs within bodies, for hosts that can keep up without them.
+    Bulk,
 }
 
 #[derive(Serialize, Deserialize, Archive, Debug)]
@@ -26,5 +113,75 @@ pub struct MessageHeader {
     pub magic: u8,
     pub opcode: Opcode,
     pub length: u16,
+    /// Only sent by hosts that want damaged packets NACKed so they can resend them
+    pub sequence: Option<Sequence>,
 }
 
+/// What a sequenced packet header has on top of a plain one. The body is checked against its CRC
+/// before it's handled, and a [`Opcode::NACK`] with the sequence number is sent back if it doesn't
+/// match or didn't all arrive.
+#[derive(Serialize, Deserialize, Archive, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Sequence {
+    pub number: u8,
+    pub body_crc: u16,
+}
+
+/// Size of the header starting with `magic`, or `None` if it isn't a magic character.
+pub const fn header_size(magic: u8) -> Option<usize> {
+    match magic {
+        MAGIC => Some(HEADER_SIZE),
+        SEQUENCED_MAGIC => Some(SEQUENCED_HEADER_SIZE),
+        _ => None,
+    }
+}
+
+/// Parses a whole header of either kind, or returns `None` if it's a sequenced header whose CRC
+/// doesn't match. Plain headers have no CRC, so they always parse. An intact header whose opcode
+/// isn't one we know is an error instead, so it's reported rather than being mistaken for damage.
+pub fn parse_header(bytes: &[u8]) -> Option<Result<MessageHeader, UnknownHeader>> {
+    let size = header_size(bytes[0])?;
+    if bytes[0] == SEQUENCED_MAGIC && u16::from_le_bytes([bytes[size - 2], bytes[size - 1]]) != crc16(&bytes[1..size - 2]) {
+        return None;
+    }
+
+    let (length, sequence) = if bytes[0] == MAGIC {
+        (u16::from_le_bytes([bytes[2], bytes[3]]), None)
+    } else {
+        (u16::from_le_bytes([bytes[3], bytes[4]]), Some(Sequence { number: bytes[2], body_crc: u16::from_le_bytes([bytes[5], bytes[6]]) }))
+    };
+    Some(match Opcode::try_from(bytes[1]) {
+        Ok(opcode) => Ok(MessageHeader { magic: bytes[0], opcode, length, sequence }),
+        Err(opcode) => Err(UnknownHeader { opcode, length }),
+    })
+}
+
+/// CRC-16/CCITT-FALSE, which sequenced packet headers and bodies are checked with.
+pub const fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    let mut i = 0;
+    while i < bytes.len() {
+        crc ^= (bytes[i] as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            bit += 1;
+        }
+        i += 1;
+    }
+    crc
+}
+
+/// A packet header as it's sent over the wire.
+pub const fn header_bytes(opcode: Opcode, length: u16) -> [u8; HEADER_SIZE] {
+    let [length_lo, length_hi] = length.to_le_bytes();
+    [MAGIC, opcode.0, length_lo, length_hi]
+}
+
+/// A sequenced packet header for `body` as a host sends it.
+#[cfg(test)]
+pub fn sequenced_header_bytes(opcode: Opcode, number: u8, body: &[u8]) -> [u8; SEQUENCED_HEADER_SIZE] {
+    let [length_lo, length_hi] = (body.len() as u16).to_le_bytes();
+    let [body_crc_lo, body_crc_hi] = crc16(body).to_le_bytes();
+    let [crc_lo, crc_hi] = crc16(&[opcode.0, number, length_lo, length_hi, body_crc_lo, body_crc_hi]).to_le_bytes();
+    [SEQUENCED_MAGIC, opcode.0, number, length_lo, length_hi, body_crc_lo, body_crc_hi, crc_lo, crc_hi]
+}
@@ -1,67 +1,164 @@
-use core::ops::Deref;
+use core::{fmt::{self, Write}, ops::Deref};
 
 use max7800x_hal::{pac, uart::BuiltUartPeripheral};
 
-use super::packet::{MessageHeader, Opcode, MAGIC};
+use super::{dma::DmaError, packet::{header_bytes, header_size, parse_header, MessageHeader, Opcode, UnknownOpcode, MAX_BODY_SIZE, SEQUENCED_HEADER_SIZE}};
 
 impl<UART, RX, TX, CTS, RTS> RawRW for BuiltUartPeripheral<UART, RX, TX, CTS, RTS>
 where
     UART: Deref<Target = pac::uart0::RegisterBlock>
 { }
 
-pub trait RawRW: Sized + embedded_io::Read + embedded_io::Write {
-    /// Blocking function that waits for an ACK to be recieved.
-    fn wait_for_ack(&mut self) {
-        let header = self.read_header();
-        
-        if header.opcode != Opcode::ACK {
-            // TODO better error handling
-            panic!("Non-ack recieved");
+/// Number of times to poll for a byte in the middle of a packet before giving up.
+pub const DEFAULT_READ_TIMEOUT: u32 = 5_000_000;
+
+/// Most DEBUG packets to skip while waiting for an ACK before giving up.
+pub const MAX_SKIPPED_DEBUG: usize = 16;
+
+/// Errors that can occur while talking to the host.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UartError {
+    /// The underlying reader failed or ran out of data.
+    Read,
+    /// A packet other than an ACK was recieved while waiting for an ACK.
+    UnexpectedPacket(Opcode),
+    /// The host stopped sending in the middle of a packet.
+    Timeout,
+    /// A DMA transfer to or from the UART failed.
+    Dma(DmaError),
+    /// The UART's RX FIFO overflowed while reading a packet body, so some of its bytes were lost.
+    /// The rest of the body is thrown away and the host has to send the packet again.
+    Overrun,
+    /// A header arrived intact, but with an opcode that isn't in [`Opcode::KNOWN`].
+    UnknownOpcode(u8),
+}
+
+impl From<UnknownOpcode> for UartError {
+    fn from(e: UnknownOpcode) -> Self {
+        Self::UnknownOpcode(e.0)
+    }
+}
+
+impl From<DmaError> for UartError {
+    fn from(e: DmaError) -> Self {
+        Self::Dma(e)
+    }
+}
+
+pub trait RawRW: Sized + embedded_io::Read + embedded_io::Write + embedded_io::ReadReady {
+    /// How many times to poll for a byte in the middle of a packet before giving up.
+    fn read_timeout(&self) -> u32 {
+        DEFAULT_READ_TIMEOUT
+    }
+
+    /// Waits until a byte can be read, giving up after [`RawRW::read_timeout`] polls.
+    fn wait_for_byte(&mut self) -> Result<(), UartError> {
+        for _ in 0..self.read_timeout() {
+            if self.read_ready().map_err(|_| UartError::Read)? {
+                return Ok(());
+            }
         }
 
-        if header.length != 0 {
-            // TODO warn because packet size should be zero
+        Err(UartError::Timeout)
+    }
+
+    /// Waits for an ACK to be recieved. DEBUG packets the host sends first are skipped, but only up
+    /// to [`MAX_SKIPPED_DEBUG`] of them so a chatty host can't keep us waiting forever.
+    fn wait_for_ack(&mut self) -> Result<(), UartError> {
+        for _ in 0..=MAX_SKIPPED_DEBUG {
+            // The host should respond promptly, so unlike `read_header` this times out
+            let header = self.scan_header(|rw, _| rw.read_u8())?;
+
+            if header.opcode != Opcode::ACK && header.opcode != Opcode::DEBUG {
+                return Err(UartError::UnexpectedPacket(header.opcode));
+            }
+
+            // TODO warn if an ACK has a body, because its size should be zero
             for _ in 0..header.length {
-                self.read(&mut [0u8]).unwrap();
+                self.read_u8()?;
+            }
+
+            if header.opcode == Opcode::ACK {
+                return Ok(());
             }
         }
-    }
 
-    fn read_u8(&mut self) -> u8 {
-        let mut buf = [0u8];
-        self.read_exact(&mut buf).unwrap();
-        buf[0]
+        Err(UartError::Timeout)
     }
 
-    fn read_u16(&mut self) -> u16 {
-        let mut buf = [0u8; 2];
-        self.read_exact(&mut buf).unwrap();
-        u16::from_le_bytes(buf)
+    fn read_u8(&mut self) -> Result<u8, UartError> {
+        self.wait_for_byte()?;
+        let mut buf = [0u8];
+        self.read_exact(&mut buf).map_err(|_| UartError::Read)?;
+        Ok(buf[0])
     }
 
     fn write_u8(&mut self, data: u8) {
         self.write_all(&data.to_le_bytes()).unwrap();
     }
 
-    fn write_u16(&mut self, data: u16) {
-        self.write_all(&data.to_le_bytes()).unwrap();
+    /// Reads a packet header. Any bytes before the magic character are discarded, so calling this
+    /// again after an error resyncs on the next packet.
+    fn read_header(&mut self) -> Result<MessageHeader, UartError> {
+        self.scan_header(|rw, waiting_for_magic| {
+            if waiting_for_magic {
+                // Block until we get the magic character
+                let mut buf = [0u8];
+                rw.read_exact(&mut buf).map_err(|_| UartError::Read)?;
+                Ok(buf[0])
+            } else {
+                rw.read_u8()
+            }
+        })
     }
 
-    /// Reads a packet header.
-    fn read_header(&mut self) -> MessageHeader {
-        // Block until we get the magic character
-        let mut buf = [0u8];
-        while buf[0] != MAGIC {
-            self.read_exact(&mut buf).unwrap();
-        }
+    /// Finds the next header, reading each byte with `read`, which is told whether we're still
+    /// waiting for a magic character. When a sequenced header's CRC doesn't match we start looking
+    /// again just after its magic character, since the real header might start in the bytes we
+    /// already read. An intact header with an unknown opcode is an error. Its body is read past
+    /// first, so nothing in it is taken for the next header.
+    fn scan_header(&mut self, mut read: impl FnMut(&mut Self, bool) -> Result<u8, UartError>) -> Result<MessageHeader, UartError> {
+        let mut buf = [0u8; SEQUENCED_HEADER_SIZE];
+        let mut len = 0;
+
+        loop {
+            // Drop anything before a magic character. Which one it is says how long the header is.
+            let size = loop {
+                if let Some(size) = header_size(buf[0]).filter(|_| len > 0) {
+                    break size;
+                }
+                buf[0] = read(self, true)?;
+                len = 1;
+            };
+            while len < size {
+                buf[len] = read(self, false)?;
+                len += 1;
 
-        let opcode = Opcode(self.read_u8());
-        let length = self.read_u16();
+                // A magic character where the opcode should be means this one was a stray. Reading
+                // a whole header from it would lose the start of the packet after it.
+                if len == 2 && header_size(buf[1]).is_some() {
+                    break;
+                }
+            }
 
-        MessageHeader {
-            magic: MAGIC,
-            opcode,
-            length
+            let header = if len == size { parse_header(&buf[..size]) } else { None };
+            match header {
+                Some(Ok(header)) => return Ok(header),
+                Some(Err(unknown)) => {
+                    // The host may never send the body once it has our error, so stop when it goes quiet
+                    for _ in 0..unknown.length {
+                        if read(self, false).is_err() {
+                            break;
+                        }
+                    }
+                    return Err(unknown.opcode.into());
+                }
+                None => {}
+            }
+
+            let next = buf[1..len].iter().position(|&b| header_size(b).is_some()).map_or(len, |i| i + 1);
+            buf.copy_within(next..len, 0);
+            len -= next;
         }
     }
 
@@ -70,25 +167,229 @@ pub trait RawRW: Sized + embedded_io::Read + embedded_io::Write {
         self.write_header(Opcode::ACK, 0);
     }
 
+    /// Asks the host to resend the sequenced packet `number`, whose body was damaged or cut short.
+    fn write_nack(&mut self, number: u8) {
+        self.write_header(Opcode::NACK, 1);
+        self.write_u8(number);
+    }
+
     /// Writes a packet header.
     fn write_header(&mut self, opcode: Opcode, length: u16) {
-        self.write_u8(MAGIC);
-        self.write_u8(opcode.0);
-        self.write_u16(length);
+        self.write_all(&header_bytes(opcode, length)).unwrap();
     }
 
+    /// Sends a debug message to the host. Messages too long for a packet are cut off.
     #[allow(dead_code)]
     fn write_debug(&mut self, msg: &str) {
+        let msg = &msg.as_bytes()[..msg.len().min(MAX_BODY_SIZE)];
         self.write_header(Opcode::DEBUG, msg.len() as u16);
-        for b in msg.as_bytes() {
+        for b in msg {
             self.write_u8(*b);
         }
     }
 
-    fn write_error(&mut self, error: &str) {
-        self.write_header(Opcode::ERROR, error.len() as u16);
-        for b in error.as_bytes() {
-            self.write_u8(*b);
+    /// Sends `error` to the host. It's formatted straight onto the wire, without allocating. Errors
+    /// too long for a packet are cut off, so the length in the header is always right.
+    fn write_error(&mut self, error: impl fmt::Display) {
+        let mut length = Count(0);
+        let _ = write!(length, "{}", error);
+        let length = length.0.min(MAX_BODY_SIZE);
+
+        self.write_header(Opcode::ERROR, length as u16);
+        let _ = write!(Bytes(self, length), "{}", error);
+    }
+}
+
+/// Counts the bytes that would be written.
+struct Count(usize);
+
+impl Write for Count {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Writes to the host byte by byte, dropping anything past the number of bytes left.
+struct Bytes<'l, RW: RawRW>(&'l mut RW, usize);
+
+impl<RW: RawRW> Write for Bytes<'_, RW> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = &s.as_bytes()[..s.len().min(self.1)];
+        for b in bytes {
+            self.0.write_u8(*b);
+        }
+        self.1 -= bytes.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uart::{mem_rw::MemRW, packet::{crc16, sequenced_header_bytes, Sequence, HEADER_SIZE, MAGIC, SEQUENCED_MAGIC}};
+
+    use super::*;
+
+    #[test]
+    fn test_read_header_resyncs_after_garbage() {
+        let input = [&b"\x00\x13garbage\xff"[..], &header_bytes(Opcode::LIST, 0), &header_bytes(Opcode::DECODE, 16)].concat();
+        let mut rw = MemRW::new(&input);
+
+        let header = rw.read_header().unwrap();
+        assert_eq!(header.opcode, Opcode::LIST);
+        assert_eq!(header.length, 0);
+
+        let header = rw.read_header().unwrap();
+        assert_eq!(header.opcode, Opcode::DECODE);
+        assert_eq!(header.length, 16);
+    }
+
+    #[test]
+    fn test_read_header_skips_corrupted_length() {
+        let mut corrupted = sequenced_header_bytes(Opcode::DECODE, 0, &[0; 16]);
+        corrupted[3] ^= 0x04;
+        let input = [&corrupted[..], &header_bytes(Opcode::LIST, 0)].concat();
+        let mut rw = MemRW::new(&input);
+
+        let header = rw.read_header().unwrap();
+        assert_eq!(header.opcode, Opcode::LIST);
+        assert_eq!(header.length, 0);
+        assert!(rw.input.is_empty());
+    }
+
+    #[test]
+    fn test_read_header_finds_magic_inside_bad_header() {
+        // A stray magic character right before a real header would make the magic its opcode
+        for stray in [MAGIC, SEQUENCED_MAGIC] {
+            let input = [&[stray][..], &header_bytes(Opcode::LIST, 0), b"body"].concat();
+            let mut rw = MemRW::new(&input);
+
+            let header = rw.read_header().unwrap();
+            assert_eq!(header.opcode, Opcode::LIST);
+            assert_eq!(rw.input, b"body");
         }
     }
+
+    #[test]
+    fn test_read_sequenced_header() {
+        let body = [3, 0, 0, 0];
+        let mut corrupted = sequenced_header_bytes(Opcode::DELETE, 9, &body);
+        corrupted[2] ^= 1;
+
+        // A stray sequenced magic character makes the plain header after it part of a longer one
+        let input = [&corrupted[..], &[SEQUENCED_MAGIC], &header_bytes(Opcode::LIST, 0), &sequenced_header_bytes(Opcode::DELETE, 10, &body)].concat();
+        let mut rw = MemRW::new(&input);
+
+        let header = rw.read_header().unwrap();
+        assert_eq!((header.opcode, header.length, header.sequence), (Opcode::LIST, 0, None));
+
+        let header = rw.read_header().unwrap();
+        assert_eq!((header.opcode, header.length), (Opcode::DELETE, 4));
+        assert_eq!(header.sequence, Some(Sequence { number: 10, body_crc: crc16(&body) }));
+        assert!(rw.input.is_empty());
+    }
+
+    #[test]
+    fn test_opcode_try_from() {
+        for byte in *b"DSLXAEGVINBK" {
+            assert_eq!(Opcode::try_from(byte), Ok(Opcode(byte)));
+        }
+        for byte in [0, b'\n', b'Z', b'a', b'%', 0x7f, 0x80, 0xff] {
+            assert_eq!(Opcode::try_from(byte), Err(UnknownOpcode(byte)));
+        }
+
+        // Opcodes behind features are only known when they're built in
+        assert_eq!(Opcode::try_from(b'M').is_ok(), cfg!(feature = "diagnostics"));
+        assert_eq!(Opcode::try_from(b'T').is_ok(), cfg!(feature = "diagnostics"));
+        assert_eq!(Opcode::try_from(b'R').is_ok(), cfg!(feature = "test-reset"));
+        assert_eq!((0..=u8::MAX).filter(|&b| Opcode::try_from(b).is_ok()).count(), Opcode::KNOWN.len());
+    }
+
+    #[test]
+    fn test_read_header_unknown_opcode() {
+        // An intact header with an unknown opcode is an error, not damage to skip over
+        let input = [&header_bytes(Opcode(b'Z'), 0)[..], &header_bytes(Opcode::LIST, 0)].concat();
+        let mut rw = MemRW::new(&input);
+        assert_eq!(rw.read_header().unwrap_err(), UartError::UnknownOpcode(b'Z'));
+        assert_eq!(rw.read_header().unwrap().opcode, Opcode::LIST);
+
+        // Its body is read past, even when it looks like a header
+        let body = [&header_bytes(Opcode::DELETE, 4)[..], b"body"].concat();
+        for unknown in [&header_bytes(Opcode(b'Z'), body.len() as u16)[..], &sequenced_header_bytes(Opcode(b'Z'), 1, &body)] {
+            let input = [unknown, &body, &header_bytes(Opcode::LIST, 0)].concat();
+            let mut rw = MemRW::new(&input);
+            assert_eq!(rw.read_header().unwrap_err(), UartError::UnknownOpcode(b'Z'));
+            assert_eq!(rw.input, header_bytes(Opcode::LIST, 0));
+        }
+    }
+
+    #[test]
+    fn test_write_error_formats_details() {
+        let mut rw = MemRW::new(b"");
+        rw.write_error(crate::error::DecoderError::Uart(UartError::Timeout));
+        assert_eq!(rw.output, [&header_bytes(Opcode::ERROR, 19)[..], b"UART error: Timeout"].concat());
+
+        let mut rw = MemRW::new(b"");
+        rw.write_error(crate::error::DecoderError::NoSubscription);
+        assert_eq!(rw.output, [&header_bytes(Opcode::ERROR, 25)[..], b"No subscription for frame"].concat());
+    }
+
+    #[test]
+    fn test_write_error_too_long() {
+        struct Long;
+        impl fmt::Display for Long {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                for _ in 0..7000 {
+                    f.write_str("0123456789")?;
+                }
+                Ok(())
+            }
+        }
+
+        // The message is cut off at the longest body a header can describe
+        let mut rw = MemRW::new(b"");
+        rw.write_error(Long);
+        assert_eq!(rw.output[..HEADER_SIZE], header_bytes(Opcode::ERROR, u16::MAX));
+        assert_eq!(rw.output.len(), HEADER_SIZE + MAX_BODY_SIZE);
+
+        let mut rw = MemRW::new(b"");
+        rw.write_debug(&Long.to_string());
+        assert_eq!(rw.output[..HEADER_SIZE], header_bytes(Opcode::DEBUG, u16::MAX));
+        assert_eq!(rw.output.len(), HEADER_SIZE + MAX_BODY_SIZE);
+    }
+
+    #[test]
+    fn test_read_header_truncated() {
+        let mut rw = MemRW::new(&header_bytes(Opcode::LIST, 0)[..3]);
+        assert_eq!(rw.read_header().unwrap_err(), UartError::Timeout);
+    }
+
+    #[test]
+    fn test_wait_for_ack_timeout() {
+        let mut rw = MemRW::new(b"");
+        rw.timeout = 1;
+        assert_eq!(rw.wait_for_ack().unwrap_err(), UartError::Timeout);
+    }
+
+    #[test]
+    fn test_wait_for_ack_rejects_other_packets() {
+        let mut rw = MemRW::new(&[header_bytes(Opcode::DECODE, 0), header_bytes(Opcode::ACK, 0)].concat());
+        assert_eq!(rw.wait_for_ack().unwrap_err(), UartError::UnexpectedPacket(Opcode::DECODE));
+        assert!(rw.wait_for_ack().is_ok());
+    }
+
+    #[test]
+    fn test_wait_for_ack_skips_debug() {
+        let input = [&header_bytes(Opcode::DEBUG, 5)[..], b"hello", &header_bytes(Opcode::ACK, 0)].concat();
+        let mut rw = MemRW::new(&input);
+        assert!(rw.wait_for_ack().is_ok());
+        assert!(rw.input.is_empty());
+    }
+
+    #[test]
+    fn test_wait_for_ack_gives_up_on_debug() {
+        let input = [header_bytes(Opcode::DEBUG, 0); MAX_SKIPPED_DEBUG + 1].concat();
+        let mut rw = MemRW::new(&[&input[..], &header_bytes(Opcode::ACK, 0)].concat());
+        assert_eq!(rw.wait_for_ack().unwrap_err(), UartError::Timeout);
+    }
 }
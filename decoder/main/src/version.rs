@@ -0,0 +1,56 @@
+use alloc::vec::Vec;
+use rkyv::util::AlignedVec;
+
+use crate::{error::DecoderError, keys::{BAUD_RATE, COMPAT_HASH, DECODER_ID, FLASH_MAGIC}, uart::{body_rw::BodyRW, dma::{RxDma, TxDma}, packet::{AckMode, Opcode}, raw_rw::{RawRW, UartError}}};
+
+/// Version of the host/decoder protocol, bumped whenever packets change incompatibly or a host
+/// needs to know the decoder understands something new before sending it. Since 3 hosts can send
+/// sequenced packets, see [`Sequence`](crate::uart::packet::Sequence), and since 4 they can send
+/// several subscriptions in one SUBSCRIBE_BATCH, see [`add_subscriptions`](crate::subscribe::add_subscriptions).
+/// Since 5 the VERSION response ends with the decoder's [`COMPAT_HASH`], and since 6 hosts can
+/// change the device key with a REKEY, see [`rekey_device`](crate::rekey::rekey_device).
+pub const PROTOCOL_VERSION: u8 = 6;
+
+/// Flag in a VERSION packet body that asks for [`AckMode::Bulk`].
+pub const VERSION_FLAG_BULK: u8 = 1;
+
+/// Tells the host which decoder it is talking to, what secrets it was built from, the baud rate it
+/// was built for, and the [`libectf::frame::compat_hash`] of the packet format it was built for.
+pub fn report_version<RW: RawRW, D: RxDma<RW> + TxDma<RW>>(body_rw: &mut BodyRW<RW, D>) -> Result<(), UartError> {
+    let mut output: Vec<u8> = Vec::new();
+
+    // (decoder_id_u32, protocol_version_u8, flash_magic_u32, baud_rate_u32, compat_hash_u32)
+    output.extend_from_slice(&DECODER_ID.to_le_bytes());
+    output.push(PROTOCOL_VERSION);
+    output.extend_from_slice(&FLASH_MAGIC.to_le_bytes());
+    output.extend_from_slice(&BAUD_RATE.to_le_bytes());
+    output.extend_from_slice(&COMPAT_HASH.to_le_bytes());
+
+    body_rw.rw.write_header(Opcode::VERSION, output.len() as u16);
+    body_rw.dma_write_bytes(&output)?;
+    body_rw.finish_write()
+}
+
+/// Handles a VERSION packet whose body is a flags byte picking the [`AckMode`] for the rest of the
+/// session. The response is the same as for a VERSION without a body, and is ACKed in the old
+/// mode. Decoders that don't support this reply with an error instead, so the host knows to keep
+/// ACKing.
+pub fn negotiate_version<RW: RawRW, D: RxDma<RW> + TxDma<RW>>(packet: &AlignedVec, body_rw: &mut BodyRW<RW, D>, ack_mode: &mut AckMode) -> Result<(), DecoderError> {
+    if packet.len() != 1 {
+        return Err(DecoderError::BadVersionRequest);
+    }
+
+    body_rw.wait_for_dma(packet.len())?;
+
+    // The other bits are reserved for later options
+    let flags = packet[0];
+    if flags & !VERSION_FLAG_BULK != 0 {
+        return Err(DecoderError::BadVersionRequest);
+    }
+
+    report_version(body_rw)?;
+
+    // Only switch once the host has the response, so both sides switch together
+    *ack_mode = if flags & VERSION_FLAG_BULK != 0 { AckMode::Bulk } else { AckMode::Ack };
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use crate::pac::{self, wdt0::ctrl::RstLateVal};
+
+/// Watchdog clock cycles without a kick before the decoder is reset. The watchdog runs off PCLK,
+/// which is half the 100 MHz IPO, so this is about 10 seconds. That's far longer than any packet
+/// takes to handle, including compacting flash.
+const RESET_PERIOD: RstLateVal = RstLateVal::Wdt2pow29;
+
+/// Resets the decoder if the main loop stops coming back around, so a wedged UART or DMA transfer
+/// doesn't leave it bricked until it's power cycled. After the reset `Flash::init` runs again, and
+/// the anti-replay state is loaded from the timestamp log, so frames from before the reset are
+/// still rejected.
+///
+/// It's kicked in two places in `main`:
+/// - at the top of the main loop, before each packet is read, so handling one packet has the whole
+///   period
+/// - while waiting for the first byte of a packet, so an idle decoder isn't reset
+pub struct Watchdog(pac::Wdt0);
+
+impl Watchdog {
+    /// Starts the watchdog. Its clock has to be enabled first.
+    pub fn start(wdt: pac::Wdt0) -> Self {
+        // Configure it while it's disabled, then kick it so it starts counting from zero
+        wdt.ctrl().modify(|_, w| w.en().dis());
+        wdt.ctrl().modify(|_, w| w
+            .win_en().clear_bit()
+            .wdt_int_en().dis()
+            .rst_late_val().variant(RESET_PERIOD)
+            .wdt_rst_en().en());
+
+        let mut watchdog = Self(wdt);
+        watchdog.kick();
+        watchdog.0.ctrl().modify(|_, w| w.en().en());
+        watchdog
+    }
+
+    /// Restarts the count towards a reset.
+    pub fn kick(&mut self) {
+        self.0.rst().write(|w| w.reset().seq0());
+        self.0.rst().write(|w| w.reset().seq1());
+    }
+}
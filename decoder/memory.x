@@ -13,7 +13,8 @@ MEMORY {
     ROM         (rx) : ORIGIN = 0x00000000, LENGTH = 0x00010000 
     BOOTLOADER  (rx) : ORIGIN = 0x10000000, LENGTH = 0x0000E000
     FLASH       (rx) : ORIGIN = 0x1000E000, LENGTH = 0x00038000
-    RESERVED    (rw) : ORIGIN = 0x10046000, LENGTH = 0x00038000
+    /* Subscriptions and the timestamp log, see src/flash.rs. Has to be whole pages. */
+    STORAGE     (rw) : ORIGIN = 0x10046000, LENGTH = 0x00038000
     ROM_BL_PAGE (rw) : ORIGIN = 0x1007E000, LENGTH = 0x00002000
     RAM        (rwx) : ORIGIN = 0x20000000, LENGTH = 0x00020000
 }
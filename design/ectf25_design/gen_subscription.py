@@ -15,7 +15,7 @@ from pathlib import Path
 
 from loguru import logger
 
-from ectf25_design_rs import gen_subscription
+from ectf25_design_rs import gen_channel_0_keys, gen_subscription
 
 
 def parse_args():
@@ -55,9 +55,16 @@ def main():
     # Parse the command line arguments
     args = parse_args()
 
-    subscription = gen_subscription(
-        args.secrets_file.read(), args.device_id, args.start, args.end, args.channel
-    )
+    # Channel 0 can't be subscribed to, but decoders built with the channel-0-refresh feature
+    # take channel 0 keys for a window in its place
+    if args.channel == 0:
+        subscription = gen_channel_0_keys(
+            args.secrets_file.read(), args.device_id, args.start, args.end
+        )
+    else:
+        subscription = gen_subscription(
+            args.secrets_file.read(), args.device_id, args.start, args.end, args.channel
+        )
 
     # Print the generated subscription for your own debugging
     # Attackers will NOT have access to the output of this (although they may have
@@ -1,45 +1,289 @@
-use libectf::{frame::Frame, subscription::SubscriptionData};
-use pyo3::prelude::*;
+use std::{mem, slice};
+
+use libectf::{frame::{compat_hash, ArchivedEncodedFramePacket, DecodeError, Frame, ParseError, ENCODED_FRAME_PACKET_SIZE, FRAME_SIZE, FULL_FRAME_LENGTH, MIN_RSA_KEY_BITS, NUM_ENCRYPTED_KEYS, RSA_KEY_BITS}, masks::MASKS, key::{Key, KEY_SIZE_BYTES}, rekey::wrap_device_key, secrets::{self, Secrets}, subscription::{ArchivedEncodedSubscriptionKey, ArchivedSubscriptionDataHeader, SubscriptionData, SubscriptionError}};
+use pyo3::{exceptions::PyValueError, prelude::*};
 use rand::rngs::OsRng;
-use rsa::{pkcs1::EncodeRsaPrivateKey, pkcs1v15::SigningKey, sha2::Sha256, RsaPrivateKey};
+use rkyv::util::AlignedVec;
+use rsa::{pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey}, pkcs1v15::SigningKey, sha2::Sha256, signature::Keypair, RsaPrivateKey};
 
-#[pyclass]
+/// Encodes frames with the key from a secrets file. Raises a `ValueError` if the secrets are
+/// malformed or their key can't be loaded.
+#[pyclass(module = "ectf25_design_rs")]
 struct Encoder {
-    secrets: Vec<u8>
+    secrets: Secrets,
+    /// Parsed from the secrets once, since parsing it dominates the cost of encoding a frame
+    signing_key: SigningKey<Sha256>,
 }
 
 #[pymethods]
 impl Encoder {
     #[new]
-    fn new(secrets: Vec<u8>) -> Self {
-        Self { secrets }
+    fn new(secrets: Vec<u8>) -> PyResult<Self> {
+        let secrets = parse_secrets(&secrets)?;
+        let signing_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets.key)
+            .map_err(|e| PyValueError::new_err(format!("Invalid secrets: {:?}", e)))?;
+        Ok(Self { secrets, signing_key })
+    }
+
+    /// Number of encrypted copies of the frame key in each encoded frame packet, one for each mask
+    /// width the secrets were generated for. Secrets for other mask widths than this build's are
+    /// refused, so it's always `NUM_ENCRYPTED_KEYS`.
+    ///
+    /// >>> Encoder(gen_secrets([1])).num_encoded_frames() == NUM_ENCRYPTED_KEYS
+    /// True
+    fn num_encoded_frames(&self) -> usize {
+        self.secrets.masks.as_ref().map_or(NUM_ENCRYPTED_KEYS, |masks| masks.len())
+    }
+
+    /// Encode a frame for a channel. Raises a `ValueError` if the frame isn't exactly `FRAME_SIZE`
+    /// (64) bytes.
+    ///
+    /// >>> Encoder(gen_secrets([1])).encode(1, b"too short", 0)
+    /// Traceback (most recent call last):
+    /// ...
+    /// ValueError: Frame must be 64 bytes, got 9
+    fn encode(&self, channel: u32, frame: Vec<u8>, timestamp: u64) -> PyResult<Vec<u8>> {
+        let len = frame.len();
+        let frame = Frame(frame.try_into().map_err(|_| PyValueError::new_err(format!("Frame must be {} bytes, got {}", FRAME_SIZE, len)))?);
+        Ok(self.encode_frame(&frame, timestamp, channel, FULL_FRAME_LENGTH))
+    }
+
+    /// Encode a payload of up to `FRAME_SIZE` (64) bytes for a channel. Short payloads are zero
+    /// padded, and the decoder only outputs the payload. Raises a `ValueError` if the payload is
+    /// longer than a frame, rather than cutting it short.
+    ///
+    /// >>> Encoder(gen_secrets([1])).encode_payload(1, bytes(65), 0)
+    /// Traceback (most recent call last):
+    /// ...
+    /// ValueError: Payload can be at most 64 bytes, got 65
+    fn encode_payload(&self, channel: u32, payload: Vec<u8>, timestamp: u64) -> PyResult<Vec<u8>> {
+        if payload.len() > FRAME_SIZE {
+            return Err(PyValueError::new_err(format!("Payload can be at most {} bytes, got {}", FRAME_SIZE, payload.len())));
+        }
+        Ok(self.encode_frame(&Frame::from_payload(&payload), timestamp, channel, payload.len() as u8))
+    }
+
+    /// Encode a list of `(channel, frame, timestamp)` tuples in one call, which is quicker than
+    /// calling `encode` for each. Every frame is checked before any are encoded, and a `ValueError`
+    /// naming the first frame that isn't exactly `FRAME_SIZE` bytes is raised for the whole batch.
+    ///
+    /// >>> Encoder(gen_secrets([1])).encode_many([(1, bytes(64), 0), (1, b"too short", 1)])
+    /// Traceback (most recent call last):
+    /// ...
+    /// ValueError: Frame 1 must be 64 bytes, got 9
+    fn encode_many(&self, py: Python<'_>, frames: Vec<(u32, Vec<u8>, u64)>) -> PyResult<Vec<Vec<u8>>> {
+        let frames = frames.into_iter().enumerate().map(|(i, (channel, frame, timestamp))| {
+            let len = frame.len();
+            let frame = Frame(frame.try_into().map_err(|_| PyValueError::new_err(format!("Frame {} must be {} bytes, got {}", i, FRAME_SIZE, len)))?);
+            Ok((channel, frame, timestamp))
+        }).collect::<PyResult<Vec<_>>>()?;
+
+        // Encoding doesn't touch any Python objects, so let other threads run meanwhile
+        Ok(py.allow_threads(|| {
+            frames.iter().map(|(channel, frame, timestamp)| self.encode_frame(frame, *timestamp, *channel, FULL_FRAME_LENGTH)).collect()
+        }))
+    }
+}
+
+impl Encoder {
+    /// Encode a frame whose first `length` bytes are payload and serialize it the way the decoder
+    /// expects to recieve it. Panics if the packet isn't [`ENCODED_FRAME_PACKET_SIZE`], since the
+    /// decoder would reject every frame we send it.
+    fn encode_frame(&self, frame: &Frame, timestamp: u64, channel: u32, length: u8) -> Vec<u8> {
+        let mut res = Vec::with_capacity(ENCODED_FRAME_PACKET_SIZE);
+        frame.encode_into::<_, rkyv::rancor::Error>(timestamp, channel, length, &self.secrets.key, &self.signing_key, &mut res).unwrap();
+        assert_eq!(res.len(), ENCODED_FRAME_PACKET_SIZE, "Encoded frame packet is the wrong size");
+        res
     }
+}
 
-    fn encode(&self, channel: u32, frame: Vec<u8>, timestamp: u64) -> Vec<u8> {
-        let frame = Frame(frame.try_into().unwrap());
-        rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode(timestamp, channel, self.secrets.as_slice())).unwrap().into_vec()
+/// Generate a subscription for a device. Raises a `ValueError` if the time range is inverted, the
+/// channel is the broadcast channel, which every decoder can already decode, or the channel isn't
+/// one of the channels the secrets were generated with.
+///
+/// >>> gen_subscription(gen_secrets([1]), 0xdeadbeef, 100, 10, 1)
+/// Traceback (most recent call last):
+/// ...
+/// ValueError: Subscription start 100 is after end 10
+/// >>> gen_subscription(gen_secrets([1]), 0xdeadbeef, 0, 10, 0)
+/// Traceback (most recent call last):
+/// ...
+/// ValueError: Can't subscribe to channel 0
+/// >>> gen_subscription(gen_secrets([1]), 0xdeadbeef, 0, 10, 2)
+/// Traceback (most recent call last):
+/// ...
+/// ValueError: Unknown channel 2
+///
+/// A decoder that's been rekeyed needs its subscriptions encrypted with the key it was given, which
+/// is passed as `device_key` in place of the one derived from `device_id`.
+#[pyfunction]
+#[pyo3(signature = (secrets, device_id, start, end, channel, device_key = None))]
+fn gen_subscription(secrets: Vec<u8>, device_id: u32, start: u64, end: u64, channel: u32, device_key: Option<Vec<u8>>) -> PyResult<Vec<u8>> {
+    let secrets = parse_secrets(&secrets)?;
+    if !secrets.has_channel(channel) {
+        return Err(PyValueError::new_err(format!("Unknown channel {}", channel)));
     }
+
+    let device_key = match device_key {
+        Some(k) => parse_key(&k)?,
+        None => Key::for_device(device_id, &secrets.key),
+    };
+    let data = SubscriptionData::generate_for_key(&secrets.key, start, end, channel, Some(&device_key)).map_err(|e| match e {
+        SubscriptionError::InvertedRange { start, end } => PyValueError::new_err(format!("Subscription start {} is after end {}", start, end)),
+        SubscriptionError::Channel0 => PyValueError::new_err("Can't subscribe to channel 0"),
+    })?;
+    Ok(subscription_to_bytes(&data))
 }
 
+/// Generate refreshed channel 0 keys for a device, covering only `start..=end`. Decoders built with
+/// the `channel-0-refresh` feature take them in a SUBSCRIBE packet and use them in place of their
+/// built in channel 0 keys. Raises a `ValueError` if the time range is inverted.
+///
+/// >>> gen_channel_0_keys(gen_secrets([1]), 0xdeadbeef, 100, 10)
+/// Traceback (most recent call last):
+/// ...
+/// ValueError: Channel 0 window start 100 is after end 10
 #[pyfunction]
-fn gen_subscription(secrets: Vec<u8>, device_id: u32, start: u64, end: u64, channel: u32) -> Vec<u8> {
-    let data = SubscriptionData::generate(secrets.as_slice(), start, end, channel, Some(device_id));
+fn gen_channel_0_keys(secrets: Vec<u8>, device_id: u32, start: u64, end: u64) -> PyResult<Vec<u8>> {
+    let secrets = parse_secrets(&secrets)?;
 
-    let mut res = rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap().into_vec();
-    
-    for key in data.keys {
-        res.extend(rkyv::to_bytes::<rkyv::rancor::Error>(&key).unwrap().into_iter());
+    let data = SubscriptionData::generate_channel_0_window(&secrets.key, start, end, Some(device_id)).map_err(|e| match e {
+        SubscriptionError::InvertedRange { start, end } => PyValueError::new_err(format!("Channel 0 window start {} is after end {}", start, end)),
+        SubscriptionError::Channel0 => unreachable!(),
+    })?;
+    Ok(subscription_to_bytes(&data))
+}
+
+/// The device key a decoder is built with, derived from the secrets and its ID. It's the key the
+/// first REKEY sent to that decoder has to be wrapped with.
+#[pyfunction]
+fn device_key(secrets: Vec<u8>, device_id: u32) -> PyResult<Vec<u8>> {
+    let secrets = parse_secrets(&secrets)?;
+    Ok(Key::for_device(device_id, &secrets.key).0.to_vec())
+}
+
+/// Generate the body of a REKEY packet, which moves a decoder from device key `current_key` to
+/// `new_key` without reflashing it. Raises a `ValueError` unless both keys are `KEY_SIZE_BYTES`
+/// long.
+///
+/// >>> gen_rekey(bytes(16), bytes(15))
+/// Traceback (most recent call last):
+/// ...
+/// ValueError: Device keys must be 16 bytes, got 15
+#[pyfunction]
+fn gen_rekey(current_key: Vec<u8>, new_key: Vec<u8>) -> PyResult<Vec<u8>> {
+    Ok(wrap_device_key(&parse_key(&current_key)?, &parse_key(&new_key)?).to_vec())
+}
+
+/// Decode a frame the same way the decoder does, using a subscription generated for `device_id`.
+/// Returns the frame's payload, or raises a `ValueError` if the frame can't be decoded or doesn't
+/// authenticate. Channel 0 frames are decoded with the keys built into every decoder, so the
+/// subscription isn't used for them.
+#[pyfunction]
+fn decode(secrets: Vec<u8>, subscription: Vec<u8>, encoded_frame: Vec<u8>, device_id: u32) -> PyResult<Vec<u8>> {
+    let secrets = parse_secrets(&secrets)?.key;
+    let header_size = mem::size_of::<ArchivedSubscriptionDataHeader>();
+    let key_size = mem::size_of::<ArchivedEncodedSubscriptionKey>();
+
+    // Copy the frame into an aligned buffer so we can read it in place like the decoder does
+    let mut frame_bytes: AlignedVec = AlignedVec::with_capacity(encoded_frame.len());
+    frame_bytes.extend_from_slice(&encoded_frame);
+    let encoded_frame = ArchivedEncodedFramePacket::from_bytes(&frame_bytes).map_err(|e| match e {
+        ParseError::UnsupportedVersion(version) => PyValueError::new_err(format!("Unsupported frame format version: {version}")),
+        _ => PyValueError::new_err("Unexpected frame packet size"),
+    })?;
+
+    // The decoder's channel 0 keys are generated the same way at build time, and aren't encrypted
+    // with the device key
+    let channel_0 = encoded_frame.header.channel == 0;
+    let subscription = if channel_0 {
+        subscription_to_bytes(&SubscriptionData::generate_channel_0(&secrets))
+    } else {
+        subscription
+    };
+
+    if subscription.len() < header_size || !(subscription.len() - header_size).is_multiple_of(key_size) {
+        return Err(PyValueError::new_err("Unexpected subscription size"));
     }
+    let mut subscription_bytes: AlignedVec = AlignedVec::with_capacity(subscription.len());
+    subscription_bytes.extend_from_slice(&subscription);
 
-    res
+    let subscription_header = unsafe { rkyv::access_unchecked::<ArchivedSubscriptionDataHeader>(&subscription_bytes[..header_size]) };
+    let subscription_keys = unsafe {
+        slice::from_raw_parts(
+            subscription_bytes[header_size..].as_ptr() as *const ArchivedEncodedSubscriptionKey,
+            (subscription_bytes.len() - header_size) / key_size
+        )
+    };
+
+    let (key, mask_idx) = subscription_header.key_for_frame(&encoded_frame.header, subscription_keys)
+        .ok_or_else(|| PyValueError::new_err("No subscription for frame"))?;
+
+    // Subscription keys are encrypted with the device key in transport
+    let mut subscription_key = key.key.0;
+    if !channel_0 {
+        Key::for_device(device_id, &secrets).cipher().decrypt(&mut subscription_key);
+    }
+
+    let verifying_key = SigningKey::<Sha256>::from_pkcs1_der(&secrets)
+        .map_err(|e| PyValueError::new_err(format!("Invalid secrets: {:?}", e)))?
+        .verifying_key();
+
+    // Decrypt the frame key with the subscription key, then the frame with the frame key
+    let frame = encoded_frame.decode(&Key(subscription_key), mask_idx, &verifying_key).map_err(|e| match e {
+        DecodeError::InvalidSignature(e) => PyValueError::new_err(format!("Signature invalid: {:?}", e)),
+        DecodeError::BadSignature => PyValueError::new_err("Frame validation failed"),
+        DecodeError::BadLength(len) => PyValueError::new_err(format!("Frame payload too long: {}", len)),
+    })?;
+
+    Ok(frame.payload(encoded_frame.header.length()).to_vec())
 }
 
+/// Generate secrets for a set of channels. Channel 0 is always valid and doesn't need to be
+/// listed. The size of the frame signing key is fixed when the module is built, so `key_bits` can
+/// only be that size and raises a `ValueError` otherwise. Keys smaller than `MIN_RSA_KEY_BITS` are
+/// always refused.
+///
+/// >>> gen_secrets([1], key_bits=512)  # doctest: +ELLIPSIS
+/// Traceback (most recent call last):
+/// ...
+/// ValueError: RSA keys must be at least ... bits, not 512
 #[pyfunction]
-#[allow(unused_variables)]
-fn gen_secrets(channels: Vec<u32>) -> Vec<u8> {
-    let private_key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+#[pyo3(signature = (channels, key_bits = RSA_KEY_BITS))]
+fn gen_secrets(channels: Vec<u32>, key_bits: usize) -> PyResult<Vec<u8>> {
+    if key_bits < MIN_RSA_KEY_BITS {
+        return Err(PyValueError::new_err(format!("RSA keys must be at least {} bits, not {}", MIN_RSA_KEY_BITS, key_bits)));
+    }
+    if key_bits != RSA_KEY_BITS {
+        return Err(PyValueError::new_err(format!("Frames are signed with {} bit keys, not {}", RSA_KEY_BITS, key_bits)));
+    }
+
+    let private_key = RsaPrivateKey::new(&mut OsRng, key_bits).unwrap();
     let signing_key = SigningKey::<Sha256>::new(private_key);
-    signing_key.to_pkcs1_der().unwrap().as_bytes().to_vec()
+    let key = signing_key.to_pkcs1_der().unwrap().as_bytes().to_vec();
+
+    Ok(Secrets { channels: Some(channels), masks: Some(MASKS.to_vec()), key }.to_bytes())
+}
+
+/// Serialize a subscription the way the decoder expects to recieve it.
+fn subscription_to_bytes(data: &SubscriptionData) -> Vec<u8> {
+    let mut res = rkyv::to_bytes::<rkyv::rancor::Error>(&data.header).unwrap().into_vec();
+
+    for key in &data.keys {
+        res.extend_from_slice(&rkyv::to_bytes::<rkyv::rancor::Error>(key).unwrap());
+    }
+
+    res
+}
+
+/// Parse a device key, raising a `ValueError` if it's the wrong size.
+fn parse_key(key: &[u8]) -> PyResult<Key> {
+    Ok(Key(key.try_into().map_err(|_| PyValueError::new_err(format!("Device keys must be {} bytes, got {}", KEY_SIZE_BYTES, key.len())))?))
+}
+
+/// Parse a secrets file, raising a `ValueError` if it's malformed.
+fn parse_secrets(secrets: &[u8]) -> PyResult<Secrets> {
+    secrets::parse_secrets(secrets).map_err(|e| PyValueError::new_err(format!("Invalid secrets: {}", e)))
 }
 
 #[pymodule]
@@ -47,6 +291,197 @@ fn ectf25_design_rs(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Encoder>()?;
     m.add_function(wrap_pyfunction!(gen_secrets, m)?)?;
     m.add_function(wrap_pyfunction!(gen_subscription, m)?)?;
+    m.add_function(wrap_pyfunction!(gen_channel_0_keys, m)?)?;
+    m.add_function(wrap_pyfunction!(device_key, m)?)?;
+    m.add_function(wrap_pyfunction!(gen_rekey, m)?)?;
+    m.add_function(wrap_pyfunction!(decode, m)?)?;
+    // So host tools size frames the same way the encoder and decoder do
+    m.add("FRAME_SIZE", FRAME_SIZE)?;
+    m.add("ENCODED_FRAME_PACKET_SIZE", ENCODED_FRAME_PACKET_SIZE)?;
+    m.add("NUM_ENCRYPTED_KEYS", NUM_ENCRYPTED_KEYS)?;
+    // What a decoder built for the same packet format reports in VERSION
+    m.add("COMPAT_HASH", compat_hash())?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use libectf::frame::{ArchivedEncodedFramePacketHeader, FRAME_FORMAT_VERSION, FRAME_PREFIX_SIZE};
+
+    use super::*;
+
+    const DEVICE_ID: u32 = 0xdeadbeef;
+
+    /// The message of a Python exception.
+    fn message(e: PyErr) -> String {
+        Python::with_gil(|py| e.value(py).to_string())
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        pyo3::prepare_freethreaded_python();
+
+        let secrets = gen_secrets(vec![1], RSA_KEY_BITS).unwrap();
+        let subscription = gen_subscription(secrets.clone(), DEVICE_ID, 100, 200, 1, None).unwrap();
+        let encoder = Encoder::new(secrets.clone()).unwrap();
+        let frame = vec![7; FRAME_SIZE];
+
+        let encoded = encoder.encode(1, frame.clone(), 150).unwrap();
+        assert_eq!(encoded.len(), ENCODED_FRAME_PACKET_SIZE);
+        assert_eq!(decode(secrets.clone(), subscription.clone(), encoded.clone(), DEVICE_ID).unwrap(), frame);
+
+        // Channel 0 frames don't need a subscription
+        let encoded_0 = encoder.encode(0, frame.clone(), 150).unwrap();
+        assert_eq!(decode(secrets.clone(), Vec::new(), encoded_0, DEVICE_ID).unwrap(), frame);
+
+        // Short payloads come back without their padding
+        for len in [1, 63, 64] {
+            let payload: Vec<u8> = (0..len).collect();
+            let encoded = encoder.encode_payload(1, payload.clone(), 150).unwrap();
+            assert_eq!(decode(secrets.clone(), subscription.clone(), encoded, DEVICE_ID).unwrap(), payload);
+        }
+
+        // Frames outside the subscription can't be decoded
+        let outside = encoder.encode(1, frame.clone(), 250).unwrap();
+        assert_eq!(message(decode(secrets.clone(), subscription.clone(), outside, DEVICE_ID).unwrap_err()), "No subscription for frame");
+
+        // Neither can frames with a tampered signature
+        let mut tampered = encoded.clone();
+        tampered[FRAME_PREFIX_SIZE + mem::offset_of!(ArchivedEncodedFramePacketHeader, signature)] ^= 1;
+        assert_eq!(message(decode(secrets.clone(), subscription.clone(), tampered, DEVICE_ID).unwrap_err()), "Frame validation failed");
+
+        // Or length, which would cut the payload short
+        let mut shortened = encoded.clone();
+        shortened[FRAME_PREFIX_SIZE + mem::offset_of!(ArchivedEncodedFramePacketHeader, length)] = 1;
+        assert_eq!(message(decode(secrets.clone(), subscription.clone(), shortened, DEVICE_ID).unwrap_err()), "Frame validation failed");
+
+        // Or frames in another format
+        let mut future = encoded;
+        future[0] = FRAME_FORMAT_VERSION + 1;
+        assert_eq!(message(decode(secrets, subscription, future, DEVICE_ID).unwrap_err()), format!("Unsupported frame format version: {}", FRAME_FORMAT_VERSION + 1));
+    }
+
+    #[test]
+    fn test_num_encoded_frames() {
+        pyo3::prepare_freethreaded_python();
+
+        let encoder = Encoder::new(gen_secrets(vec![1], RSA_KEY_BITS).unwrap()).unwrap();
+        let encoded = encoder.encode(1, vec![7; FRAME_SIZE], 1000).unwrap();
+
+        let packet = libectf::frame::EncodedFramePacket::try_from_bytes(&encoded).unwrap();
+        assert_eq!(packet.keys.len(), encoder.num_encoded_frames());
+        assert_eq!(encoded.len(), FRAME_PREFIX_SIZE + mem::size_of::<ArchivedEncodedFramePacket>());
+
+        // Secrets for another mask table can't be used to encode
+        let mut secrets = parse_secrets(&gen_secrets(vec![1], RSA_KEY_BITS).unwrap()).unwrap();
+        secrets.masks = Some(MASKS[1..].to_vec());
+        let e = Encoder::new(secrets.to_bytes()).err().unwrap();
+        assert!(message(e).starts_with("Invalid secrets: secrets are for mask widths"));
+    }
+
+    #[test]
+    fn test_encode_many() {
+        pyo3::prepare_freethreaded_python();
+
+        let encoder = Encoder::new(gen_secrets(vec![1, 2], RSA_KEY_BITS).unwrap()).unwrap();
+        let frames: Vec<(u32, Vec<u8>, u64)> = (0..50).map(|i| (i % 3, vec![i as u8; FRAME_SIZE], i as u64 * 10)).collect();
+
+        let start = std::time::Instant::now();
+        let separate: Vec<Vec<u8>> = frames.iter().map(|(c, f, t)| encoder.encode(*c, f.clone(), *t).unwrap()).collect();
+        let separate_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let batch = Python::with_gil(|py| encoder.encode_many(py, frames.clone())).unwrap();
+        let batch_time = start.elapsed();
+
+        println!("{} frames: {:?} with encode, {:?} with encode_many", frames.len(), separate_time, batch_time);
+        assert_eq!(batch, separate);
+
+        // One bad frame fails the whole batch
+        let mut bad = frames;
+        bad[7].1.pop();
+        let err = Python::with_gil(|py| encoder.encode_many(py, bad)).unwrap_err();
+        assert_eq!(message(err), format!("Frame 7 must be {} bytes, got {}", FRAME_SIZE, FRAME_SIZE - 1));
+    }
+
+    #[test]
+    fn test_encoder_reuses_signing_key() {
+        pyo3::prepare_freethreaded_python();
+
+        let secrets = gen_secrets(vec![1], RSA_KEY_BITS).unwrap();
+        let encoder = Encoder::new(secrets.clone()).unwrap();
+        let key = parse_secrets(&secrets).unwrap().key;
+        let frame = Frame([3; FRAME_SIZE]);
+
+        // Parsing the key for every frame, like Frame::encode does
+        let start = std::time::Instant::now();
+        let parsed: Vec<Vec<u8>> = (0..20).map(|t| rkyv::to_bytes::<rkyv::rancor::Error>(&frame.encode(t, 1, FULL_FRAME_LENGTH, &key)).unwrap().into_vec()).collect();
+        let parsed_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let cached: Vec<Vec<u8>> = (0..20).map(|t| encoder.encode_frame(&frame, t, 1, FULL_FRAME_LENGTH)).collect();
+        let cached_time = start.elapsed();
+
+        println!("20 frames: {:?} parsing the key each time, {:?} with it cached", parsed_time, cached_time);
+        let cached: Vec<Vec<u8>> = cached.into_iter().map(|packet| packet[FRAME_PREFIX_SIZE..].to_vec()).collect();
+        assert_eq!(cached, parsed);
+    }
+
+    #[test]
+    fn test_encoder_invalid_key() {
+        pyo3::prepare_freethreaded_python();
+
+        let secrets = Secrets { channels: Some(vec![1]), masks: None, key: b"not a key".to_vec() }.to_bytes();
+        let err = Encoder::new(secrets).err().unwrap();
+        assert_eq!(message(err), "Invalid secrets: secrets key isn't a PKCS#1 RSA private key");
+    }
+
+    #[test]
+    fn test_gen_secrets_key_bits() {
+        pyo3::prepare_freethreaded_python();
+
+        assert_eq!(message(gen_secrets(vec![1], 512).unwrap_err()), format!("RSA keys must be at least {} bits, not 512", MIN_RSA_KEY_BITS));
+        assert_eq!(message(gen_secrets(vec![1], RSA_KEY_BITS * 2).unwrap_err()), format!("Frames are signed with {} bit keys, not {}", RSA_KEY_BITS, RSA_KEY_BITS * 2));
+    }
+
+    #[test]
+    fn test_gen_subscription_channel_0() {
+        pyo3::prepare_freethreaded_python();
+        let secrets = gen_secrets(vec![1], RSA_KEY_BITS).unwrap();
+
+        // Every decoder already has the channel 0 keys, so there's nothing to subscribe to
+        assert_eq!(message(gen_subscription(secrets.clone(), 1, 0, 10, 0, None).unwrap_err()), "Can't subscribe to channel 0");
+        assert!(gen_subscription(secrets, 1, 0, 10, 1, None).is_ok());
+    }
+
+    #[test]
+    fn test_gen_rekey() {
+        pyo3::prepare_freethreaded_python();
+        let secrets = gen_secrets(vec![1], RSA_KEY_BITS).unwrap();
+        let current = device_key(secrets.clone(), DEVICE_ID).unwrap();
+        let new = vec![0x5a; KEY_SIZE_BYTES];
+
+        let body = gen_rekey(current.clone(), new.clone()).unwrap();
+        let unwrapped = libectf::rekey::unwrap_device_key(&parse_key(&current).unwrap(), &body).unwrap();
+        assert_eq!(unwrapped.0.to_vec(), new);
+        assert_eq!(message(gen_rekey(current.clone(), vec![0; 15]).unwrap_err()), "Device keys must be 16 bytes, got 15");
+
+        // Subscriptions for a rekeyed decoder are the same as ones for its ID, just under the new key
+        assert_eq!(gen_subscription(secrets.clone(), DEVICE_ID, 0, 10, 1, None).unwrap(), gen_subscription(secrets.clone(), DEVICE_ID, 0, 10, 1, Some(current)).unwrap());
+        assert_ne!(gen_subscription(secrets.clone(), DEVICE_ID, 0, 10, 1, None).unwrap(), gen_subscription(secrets, DEVICE_ID, 0, 10, 1, Some(new)).unwrap());
+    }
+
+    #[test]
+    fn test_gen_channel_0_keys() {
+        pyo3::prepare_freethreaded_python();
+        let secrets = gen_secrets(vec![1], RSA_KEY_BITS).unwrap();
+
+        assert_eq!(message(gen_channel_0_keys(secrets.clone(), 1, 100, 10).unwrap_err()), "Channel 0 window start 100 is after end 10");
+
+        let mut bytes: AlignedVec = AlignedVec::new();
+        bytes.extend_from_slice(&gen_channel_0_keys(secrets, 1, 10, 100).unwrap());
+        let header = unsafe { rkyv::access_unchecked::<ArchivedSubscriptionDataHeader>(&bytes[..mem::size_of::<ArchivedSubscriptionDataHeader>()]) };
+        assert_eq!((header.channel(), header.start_timestamp(), header.end_timestamp()), (0, 10, 100));
+    }
+}
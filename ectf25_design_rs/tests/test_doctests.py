@@ -0,0 +1,15 @@
+"""Runs the examples in the ectf25_design_rs docstrings against the installed module."""
+
+import doctest
+
+import ectf25_design_rs
+
+
+def test_doctests():
+    results = doctest.testmod(ectf25_design_rs)
+    assert results.attempted > 0, "no examples were found"
+    assert results.failed == 0
+
+
+if __name__ == "__main__":
+    test_doctests()
@@ -152,15 +152,17 @@ class DecoderIntf:
         if resp != Message(Opcode.SUBSCRIBE, b""):
             raise DecoderError(f"Bad subscribe response {resp}")
 
-    def list(self) -> list[tuple[int, int, int]]:
+    def list(self, channel: Optional[int] = None) -> list[tuple[int, int, int]]:
         """List the subscribed channels of a Decoder
 
+        :param channel: Only list subscriptions for this channel
         :returns: A list of tuples containing the subscribed channels and start and end
-            timestamps
+            timestamps, sorted by channel and then start timestamp
         :raises DecoderError: Error on list failure
         """
         # send list message
-        msg = Message(Opcode.LIST, b"")
+        body = b"" if channel is None else struct.pack("<I", channel)
+        msg = Message(Opcode.LIST, body)
         self.send_msg(msg)
 
         # receive response